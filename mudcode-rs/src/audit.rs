@@ -0,0 +1,239 @@
+use crate::receipt::DeliveryReceipt;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+/// One line of the audit log. Field names are a stable schema; add, don't rename.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AuditRecord {
+    #[serde(rename = "timestampMs")]
+    pub timestamp_ms: u64,
+    pub project: String,
+    pub agent: String,
+    #[serde(rename = "channelId")]
+    pub channel_id: String,
+    #[serde(rename = "eventType")]
+    pub event_type: String,
+    #[serde(rename = "contentLength")]
+    pub content_length: usize,
+    #[serde(rename = "fileCount")]
+    pub file_count: usize,
+    pub ok: bool,
+    pub error: Option<String>,
+}
+
+impl AuditRecord {
+    pub fn new(
+        project: &str,
+        agent: &str,
+        event_type: &str,
+        content_length: usize,
+        receipt: &DeliveryReceipt,
+    ) -> Self {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+
+        Self {
+            timestamp_ms,
+            project: project.to_string(),
+            agent: agent.to_string(),
+            channel_id: receipt.channel_id.clone(),
+            event_type: event_type.to_string(),
+            content_length,
+            file_count: receipt.files_sent,
+            ok: receipt.ok,
+            error: receipt.error.clone(),
+        }
+    }
+}
+
+/// Size-based rotation: once a write would push the file past `max_bytes` it is
+/// renamed to `<file>.1` (shifting older ones up) and only `keep` old files are kept.
+/// A zero `max_bytes` disables rotation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rotation {
+    pub max_bytes: u64,
+    pub keep: usize,
+}
+
+/// Append-only JSONL audit log. Records are queued and written by a background
+/// task so handlers never wait on disk; write failures are only logged.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    tx: mpsc::UnboundedSender<AuditRecord>,
+}
+
+impl AuditLog {
+    pub fn open(path: PathBuf, rotation: Rotation) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(write_records(path, rotation, rx));
+        Self { tx }
+    }
+
+    pub fn record(&self, record: AuditRecord) {
+        if self.tx.send(record).is_err() {
+            warn!("audit log writer has stopped; dropping record");
+        }
+    }
+}
+
+// A single task owns the file, so rotation never races with another append.
+async fn write_records(
+    path: PathBuf,
+    rotation: Rotation,
+    mut rx: mpsc::UnboundedReceiver<AuditRecord>,
+) {
+    while let Some(record) = rx.recv().await {
+        let mut batch = vec![record];
+        while let Ok(record) = rx.try_recv() {
+            batch.push(record);
+        }
+
+        let mut lines = String::new();
+        for record in &batch {
+            match serde_json::to_string(record) {
+                Ok(line) => {
+                    lines.push_str(&line);
+                    lines.push('\n');
+                }
+                Err(error) => warn!("failed to serialize audit record: {error}"),
+            }
+        }
+
+        if let Err(error) = append_rotating(&path, lines.as_bytes(), rotation).await {
+            warn!(
+                "failed to write {} audit record(s) to {}: {}",
+                batch.len(),
+                path.display(),
+                error
+            );
+        }
+    }
+}
+
+async fn append_rotating(path: &Path, data: &[u8], rotation: Rotation) -> std::io::Result<()> {
+    if rotation.max_bytes > 0 {
+        let current = tokio::fs::metadata(path).await.map_or(0, |meta| meta.len());
+        if current > 0 && current + data.len() as u64 > rotation.max_bytes {
+            rotate(path, rotation.keep).await?;
+        }
+    }
+
+    append(path, data).await
+}
+
+async fn rotate(path: &Path, keep: usize) -> std::io::Result<()> {
+    let numbered = |n: usize| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{n}"));
+        PathBuf::from(name)
+    };
+
+    if keep == 0 {
+        return tokio::fs::remove_file(path).await;
+    }
+
+    let _ = tokio::fs::remove_file(numbered(keep)).await;
+    for n in (1..keep).rev() {
+        let from = numbered(n);
+        if tokio::fs::try_exists(&from).await.unwrap_or(false) {
+            tokio::fs::rename(&from, numbered(n + 1)).await?;
+        }
+    }
+    tokio::fs::rename(path, numbered(1)).await
+}
+
+async fn append(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(data).await?;
+    file.flush().await
+}
+
+#[cfg(test)]
+pub async fn read_records(path: &Path, count: usize) -> Vec<serde_json::Value> {
+    for _ in 0..200 {
+        let records: Vec<serde_json::Value> = std::fs::read_to_string(path)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect();
+        if records.len() >= count {
+            return records;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    panic!("timed out waiting for {count} audit record(s)");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn appends_one_json_line_per_record() {
+        let dir = TempDir::new("audit");
+        let path = dir.path().join("audit.jsonl");
+        let log = AuditLog::open(path.clone(), Rotation::default());
+
+        let mut receipt = DeliveryReceipt::new("ch-1");
+        receipt.files_sent = 2;
+        log.record(AuditRecord::new(
+            "proj",
+            "claude",
+            "session.idle",
+            5,
+            &receipt,
+        ));
+        receipt.fail(&anyhow::anyhow!("boom"));
+        log.record(AuditRecord::new(
+            "proj",
+            "claude",
+            "send-files",
+            0,
+            &receipt,
+        ));
+
+        let records = read_records(&path, 2).await;
+        assert!(records[0]["timestampMs"].as_u64().unwrap() > 0);
+        assert_eq!(records[0]["eventType"], json!("session.idle"));
+        assert_eq!(records[0]["contentLength"], json!(5));
+        assert_eq!(records[0]["fileCount"], json!(2));
+        assert_eq!(records[0]["ok"], json!(true));
+        assert_eq!(records[1]["ok"], json!(false));
+        assert_eq!(records[1]["error"], json!("boom"));
+    }
+
+    #[tokio::test]
+    async fn rotates_past_max_bytes_and_prunes_beyond_keep() {
+        let dir = TempDir::new("audit-rotate");
+        let path = dir.path().join("audit.jsonl");
+        let rotation = Rotation {
+            max_bytes: 15,
+            keep: 2,
+        };
+
+        for line in ["first-rec\n", "second-re\n", "third-rec\n", "fourth-re\n"] {
+            append_rotating(&path, line.as_bytes(), rotation)
+                .await
+                .unwrap();
+        }
+
+        let read = |suffix: &str| {
+            std::fs::read_to_string(format!("{}{suffix}", path.display())).unwrap_or_default()
+        };
+        assert_eq!(read(""), "fourth-re\n");
+        assert_eq!(read(".1"), "third-rec\n");
+        assert_eq!(read(".2"), "second-re\n");
+        assert!(!dir.path().join("audit.jsonl.3").exists());
+    }
+}
@@ -0,0 +1,181 @@
+use axum::body::{Body, Bytes};
+use axum::extract::{Request, State};
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde_json::Value;
+use std::sync::{Arc, RwLock};
+use tracing::info;
+
+pub const DEFAULT_LOG_BODY_MAX_CHARS: usize = 2000;
+/// Largest body buffered for logging, matching axum's default `Json` limit.
+pub const MAX_LOGGED_BODY_BYTES: usize = 2 * 1024 * 1024;
+const REDACTED: &str = "[redacted]";
+
+/// Debug logging of raw hook request bodies, enabled with `MUDCODE_LOG_BODIES=1`.
+#[derive(Debug, Clone, Default)]
+pub struct BodyLog {
+    pub enabled: bool,
+    pub max_chars: usize,
+    /// Values masked wherever they appear, e.g. the bot token. Shared by clones
+    /// so `/reload` can swap them.
+    secrets: Arc<RwLock<Vec<String>>>,
+}
+
+impl BodyLog {
+    pub fn new(enabled: bool, max_chars: usize, secrets: Vec<String>) -> Self {
+        Self {
+            enabled,
+            max_chars,
+            secrets: Arc::new(RwLock::new(secrets)),
+        }
+    }
+
+    pub fn set_secrets(&self, secrets: Vec<String>) {
+        *self
+            .secrets
+            .write()
+            .unwrap_or_else(std::sync::PoisonError::into_inner) = secrets;
+    }
+
+    /// The body as logged: JSON values under token/secret-like keys and any known
+    /// secret are masked, and the result is cut to `max_chars`.
+    pub fn render(&self, body: &[u8]) -> String {
+        let mut text = match serde_json::from_slice::<Value>(body) {
+            Ok(mut value) => {
+                redact_value(&mut value);
+                value.to_string()
+            }
+            Err(_) => String::from_utf8_lossy(body).into_owned(),
+        };
+        let secrets = self
+            .secrets
+            .read()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        for secret in secrets.iter().filter(|s| !s.is_empty()) {
+            text = text.replace(secret.as_str(), REDACTED);
+        }
+
+        if text.chars().count() > self.max_chars {
+            let cut: String = text.chars().take(self.max_chars).collect();
+            return format!("{cut}… ({} bytes total)", body.len());
+        }
+        text
+    }
+}
+
+fn redact_value(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                let key = key.to_ascii_lowercase();
+                if ["token", "secret", "password", "authorization"]
+                    .iter()
+                    .any(|needle| key.contains(needle))
+                {
+                    *value = Value::String(REDACTED.to_string());
+                } else {
+                    redact_value(value);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_value),
+        _ => {}
+    }
+}
+
+/// Middleware logging the request body before the handler parses it, then
+/// handing the buffered body on unchanged.
+pub async fn log_request_body(
+    State(log): State<BodyLog>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !log.enabled {
+        return next.run(request).await;
+    }
+
+    let (parts, body) = request.into_parts();
+    let bytes: Bytes = match axum::body::to_bytes(body, MAX_LOGGED_BODY_BYTES).await {
+        Ok(bytes) => bytes,
+        Err(_) => return (StatusCode::BAD_REQUEST, "Unreadable body").into_response(),
+    };
+    info!(
+        "request body path={} body={}",
+        parts.uri.path(),
+        log.render(&bytes)
+    );
+
+    next.run(Request::from_parts(parts, Body::from(bytes)))
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use serde_json::json;
+
+    fn body_log(max_chars: usize) -> BodyLog {
+        BodyLog::new(true, max_chars, vec!["bot-token".to_string()])
+    }
+
+    #[test]
+    fn render_redacts_and_truncates() {
+        let body = json!({
+            "projectName": "proj",
+            "auth": { "apiToken": "abc" },
+            "text": "uses bot-token inline",
+        })
+        .to_string();
+
+        let logged = body_log(500).render(body.as_bytes());
+        assert!(logged.contains("\"apiToken\":\"[redacted]\""));
+        assert!(logged.contains("uses [redacted] inline"));
+        assert!(!logged.contains("abc") && !logged.contains("bot-token"));
+
+        let logged = body_log(10).render(b"not json at all, quite long");
+        assert_eq!(logged, "not json a… (27 bytes total)");
+    }
+
+    #[tokio::test]
+    async fn handler_still_parses_the_logged_body() {
+        let app = Router::new()
+            .route(
+                "/echo",
+                post(|Json(value): Json<Value>| async move { Json(value) }),
+            )
+            .layer(axum::middleware::from_fn_with_state(
+                body_log(100),
+                log_request_body,
+            ));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/echo", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let _ = axum::serve(listener, app).await;
+        });
+
+        let payload = json!({ "projectName": "proj", "type": "session.idle" });
+        let echoed: Value = reqwest::Client::new()
+            .post(&url)
+            .json(&payload)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+
+        assert_eq!(echoed, payload);
+
+        let oversized = "x".repeat(MAX_LOGGED_BODY_BYTES + 1);
+        let response = reqwest::Client::new()
+            .post(&url)
+            .body(oversized)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
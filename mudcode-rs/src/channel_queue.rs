@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+type Queue = Arc<tokio::sync::Mutex<()>>;
+
+/// One fair lock per channel id, so work on a channel runs in the order it
+/// queued while other channels proceed in parallel. A channel's entry is dropped
+/// once nobody holds or waits on it.
+#[derive(Debug, Clone, Default)]
+pub struct ChannelQueues(Arc<Mutex<HashMap<String, Queue>>>);
+
+impl ChannelQueues {
+    /// Wait for earlier turns on `channel_id` to finish.
+    pub async fn turn(&self, channel_id: &str) -> ChannelTurn {
+        let queue = Arc::clone(
+            self.0
+                .lock()
+                .unwrap()
+                .entry(channel_id.to_string())
+                .or_default(),
+        );
+        ChannelTurn {
+            guard: Some(queue.lock_owned().await),
+            channel_id: channel_id.to_string(),
+            queues: self.clone(),
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+}
+
+/// Exclusive use of a channel until dropped.
+#[derive(Debug)]
+pub struct ChannelTurn {
+    guard: Option<tokio::sync::OwnedMutexGuard<()>>,
+    channel_id: String,
+    queues: ChannelQueues,
+}
+
+impl Drop for ChannelTurn {
+    fn drop(&mut self) {
+        let mut queues = self.queues.0.lock().unwrap();
+        self.guard = None;
+        // Holders and waiters each keep a clone, so only the map's is left when idle.
+        if queues
+            .get(&self.channel_id)
+            .is_some_and(|queue| Arc::strong_count(queue) == 1)
+        {
+            queues.remove(&self.channel_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn turns_run_in_order_and_idle_channels_are_pruned() {
+        let queues = ChannelQueues::default();
+        let first = queues.turn("ch-1").await;
+        let waiter = {
+            let queues = queues.clone();
+            tokio::spawn(async move { queues.turn("ch-1").await })
+        };
+        let other = queues.turn("ch-2").await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiter.is_finished());
+
+        drop(other);
+        assert_eq!(queues.len(), 1);
+        drop(first);
+        let second = waiter.await.unwrap();
+        assert_eq!(queues.len(), 1);
+        drop(second);
+        assert_eq!(queues.len(), 0);
+    }
+}
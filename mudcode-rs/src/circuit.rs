@@ -0,0 +1,134 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_CIRCUIT_BREAKER_THRESHOLD: usize = 0;
+pub const DEFAULT_CIRCUIT_BREAKER_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// Opens after `threshold` consecutive failures so callers fail fast for
+/// `cooldown`; the first request after the cooldown is a probe that closes the
+/// circuit on success or reopens it on failure, and the others keep failing fast
+/// until it does. A zero threshold disables it.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: usize,
+    cooldown: Duration,
+    inner: Mutex<BreakerState>,
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    consecutive_failures: usize,
+    open_until: Option<Instant>,
+    /// When the half-open probe was let through. A probe that never reports back
+    /// is replaced after another `cooldown`.
+    probe_started: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: usize, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            inner: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    /// `Err` with the remaining cooldown while the circuit is open.
+    pub fn check(&self) -> Result<(), Duration> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        match inner.open_until {
+            Some(until) if until > now => Err(until - now),
+            Some(_) => match inner.probe_started {
+                Some(started) if now - started < self.cooldown => {
+                    Err(self.cooldown - (now - started))
+                }
+                _ => {
+                    // Half-open: let this request through as the only probe.
+                    inner.probe_started = Some(now);
+                    Ok(())
+                }
+            },
+            None => Ok(()),
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures = 0;
+        inner.open_until = None;
+        inner.probe_started = None;
+    }
+
+    /// Count a failure, returning `true` when it opened the circuit.
+    pub fn record_failure(&self) -> bool {
+        if self.threshold == 0 {
+            return false;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures += 1;
+        let probe_failed = inner.probe_started.take().is_some();
+        if probe_failed
+            || (inner.consecutive_failures >= self.threshold && inner.open_until.is_none())
+        {
+            inner.open_until = Some(Instant::now() + self.cooldown);
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_after_threshold_and_fails_fast() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+
+        assert!(!breaker.record_failure());
+        assert!(breaker.check().is_ok());
+        assert!(breaker.record_failure());
+        assert!(breaker.check().is_err());
+    }
+
+    #[test]
+    fn probe_after_cooldown_closes_or_reopens() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(10));
+        assert!(breaker.record_failure());
+        std::thread::sleep(Duration::from_millis(20));
+
+        assert!(breaker.check().is_ok(), "probe allowed after cooldown");
+        assert!(breaker.record_failure(), "failed probe reopens");
+        assert!(breaker.check().is_err());
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(breaker.check().is_ok());
+        breaker.record_success();
+        assert!(breaker.check().is_ok());
+        assert!(breaker.record_failure(), "threshold counts from zero again");
+    }
+
+    #[test]
+    fn half_open_admits_a_single_probe() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(50));
+        assert!(breaker.record_failure());
+        std::thread::sleep(Duration::from_millis(60));
+
+        assert!(breaker.check().is_ok(), "first request is the probe");
+        assert!(breaker.check().is_err(), "others wait for it");
+        breaker.record_success();
+        assert!(breaker.check().is_ok());
+        assert!(breaker.check().is_ok());
+    }
+
+    #[test]
+    fn zero_threshold_never_opens() {
+        let breaker = CircuitBreaker::new(0, Duration::from_secs(60));
+        for _ in 0..10 {
+            assert!(!breaker.record_failure());
+        }
+        assert!(breaker.check().is_ok());
+    }
+}
@@ -1,15 +1,351 @@
+use crate::audit::Rotation;
+use crate::body_log::DEFAULT_LOG_BODY_MAX_CHARS;
+use crate::dampening::{DEFAULT_ERROR_IDLE_WINDOW, DEFAULT_SUBSTANTIVE_IDLE_CHARS};
+use crate::dedup::{DEFAULT_EVENT_DEDUP_CAPACITY, DEFAULT_EVENT_DEDUP_FLUSH_INTERVAL};
+use crate::discord::{AttachmentOrder, ChunkPacing, DiscordSettings, UnknownSize};
+use crate::parser::{
+    DEFAULT_FILE_SEARCH_MAX_BYTES, DEFAULT_FILE_SEARCH_SCAN_BYTES, DISCORD_MAX_MESSAGE_LENGTH,
+    continuation_markers_fit, default_attachment_extensions, default_attachment_prefixes,
+};
+use crate::response::ResponseFormat;
+use crate::state::{ChannelLookup, LegacyChannels, normalize_project_name};
 use anyhow::{Context, anyhow};
-use serde::Deserialize;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fs;
+use std::net::{IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::warn;
 
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 15;
+const DEFAULT_HOOK_SERVER_HOST: &str = "127.0.0.1";
+const DEFAULT_AUDIT_LOG_KEEP: usize = 5;
+const DEFAULT_MAX_GLOB_MATCHES: usize = 50;
+const DEFAULT_MAX_DEFERRED_EVENTS: usize = 100;
+const DEFAULT_MAX_DIRECTORY_FILES: usize = 50;
+/// Event types allowed to create channels or write state unless configured otherwise.
+const DEFAULT_SIDE_EFFECT_EVENT_TYPES: &[&str] = &["session.idle", "send-files"];
+const DEFAULT_FILE_LINK_TEMPLATE: &str = "{baseUrl}/{relativePath}";
+const DEFAULT_FILE_LINK_MIN_BYTES: u64 = 25 * 1024 * 1024;
+
+/// Options shaping how events are turned into Discord messages and attachments.
 #[derive(Debug, Clone)]
+pub struct FormatOptions {
+    /// Add a caption line naming each attached file to uploads.
+    pub attachment_captions: bool,
+    /// Caption prefix per lowercase extension; unknown extensions get a generic prefix.
+    pub attachment_prefixes: HashMap<String, String>,
+    /// Extensions (lowercase, without the dot) of referenced paths that get attached.
+    pub attachment_extensions: Vec<String>,
+    /// Line posted ahead of an upload when stripping paths left no text to send.
+    pub file_only_lead_in: Option<String>,
+    /// Only this many leading bytes of the file-search text are scanned for paths.
+    pub file_search_scan_bytes: usize,
+    /// File-search text larger than this skips path extraction entirely.
+    pub file_search_max_bytes: usize,
+    /// When set, files larger than `file_link_min_bytes` are posted as links
+    /// built from `file_link_template` instead of being uploaded.
+    pub file_link_base_url: Option<String>,
+    pub file_link_template: String,
+    pub file_link_min_bytes: u64,
+    /// Wrap idle text that looks like code (or JSON, pretty-printed) in a fence.
+    pub wrap_code: bool,
+    /// Map smart quotes, dashes and slash lookalikes to ASCII before path extraction.
+    pub normalize_typography: bool,
+    /// Lines of display text matching any of these are dropped before splitting.
+    pub strip_line_patterns: Vec<Regex>,
+    /// Collapse runs of at least this many identical lines into `line  (×N)`.
+    pub collapse_repeated_lines: Option<usize>,
+    /// Send longer idle text as one message cut to this many characters, with
+    /// the full text attached as `full-output.txt`.
+    pub truncate_with_attachment: Option<usize>,
+    /// Percent-decode referenced paths (`my%20file.png`) before validating them.
+    pub decode_percent_paths: bool,
+    /// Also strip paths in the display text that spell a validated file
+    /// differently (e.g. `./` segments or symlinks) from where it was found.
+    pub strip_resolved_paths: bool,
+    /// Also attach project-relative paths (`charts/b.png`), resolved against the
+    /// project root. Absolute paths must still lie inside it.
+    pub relative_paths: bool,
+    /// Longest run of blank lines kept in relayed display text; `None` leaves
+    /// the text's blank lines as they are.
+    pub max_blank_lines: Option<usize>,
+    /// Keep file paths in the text and list the attached basenames in a footer
+    /// instead of stripping them.
+    pub file_footer: bool,
+    /// Re-checks for referenced files that do not exist yet.
+    pub file_settle: FileSettle,
+    /// Marker starting every split chunk after the first, e.g. `… `.
+    pub continuation_prefix: String,
+    /// Marker ending every split chunk before the last.
+    pub continuation_suffix: String,
+    /// Render markdown headings as bold lines.
+    pub bold_headings: bool,
+    /// Idle text chunk size, at most Discord's 2000-character limit. Markers and
+    /// footers are fitted within it.
+    pub max_message_length: usize,
+    /// Event fields scanned for file paths to attach.
+    pub file_scan_scope: FileScanScope,
+    /// Where idle attachments go relative to the text.
+    pub attachment_placement: AttachmentPlacement,
+    /// Upload idle files while the text is still being sent. Only applies to
+    /// `after` placement; `before` and `combined` keep their order.
+    pub concurrent_file_delivery: bool,
+    /// Idle message templates keyed by channel id or project name, e.g.
+    /// `**[{project}/{agent}]** {text}`.
+    pub message_templates: HashMap<String, String>,
+    /// Idle texts dropped when the whole trimmed message equals one of these.
+    pub ignored_idle_texts: Vec<String>,
+    /// Per agent type, whether idle events are scanned for files to attach.
+    /// Agent types mapped to `false` relay text only.
+    pub agent_attachments: HashMap<String, bool>,
+    /// Keep delivering files after text fails and report each stage separately.
+    pub best_effort_delivery: bool,
+    /// Delay between chunks of a split message, applied by the project's client.
+    pub chunk_pacing: ChunkPacing,
+    /// Typing indicator interval, applied by the project's client; `None` disables it.
+    pub typing_interval: Option<Duration>,
+}
+
+/// Which idle event fields are scanned for file paths.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FileScanScope {
+    /// `turnText` when present, else `text`.
+    #[default]
+    Auto,
+    TurnText,
+    Text,
+    /// Both fields, deduplicated.
+    Both,
+}
+
+/// Order of idle text and attached files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AttachmentPlacement {
+    /// Text first, then files.
+    #[default]
+    After,
+    /// Files first, then text.
+    Before,
+    /// Text as the caption of the file message when it fits in one message,
+    /// otherwise as `After`.
+    Combined,
+}
+
+/// What an incoming event does while Discord has paused all requests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GlobalPauseMode {
+    /// Wait for the pause to clear; with a limit, respond 503 when the pause is
+    /// longer than that.
+    Wait(Option<Duration>),
+    /// Respond 202 and deliver in the background once the pause clears. Deferred
+    /// events are held in memory only, so a restart before then loses them.
+    Defer,
+}
+
+impl Default for GlobalPauseMode {
+    fn default() -> Self {
+        Self::Wait(None)
+    }
+}
+
+/// Re-check missing files `retries` times, `delay` apart, before dropping them, in
+/// case the agent is still writing them.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileSettle {
+    pub retries: u32,
+    pub delay: Duration,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            attachment_captions: false,
+            attachment_prefixes: default_attachment_prefixes(),
+            file_only_lead_in: None,
+            attachment_extensions: default_attachment_extensions(),
+            file_search_scan_bytes: DEFAULT_FILE_SEARCH_SCAN_BYTES,
+            file_search_max_bytes: DEFAULT_FILE_SEARCH_MAX_BYTES,
+            file_link_base_url: None,
+            file_link_template: DEFAULT_FILE_LINK_TEMPLATE.to_string(),
+            file_link_min_bytes: DEFAULT_FILE_LINK_MIN_BYTES,
+            wrap_code: false,
+            normalize_typography: false,
+            strip_line_patterns: Vec::new(),
+            collapse_repeated_lines: None,
+            truncate_with_attachment: None,
+            decode_percent_paths: false,
+            strip_resolved_paths: false,
+            relative_paths: false,
+            max_blank_lines: None,
+            file_footer: false,
+            file_settle: FileSettle::default(),
+            continuation_prefix: String::new(),
+            continuation_suffix: String::new(),
+            bold_headings: false,
+            max_message_length: DISCORD_MAX_MESSAGE_LENGTH,
+            file_scan_scope: FileScanScope::default(),
+            attachment_placement: AttachmentPlacement::default(),
+            concurrent_file_delivery: false,
+            message_templates: HashMap::new(),
+            ignored_idle_texts: Vec::new(),
+            agent_attachments: HashMap::new(),
+            best_effort_delivery: false,
+            chunk_pacing: ChunkPacing::default(),
+            typing_interval: None,
+        }
+    }
+}
+
+/// Discord API endpoint overrides for one project; unset fields use the globals.
+#[derive(Debug, Clone, Default)]
+pub struct ProjectDiscord {
+    pub api_base: Option<String>,
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StoredProjectDiscord {
+    #[serde(rename = "apiBaseUrl")]
+    api_base_url: Option<String>,
+    token: Option<String>,
+}
+
+/// Where channels are created for projects/agents that have none mapped.
+#[derive(Debug, Clone)]
+pub struct ChannelAutoCreate {
+    pub guild_id: String,
+    pub category_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct RuntimeConfig {
     pub discord_token: String,
+    /// Address the hook server binds to, `127.0.0.1` unless configured.
+    pub hook_server_host: String,
     pub hook_server_port: u16,
+    /// Secret hook requests must send (`Authorization: Bearer` or
+    /// `X-Mudcode-Secret`); `None` leaves the endpoints open.
+    pub hook_secret: Option<String>,
     pub config_path: PathBuf,
     pub state_path: PathBuf,
+    /// Per-project state files that replace the shared `state_path` for that project.
+    pub project_state_paths: HashMap<String, PathBuf>,
+    pub shutdown_timeout: Duration,
+    pub discord: DiscordSettings,
+    /// Hosts besides loopback that an event's `callbackUrl` may point at.
+    pub callback_allowed_hosts: Vec<String>,
+    /// Per-project Discord-compatible endpoints (base URL and/or token).
+    pub project_discord: HashMap<String, ProjectDiscord>,
+    /// How long after a `session.error` a short `session.idle` is suppressed (zero disables).
+    pub error_idle_window: Duration,
+    /// Idle text at least this long is delivered even inside the dampening window.
+    pub substantive_idle_chars: usize,
+    /// How many recent `eventId`s are remembered for duplicate suppression (zero disables).
+    pub event_dedup_capacity: usize,
+    /// File the dedup ids are persisted to so they survive restarts.
+    pub event_dedup_path: Option<PathBuf>,
+    pub event_dedup_flush_interval: Duration,
+    pub format: FormatOptions,
+    /// Per-project `format`, from `projectFormat` merged over the global keys.
+    /// Look entries up with `format_for`.
+    pub project_format: HashMap<String, FormatOptions>,
+    /// Opt-in creation of missing channels; `None` unless enabled with a guild id.
+    pub channel_auto_create: Option<ChannelAutoCreate>,
+    /// Event types (`send-files` for the upload endpoint) allowed to trigger
+    /// creation/persistence such as channel auto-creation.
+    pub side_effect_event_types: HashSet<String>,
+    /// Refuse to start when state validation reports ambiguous instances.
+    pub strict_state: bool,
+    /// JSONL file receiving one audit record per handled event.
+    pub audit_log_path: Option<PathBuf>,
+    pub audit_log_rotation: Rotation,
+    /// Match event project names to state ignoring case and extra whitespace.
+    pub case_insensitive_projects: bool,
+    /// Alternate project names mapped to the canonical key used in state.
+    pub project_aliases: HashMap<String, String>,
+    /// Include the posted message ids (`messageIds`) in successful responses.
+    pub return_message_ids: bool,
+    /// Re-reads of a state file that fails to parse, `state_read_retry_delay`
+    /// apart, to ride out a partial write.
+    pub state_read_retries: u32,
+    pub state_read_retry_delay: Duration,
+    /// Reject event payloads with keys the event type doesn't define.
+    pub strict_event_fields: bool,
+    /// Emoji added to the last posted message once a delivery fully succeeds.
+    pub success_reaction: Option<String>,
+    pub channel_lookup: ChannelLookup,
+    /// How long after an idle message a `/send-files` for the same instance is
+    /// attached to it; zero posts files separately.
+    pub merge_files_window: Duration,
+    pub response_format: ResponseFormat,
+    /// Log raw hook request bodies (redacted), from `MUDCODE_LOG_BODIES=1`.
+    pub log_bodies: bool,
+    pub log_body_max_chars: usize,
+    /// Log Discord sends instead of making them, from `MUDCODE_DRY_RUN=1`.
+    pub dry_run: bool,
+    /// Accept any existing absolute path in `/send-files` for projects without a
+    /// `projectPath`, instead of rejecting the request.
+    pub trust_paths_without_project_path: bool,
+    /// Expand wildcard `/send-files` entries by default.
+    pub glob_files: bool,
+    /// Most files one `/send-files` glob expansion may add.
+    pub max_glob_matches: usize,
+    /// Include subdirectories when expanding `/send-files` directory entries.
+    pub recursive_directories: bool,
+    /// Most files one `/send-files` directory expansion may add.
+    pub max_directory_files: usize,
+    pub global_pause_mode: GlobalPauseMode,
+    /// Most events `GlobalPauseMode::Defer` holds at once; more are refused with 503.
+    pub max_deferred_events: usize,
+}
+
+impl RuntimeConfig {
+    pub fn hook_server_addr(&self) -> anyhow::Result<SocketAddr> {
+        let host = parse_hook_server_host(&self.hook_server_host)?;
+        Ok(SocketAddr::new(host, self.hook_server_port))
+    }
+
+    /// The formatting and delivery options in effect for `project_name`.
+    pub fn format_for(&self, project_name: &str) -> &FormatOptions {
+        self.project_entry(&self.project_format, project_name)
+            .unwrap_or(&self.format)
+    }
+
+    /// The entry of a per-project map for `project_name`, whose key may be the
+    /// name itself, an alias of it, or with `caseInsensitiveProjects` another
+    /// spelling of it.
+    pub fn project_entry<'a, T>(
+        &self,
+        entries: &'a HashMap<String, T>,
+        project_name: &str,
+    ) -> Option<&'a T> {
+        if let Some(entry) = entries.get(project_name) {
+            return Some(entry);
+        }
+        let resolve = |name: &str| {
+            let name = self
+                .project_aliases
+                .get(name)
+                .cloned()
+                .unwrap_or_else(|| name.to_string());
+            if self.case_insensitive_projects {
+                normalize_project_name(&name)
+            } else {
+                name
+            }
+        };
+        let wanted = resolve(project_name);
+        entries
+            .iter()
+            .filter(|(key, _)| resolve(key) == wanted)
+            .min_by(|(a, _), (b, _)| a.cmp(b))
+            .map(|(_, entry)| entry)
+    }
 }
 
 #[derive(Debug, Default, Deserialize)]
@@ -17,28 +353,259 @@ struct StoredConfig {
     token: Option<String>,
     #[serde(rename = "hookServerPort")]
     hook_server_port: Option<u16>,
+    #[serde(rename = "hookServerHost")]
+    hook_server_host: Option<String>,
+    #[serde(rename = "hookSecret")]
+    hook_secret: Option<String>,
+    #[serde(default, rename = "projectStatePaths")]
+    project_state_paths: HashMap<String, String>,
+    #[serde(rename = "shutdownTimeoutSecs")]
+    shutdown_timeout_secs: Option<u64>,
+    #[serde(default, rename = "projectDiscord")]
+    project_discord: HashMap<String, StoredProjectDiscord>,
+    #[serde(default, rename = "projectAliases")]
+    project_aliases: HashMap<String, String>,
+    #[serde(rename = "returnMessageIds")]
+    return_message_ids: Option<bool>,
+    #[serde(rename = "discordApiBaseUrl")]
+    discord_api_base_url: Option<String>,
+    #[serde(rename = "pauseOnGlobalRateLimit")]
+    pause_on_global_rate_limit: Option<bool>,
+    #[serde(rename = "rateLimitRetries")]
+    rate_limit_retries: Option<usize>,
+    #[serde(default, rename = "callbackAllowedHosts")]
+    callback_allowed_hosts: Vec<String>,
+    #[serde(rename = "connectRetries")]
+    connect_retries: Option<usize>,
+    #[serde(rename = "connectRetryBackoffMs")]
+    connect_retry_backoff_ms: Option<u64>,
+    #[serde(rename = "attachmentOrder")]
+    attachment_order: Option<AttachmentOrder>,
+    #[serde(rename = "maxEventAttachmentBytes")]
+    max_event_attachment_bytes: Option<u64>,
+    #[serde(rename = "allowMentions")]
+    allow_mentions: Option<bool>,
+    #[serde(rename = "serverErrorRetries")]
+    server_error_retries: Option<usize>,
+    #[serde(rename = "serverErrorBackoffMs")]
+    server_error_backoff_ms: Option<u64>,
+    #[serde(rename = "connectTimeoutMs")]
+    connect_timeout_ms: Option<u64>,
+    #[serde(rename = "requestTimeoutMs")]
+    request_timeout_ms: Option<u64>,
+    #[serde(rename = "maxAttachmentBytes")]
+    max_attachment_bytes: Option<u64>,
+    #[serde(rename = "unknownFileSize")]
+    unknown_file_size: Option<UnknownSize>,
+    #[serde(rename = "circuitBreakerThreshold")]
+    circuit_breaker_threshold: Option<usize>,
+    #[serde(rename = "circuitBreakerCooldownMs")]
+    circuit_breaker_cooldown_ms: Option<u64>,
+    #[serde(rename = "defaultAttachmentMime")]
+    default_attachment_mime: Option<String>,
+    #[serde(rename = "lossyFileNames")]
+    lossy_file_names: Option<bool>,
+    #[serde(rename = "repostOnUnknownMessage")]
+    repost_on_unknown_message: Option<bool>,
+    #[serde(rename = "repostNote")]
+    repost_note: Option<String>,
+    #[serde(rename = "verifyDelivery")]
+    verify_delivery: Option<bool>,
+    #[serde(rename = "errorIdleDampeningMs")]
+    error_idle_dampening_ms: Option<u64>,
+    #[serde(rename = "substantiveIdleChars")]
+    substantive_idle_chars: Option<usize>,
+    #[serde(rename = "eventDedupCapacity")]
+    event_dedup_capacity: Option<usize>,
+    #[serde(rename = "eventDedupPath")]
+    event_dedup_path: Option<String>,
+    #[serde(rename = "eventDedupFlushSecs")]
+    event_dedup_flush_secs: Option<u64>,
+    #[serde(rename = "successReaction")]
+    success_reaction: Option<String>,
+    #[serde(rename = "legacyChannels")]
+    legacy_channels: Option<String>,
+    #[serde(rename = "strictInstanceAgentType")]
+    strict_instance_agent_type: Option<bool>,
+    #[serde(rename = "agentTypeFallbacks")]
+    agent_type_fallbacks: Option<Vec<String>>,
+    #[serde(rename = "mergeFilesWindowMs")]
+    merge_files_window_ms: Option<u64>,
+    #[serde(rename = "responseFormat")]
+    response_format: Option<String>,
+    #[serde(rename = "logBodyMaxChars")]
+    log_body_max_chars: Option<usize>,
+    #[serde(rename = "trustPathsWithoutProjectPath")]
+    trust_paths_without_project_path: Option<bool>,
+    #[serde(rename = "globFiles")]
+    glob_files: Option<bool>,
+    #[serde(rename = "maxGlobMatches")]
+    max_glob_matches: Option<usize>,
+    #[serde(rename = "recursiveDirectories")]
+    recursive_directories: Option<bool>,
+    #[serde(rename = "maxDirectoryFiles")]
+    max_directory_files: Option<usize>,
+    #[serde(rename = "globalPauseMode")]
+    global_pause_mode: Option<String>,
+    #[serde(rename = "globalPauseWaitMs")]
+    global_pause_wait_ms: Option<u64>,
+    #[serde(rename = "maxDeferredEvents")]
+    max_deferred_events: Option<usize>,
+    #[serde(rename = "strictEventFields")]
+    strict_event_fields: Option<bool>,
+    #[serde(rename = "caseInsensitiveProjects")]
+    case_insensitive_projects: Option<bool>,
+    #[serde(rename = "stateReadRetries")]
+    state_read_retries: Option<u32>,
+    #[serde(rename = "stateReadRetryDelayMs")]
+    state_read_retry_delay_ms: Option<u64>,
+    #[serde(rename = "auditLogPath")]
+    audit_log_path: Option<String>,
+    #[serde(rename = "auditLogRotateMib")]
+    audit_log_rotate_mib: Option<u64>,
+    #[serde(rename = "auditLogKeep")]
+    audit_log_keep: Option<usize>,
+    #[serde(rename = "strictState")]
+    strict_state: Option<bool>,
+    #[serde(rename = "sideEffectEventTypes")]
+    side_effect_event_types: Option<Vec<String>>,
+    #[serde(rename = "autoCreateChannels")]
+    auto_create_channels: Option<bool>,
+    #[serde(rename = "discordGuildId")]
+    discord_guild_id: Option<String>,
+    #[serde(rename = "discordCategoryId")]
+    discord_category_id: Option<String>,
+    #[serde(default, rename = "projectFormat")]
+    project_format: HashMap<String, serde_json::Map<String, Value>>,
+    #[serde(flatten)]
+    format: StoredFormat,
+}
+
+/// The keys behind `FormatOptions`, shared by the top level and `projectFormat`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct StoredFormat {
+    #[serde(rename = "attachmentCaptions")]
+    attachment_captions: Option<bool>,
+    #[serde(default, rename = "attachmentPrefixes")]
+    attachment_prefixes: HashMap<String, String>,
+    #[serde(rename = "attachmentExtensions")]
+    attachment_extensions: Option<Vec<String>>,
+    #[serde(rename = "fileOnlyLeadIn")]
+    file_only_lead_in: Option<String>,
+    #[serde(rename = "fileSearchScanBytes")]
+    file_search_scan_bytes: Option<usize>,
+    #[serde(rename = "fileSearchMaxBytes")]
+    file_search_max_bytes: Option<usize>,
+    #[serde(rename = "fileLinkBaseUrl")]
+    file_link_base_url: Option<String>,
+    #[serde(rename = "fileLinkTemplate")]
+    file_link_template: Option<String>,
+    #[serde(rename = "fileLinkMinBytes")]
+    file_link_min_bytes: Option<u64>,
+    #[serde(rename = "wrapCode")]
+    wrap_code: Option<bool>,
+    #[serde(rename = "normalizeTypography")]
+    normalize_typography: Option<bool>,
+    #[serde(default, rename = "stripLinePatterns")]
+    strip_line_patterns: Vec<String>,
+    #[serde(rename = "collapseRepeatedLines")]
+    collapse_repeated_lines: Option<usize>,
+    #[serde(rename = "truncateWithAttachment")]
+    truncate_with_attachment: Option<usize>,
+    #[serde(rename = "decodePercentPaths")]
+    decode_percent_paths: Option<bool>,
+    #[serde(rename = "stripResolvedPaths")]
+    strip_resolved_paths: Option<bool>,
+    #[serde(rename = "relativePaths")]
+    relative_paths: Option<bool>,
+    #[serde(rename = "maxBlankLines")]
+    max_blank_lines: Option<usize>,
+    #[serde(rename = "fileFooter")]
+    file_footer: Option<bool>,
+    #[serde(rename = "fileSettleRetries")]
+    file_settle_retries: Option<u32>,
+    #[serde(rename = "fileSettleDelayMs")]
+    file_settle_delay_ms: Option<u64>,
+    #[serde(rename = "continuationPrefix")]
+    continuation_prefix: Option<String>,
+    #[serde(rename = "continuationSuffix")]
+    continuation_suffix: Option<String>,
+    #[serde(rename = "boldHeadings")]
+    bold_headings: Option<bool>,
+    #[serde(rename = "maxMessageLength")]
+    max_message_length: Option<usize>,
+    #[serde(rename = "fileScanScope")]
+    file_scan_scope: Option<String>,
+    #[serde(rename = "attachmentPlacement")]
+    attachment_placement: Option<String>,
+    #[serde(rename = "concurrentFileDelivery")]
+    concurrent_file_delivery: Option<bool>,
+    #[serde(default, rename = "messageTemplates")]
+    message_templates: HashMap<String, String>,
+    #[serde(rename = "ignoredIdleTexts")]
+    ignored_idle_texts: Option<Vec<String>>,
+    #[serde(default, rename = "agentAttachments")]
+    agent_attachments: HashMap<String, bool>,
+    #[serde(rename = "bestEffortDelivery")]
+    best_effort_delivery: Option<bool>,
+    #[serde(rename = "chunkDelayBaseMs")]
+    chunk_delay_base_ms: Option<u64>,
+    #[serde(rename = "chunkDelayPerChunkMs")]
+    chunk_delay_per_chunk_ms: Option<u64>,
+    #[serde(rename = "chunkDelayMaxMs")]
+    chunk_delay_max_ms: Option<u64>,
+    #[serde(rename = "typingIntervalMs")]
+    typing_interval_ms: Option<u64>,
+}
+
+/// `HOME`, or `USERPROFILE` on Windows where `HOME` is usually unset.
+pub fn home_dir() -> Option<PathBuf> {
+    let from = |key| env::var(key).ok().filter(|v| !v.trim().is_empty());
+    let home = from("HOME").or_else(|| {
+        if cfg!(windows) {
+            from("USERPROFILE")
+        } else {
+            None
+        }
+    });
+    home.map(PathBuf::from)
+}
+
+/// Expand a leading `~` (alone, or followed by a separator) to `home`, usually
+/// `home_dir()`. Paths are returned unchanged when there is no home directory.
+pub fn expand_home_in(path: &str, home: Option<&Path>) -> PathBuf {
+    let Some(home) = home else {
+        return PathBuf::from(path);
+    };
+    match path.strip_prefix('~') {
+        Some("") => home.to_path_buf(),
+        Some(rest) if rest.starts_with('/') || (cfg!(windows) && rest.starts_with('\\')) => {
+            home.join(&rest[1..])
+        }
+        _ => PathBuf::from(path),
+    }
 }
 
 fn default_mudcode_dir() -> anyhow::Result<PathBuf> {
-    let home = env::var("HOME").context("HOME is not set")?;
-    Ok(Path::new(&home).join(".mudcode"))
+    let home = home_dir().context("HOME is not set")?;
+    Ok(home.join(".mudcode"))
 }
 
 fn resolve_config_path() -> anyhow::Result<PathBuf> {
-    if let Ok(path) = env::var("MUDCODE_CONFIG_PATH") {
-        if !path.trim().is_empty() {
-            return Ok(PathBuf::from(path));
-        }
+    if let Ok(path) = env::var("MUDCODE_CONFIG_PATH")
+        && !path.trim().is_empty()
+    {
+        return Ok(PathBuf::from(path));
     }
 
     Ok(default_mudcode_dir()?.join("config.json"))
 }
 
 fn resolve_state_path() -> anyhow::Result<PathBuf> {
-    if let Ok(path) = env::var("MUDCODE_STATE_PATH") {
-        if !path.trim().is_empty() {
-            return Ok(PathBuf::from(path));
-        }
+    if let Ok(path) = env::var("MUDCODE_STATE_PATH")
+        && !path.trim().is_empty()
+    {
+        return Ok(PathBuf::from(path));
     }
 
     Ok(default_mudcode_dir()?.join("state.json"))
@@ -52,6 +619,12 @@ fn read_stored_config(path: &Path) -> StoredConfig {
     serde_json::from_str::<StoredConfig>(&data).unwrap_or_default()
 }
 
+fn parse_hook_server_host(host: &str) -> anyhow::Result<IpAddr> {
+    host.parse().with_context(|| {
+        format!("invalid hookServerHost {host:?}: expected an IP address such as 0.0.0.0")
+    })
+}
+
 pub fn normalize_discord_token(input: &str) -> String {
     let mut token = input.trim().to_string();
     if token.is_empty() {
@@ -75,6 +648,215 @@ pub fn normalize_discord_token(input: &str) -> String {
     token
 }
 
+fn format_options(stored: StoredFormat) -> FormatOptions {
+    let format_defaults = FormatOptions::default();
+    let mut attachment_prefixes = format_defaults.attachment_prefixes;
+    for (ext, prefix) in stored.attachment_prefixes {
+        let ext = ext.trim().trim_start_matches('.').to_ascii_lowercase();
+        if !ext.is_empty() {
+            attachment_prefixes.insert(ext, prefix);
+        }
+    }
+    FormatOptions {
+        attachment_captions: stored
+            .attachment_captions
+            .unwrap_or(format_defaults.attachment_captions),
+        attachment_prefixes,
+        attachment_extensions: stored.attachment_extensions.map_or(
+            format_defaults.attachment_extensions,
+            |extensions| {
+                extensions
+                    .iter()
+                    .map(|ext| ext.trim().trim_start_matches('.').to_ascii_lowercase())
+                    .filter(|ext| !ext.is_empty())
+                    .collect()
+            },
+        ),
+        file_only_lead_in: stored
+            .file_only_lead_in
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty()),
+        file_search_scan_bytes: stored
+            .file_search_scan_bytes
+            .unwrap_or(format_defaults.file_search_scan_bytes),
+        file_search_max_bytes: stored
+            .file_search_max_bytes
+            .unwrap_or(format_defaults.file_search_max_bytes),
+        file_link_base_url: stored
+            .file_link_base_url
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty()),
+        file_link_template: stored
+            .file_link_template
+            .filter(|v| !v.trim().is_empty())
+            .unwrap_or(format_defaults.file_link_template),
+        file_link_min_bytes: stored
+            .file_link_min_bytes
+            .unwrap_or(format_defaults.file_link_min_bytes),
+        wrap_code: stored.wrap_code.unwrap_or(format_defaults.wrap_code),
+        normalize_typography: stored
+            .normalize_typography
+            .unwrap_or(format_defaults.normalize_typography),
+        strip_line_patterns: stored
+            .strip_line_patterns
+            .iter()
+            .filter_map(|pattern| match Regex::new(pattern) {
+                Ok(re) => Some(re),
+                Err(error) => {
+                    warn!("ignoring invalid stripLinePatterns entry {pattern:?}: {error}");
+                    None
+                }
+            })
+            .collect(),
+        collapse_repeated_lines: stored.collapse_repeated_lines.filter(|&n| n > 0),
+        truncate_with_attachment: stored.truncate_with_attachment.filter(|&n| n > 0),
+        decode_percent_paths: stored
+            .decode_percent_paths
+            .unwrap_or(format_defaults.decode_percent_paths),
+        strip_resolved_paths: stored
+            .strip_resolved_paths
+            .unwrap_or(format_defaults.strip_resolved_paths),
+        relative_paths: stored
+            .relative_paths
+            .unwrap_or(format_defaults.relative_paths),
+        max_blank_lines: stored.max_blank_lines,
+        file_footer: stored.file_footer.unwrap_or(format_defaults.file_footer),
+        file_settle: FileSettle {
+            retries: stored.file_settle_retries.unwrap_or(0),
+            delay: Duration::from_millis(stored.file_settle_delay_ms.unwrap_or(100)),
+        },
+        continuation_prefix: stored
+            .continuation_prefix
+            .unwrap_or(format_defaults.continuation_prefix),
+        continuation_suffix: stored
+            .continuation_suffix
+            .unwrap_or(format_defaults.continuation_suffix),
+        bold_headings: stored
+            .bold_headings
+            .unwrap_or(format_defaults.bold_headings),
+        max_message_length: match stored.max_message_length {
+            None => format_defaults.max_message_length,
+            Some(n) if (1..=DISCORD_MAX_MESSAGE_LENGTH).contains(&n) => n,
+            Some(n) => {
+                warn!("maxMessageLength {n} outside 1..={DISCORD_MAX_MESSAGE_LENGTH}; clamping");
+                n.clamp(1, DISCORD_MAX_MESSAGE_LENGTH)
+            }
+        },
+        file_scan_scope: match stored.file_scan_scope.as_deref().map(str::trim) {
+            None | Some("auto") => FileScanScope::Auto,
+            Some("turnText") => FileScanScope::TurnText,
+            Some("text") => FileScanScope::Text,
+            Some("both") => FileScanScope::Both,
+            Some(other) => {
+                warn!("ignoring invalid fileScanScope value {other:?}; using auto");
+                FileScanScope::Auto
+            }
+        },
+        attachment_placement: match stored.attachment_placement.as_deref().map(str::trim) {
+            None | Some("after") => AttachmentPlacement::After,
+            Some("before") => AttachmentPlacement::Before,
+            Some("combined") => AttachmentPlacement::Combined,
+            Some(other) => {
+                warn!("ignoring invalid attachmentPlacement value {other:?}; using after");
+                AttachmentPlacement::After
+            }
+        },
+        concurrent_file_delivery: stored
+            .concurrent_file_delivery
+            .unwrap_or(format_defaults.concurrent_file_delivery),
+        message_templates: stored
+            .message_templates
+            .into_iter()
+            .map(|(key, template)| (key.trim().to_string(), template))
+            .filter(|(key, template)| !key.is_empty() && !template.trim().is_empty())
+            .collect(),
+        ignored_idle_texts: stored
+            .ignored_idle_texts
+            .unwrap_or_default()
+            .into_iter()
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty())
+            .collect(),
+        agent_attachments: stored
+            .agent_attachments
+            .into_iter()
+            .map(|(agent_type, enabled)| (agent_type.trim().to_string(), enabled))
+            .collect(),
+        best_effort_delivery: stored
+            .best_effort_delivery
+            .unwrap_or(format_defaults.best_effort_delivery),
+        chunk_pacing: ChunkPacing {
+            base: stored
+                .chunk_delay_base_ms
+                .map_or(format_defaults.chunk_pacing.base, Duration::from_millis),
+            per_chunk: stored.chunk_delay_per_chunk_ms.map_or(
+                format_defaults.chunk_pacing.per_chunk,
+                Duration::from_millis,
+            ),
+            max: stored
+                .chunk_delay_max_ms
+                .map_or(format_defaults.chunk_pacing.max, Duration::from_millis),
+        },
+        typing_interval: stored
+            .typing_interval_ms
+            .filter(|&ms| ms > 0)
+            .map(Duration::from_millis),
+    }
+}
+
+/// Warn when the continuation markers can't fit in `format`'s message limit, as
+/// messages are then split without them.
+fn warn_if_markers_dropped(setting: &str, format: &FormatOptions) {
+    if !continuation_markers_fit(
+        format.max_message_length,
+        &format.continuation_prefix,
+        &format.continuation_suffix,
+    ) {
+        warn!(
+            "{setting} {} leaves too little room for continuationPrefix/continuationSuffix; long messages will be split without them",
+            format.max_message_length
+        );
+    }
+}
+
+/// Each project's `projectFormat` keys laid over the top-level ones, so options a
+/// project leaves out inherit the global value. Map-valued keys such as
+/// `messageTemplates` are merged entry by entry.
+fn project_format_options(
+    global: &StoredFormat,
+    overrides: HashMap<String, serde_json::Map<String, Value>>,
+) -> HashMap<String, FormatOptions> {
+    let Ok(Value::Object(global)) = serde_json::to_value(global) else {
+        return HashMap::new();
+    };
+
+    overrides
+        .into_iter()
+        .map(|(name, keys)| (name.trim().to_string(), keys))
+        .filter(|(name, _)| !name.is_empty())
+        .filter_map(|(name, keys)| {
+            let mut merged = global.clone();
+            for (key, value) in keys {
+                match (merged.get_mut(&key), value) {
+                    (Some(Value::Object(entries)), Value::Object(overrides)) => {
+                        entries.extend(overrides);
+                    }
+                    (_, value) => {
+                        merged.insert(key, value);
+                    }
+                }
+            }
+            match serde_json::from_value::<StoredFormat>(Value::Object(merged)) {
+                Ok(stored) => Some((name, format_options(stored))),
+                Err(error) => {
+                    warn!("ignoring projectFormat for {name}: {error}");
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
 pub fn load_runtime_config() -> anyhow::Result<RuntimeConfig> {
     let config_path = resolve_config_path()?;
     let state_path = resolve_state_path()?;
@@ -109,17 +891,415 @@ pub fn load_runtime_config() -> anyhow::Result<RuntimeConfig> {
 
     let hook_server_port = stored.hook_server_port.or(env_port).unwrap_or(18470);
 
+    let env_host = env::var("HOOK_SERVER_HOST").ok();
+    let hook_server_host = stored
+        .hook_server_host
+        .or(env_host)
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| DEFAULT_HOOK_SERVER_HOST.to_string());
+    parse_hook_server_host(&hook_server_host)?;
+
+    let hook_secret = stored
+        .hook_secret
+        .or_else(|| env::var("MUDCODE_HOOK_SECRET").ok())
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty());
+
+    let env_shutdown_timeout = env::var("MUDCODE_SHUTDOWN_TIMEOUT_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok());
+
+    let shutdown_timeout = Duration::from_secs(
+        stored
+            .shutdown_timeout_secs
+            .or(env_shutdown_timeout)
+            .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_SECS),
+    );
+
+    let project_format = project_format_options(&stored.format, stored.project_format);
+    let format = format_options(stored.format);
+    warn_if_markers_dropped("maxMessageLength", &format);
+    for (name, options) in &project_format {
+        warn_if_markers_dropped(&format!("projectFormat.{name}.maxMessageLength"), options);
+    }
+
+    let dry_run = env::var("MUDCODE_DRY_RUN").is_ok_and(|v| v.trim() == "1");
+    let discord_defaults = DiscordSettings::default();
+    let discord = DiscordSettings {
+        dry_run,
+        api_base: stored
+            .discord_api_base_url
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .unwrap_or(discord_defaults.api_base),
+        pause_on_global_rate_limit: stored
+            .pause_on_global_rate_limit
+            .unwrap_or(discord_defaults.pause_on_global_rate_limit),
+        rate_limit_retries: stored
+            .rate_limit_retries
+            .unwrap_or(discord_defaults.rate_limit_retries),
+        connect_retries: stored
+            .connect_retries
+            .unwrap_or(discord_defaults.connect_retries),
+        connect_retry_backoff: stored.connect_retry_backoff_ms.map_or(
+            discord_defaults.connect_retry_backoff,
+            Duration::from_millis,
+        ),
+        allow_mentions: stored
+            .allow_mentions
+            .unwrap_or(discord_defaults.allow_mentions),
+        server_error_retries: stored
+            .server_error_retries
+            .unwrap_or(discord_defaults.server_error_retries),
+        server_error_backoff: stored
+            .server_error_backoff_ms
+            .map_or(discord_defaults.server_error_backoff, Duration::from_millis),
+        connect_timeout: stored
+            .connect_timeout_ms
+            .filter(|&ms| ms > 0)
+            .map_or(discord_defaults.connect_timeout, Duration::from_millis),
+        request_timeout: stored
+            .request_timeout_ms
+            .filter(|&ms| ms > 0)
+            .map_or(discord_defaults.request_timeout, Duration::from_millis),
+        attachment_order: stored
+            .attachment_order
+            .unwrap_or(discord_defaults.attachment_order),
+        default_attachment_mime: stored
+            .default_attachment_mime
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .unwrap_or(discord_defaults.default_attachment_mime),
+        lossy_file_names: stored
+            .lossy_file_names
+            .unwrap_or(discord_defaults.lossy_file_names),
+        typing_interval: format.typing_interval,
+        repost_on_unknown_message: stored
+            .repost_on_unknown_message
+            .unwrap_or(discord_defaults.repost_on_unknown_message),
+        repost_note: stored.repost_note.unwrap_or(discord_defaults.repost_note),
+        verify_delivery: stored
+            .verify_delivery
+            .unwrap_or(discord_defaults.verify_delivery),
+        circuit_breaker_threshold: stored
+            .circuit_breaker_threshold
+            .unwrap_or(discord_defaults.circuit_breaker_threshold),
+        circuit_breaker_cooldown: stored.circuit_breaker_cooldown_ms.map_or(
+            discord_defaults.circuit_breaker_cooldown,
+            Duration::from_millis,
+        ),
+        max_attachment_bytes: match stored.max_attachment_bytes {
+            None => discord_defaults.max_attachment_bytes,
+            Some(cap) => Some(cap).filter(|&cap| cap > 0),
+        },
+        max_event_attachment_bytes: stored.max_event_attachment_bytes.filter(|&cap| cap > 0),
+        unknown_size: stored
+            .unknown_file_size
+            .unwrap_or(discord_defaults.unknown_size),
+        chunk_pacing: format.chunk_pacing,
+    };
+
+    let error_idle_window = stored
+        .error_idle_dampening_ms
+        .map_or(DEFAULT_ERROR_IDLE_WINDOW, Duration::from_millis);
+    let substantive_idle_chars = stored
+        .substantive_idle_chars
+        .unwrap_or(DEFAULT_SUBSTANTIVE_IDLE_CHARS);
+
+    let guild_id = stored
+        .discord_guild_id
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty());
+    let channel_auto_create = match (stored.auto_create_channels.unwrap_or(false), guild_id) {
+        (true, Some(guild_id)) => Some(ChannelAutoCreate {
+            guild_id,
+            category_id: stored
+                .discord_category_id
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty()),
+        }),
+        (true, None) => {
+            warn!("autoCreateChannels is enabled but discordGuildId is missing; ignoring");
+            None
+        }
+        (false, _) => None,
+    };
+
+    let trimmed = |v: Option<String>| v.map(|v| v.trim().to_string()).filter(|v| !v.is_empty());
+    let project_discord = stored
+        .project_discord
+        .into_iter()
+        .map(|(name, endpoint)| {
+            (
+                name.trim().to_string(),
+                ProjectDiscord {
+                    api_base: trimmed(endpoint.api_base_url),
+                    token: trimmed(endpoint.token),
+                },
+            )
+        })
+        .filter(|(name, endpoint)| {
+            !name.is_empty() && (endpoint.api_base.is_some() || endpoint.token.is_some())
+        })
+        .collect();
+
+    let project_state_paths = stored
+        .project_state_paths
+        .into_iter()
+        .map(|(name, path)| (name.trim().to_string(), path.trim().to_string()))
+        .filter(|(name, path)| !name.is_empty() && !path.is_empty())
+        .map(|(name, path)| (name, PathBuf::from(path)))
+        .collect();
+
+    let project_aliases = stored
+        .project_aliases
+        .into_iter()
+        .map(|(alias, project)| (alias.trim().to_string(), project.trim().to_string()))
+        .filter(|(alias, project)| !alias.is_empty() && !project.is_empty())
+        .collect();
+
     Ok(RuntimeConfig {
         discord_token,
+        hook_server_host,
         hook_server_port,
+        hook_secret,
         config_path,
         state_path,
+        project_state_paths,
+        shutdown_timeout,
+        discord,
+        callback_allowed_hosts: stored
+            .callback_allowed_hosts
+            .into_iter()
+            .map(|host| host.trim().to_ascii_lowercase())
+            .filter(|host| !host.is_empty())
+            .collect(),
+        project_discord,
+        error_idle_window,
+        substantive_idle_chars,
+        event_dedup_capacity: stored
+            .event_dedup_capacity
+            .unwrap_or(DEFAULT_EVENT_DEDUP_CAPACITY),
+        event_dedup_path: stored
+            .event_dedup_path
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from),
+        event_dedup_flush_interval: stored
+            .event_dedup_flush_secs
+            .filter(|&secs| secs > 0)
+            .map_or(DEFAULT_EVENT_DEDUP_FLUSH_INTERVAL, Duration::from_secs),
+        format,
+        project_format,
+        channel_auto_create,
+        side_effect_event_types: stored.side_effect_event_types.map_or_else(
+            || {
+                DEFAULT_SIDE_EFFECT_EVENT_TYPES
+                    .iter()
+                    .map(|t| t.to_string())
+                    .collect()
+            },
+            |types| {
+                types
+                    .into_iter()
+                    .map(|t| t.trim().to_string())
+                    .filter(|t| !t.is_empty())
+                    .collect()
+            },
+        ),
+        strict_state: stored.strict_state.unwrap_or(false),
+        audit_log_path: stored
+            .audit_log_path
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from),
+        audit_log_rotation: Rotation {
+            max_bytes: stored
+                .audit_log_rotate_mib
+                .unwrap_or(0)
+                .saturating_mul(1024 * 1024),
+            keep: stored.audit_log_keep.unwrap_or(DEFAULT_AUDIT_LOG_KEEP),
+        },
+        case_insensitive_projects: stored.case_insensitive_projects.unwrap_or(false),
+        project_aliases,
+        return_message_ids: stored.return_message_ids.unwrap_or(false),
+        state_read_retries: stored.state_read_retries.unwrap_or(0),
+        state_read_retry_delay: Duration::from_millis(
+            stored.state_read_retry_delay_ms.unwrap_or(50),
+        ),
+        strict_event_fields: stored.strict_event_fields.unwrap_or(false),
+        success_reaction: stored
+            .success_reaction
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty()),
+        channel_lookup: ChannelLookup {
+            legacy: stored
+                .legacy_channels
+                .as_deref()
+                .map_or(LegacyChannels::Auto, |value| {
+                    LegacyChannels::parse(value).unwrap_or_else(|| {
+                        warn!("ignoring invalid legacyChannels value {value:?}; using auto");
+                        LegacyChannels::Auto
+                    })
+                }),
+            strict_instance_agent: stored.strict_instance_agent_type.unwrap_or(false),
+            agent_fallbacks: stored
+                .agent_type_fallbacks
+                .unwrap_or_default()
+                .into_iter()
+                .map(|t| t.trim().to_string())
+                .filter(|t| !t.is_empty())
+                .collect(),
+        },
+        merge_files_window: Duration::from_millis(stored.merge_files_window_ms.unwrap_or(0)),
+        response_format: stored
+            .response_format
+            .as_deref()
+            .map_or(ResponseFormat::Text, |value| {
+                ResponseFormat::parse(value).unwrap_or_else(|| {
+                    warn!("ignoring invalid responseFormat value {value:?}; using text");
+                    ResponseFormat::Text
+                })
+            }),
+        log_bodies: env::var("MUDCODE_LOG_BODIES").is_ok_and(|v| v.trim() == "1"),
+        dry_run,
+        log_body_max_chars: stored
+            .log_body_max_chars
+            .unwrap_or(DEFAULT_LOG_BODY_MAX_CHARS),
+        trust_paths_without_project_path: stored.trust_paths_without_project_path.unwrap_or(false),
+        glob_files: stored.glob_files.unwrap_or(false),
+        max_glob_matches: stored.max_glob_matches.unwrap_or(DEFAULT_MAX_GLOB_MATCHES),
+        recursive_directories: stored.recursive_directories.unwrap_or(false),
+        max_directory_files: stored
+            .max_directory_files
+            .unwrap_or(DEFAULT_MAX_DIRECTORY_FILES),
+        global_pause_mode: match stored.global_pause_mode.as_deref().map(str::trim) {
+            Some("defer") => GlobalPauseMode::Defer,
+            None | Some("wait") => {
+                GlobalPauseMode::Wait(stored.global_pause_wait_ms.map(Duration::from_millis))
+            }
+            Some(other) => {
+                warn!("ignoring invalid globalPauseMode value {other:?}; using wait");
+                GlobalPauseMode::Wait(stored.global_pause_wait_ms.map(Duration::from_millis))
+            }
+        },
+        max_deferred_events: stored
+            .max_deferred_events
+            .unwrap_or(DEFAULT_MAX_DEFERRED_EVENTS),
     })
 }
 
 #[cfg(test)]
 mod tests {
-    use super::normalize_discord_token;
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn expand_home_replaces_a_leading_tilde_only() {
+        let home = Path::new("/home/mud");
+        assert_eq!(expand_home_in("~", Some(home)), home);
+        assert_eq!(
+            expand_home_in("~/work/proj", Some(home)),
+            home.join("work/proj")
+        );
+        assert_eq!(
+            expand_home_in("~other/x", Some(home)),
+            Path::new("~other/x")
+        );
+        assert_eq!(
+            expand_home_in("/srv/~/x", Some(home)),
+            Path::new("/srv/~/x")
+        );
+        assert_eq!(expand_home_in("~/x", None), Path::new("~/x"));
+    }
+
+    #[test]
+    fn hook_server_addr_accepts_ip_hosts_only() {
+        let config = |host: &str| RuntimeConfig {
+            hook_server_host: host.to_string(),
+            hook_server_port: 18470,
+            ..RuntimeConfig::default()
+        };
+        assert_eq!(
+            config("0.0.0.0").hook_server_addr().unwrap().to_string(),
+            "0.0.0.0:18470"
+        );
+        assert_eq!(
+            config("::1").hook_server_addr().unwrap().to_string(),
+            "[::1]:18470"
+        );
+        let error = config("my-host").hook_server_addr().unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("invalid hookServerHost \"my-host\"")
+        );
+    }
+
+    #[test]
+    fn project_format_overrides_inherit_unset_options() {
+        let stored: StoredConfig = serde_json::from_value(json!({
+            "wrapCode": true,
+            "maxMessageLength": 500,
+            "messageTemplates": { "ch-9": "[{project}] {text}" },
+            "chunkDelayBaseMs": 50,
+            "projectFormat": {
+                "quiet": {
+                    "maxMessageLength": 100,
+                    "fileFooter": true,
+                    "messageTemplates": { "quiet": "{text}" },
+                    "ignoredIdleTexts": ["..."],
+                    "agentAttachments": { "claude": false },
+                    "bestEffortDelivery": true,
+                    "typingIntervalMs": 5000
+                },
+                "bad": { "maxMessageLength": "long" }
+            }
+        }))
+        .unwrap();
+
+        let projects = project_format_options(&stored.format, stored.project_format);
+        let format = format_options(stored.format);
+        let quiet = &projects["quiet"];
+        assert_eq!(quiet.max_message_length, 100);
+        assert!(quiet.file_footer && quiet.wrap_code);
+        assert_eq!(quiet.message_templates.len(), 2);
+        assert_eq!(quiet.ignored_idle_texts, ["..."]);
+        assert_eq!(quiet.agent_attachments.get("claude"), Some(&false));
+        assert!(quiet.best_effort_delivery);
+        assert_eq!(quiet.typing_interval, Some(Duration::from_secs(5)));
+        assert_eq!(quiet.chunk_pacing.base, Duration::from_millis(50));
+        assert!(!format.best_effort_delivery && format.typing_interval.is_none());
+        assert!(!projects.contains_key("bad"));
+
+        let config = RuntimeConfig {
+            format,
+            project_format: projects,
+            ..RuntimeConfig::default()
+        };
+        assert_eq!(config.format_for("quiet").max_message_length, 100);
+        assert_eq!(config.format_for("other").max_message_length, 500);
+        assert!(!config.format_for("other").file_footer);
+    }
+
+    #[test]
+    fn project_entries_are_found_by_alias_and_spelling() {
+        let entries = HashMap::from([("q".to_string(), 1), ("Other  Proj".to_string(), 2)]);
+        let config = RuntimeConfig {
+            project_aliases: HashMap::from([("q".to_string(), "quiet".to_string())]),
+            ..RuntimeConfig::default()
+        };
+        assert_eq!(config.project_entry(&entries, "quiet"), Some(&1));
+        assert_eq!(config.project_entry(&entries, "other proj"), None);
+
+        let config = RuntimeConfig {
+            case_insensitive_projects: true,
+            ..config
+        };
+        assert_eq!(config.project_entry(&entries, "other proj"), Some(&2));
+        assert_eq!(config.project_entry(&entries, "missing"), None);
+    }
 
     #[test]
     fn normalize_discord_token_handles_common_copy_paste_issues() {
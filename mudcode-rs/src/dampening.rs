@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Dampening is off unless `errorIdleDampeningMs` sets a window.
+pub const DEFAULT_ERROR_IDLE_WINDOW: Duration = Duration::ZERO;
+pub const DEFAULT_SUBSTANTIVE_IDLE_CHARS: usize = 200;
+
+/// Suppresses a `session.idle` that closely follows a `session.error` for the same
+/// instance, treating it as part of the same retry/recovery burst.
+#[derive(Debug)]
+pub struct ErrorIdleDampener {
+    window: Duration,
+    substantive_chars: usize,
+    last_errors: Mutex<HashMap<String, Instant>>,
+}
+
+impl ErrorIdleDampener {
+    pub fn new(window: Duration, substantive_chars: usize) -> Self {
+        Self {
+            window,
+            substantive_chars,
+            last_errors: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn record_error(&self, key: &str) {
+        if self.window.is_zero() {
+            return;
+        }
+
+        let mut last_errors = self.last_errors.lock().unwrap();
+        last_errors.retain(|_, at| at.elapsed() < self.window);
+        last_errors.insert(key.to_string(), Instant::now());
+    }
+
+    /// Whether an idle event for `key` should be dropped. Clears the pending error
+    /// either way, so only the first idle after an error is considered.
+    pub fn should_suppress_idle(&self, key: &str, text: &str) -> bool {
+        let Some(error_at) = self.last_errors.lock().unwrap().remove(key) else {
+            return false;
+        };
+
+        error_at.elapsed() < self.window && text.trim().chars().count() < self.substantive_chars
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suppresses_short_idle_right_after_error() {
+        let dampener = ErrorIdleDampener::new(Duration::from_secs(5), 20);
+        dampener.record_error("proj/opencode");
+
+        assert!(dampener.should_suppress_idle("proj/opencode", "retrying"));
+        assert!(!dampener.should_suppress_idle("proj/opencode", "retrying"));
+    }
+
+    #[test]
+    fn passes_substantive_idle_and_other_instances() {
+        let dampener = ErrorIdleDampener::new(Duration::from_secs(5), 20);
+        dampener.record_error("proj/opencode");
+
+        assert!(!dampener.should_suppress_idle("proj/claude", "ok"));
+        assert!(!dampener.should_suppress_idle("proj/opencode", &"done ".repeat(10)));
+    }
+
+    #[test]
+    fn passes_idle_outside_window_or_when_disabled() {
+        let dampener = ErrorIdleDampener::new(Duration::from_millis(10), 20);
+        dampener.record_error("proj/opencode");
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!dampener.should_suppress_idle("proj/opencode", "ok"));
+
+        let disabled = ErrorIdleDampener::new(Duration::ZERO, 20);
+        disabled.record_error("proj/opencode");
+        assert!(!disabled.should_suppress_idle("proj/opencode", "ok"));
+    }
+}
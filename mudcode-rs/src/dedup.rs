@@ -0,0 +1,268 @@
+use anyhow::Context;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::warn;
+
+pub const DEFAULT_EVENT_DEDUP_CAPACITY: usize = 1024;
+pub const DEFAULT_EVENT_DEDUP_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Bounded least-recently-seen set of event ids used to drop redelivered events.
+#[derive(Debug)]
+pub struct SeenIds {
+    capacity: usize,
+    inner: Mutex<SeenInner>,
+}
+
+/// What `SeenIds::claim` found for an id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Claim {
+    /// Not seen before; now recorded as in progress.
+    New,
+    /// Already delivered.
+    Seen,
+    /// Claimed by a delivery that hasn't finished or been forgotten yet.
+    InProgress,
+}
+
+#[derive(Debug, Default)]
+struct SeenInner {
+    /// Ids oldest first, each tagged with the sequence number it was seen at.
+    /// Entries whose number no longer matches `ids` are stale and skipped, so a
+    /// refresh is a push rather than a search.
+    order: VecDeque<(u64, String)>,
+    /// Each remembered id and the sequence number of its live `order` entry.
+    ids: HashMap<String, u64>,
+    next_seq: u64,
+    /// Claimed ids whose delivery is still running.
+    pending: HashSet<String>,
+    /// Changed since the last `flush`.
+    dirty: bool,
+}
+
+impl SeenIds {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::new(SeenInner::default()),
+        }
+    }
+
+    /// Seed the set from the ids saved at `path`, oldest first. A missing file
+    /// starts empty; an unreadable one is logged and ignored.
+    pub fn load(capacity: usize, path: &Path) -> Self {
+        let seen = Self::new(capacity);
+        let ids = match fs::read_to_string(path) {
+            Ok(data) => serde_json::from_str::<Vec<String>>(&data).unwrap_or_else(|error| {
+                warn!("ignoring unreadable dedup file {}: {error}", path.display());
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+        for id in ids {
+            seen.insert(&id);
+        }
+        seen.inner.lock().unwrap().dirty = false;
+        seen
+    }
+
+    /// Write the delivered ids to `path` if they changed since the last flush,
+    /// atomically via a sibling temp file.
+    pub fn flush(&self, path: &Path) -> anyhow::Result<()> {
+        let ids = {
+            let mut inner = self.inner.lock().unwrap();
+            if !inner.dirty {
+                return Ok(());
+            }
+            inner.dirty = false;
+            inner
+                .live_order()
+                .filter(|id| !inner.pending.contains(*id))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+
+        let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(".tmp");
+        let tmp_path = path.with_file_name(tmp_name);
+        let result = fs::write(&tmp_path, serde_json::to_string(&ids)?)
+            .and_then(|()| fs::rename(&tmp_path, path))
+            .with_context(|| format!("failed to write {}", path.display()));
+        if result.is_err() {
+            self.inner.lock().unwrap().dirty = true;
+        }
+        result
+    }
+
+    /// Record `id` as delivered, returning `false` when it was already seen or
+    /// is in progress. A zero capacity disables deduplication.
+    pub fn insert(&self, id: &str) -> bool {
+        let claim = self.claim(id);
+        if claim == Claim::New {
+            self.finish(id);
+        }
+        claim == Claim::New
+    }
+
+    /// Record `id` as in progress unless it was already seen or claimed. Follow
+    /// with `finish` once delivered, or `forget` so a retry is accepted.
+    pub fn claim(&self, id: &str) -> Claim {
+        if self.capacity == 0 {
+            return Claim::New;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        if inner.pending.contains(id) {
+            return Claim::InProgress;
+        }
+        if inner.ids.contains_key(id) {
+            inner.touch(id);
+            return Claim::Seen;
+        }
+
+        while inner.ids.len() >= self.capacity {
+            let Some((seq, oldest)) = inner.order.pop_front() else {
+                break;
+            };
+            if inner.ids.get(&oldest) == Some(&seq) {
+                inner.ids.remove(&oldest);
+                inner.pending.remove(&oldest);
+            }
+        }
+
+        inner.touch(id);
+        inner.pending.insert(id.to_string());
+        inner.dirty = true;
+        Claim::New
+    }
+
+    /// Mark a claimed `id` as delivered.
+    pub fn finish(&self, id: &str) {
+        self.inner.lock().unwrap().pending.remove(id);
+    }
+
+    /// Forget `id` so a retried delivery of the same event is accepted again.
+    pub fn forget(&self, id: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.pending.remove(id);
+        if inner.ids.remove(id).is_some() {
+            inner.dirty = true;
+        }
+    }
+}
+
+impl SeenInner {
+    /// Make `id` the most recently seen, dropping stale `order` entries once
+    /// they outnumber the live ones.
+    fn touch(&mut self, id: &str) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.ids.insert(id.to_string(), seq);
+        self.order.push_back((seq, id.to_string()));
+
+        if self.order.len() > 2 * self.ids.len() + 16 {
+            let ids = &self.ids;
+            self.order
+                .retain(|(seq, id)| ids.get(id.as_str()) == Some(seq));
+        }
+    }
+
+    /// The remembered ids, least recently seen first.
+    fn live_order(&self) -> impl Iterator<Item = &String> {
+        self.order
+            .iter()
+            .filter(|(seq, id)| self.ids.get(id.as_str()) == Some(seq))
+            .map(|(_, id)| id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn duplicate_id_is_rejected_and_new_id_passes() {
+        let seen = SeenIds::new(8);
+        assert!(seen.insert("turn-1"));
+        assert!(!seen.insert("turn-1"));
+        assert!(seen.insert("turn-2"));
+    }
+
+    #[test]
+    fn capacity_evicts_least_recently_seen() {
+        let seen = SeenIds::new(2);
+        assert!(seen.insert("a"));
+        assert!(seen.insert("b"));
+        assert!(!seen.insert("a"));
+        assert!(seen.insert("c"));
+
+        assert!(!seen.insert("a"));
+        assert!(seen.insert("b"));
+    }
+
+    #[test]
+    fn repeated_refreshes_keep_the_order_bounded() {
+        let seen = SeenIds::new(2);
+        assert!(seen.insert("a"));
+        assert!(seen.insert("b"));
+        for _ in 0..1000 {
+            assert!(!seen.insert("a"));
+        }
+
+        assert!(seen.inner.lock().unwrap().order.len() <= 2 * 2 + 17);
+        assert!(seen.insert("c"));
+        assert!(!seen.insert("a"));
+        assert!(seen.insert("b"));
+    }
+
+    #[test]
+    fn forgotten_id_is_accepted_again() {
+        let seen = SeenIds::new(8);
+        assert!(seen.insert("turn-1"));
+        seen.forget("turn-1");
+        assert!(seen.insert("turn-1"));
+    }
+
+    #[test]
+    fn claimed_id_is_in_progress_until_finished_or_forgotten() {
+        let seen = SeenIds::new(8);
+        assert_eq!(seen.claim("turn-1"), Claim::New);
+        assert_eq!(seen.claim("turn-1"), Claim::InProgress);
+        seen.finish("turn-1");
+        assert_eq!(seen.claim("turn-1"), Claim::Seen);
+
+        assert_eq!(seen.claim("turn-2"), Claim::New);
+        seen.forget("turn-2");
+        assert_eq!(seen.claim("turn-2"), Claim::New);
+    }
+
+    #[test]
+    fn zero_capacity_disables_dedup() {
+        let seen = SeenIds::new(0);
+        assert!(seen.insert("turn-1"));
+        assert!(seen.insert("turn-1"));
+    }
+
+    #[test]
+    fn flushed_ids_are_still_seen_after_reload() {
+        let dir = TempDir::new("dedup-persist");
+        let path = dir.path().join("seen.json");
+
+        let before = SeenIds::load(2, &path);
+        assert!(before.insert("turn-1"));
+        assert!(before.insert("turn-2"));
+        assert!(before.insert("turn-3"));
+        before.flush(&path).unwrap();
+
+        let after = SeenIds::load(2, &path);
+        assert!(!after.insert("turn-3"));
+        assert!(!after.insert("turn-2"));
+        assert!(after.insert("turn-1"));
+
+        fs::write(&path, "not json").unwrap();
+        assert!(SeenIds::load(2, &path).insert("turn-3"));
+    }
+}
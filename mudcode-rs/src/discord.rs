@@ -1,65 +1,660 @@
-use crate::parser::split_for_discord;
+use crate::channel_queue::{ChannelQueues, ChannelTurn};
+use crate::circuit::{
+    CircuitBreaker, DEFAULT_CIRCUIT_BREAKER_COOLDOWN, DEFAULT_CIRCUIT_BREAKER_THRESHOLD,
+};
+use crate::parser::{encode_url_segment, split_for_discord};
 use anyhow::{Context, anyhow};
+use reqwest::StatusCode;
 use reqwest::multipart::{Form, Part};
-use serde_json::json;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::collections::HashMap;
 use std::path::Path;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+use tracing::{debug, info, warn};
+
+pub const DEFAULT_DISCORD_API_BASE: &str = "https://discord.com/api/v10";
+pub const DEFAULT_RATE_LIMIT_RETRIES: usize = 5;
+pub const DEFAULT_CONNECT_RETRIES: usize = 2;
+pub const DEFAULT_CONNECT_RETRY_BACKOFF: Duration = Duration::from_millis(200);
+pub const DEFAULT_SERVER_ERROR_RETRIES: usize = 2;
+pub const DEFAULT_SERVER_ERROR_BACKOFF: Duration = Duration::from_millis(250);
+pub const DEFAULT_CHUNK_DELAY_BASE: Duration = Duration::from_millis(250);
+pub const DEFAULT_CHUNK_DELAY_PER_CHUNK: Duration = Duration::from_millis(50);
+pub const DEFAULT_CHUNK_DELAY_MAX: Duration = Duration::from_millis(1500);
+pub const DEFAULT_ATTACHMENT_MIME: &str = "application/octet-stream";
+pub const DEFAULT_MAX_ATTACHMENT_BYTES: u64 = 25 * 1024 * 1024;
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const USER_AGENT: &str = concat!(
+    "DiscordBot (https://github.com/mud-the-developer/mudcode, ",
+    env!("CARGO_PKG_VERSION"),
+    ")"
+);
+
+/// Fallback wait when a 429 response carries no usable `retry_after`.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+/// Discord rejects messages with more attachments than this.
+const MAX_ATTACHMENTS_PER_MESSAGE: usize = 10;
+/// Discord's per-field embed limits, in characters.
+const MAX_EMBED_TITLE_CHARS: usize = 256;
+const MAX_EMBED_DESCRIPTION_CHARS: usize = 4096;
+const MAX_EMBED_FOOTER_CHARS: usize = 2048;
+
+/// Order in which attachments appear in an upload.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AttachmentOrder {
+    #[default]
+    AsProvided,
+    ByName,
+    /// Oldest first, so generated files read in the order they were written.
+    ByMtime,
+}
+
+/// What the byte caps do with a file whose size can't be read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnknownSize {
+    /// Skip it as if it were over the cap.
+    Skip,
+    /// Attach it whatever its size; its bytes still count toward the per-event
+    /// cap for the files after it.
+    Allow,
+    /// Read at most the per-file cap or what's left of the per-event cap, and
+    /// skip it if there is more.
+    #[default]
+    ReadThenCheck,
+}
+
+/// A single message embed, as posted by `DiscordClient::send_embed`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Embed {
+    pub title: String,
+    pub description: String,
+    pub color: u32,
+    pub footer: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DiscordSettings {
+    pub api_base: String,
+    /// Pause every outbound request when Discord reports a global rate limit.
+    pub pause_on_global_rate_limit: bool,
+    pub rate_limit_retries: usize,
+    /// Retries for failures to connect (DNS, refused connections, connect timeouts).
+    pub connect_retries: usize,
+    /// Base delay for connection retries, doubled on each attempt.
+    pub connect_retry_backoff: Duration,
+    /// Retries for 5xx responses, `server_error_backoff` doubled (plus jitter) apart.
+    pub server_error_retries: usize,
+    pub server_error_backoff: Duration,
+    pub connect_timeout: Duration,
+    /// Limit on a whole request, from connecting to the end of the response.
+    pub request_timeout: Duration,
+    pub attachment_order: AttachmentOrder,
+    /// Delay between chunks of a split message; grows with the chunk count.
+    pub chunk_pacing: ChunkPacing,
+    /// Files larger than this are skipped instead of failing the whole upload.
+    pub max_attachment_bytes: Option<u64>,
+    /// Cap on the summed size of one upload's attachments; later files are skipped.
+    pub max_event_attachment_bytes: Option<u64>,
+    pub unknown_size: UnknownSize,
+    /// Content type for attachments whose extension isn't recognized.
+    pub default_attachment_mime: String,
+    /// Keep a readable approximation of non-UTF-8 file names instead of falling
+    /// back to `attachment.bin`.
+    pub lossy_file_names: bool,
+    /// Consecutive failed requests that open the circuit breaker; 0 disables it.
+    pub circuit_breaker_threshold: usize,
+    pub circuit_breaker_cooldown: Duration,
+    /// Show the typing indicator before sending messages, at most once per
+    /// interval per channel. `None` disables it.
+    pub typing_interval: Option<Duration>,
+    /// When adding files to a message that no longer exists, post them as a new
+    /// message instead of failing.
+    pub repost_on_unknown_message: bool,
+    /// Content of that reposted message, e.g. a "continued" marker.
+    pub repost_note: String,
+    /// Fetch each posted text message back by id and post it again once if
+    /// Discord doesn't have it. Costs an extra request per message.
+    pub verify_delivery: bool,
+    /// Let `@everyone`, `@here`, role and user mentions in posted text notify.
+    /// Off by default, so mentions render without pinging anyone.
+    pub allow_mentions: bool,
+    /// Log messages, embeds and uploads instead of sending them; other requests
+    /// are skipped, and creating channels fails.
+    pub dry_run: bool,
+}
+
+impl Default for DiscordSettings {
+    fn default() -> Self {
+        Self {
+            api_base: DEFAULT_DISCORD_API_BASE.to_string(),
+            pause_on_global_rate_limit: true,
+            rate_limit_retries: DEFAULT_RATE_LIMIT_RETRIES,
+            connect_retries: DEFAULT_CONNECT_RETRIES,
+            connect_retry_backoff: DEFAULT_CONNECT_RETRY_BACKOFF,
+            server_error_retries: DEFAULT_SERVER_ERROR_RETRIES,
+            server_error_backoff: DEFAULT_SERVER_ERROR_BACKOFF,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            attachment_order: AttachmentOrder::default(),
+            default_attachment_mime: DEFAULT_ATTACHMENT_MIME.to_string(),
+            lossy_file_names: false,
+            chunk_pacing: ChunkPacing::default(),
+            max_attachment_bytes: Some(DEFAULT_MAX_ATTACHMENT_BYTES),
+            max_event_attachment_bytes: None,
+            unknown_size: UnknownSize::default(),
+            circuit_breaker_threshold: DEFAULT_CIRCUIT_BREAKER_THRESHOLD,
+            circuit_breaker_cooldown: DEFAULT_CIRCUIT_BREAKER_COOLDOWN,
+            typing_interval: None,
+            repost_on_unknown_message: false,
+            repost_note: String::new(),
+            verify_delivery: false,
+            allow_mentions: false,
+            dry_run: false,
+        }
+    }
+}
+
+/// Inter-chunk delay curve: `base + per_chunk * (chunks - 1)`, capped at `max`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkPacing {
+    pub base: Duration,
+    pub per_chunk: Duration,
+    pub max: Duration,
+}
+
+impl Default for ChunkPacing {
+    fn default() -> Self {
+        Self {
+            base: DEFAULT_CHUNK_DELAY_BASE,
+            per_chunk: DEFAULT_CHUNK_DELAY_PER_CHUNK,
+            max: DEFAULT_CHUNK_DELAY_MAX,
+        }
+    }
+}
+
+impl ChunkPacing {
+    /// Delay between consecutive sends of a message split into `chunks` parts.
+    pub fn delay_for(&self, chunks: usize) -> Duration {
+        let extra = u32::try_from(chunks.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.base
+            .saturating_add(self.per_chunk.saturating_mul(extra))
+            .min(self.max)
+    }
+}
 
 #[derive(Clone)]
 pub struct DiscordClient {
     http: reqwest::Client,
     bot_token: String,
+    settings: Arc<DiscordSettings>,
+    global_pause: Arc<Mutex<Option<Instant>>>,
+    breaker: Arc<CircuitBreaker>,
+    last_typing: Arc<Mutex<HashMap<String, Instant>>>,
+    /// Held while posting to a channel so concurrent sends land in submission order.
+    channel_queues: ChannelQueues,
+    /// Held by `hold_channel` for a whole event delivery.
+    event_queues: ChannelQueues,
 }
 
 impl DiscordClient {
-    pub fn new(bot_token: String) -> Self {
+    pub fn new(bot_token: String, mut settings: DiscordSettings) -> Self {
+        settings.api_base = settings.api_base.trim_end_matches('/').to_string();
+
         Self {
-            http: reqwest::Client::new(),
+            http: http_client(&settings),
             bot_token,
+            breaker: Arc::new(CircuitBreaker::new(
+                settings.circuit_breaker_threshold,
+                settings.circuit_breaker_cooldown,
+            )),
+            settings: Arc::new(settings),
+            global_pause: Arc::new(Mutex::new(None)),
+            last_typing: Arc::default(),
+            channel_queues: ChannelQueues::default(),
+            event_queues: ChannelQueues::default(),
         }
     }
 
+    /// This client with `settings` applied. When the token and API base are
+    /// unchanged it keeps its channel queues, global pause and typing throttle,
+    /// and its circuit breaker unless the breaker settings changed; otherwise
+    /// it's a new client.
+    pub fn reconfigured(&self, bot_token: String, mut settings: DiscordSettings) -> Self {
+        if !self.uses(&bot_token, &settings.api_base) {
+            return Self::new(bot_token, settings);
+        }
+        settings.api_base = self.settings.api_base.clone();
+
+        let old = &self.settings;
+        let http = if (old.connect_timeout, old.request_timeout)
+            == (settings.connect_timeout, settings.request_timeout)
+        {
+            self.http.clone()
+        } else {
+            http_client(&settings)
+        };
+        let breaker = if (old.circuit_breaker_threshold, old.circuit_breaker_cooldown)
+            == (
+                settings.circuit_breaker_threshold,
+                settings.circuit_breaker_cooldown,
+            ) {
+            Arc::clone(&self.breaker)
+        } else {
+            Arc::new(CircuitBreaker::new(
+                settings.circuit_breaker_threshold,
+                settings.circuit_breaker_cooldown,
+            ))
+        };
+        Self {
+            http,
+            breaker,
+            settings: Arc::new(settings),
+            ..self.clone()
+        }
+    }
+
+    /// Whether this client talks to `api_base` as the bot `bot_token`.
+    pub fn uses(&self, bot_token: &str, api_base: &str) -> bool {
+        self.bot_token == bot_token && self.settings.api_base == api_base.trim_end_matches('/')
+    }
+
+    /// Pause between consecutive messages of a reply split into `chunks` parts.
+    pub fn chunk_delay(&self, chunks: usize) -> Duration {
+        self.settings.chunk_pacing.delay_for(chunks)
+    }
+
+    fn messages_url(&self, channel_id: &str) -> String {
+        format!("{}/channels/{channel_id}/messages", self.settings.api_base)
+    }
+
     fn auth_header(&self) -> String {
         format!("Bot {}", self.bot_token)
     }
 
-    pub async fn send_message(&self, channel_id: &str, content: &str) -> anyhow::Result<()> {
+    /// Wait for earlier sends to `channel_id` to finish. The lock is fair, so
+    /// waiters are served in the order they queued.
+    async fn queue_for(&self, channel_id: &str) -> ChannelTurn {
+        self.channel_queues.turn(channel_id).await
+    }
+
+    /// Keep other events off `channel_id` until the returned turn is dropped, so
+    /// one event's chunks, files and reaction aren't interleaved with another's.
+    /// Each send inside still queues on its own.
+    pub async fn hold_channel(&self, channel_id: &str) -> ChannelTurn {
+        self.event_queues.turn(channel_id).await
+    }
+
+    /// Time left on a global rate-limit pause, if one is active.
+    pub fn global_pause_remaining(&self) -> Option<Duration> {
+        let until = (*self.global_pause.lock().unwrap())?;
+        until.checked_duration_since(Instant::now())
+    }
+
+    pub fn pause_globally(&self, wait: Duration) {
+        let until = Instant::now() + wait;
+        let mut pause = self.global_pause.lock().unwrap();
+        if pause.is_none_or(|current| current < until) {
+            *pause = Some(until);
+        }
+    }
+
+    pub async fn wait_for_global_pause(&self) {
+        while let Some(remaining) = self.global_pause_remaining() {
+            tokio::time::sleep(remaining).await;
+        }
+    }
+
+    /// Send through the circuit breaker: fail fast while it is open, and count
+    /// connection failures and 5xx responses towards opening it.
+    async fn execute<F>(&self, what: &str, build: F) -> anyhow::Result<reqwest::Response>
+    where
+        F: Fn() -> reqwest::RequestBuilder,
+    {
+        if let Err(retry_in) = self.breaker.check() {
+            return Err(anyhow::Error::new(DiscordError::CircuitOpen { retry_in }));
+        }
+
+        let result = self.execute_with_retries(what, build).await;
+        match &result {
+            Ok(response) if !response.status().is_server_error() => self.breaker.record_success(),
+            _ => {
+                if self.breaker.record_failure() {
+                    warn!(
+                        "Discord circuit opened after repeated failures; failing fast for {}s",
+                        self.settings.circuit_breaker_cooldown.as_secs()
+                    );
+                }
+            }
+        }
+        result
+    }
+
+    /// Send a request built by `build`, waiting out 429 responses and retrying
+    /// 5xx responses and failures to connect before giving up.
+    async fn execute_with_retries<F>(
+        &self,
+        what: &str,
+        build: F,
+    ) -> anyhow::Result<reqwest::Response>
+    where
+        F: Fn() -> reqwest::RequestBuilder,
+    {
+        let mut attempt = 0;
+        let mut connect_attempt = 0;
+        let mut server_error_attempt = 0;
+
+        loop {
+            self.wait_for_global_pause().await;
+
+            let response = match build()
+                .header("Authorization", self.auth_header())
+                .send()
+                .await
+            {
+                Ok(response) => response,
+                Err(error)
+                    if is_connection_error(&error)
+                        && connect_attempt < self.settings.connect_retries =>
+                {
+                    let backoff =
+                        backoff_with_jitter(self.settings.connect_retry_backoff, connect_attempt);
+                    connect_attempt += 1;
+                    warn!(
+                        "Discord {what} request connection failed; retrying in {}ms (attempt {connect_attempt}): {error}",
+                        backoff.as_millis()
+                    );
+                    tokio::time::sleep(backoff).await;
+                    continue;
+                }
+                Err(error) if error.is_timeout() => {
+                    let limit = self.settings.request_timeout.as_millis();
+                    return Err(error).with_context(|| {
+                        format!("Discord {what} request timed out (limit {limit}ms)")
+                    });
+                }
+                Err(error) => {
+                    return Err(error)
+                        .with_context(|| format!("failed to send Discord {what} request"));
+                }
+            };
+
+            if response.status().is_server_error()
+                && server_error_attempt < self.settings.server_error_retries
+            {
+                let backoff =
+                    backoff_with_jitter(self.settings.server_error_backoff, server_error_attempt);
+                server_error_attempt += 1;
+                warn!(
+                    "Discord {what} request failed ({}); retrying in {}ms (attempt {server_error_attempt})",
+                    response.status(),
+                    backoff.as_millis()
+                );
+                tokio::time::sleep(backoff).await;
+                continue;
+            }
+
+            if response.status() != StatusCode::TOO_MANY_REQUESTS
+                || attempt >= self.settings.rate_limit_retries
+            {
+                return Ok(response);
+            }
+
+            attempt += 1;
+            let headers = response.headers().clone();
+            let body = response.json::<Value>().await.unwrap_or_default();
+            let retry_after = retry_after(&headers, &body);
+            let global = body.get("global").and_then(Value::as_bool) == Some(true);
+
+            if global && self.settings.pause_on_global_rate_limit {
+                warn!(
+                    "Discord global rate limit hit; pausing all requests for {}ms",
+                    retry_after.as_millis()
+                );
+                self.pause_globally(retry_after);
+            } else {
+                warn!(
+                    "Discord rate limited {what} request; retrying in {}ms (attempt {attempt})",
+                    retry_after.as_millis()
+                );
+                tokio::time::sleep(retry_after).await;
+            }
+        }
+    }
+
+    /// Check the bot token with `GET /users/@me`, returning the bot's username.
+    pub async fn verify_token(&self) -> anyhow::Result<String> {
+        if self.settings.dry_run {
+            return Ok("dry run".to_string());
+        }
+        let url = format!("{}/users/@me", self.settings.api_base);
+        let response = self.execute("token check", || self.http.get(&url)).await?;
+        let response = read_response("token check", response).await?;
+        Ok(response
+            .body
+            .as_ref()
+            .and_then(|body| body.get("username"))
+            .and_then(Value::as_str)
+            .unwrap_or("unknown")
+            .to_string())
+    }
+
+    /// Create a text channel in `guild_id`, optionally under category `parent_id`,
+    /// returning the new channel id.
+    pub async fn create_channel(
+        &self,
+        guild_id: &str,
+        name: &str,
+        parent_id: Option<&str>,
+    ) -> anyhow::Result<String> {
+        if self.settings.dry_run {
+            info!("dry run: create channel {name} guild={guild_id} parent={parent_id:?}");
+            return Ok(format!("dry-run-{name}"));
+        }
+        let url = format!("{}/guilds/{guild_id}/channels", self.settings.api_base);
+        let mut body = json!({ "name": name, "type": 0 });
+        if let Some(parent_id) = parent_id {
+            body["parent_id"] = json!(parent_id);
+        }
+
+        let response = self
+            .execute("channel create", || self.http.post(&url).json(&body))
+            .await?;
+
+        read_response("create channel", response)
+            .await?
+            .id()
+            .ok_or_else(|| anyhow!("Discord create channel response has no id"))
+    }
+
+    /// Send `content`, split as needed, returning the ids of the posted messages.
+    pub async fn send_message(
+        &self,
+        channel_id: &str,
+        content: &str,
+    ) -> anyhow::Result<Vec<String>> {
+        self.send_reply(channel_id, content, None).await
+    }
+
+    /// Like `send_message`, with the first message replying to `reply_to` when
+    /// given. A reply to a deleted message is posted as a plain message.
+    pub async fn send_reply(
+        &self,
+        channel_id: &str,
+        content: &str,
+        reply_to: Option<&str>,
+    ) -> anyhow::Result<Vec<String>> {
         let chunks = split_for_discord(content);
+        let delay = self.settings.chunk_pacing.delay_for(chunks.len());
+        let mut message_ids = Vec::new();
+        let _queued = self.queue_for(channel_id).await;
 
         for (idx, chunk) in chunks.iter().enumerate() {
-            self.send_message_chunk(channel_id, chunk).await?;
+            self.trigger_typing(channel_id);
+            let reply_to = reply_to.filter(|_| idx == 0);
+            message_ids.extend(self.send_message_chunk(channel_id, chunk, reply_to).await?);
             if idx < chunks.len() - 1 {
-                tokio::time::sleep(Duration::from_millis(500)).await;
+                tokio::time::sleep(delay).await;
             }
         }
 
-        Ok(())
+        Ok(message_ids)
     }
 
-    async fn send_message_chunk(&self, channel_id: &str, content: &str) -> anyhow::Result<()> {
-        let url = format!("https://discord.com/api/v10/channels/{channel_id}/messages");
-        let body = json!({ "content": content });
+    /// Best-effort typing indicator, throttled per channel by `typing_interval`.
+    /// Sent once in the background, outside the circuit breaker and retries, so
+    /// it never delays or fails a delivery.
+    fn trigger_typing(&self, channel_id: &str) {
+        let Some(interval) = self
+            .settings
+            .typing_interval
+            .filter(|_| !self.settings.dry_run)
+        else {
+            return;
+        };
+        {
+            let mut last_typing = self.last_typing.lock().unwrap();
+            if last_typing
+                .get(channel_id)
+                .is_some_and(|at| at.elapsed() < interval)
+            {
+                return;
+            }
+            last_typing.insert(channel_id.to_string(), Instant::now());
+        }
 
-        let response = self
+        let url = format!("{}/channels/{channel_id}/typing", self.settings.api_base);
+        let request = self
             .http
             .post(url)
             .header("Authorization", self.auth_header())
-            .json(&body)
-            .send()
-            .await
-            .context("failed to send Discord message request")?;
+            .header(reqwest::header::CONTENT_LENGTH, 0);
+        let channel_id = channel_id.to_string();
+        tokio::spawn(async move {
+            match request.send().await {
+                Ok(response) if response.status().is_success() => {}
+                Ok(response) => debug!(
+                    "typing indicator rejected channel={channel_id} status={}",
+                    response.status()
+                ),
+                Err(error) => debug!("typing indicator failed channel={channel_id}: {error}"),
+            }
+        });
+    }
 
-        if response.status().is_success() {
-            return Ok(());
+    async fn send_message_chunk(
+        &self,
+        channel_id: &str,
+        content: &str,
+        reply_to: Option<&str>,
+    ) -> anyhow::Result<Option<String>> {
+        let message_id = self.post_message(channel_id, content, reply_to).await?;
+        if !self.settings.verify_delivery {
+            return Ok(message_id);
         }
 
-        let status = response.status();
-        let text = response
-            .text()
-            .await
-            .unwrap_or_else(|e| format!("<failed to read response body: {e}>"));
+        match message_id.as_deref() {
+            Some(id) if !self.message_exists(channel_id, id).await => {
+                warn!("message {id} missing right after send; posting again channel={channel_id}");
+                self.post_message(channel_id, content, reply_to).await
+            }
+            _ => Ok(message_id),
+        }
+    }
+
+    /// Whether `message_id` can be fetched. Only an unknown message error counts
+    /// as missing; other failures are logged and assumed delivered.
+    async fn message_exists(&self, channel_id: &str, message_id: &str) -> bool {
+        let url = format!("{}/{message_id}", self.messages_url(channel_id));
+        let result = match self.execute("verify", || self.http.get(&url)).await {
+            Ok(response) => read_response("verify message", response).await.map(drop),
+            Err(error) => Err(error),
+        };
+        match result {
+            Ok(()) => true,
+            Err(error) if DiscordError::is_unknown_message(&error) => false,
+            Err(error) => {
+                warn!("could not verify message {message_id} channel={channel_id}: {error:#}");
+                true
+            }
+        }
+    }
 
-        Err(anyhow!("Discord send message failed ({status}): {text}"))
+    /// `body` with mentions suppressed unless `allow_mentions` is on.
+    fn with_mentions_policy(&self, mut body: Value) -> Value {
+        if !self.settings.allow_mentions {
+            body["allowed_mentions"] = json!({ "parse": [] });
+        }
+        body
+    }
+
+    async fn post_message(
+        &self,
+        channel_id: &str,
+        content: &str,
+        reply_to: Option<&str>,
+    ) -> anyhow::Result<Option<String>> {
+        if self.settings.dry_run {
+            info!(
+                "dry run: message channel={channel_id} reply_to={reply_to:?} content={content:?}"
+            );
+            return Ok(None);
+        }
+        let url = self.messages_url(channel_id);
+        let mut body = self.with_mentions_policy(json!({ "content": content }));
+        if let Some(message_id) = reply_to {
+            body["message_reference"] =
+                json!({ "message_id": message_id, "fail_if_not_exists": false });
+        }
+
+        let response = self
+            .execute("message", || self.http.post(&url).json(&body))
+            .await?;
+
+        Ok(read_response("send message", response).await?.id())
+    }
+
+    /// Post `embed` as its own message, truncating fields to Discord's limits.
+    pub async fn send_embed(
+        &self,
+        channel_id: &str,
+        embed: &Embed,
+        reply_to: Option<&str>,
+    ) -> anyhow::Result<Option<String>> {
+        if self.settings.dry_run {
+            info!(
+                "dry run: embed channel={channel_id} title={:?} description={:?}",
+                embed.title, embed.description
+            );
+            return Ok(None);
+        }
+        let url = self.messages_url(channel_id);
+        let mut rendered = json!({
+            "title": truncate_chars(&embed.title, MAX_EMBED_TITLE_CHARS),
+            "description": truncate_chars(&embed.description, MAX_EMBED_DESCRIPTION_CHARS),
+            "color": embed.color,
+        });
+        if let Some(footer) = &embed.footer {
+            rendered["footer"] = json!({ "text": truncate_chars(footer, MAX_EMBED_FOOTER_CHARS) });
+        }
+        let mut body = self.with_mentions_policy(json!({ "embeds": [rendered] }));
+        if let Some(message_id) = reply_to {
+            body["message_reference"] =
+                json!({ "message_id": message_id, "fail_if_not_exists": false });
+        }
+
+        let _queued = self.queue_for(channel_id).await;
+        self.trigger_typing(channel_id);
+        let response = self
+            .execute("embed", || self.http.post(&url).json(&body))
+            .await?;
+
+        Ok(read_response("send embed", response).await?.id())
     }
 
     pub async fn send_files(
@@ -67,54 +662,1659 @@ impl DiscordClient {
         channel_id: &str,
         content: &str,
         file_paths: &[String],
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<Vec<String>> {
         if file_paths.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let (attachments, skipped) = self.read_attachments(channel_id, file_paths).await?;
+        let _queued = self.queue_for(channel_id).await;
+
+        let mut content = content.trim().to_string();
+        if !skipped.is_empty() {
+            let note = skipped_note(&skipped);
+            content = if content.is_empty() {
+                note
+            } else {
+                format!("{content}\n{note}")
+            };
+            if attachments.is_empty() {
+                return Ok(self
+                    .send_message_chunk(channel_id, &content, None)
+                    .await?
+                    .into_iter()
+                    .collect());
+            }
+        }
+
+        let mut message_ids = Vec::new();
+        for (idx, batch) in attachments.chunks(MAX_ATTACHMENTS_PER_MESSAGE).enumerate() {
+            let content = if idx == 0 { content.as_str() } else { "" };
+            message_ids.extend(self.upload(channel_id, None, content, batch).await?);
+        }
+        Ok(message_ids)
+    }
+
+    /// Read `file_paths` in upload order, skipping files past the per-event byte cap.
+    /// Returns the attachments and the display names of skipped files.
+    async fn read_attachments(
+        &self,
+        channel_id: &str,
+        file_paths: &[String],
+    ) -> anyhow::Result<(Vec<(String, &str, Vec<u8>)>, Vec<String>)> {
+        let ordered = order_attachments(file_paths, self.settings.attachment_order);
+        let mut attachments = Vec::with_capacity(ordered.len());
+        let mut total_bytes: u64 = 0;
+        let mut skipped = Vec::new();
+        let mut over_event_cap = 0;
+        for path in &ordered {
+            let size = file_size(path).await;
+            let file_cap = self.settings.max_attachment_bytes;
+            if let (Some(cap), Some(size)) = (file_cap, size)
+                && size > cap
+            {
+                warn!(
+                    "skipping attachment {path} of {size} bytes over the {cap} byte limit channel={channel_id}"
+                );
+                skipped.push(format!(
+                    "{} ({} > {})",
+                    file_display_name(path),
+                    format_size(size),
+                    format_size(cap)
+                ));
+                continue;
+            }
+            let event_remaining = self
+                .settings
+                .max_event_attachment_bytes
+                .map(|cap| cap.saturating_sub(total_bytes));
+            let limit = match (file_cap, event_remaining) {
+                (Some(cap), Some(remaining)) => Some(cap.min(remaining)),
+                (cap, remaining) => cap.or(remaining),
+            };
+            let bytes = match limit {
+                Some(limit) => read_capped(path, size, limit, self.settings.unknown_size).await?,
+                None => Some(
+                    tokio::fs::read(path)
+                        .await
+                        .with_context(|| format!("failed to read attachment file: {path}"))?,
+                ),
+            };
+            let Some(bytes) = bytes else {
+                match file_cap
+                    .filter(|cap| event_remaining.is_none_or(|remaining| *cap < remaining))
+                {
+                    Some(cap) => {
+                        warn!(
+                            "skipping attachment {path} of unknown size over the {cap} byte limit channel={channel_id}"
+                        );
+                        skipped.push(format!(
+                            "{} (> {})",
+                            file_display_name(path),
+                            format_size(cap)
+                        ));
+                    }
+                    None => {
+                        skipped.push(file_display_name(path));
+                        over_event_cap += 1;
+                    }
+                }
+                continue;
+            };
+            total_bytes += bytes.len() as u64;
+
+            let filename = attachment_filename(Path::new(path), self.settings.lossy_file_names);
+
+            let mime = attachment_mime(path, &self.settings.default_attachment_mime);
+            attachments.push((filename, mime, bytes));
+        }
+
+        if over_event_cap > 0 {
+            warn!(
+                "skipping {over_event_cap} attachment(s) over the per-event byte cap channel={channel_id}"
+            );
+        }
+        Ok((attachments, skipped))
+    }
+
+    /// Add `file_paths` as attachments to an existing message, keeping its content.
+    /// Files over the byte cap are reported in a separate message. Returns the ids
+    /// of every message the files ended up in.
+    pub async fn attach_files(
+        &self,
+        channel_id: &str,
+        message_id: &str,
+        file_paths: &[String],
+    ) -> anyhow::Result<Vec<String>> {
+        let (attachments, skipped) = self.read_attachments(channel_id, file_paths).await?;
+        let _queued = self.queue_for(channel_id).await;
+        let mut message_ids = Vec::new();
+        let (first_batch, rest) =
+            attachments.split_at(attachments.len().min(MAX_ATTACHMENTS_PER_MESSAGE));
+        if !first_batch.is_empty() {
+            let first_id = match self
+                .upload(channel_id, Some(message_id), "", first_batch)
+                .await
+            {
+                Ok(_) => Some(message_id.to_string()),
+                Err(error)
+                    if self.settings.repost_on_unknown_message
+                        && DiscordError::is_unknown_message(&error) =>
+                {
+                    warn!(
+                        "message {message_id} is gone; posting files as a new message channel={channel_id}"
+                    );
+                    self.upload(channel_id, None, &self.settings.repost_note, first_batch)
+                        .await?
+                }
+                Err(error) => return Err(error.context(MergeFailed)),
+            };
+            message_ids.extend(first_id);
+        }
+        for batch in rest.chunks(MAX_ATTACHMENTS_PER_MESSAGE) {
+            message_ids.extend(self.upload(channel_id, None, "", batch).await?);
+        }
+        if !skipped.is_empty() {
+            let note = skipped_note(&skipped);
+            message_ids.extend(self.send_message_chunk(channel_id, &note, None).await?);
+        }
+        Ok(message_ids)
+    }
+
+    /// Post `content` with `text` attached as an in-memory `filename` file.
+    pub async fn send_text_attachment(
+        &self,
+        channel_id: &str,
+        content: &str,
+        filename: &str,
+        text: &str,
+    ) -> anyhow::Result<Option<String>> {
+        let attachment = (
+            filename.to_string(),
+            "text/plain; charset=utf-8",
+            text.as_bytes().to_vec(),
+        );
+        let _queued = self.queue_for(channel_id).await;
+        self.upload(channel_id, None, content, &[attachment]).await
+    }
+
+    /// Post a new message with `attachments`, or add them to `edit` when given.
+    async fn upload(
+        &self,
+        channel_id: &str,
+        edit: Option<&str>,
+        content: &str,
+        attachments: &[(String, &str, Vec<u8>)],
+    ) -> anyhow::Result<Option<String>> {
+        if self.settings.dry_run {
+            let files: Vec<&str> = attachments
+                .iter()
+                .map(|(name, _, _)| name.as_str())
+                .collect();
+            info!(
+                "dry run: upload channel={channel_id} edit={edit:?} files={files:?} content={content:?}"
+            );
+            return Ok(None);
+        }
+        let payload = match edit {
+            Some(_) => json!({
+                "attachments": attachments
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, (filename, _, _))| json!({ "id": idx, "filename": filename }))
+                    .collect::<Vec<_>>()
+            }),
+            None if content.trim().is_empty() => json!({}),
+            None => self.with_mentions_policy(json!({ "content": content })),
+        };
+
+        let build_form = || {
+            let mut form = Form::new().text("payload_json", payload.to_string());
+            for (idx, (filename, mime, bytes)) in attachments.iter().enumerate() {
+                let part = Part::bytes(bytes.clone())
+                    .file_name(filename.clone())
+                    .mime_str(mime)
+                    .unwrap_or_else(|_| Part::bytes(bytes.clone()).file_name(filename.clone()));
+                form = form.part(format!("files[{idx}]"), part);
+            }
+            form
+        };
+
+        let response = match edit {
+            Some(message_id) => {
+                let url = format!("{}/{message_id}", self.messages_url(channel_id));
+                self.execute("file upload", || {
+                    self.http.patch(&url).multipart(build_form())
+                })
+                .await?
+            }
+            None => {
+                let url = self.messages_url(channel_id);
+                self.execute("file upload", || {
+                    self.http.post(&url).multipart(build_form())
+                })
+                .await?
+            }
+        };
+
+        Ok(read_response("send files", response).await?.id())
+    }
+
+    /// React to `message_id` as the bot with a unicode emoji (or `name:id` custom emoji).
+    pub async fn add_reaction(
+        &self,
+        channel_id: &str,
+        message_id: &str,
+        emoji: &str,
+    ) -> anyhow::Result<()> {
+        if self.settings.dry_run {
+            info!("dry run: reaction {emoji} channel={channel_id} message={message_id}");
             return Ok(());
         }
+        let url = format!(
+            "{}/{message_id}/reactions/{}/@me",
+            self.messages_url(channel_id),
+            encode_url_segment(emoji)
+        );
+
+        let response = self
+            .execute("reaction", || {
+                self.http
+                    .put(&url)
+                    .header(reqwest::header::CONTENT_LENGTH, 0)
+            })
+            .await?;
+
+        read_response("add reaction", response).await?;
+        Ok(())
+    }
+}
+
+/// Discord's JSON error code for "Missing Permissions".
+const MISSING_PERMISSIONS_CODE: u64 = 50013;
+/// Discord's JSON error code for "Unknown Message".
+const UNKNOWN_MESSAGE_CODE: u64 = 10008;
+
+/// A non-success Discord API response.
+#[derive(Debug)]
+pub enum DiscordError {
+    /// 403 with code 50013: the bot can't post (or attach, react) in the channel.
+    MissingPermissions { what: String, body: String },
+    /// 401: the bot token is invalid or was revoked.
+    Unauthorized { what: String, body: String },
+    Api {
+        what: String,
+        status: StatusCode,
+        body: String,
+    },
+    /// Requests are failing fast after repeated failures.
+    CircuitOpen { retry_in: Duration },
+}
 
-        let payload = if content.trim().is_empty() {
-            json!({})
+impl std::fmt::Display for DiscordError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingPermissions { what, body } => {
+                write!(f, "Discord {what} failed (missing permissions): {body}")
+            }
+            Self::Unauthorized { what, body } => write!(
+                f,
+                "Discord {what} failed: token rejected (401), check discordToken in config.json: {body}"
+            ),
+            Self::Api { what, status, body } => {
+                write!(f, "Discord {what} failed ({status}): {body}")
+            }
+            Self::CircuitOpen { retry_in } => write!(
+                f,
+                "Discord circuit open after repeated failures; retry in {}ms",
+                retry_in.as_millis()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DiscordError {}
+
+/// Context on an `attach_files` error from adding files to the existing message,
+/// raised before anything was posted, so the caller can post them anew instead.
+#[derive(Debug)]
+pub struct MergeFailed;
+
+impl std::fmt::Display for MergeFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("adding files to the earlier message failed")
+    }
+}
+
+impl DiscordError {
+    pub fn is_missing_permissions(error: &anyhow::Error) -> bool {
+        matches!(
+            error.downcast_ref::<Self>(),
+            Some(Self::MissingPermissions { .. })
+        )
+    }
+
+    pub fn is_unauthorized(error: &anyhow::Error) -> bool {
+        matches!(
+            error.downcast_ref::<Self>(),
+            Some(Self::Unauthorized { .. })
+        )
+    }
+
+    /// Code 10008: the message was deleted. Other 404s, such as an unknown
+    /// channel or a wrong api base, don't count.
+    pub fn is_unknown_message(error: &anyhow::Error) -> bool {
+        match error.downcast_ref::<Self>() {
+            Some(Self::Api { body, .. }) => {
+                serde_json::from_str::<Value>(body)
+                    .ok()
+                    .and_then(|v| v.get("code").and_then(Value::as_u64))
+                    == Some(UNKNOWN_MESSAGE_CODE)
+            }
+            _ => false,
+        }
+    }
+
+    pub fn is_circuit_open(error: &anyhow::Error) -> bool {
+        matches!(error.downcast_ref::<Self>(), Some(Self::CircuitOpen { .. }))
+    }
+}
+
+async fn api_error(what: &str, response: reqwest::Response) -> anyhow::Error {
+    let status = response.status();
+    let body = response
+        .text()
+        .await
+        .unwrap_or_else(|e| format!("<failed to read response body: {e}>"));
+    let code = serde_json::from_str::<Value>(&body)
+        .ok()
+        .and_then(|v| v.get("code").and_then(Value::as_u64));
+
+    let what = what.to_string();
+    let error = if status == StatusCode::FORBIDDEN && code == Some(MISSING_PERMISSIONS_CODE) {
+        DiscordError::MissingPermissions { what, body }
+    } else if status == StatusCode::UNAUTHORIZED {
+        DiscordError::Unauthorized { what, body }
+    } else {
+        DiscordError::Api { what, status, body }
+    };
+    anyhow::Error::new(error)
+}
+
+fn http_client(settings: &DiscordSettings) -> reqwest::Client {
+    reqwest::Client::builder()
+        .connect_timeout(settings.connect_timeout)
+        .timeout(settings.request_timeout)
+        .user_agent(USER_AGENT)
+        .build()
+        .expect("failed to build the Discord HTTP client")
+}
+
+/// Length of `path` when it's a regular file. Pipes and devices report a length
+/// that says nothing about what a read returns.
+async fn file_size(path: &str) -> Option<u64> {
+    let meta = tokio::fs::metadata(path).await.ok()?;
+    meta.is_file().then_some(meta.len())
+}
+
+/// Read `path` if it fits in the `remaining` bytes allowed by the caps, `None`
+/// when it doesn't. `size` is its metadata length, if that could be read.
+async fn read_capped(
+    path: &str,
+    size: Option<u64>,
+    remaining: u64,
+    unknown: UnknownSize,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let context = || format!("failed to read attachment file: {path}");
+    match (size, unknown) {
+        (Some(size), _) if size > remaining => Ok(None),
+        (None, UnknownSize::Skip) => Ok(None),
+        (None, UnknownSize::ReadThenCheck) => {
+            let file = tokio::fs::File::open(path).await.with_context(context)?;
+            let mut bytes = Vec::new();
+            file.take(remaining.saturating_add(1))
+                .read_to_end(&mut bytes)
+                .await
+                .with_context(context)?;
+            Ok((bytes.len() as u64 <= remaining).then_some(bytes))
+        }
+        (Some(_), _) | (None, UnknownSize::Allow) => {
+            tokio::fs::read(path).await.with_context(context).map(Some)
+        }
+    }
+}
+
+/// How long a 429 asks us to wait: `X-RateLimit-Reset-After`, then the body's
+/// `retry_after`, then the `Retry-After` header, all in (fractional) seconds.
+fn retry_after(headers: &reqwest::header::HeaderMap, body: &Value) -> Duration {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<f64>().ok())
+    };
+    header("x-ratelimit-reset-after")
+        .or_else(|| body.get("retry_after").and_then(Value::as_f64))
+        .or_else(|| header("retry-after"))
+        .filter(|secs| secs.is_finite() && *secs >= 0.0)
+        .map_or(DEFAULT_RETRY_AFTER, Duration::from_secs_f64)
+}
+
+/// `bytes` in the largest binary unit it reaches, e.g. `30 MiB` or `1.5 KiB`.
+fn format_size(bytes: u64) -> String {
+    let mut value = bytes as f64;
+    let mut unit = "B";
+    for next in ["KiB", "MiB", "GiB"] {
+        if value < 1024.0 {
+            break;
+        }
+        value /= 1024.0;
+        unit = next;
+    }
+    let value = format!("{value:.1}");
+    format!("{} {unit}", value.trim_end_matches(".0"))
+}
+
+/// `text` cut to at most `limit` characters, ending in `…` when shortened.
+fn truncate_chars(text: &str, limit: usize) -> String {
+    if text.chars().count() <= limit {
+        return text.to_string();
+    }
+    let kept: String = text.chars().take(limit.saturating_sub(1)).collect();
+    format!("{}…", kept.trim_end())
+}
+
+fn skipped_note(skipped: &[String]) -> String {
+    format!(
+        "⚠️ Skipped {} file(s) over the attachment size cap: {}",
+        skipped.len(),
+        skipped.join(", ")
+    )
+}
+
+/// Upload name for `path`. Names that aren't valid UTF-8 become `attachment.bin`,
+/// or with `lossy` keep their readable parts with invalid sequences as `_`.
+fn attachment_filename(path: &Path, lossy: bool) -> String {
+    let name = path.file_name().and_then(|name| {
+        if lossy {
+            Some(
+                name.to_string_lossy()
+                    .replace(char::REPLACEMENT_CHARACTER, "_"),
+            )
         } else {
-            json!({ "content": content })
+            name.to_str().map(str::to_string)
+        }
+    });
+
+    name.filter(|v| !v.trim().is_empty())
+        .unwrap_or_else(|| "attachment.bin".to_string())
+}
+
+fn file_display_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map_or_else(|| path.to_string(), |n| n.to_string_lossy().into_owned())
+}
+
+/// A successful Discord response. `body` is `None` for 204 and other empty or
+/// non-JSON bodies.
+#[derive(Debug)]
+pub struct ApiResponse {
+    pub body: Option<Value>,
+}
+
+impl ApiResponse {
+    /// The `id` field of the body, e.g. a created message or channel.
+    pub fn id(&self) -> Option<String> {
+        self.body
+            .as_ref()?
+            .get("id")
+            .and_then(Value::as_str)
+            .map(str::to_string)
+    }
+}
+
+/// Treat any 2xx as success, parsing the body only when there is one; anything
+/// else becomes an `api_error` for `what`.
+async fn read_response(what: &str, response: reqwest::Response) -> anyhow::Result<ApiResponse> {
+    let status = response.status();
+    if !status.is_success() {
+        return Err(api_error(what, response).await);
+    }
+
+    let body = if status == reqwest::StatusCode::NO_CONTENT {
+        None
+    } else {
+        let bytes = response.bytes().await.unwrap_or_default();
+        serde_json::from_slice(&bytes).ok()
+    };
+    Ok(ApiResponse { body })
+}
+
+/// Content type for an attachment by extension, or `fallback` when unknown.
+pub fn attachment_mime<'a>(path: &str, fallback: &'a str) -> &'a str {
+    mime_guess::from_path(path).first_raw().unwrap_or(fallback)
+}
+
+/// Discord-safe channel name for a project/agent pair, e.g. `my-proj-claude`.
+pub fn channel_name_for(project_name: &str, agent_type: &str) -> String {
+    let mut name = String::new();
+    for c in format!("{project_name}-{agent_type}").chars() {
+        let c = c.to_ascii_lowercase();
+        if c.is_ascii_alphanumeric() || c == '_' {
+            name.push(c);
+        } else if !name.ends_with('-') {
+            name.push('-');
+        }
+    }
+
+    let name = name.trim_matches('-');
+    let name: String = name.chars().take(100).collect();
+    if name.is_empty() {
+        "mudcode".to_string()
+    } else {
+        name
+    }
+}
+
+/// `base` doubled per previous `attempt`, plus up to half of that again at random
+/// so retrying clients don't stay in lockstep.
+fn backoff_with_jitter(base: Duration, attempt: usize) -> Duration {
+    use std::hash::{BuildHasher, Hasher};
+
+    let backoff = base.saturating_mul(2u32.saturating_pow(attempt as u32));
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    backoff + backoff.mul_f64((random % 1000) as f64 / 2000.0)
+}
+
+/// Failures to connect: DNS, refused connections, connect timeouts. A request that
+/// timed out or broke after it was sent may have been delivered, so isn't one.
+fn is_connection_error(error: &reqwest::Error) -> bool {
+    error.is_connect()
+}
+
+pub fn order_attachments(file_paths: &[String], order: AttachmentOrder) -> Vec<String> {
+    let mut ordered = file_paths.to_vec();
+
+    match order {
+        AttachmentOrder::AsProvided => {}
+        AttachmentOrder::ByName => {
+            ordered.sort_by(|a, b| {
+                let name_a = Path::new(a).file_name().unwrap_or_default();
+                let name_b = Path::new(b).file_name().unwrap_or_default();
+                name_a.cmp(name_b).then_with(|| a.cmp(b))
+            });
+        }
+        AttachmentOrder::ByMtime => {
+            // Files whose mtime can't be read sort last, keeping their given order.
+            ordered.sort_by_cached_key(|path| {
+                let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+                (modified.is_none(), modified)
+            });
+        }
+    }
+
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{MockResponse, MockServer, TempDir, flaky_proxy, late_proxy};
+
+    fn client_for(server: &MockServer) -> DiscordClient {
+        DiscordClient::new(
+            "token".to_string(),
+            DiscordSettings {
+                api_base: server.url.clone(),
+                ..DiscordSettings::default()
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn global_rate_limit_halts_concurrent_sends() {
+        let server = MockServer::with_responder(|_, idx| {
+            if idx == 0 {
+                MockResponse::json(
+                    429,
+                    json!({ "message": "rate limited", "retry_after": 0.3, "global": true }),
+                )
+            } else {
+                MockResponse::message(format!("msg-{idx}"))
+            }
+        })
+        .await;
+        let client = client_for(&server);
+
+        let first = tokio::spawn({
+            let client = client.clone();
+            async move { client.send_message("ch-a", "first").await }
+        });
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while client.global_pause_remaining().is_none() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let second = client.send_message("ch-b", "second").await;
+        assert!(second.is_ok());
+        assert!(first.await.unwrap().is_ok());
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        let limited_at = requests[0].received_at;
+        for request in &requests[1..] {
+            assert!(request.received_at.duration_since(limited_at) >= Duration::from_millis(250));
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_to_one_channel_keep_submission_order() {
+        let server = MockServer::with_responder(|_, idx| {
+            let reply = MockResponse::message(format!("msg-{idx}"));
+            if idx == 0 {
+                reply.delayed(Duration::from_millis(150))
+            } else {
+                reply
+            }
+        })
+        .await;
+        let client = client_for(&server);
+
+        let long = "a".repeat(2500);
+        let first = tokio::spawn({
+            let client = client.clone();
+            async move { client.send_message("ch-1", &long).await }
+        });
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        let (second, other) = tokio::join!(
+            client.send_message("ch-1", "second"),
+            client.send_message("ch-2", "other"),
+        );
+        first.await.unwrap().unwrap();
+        second.unwrap();
+        other.unwrap();
+
+        let sent: Vec<(String, String)> = server
+            .requests()
+            .iter()
+            .map(|r| {
+                (
+                    r.path.clone(),
+                    r.json()["content"].as_str().unwrap().to_string(),
+                )
+            })
+            .collect();
+        assert_eq!(sent.len(), 4);
+        assert_eq!(
+            sent[1],
+            ("/channels/ch-2/messages".to_string(), "other".to_string())
+        );
+        assert!(sent[2].1.starts_with('a'));
+        assert_eq!(
+            sent[3],
+            ("/channels/ch-1/messages".to_string(), "second".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn reply_reference_goes_on_the_first_chunk_only() {
+        let server = MockServer::start().await;
+        let client = client_for(&server);
+
+        client
+            .send_reply("ch-1", &"a".repeat(2500), Some("orig-1"))
+            .await
+            .unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            requests[0].json()["message_reference"],
+            json!({ "message_id": "orig-1", "fail_if_not_exists": false })
+        );
+        assert!(requests[1].json().get("message_reference").is_none());
+    }
+
+    #[tokio::test]
+    async fn slow_responses_time_out_with_a_clear_error() {
+        let server = MockServer::with_responder(|_, _| {
+            MockResponse::message("msg-1").delayed(Duration::from_millis(500))
+        })
+        .await;
+        let client = DiscordClient::new(
+            "token".to_string(),
+            DiscordSettings {
+                api_base: server.url.clone(),
+                request_timeout: Duration::from_millis(50),
+                connect_retries: 3,
+                connect_retry_backoff: Duration::from_millis(1),
+                ..DiscordSettings::default()
+            },
+        );
+
+        let error = client.send_message("ch-1", "hello").await.unwrap_err();
+        assert!(
+            format!("{error:#}").contains("message request timed out (limit 50ms)"),
+            "{error:#}"
+        );
+        assert_eq!(server.requests().len(), 1);
+        let user_agent = server.requests()[0]
+            .header("user-agent")
+            .unwrap()
+            .to_string();
+        assert!(
+            user_agent.starts_with("DiscordBot (https://github.com/mud-the-developer/mudcode, ")
+        );
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried_but_client_errors_are_not() {
+        let server = MockServer::with_responder(|_, idx| match idx {
+            0 | 1 => MockResponse::json(503, json!({ "message": "unavailable" })),
+            2 => MockResponse::message("msg-2"),
+            _ => MockResponse::json(400, json!({ "message": "bad", "code": 50035 })),
+        })
+        .await;
+        let client = DiscordClient::new(
+            "token".to_string(),
+            DiscordSettings {
+                api_base: server.url.clone(),
+                server_error_backoff: Duration::from_millis(10),
+                ..DiscordSettings::default()
+            },
+        );
+
+        assert_eq!(
+            client.send_message("ch-1", "hello").await.unwrap(),
+            vec!["msg-2"]
+        );
+        assert!(client.send_message("ch-1", "again").await.is_err());
+        assert_eq!(server.requests().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn mentions_are_suppressed_unless_allowed() {
+        let server = MockServer::start().await;
+        let silent = client_for(&server);
+        let pinging = DiscordClient::new(
+            "token".to_string(),
+            DiscordSettings {
+                api_base: server.url.clone(),
+                allow_mentions: true,
+                ..DiscordSettings::default()
+            },
+        );
+
+        silent.send_message("ch-1", "@everyone done").await.unwrap();
+        pinging
+            .send_message("ch-1", "@everyone done")
+            .await
+            .unwrap();
+
+        let requests = server.requests();
+        assert_eq!(
+            requests[0].json()["allowed_mentions"],
+            json!({ "parse": [] })
+        );
+        assert!(requests[1].json().get("allowed_mentions").is_none());
+    }
+
+    #[tokio::test]
+    async fn route_rate_limit_does_not_pause_other_requests() {
+        let server = MockServer::with_responder(|_, idx| {
+            if idx == 0 {
+                MockResponse::json(429, json!({ "retry_after": 0.05, "global": false }))
+            } else {
+                MockResponse::message(format!("msg-{idx}"))
+            }
+        })
+        .await;
+        let client = client_for(&server);
+
+        assert!(client.send_message("ch-a", "hello").await.is_ok());
+        assert!(client.global_pause_remaining().is_none());
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[test]
+    fn reconfigured_client_keeps_its_pause_only_for_the_same_endpoint() {
+        let settings = |api_base: &str| DiscordSettings {
+            api_base: api_base.to_string(),
+            ..DiscordSettings::default()
         };
+        let client = DiscordClient::new("token".to_string(), settings("http://a/"));
+        client.pause_globally(Duration::from_secs(30));
+
+        let same = client.reconfigured("token".to_string(), settings("http://a"));
+        assert!(same.global_pause_remaining().is_some());
+        let rotated = client.reconfigured("rotated".to_string(), settings("http://a"));
+        assert!(rotated.global_pause_remaining().is_none());
+        let moved = client.reconfigured("token".to_string(), settings("http://b"));
+        assert!(moved.global_pause_remaining().is_none());
+    }
+
+    #[tokio::test]
+    async fn rate_limit_waits_are_read_from_headers() {
+        let limited = |headers: &[(&str, &str)], body: &str| MockResponse {
+            status: 429,
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body: body.to_string(),
+            delay: Duration::ZERO,
+        };
+        let server = MockServer::with_responder(move |_, idx| match idx {
+            0 => limited(&[("Retry-After", "0.05")], ""),
+            1 => limited(
+                &[
+                    ("X-RateLimit-Reset-After", "0.05"),
+                    ("content-type", "application/json"),
+                ],
+                r#"{"retry_after": 30}"#,
+            ),
+            _ => MockResponse::message("msg-1"),
+        })
+        .await;
+        let client = client_for(&server);
+
+        let sent = tokio::time::timeout(Duration::from_secs(5), client.send_message("ch-1", "hi"))
+            .await
+            .expect("header waits are honoured over the body's");
+        assert_eq!(sent.unwrap(), vec!["msg-1"]);
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[test]
+    fn attachment_order_as_provided_keeps_input_order() {
+        let paths = vec!["/tmp/b.png".to_string(), "/tmp/a.png".to_string()];
+        assert_eq!(
+            order_attachments(&paths, AttachmentOrder::AsProvided),
+            paths
+        );
+    }
+
+    #[test]
+    fn attachment_order_by_name_sorts_on_basename() {
+        let paths = vec![
+            "/tmp/z/b.png".to_string(),
+            "/tmp/y/c.png".to_string(),
+            "/tmp/x/a.png".to_string(),
+        ];
+        assert_eq!(
+            order_attachments(&paths, AttachmentOrder::ByName),
+            vec!["/tmp/x/a.png", "/tmp/z/b.png", "/tmp/y/c.png"]
+        );
+    }
+
+    #[test]
+    fn attachment_order_by_mtime_puts_oldest_first() {
+        let dir = TempDir::new("attachment-order");
+        let newer = dir.write("newer.png", "2");
+        let older = dir.write("older.png", "1");
+        let past = std::time::SystemTime::now() - Duration::from_secs(3600);
+        std::fs::File::options()
+            .write(true)
+            .open(&older)
+            .unwrap()
+            .set_modified(past)
+            .unwrap();
+
+        let paths = vec![
+            "/nonexistent/missing.png".to_string(),
+            newer.display().to_string(),
+            older.display().to_string(),
+        ];
+        assert_eq!(
+            order_attachments(&paths, AttachmentOrder::ByMtime),
+            vec![
+                older.display().to_string(),
+                newer.display().to_string(),
+                "/nonexistent/missing.png".to_string(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn refused_connection_is_retried() {
+        let server = MockServer::start().await;
+        let proxy_url = late_proxy(&server, Duration::from_millis(50)).await;
+        let client = DiscordClient::new(
+            "token".to_string(),
+            DiscordSettings {
+                api_base: proxy_url,
+                connect_retries: 5,
+                connect_retry_backoff: Duration::from_millis(20),
+                ..DiscordSettings::default()
+            },
+        );
+
+        assert!(client.send_message("ch-1", "hello").await.is_ok());
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn connection_retries_are_bounded() {
+        let server = MockServer::start().await;
+        let proxy_url = late_proxy(&server, Duration::from_secs(5)).await;
+        let client = DiscordClient::new(
+            "token".to_string(),
+            DiscordSettings {
+                api_base: proxy_url,
+                connect_retries: 1,
+                connect_retry_backoff: Duration::from_millis(10),
+                ..DiscordSettings::default()
+            },
+        );
+
+        let error = client.send_message("ch-1", "hello").await.unwrap_err();
+        assert!(format!("{error:#}").contains("failed to send Discord message request"));
+        assert!(server.requests().is_empty());
+    }
 
-        let mut form = Form::new().text("payload_json", payload.to_string());
+    #[tokio::test]
+    async fn connection_dropped_after_sending_is_not_retried() {
+        let server = MockServer::start().await;
+        let proxy_url = flaky_proxy(&server, 1).await;
+        let client = DiscordClient::new(
+            "token".to_string(),
+            DiscordSettings {
+                api_base: proxy_url,
+                connect_retries: 3,
+                connect_retry_backoff: Duration::from_millis(10),
+                ..DiscordSettings::default()
+            },
+        );
+
+        assert!(client.send_message("ch-1", "hello").await.is_err());
+        assert!(server.requests().is_empty());
+    }
+
+    #[test]
+    fn channel_name_is_sanitized() {
+        assert_eq!(channel_name_for("My Proj", "claude"), "my-proj-claude");
+        assert_eq!(channel_name_for("a//b", "open code"), "a-b-open-code");
+        assert_eq!(channel_name_for("!!!", "???"), "mudcode");
+    }
+
+    #[tokio::test]
+    async fn create_channel_posts_under_category_and_returns_id() {
+        let server =
+            MockServer::with_responder(|_, _| MockResponse::json(201, json!({ "id": "new-ch" })))
+                .await;
+        let client = client_for(&server);
+
+        let id = client
+            .create_channel("guild-1", "proj-claude", Some("cat-1"))
+            .await
+            .unwrap();
 
-        for (idx, path) in file_paths.iter().enumerate() {
-            let bytes = tokio::fs::read(path)
+        assert_eq!(id, "new-ch");
+        let request = &server.requests()[0];
+        assert_eq!(request.path, "/guilds/guild-1/channels");
+        assert_eq!(
+            request.json(),
+            json!({ "name": "proj-claude", "type": 0, "parent_id": "cat-1" })
+        );
+    }
+
+    #[test]
+    fn attachment_mime_uses_fallback_for_unknown_extensions() {
+        assert_eq!(attachment_mime("/p/chart.PNG", "text/plain"), "image/png");
+        assert_eq!(
+            attachment_mime("/p/notes.md", "text/plain"),
+            "text/markdown"
+        );
+        assert_eq!(attachment_mime("/p/run.trace", "text/plain"), "text/plain");
+        assert_eq!(
+            attachment_mime("/p/Makefile", DEFAULT_ATTACHMENT_MIME),
+            "application/octet-stream"
+        );
+    }
+
+    #[tokio::test]
+    async fn upload_sends_configured_fallback_content_type() {
+        let server = MockServer::start().await;
+        let dir = TempDir::new("mime");
+        let log = dir.write("run.trace", "line");
+        let client = DiscordClient::new(
+            "token".to_string(),
+            DiscordSettings {
+                api_base: server.url.clone(),
+                default_attachment_mime: "text/plain".to_string(),
+                ..DiscordSettings::default()
+            },
+        );
+
+        client
+            .send_files("ch-1", "", &[log.display().to_string()])
+            .await
+            .unwrap();
+
+        let body = server.requests()[0].body_text().to_ascii_lowercase();
+        assert!(body.contains("filename=\"run.trace\"\r\ncontent-type: text/plain"));
+    }
+
+    #[test]
+    fn chunk_delay_scales_with_chunk_count() {
+        let pacing = ChunkPacing {
+            base: Duration::from_millis(100),
+            per_chunk: Duration::from_millis(50),
+            max: Duration::from_millis(600),
+        };
+
+        assert_eq!(pacing.delay_for(2), Duration::from_millis(150));
+        assert_eq!(pacing.delay_for(5), Duration::from_millis(300));
+        assert_eq!(pacing.delay_for(20), Duration::from_millis(600));
+        assert!(ChunkPacing::default().delay_for(2) < ChunkPacing::default().delay_for(20));
+    }
+
+    #[tokio::test]
+    async fn send_message_returns_posted_ids_and_reaction_is_encoded() {
+        let server = MockServer::start().await;
+        let client = client_for(&server);
+
+        let ids = client.send_message("ch-1", "hello").await.unwrap();
+        client.add_reaction("ch-1", &ids[0], "✅").await.unwrap();
+
+        assert_eq!(ids, vec!["msg-0".to_string()]);
+        assert_eq!(
+            server.requests()[1].path,
+            "/channels/ch-1/messages/msg-0/reactions/%E2%9C%85/@me"
+        );
+    }
+
+    #[tokio::test]
+    async fn files_past_the_per_event_byte_cap_are_skipped_with_a_note() {
+        let server = MockServer::start().await;
+        let dir = TempDir::new("byte-cap");
+        let files: Vec<String> = ["a.txt", "b.txt", "c.txt"]
+            .iter()
+            .map(|name| dir.write(name, "0123456789").display().to_string())
+            .collect();
+        let client = DiscordClient::new(
+            "token".to_string(),
+            DiscordSettings {
+                api_base: server.url.clone(),
+                max_event_attachment_bytes: Some(25),
+                ..DiscordSettings::default()
+            },
+        );
+
+        client.send_files("ch-1", "results", &files).await.unwrap();
+
+        let upload = &server.requests()[0];
+        let body = upload.body_text();
+        assert!(body.contains("filename=\"a.txt\"") && body.contains("filename=\"b.txt\""));
+        assert!(!body.contains("filename=\"c.txt\""));
+        assert_eq!(
+            upload.payload_json()["content"],
+            json!("results\n⚠️ Skipped 1 file(s) over the attachment size cap: c.txt")
+        );
+    }
+
+    #[tokio::test]
+    async fn files_over_the_per_file_limit_are_skipped_with_sizes() {
+        let server = MockServer::start().await;
+        let dir = TempDir::new("file-limit");
+        let small = dir.write("small.txt", "tiny");
+        let large = dir.write("large.bin", "x".repeat(3 * 1024));
+        let client = DiscordClient::new(
+            "token".to_string(),
+            DiscordSettings {
+                api_base: server.url.clone(),
+                max_attachment_bytes: Some(2 * 1024),
+                ..DiscordSettings::default()
+            },
+        );
+        let files = [small, large].map(|path| path.display().to_string());
+
+        client.send_files("ch-1", "", &files).await.unwrap();
+
+        let upload = &server.requests()[0];
+        let body = upload.body_text();
+        assert!(body.contains("filename=\"small.txt\""));
+        assert!(!body.contains("filename=\"large.bin\""));
+        assert_eq!(
+            upload.payload_json()["content"],
+            json!("⚠️ Skipped 1 file(s) over the attachment size cap: large.bin (3 KiB > 2 KiB)")
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unsized_files_over_the_per_file_limit_are_skipped() {
+        let server = MockServer::start().await;
+        let dir = TempDir::new("unsized-file-limit");
+        let (pipe, writer) = unsized_file(&dir, "pipe.txt", "0123456789");
+        let client = DiscordClient::new(
+            "token".to_string(),
+            DiscordSettings {
+                api_base: server.url.clone(),
+                max_attachment_bytes: Some(4),
+                ..DiscordSettings::default()
+            },
+        );
+
+        client
+            .send_files("ch-1", "", &[pipe.display().to_string()])
+            .await
+            .unwrap();
+        writer.join().unwrap();
+
+        assert_eq!(
+            server.requests()[0].json()["content"],
+            json!("⚠️ Skipped 1 file(s) over the attachment size cap: pipe.txt (> 4 B)")
+        );
+    }
+
+    #[test]
+    fn sizes_use_the_largest_reached_unit() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(30 * 1024 * 1024), "30 MiB");
+    }
+
+    #[tokio::test]
+    async fn uploads_are_split_into_batches_of_ten() {
+        let server = MockServer::start().await;
+        let dir = TempDir::new("batches");
+        let files: Vec<String> = (0..12)
+            .map(|n| {
+                dir.write(&format!("f{n:02}.txt"), "x")
+                    .display()
+                    .to_string()
+            })
+            .collect();
+        let client = client_for(&server);
+
+        let ids = client.send_files("ch-1", "results", &files).await.unwrap();
+
+        let requests = server.requests();
+        let parts = |idx: usize| requests[idx].body_text().matches("name=\"files[").count();
+        assert_eq!(requests.len(), 2);
+        assert_eq!((parts(0), parts(1)), (10, 2));
+        assert_eq!(requests[0].payload_json()["content"], json!("results"));
+        assert!(requests[1].payload_json().get("content").is_none());
+        assert_eq!(ids, ["msg-0", "msg-1"]);
+    }
+
+    #[tokio::test]
+    async fn attaching_more_than_ten_files_returns_every_message_id() {
+        let server = MockServer::start().await;
+        let dir = TempDir::new("attach-batches");
+        let files: Vec<String> = (0..12)
+            .map(|n| {
+                dir.write(&format!("f{n:02}.txt"), "x")
+                    .display()
+                    .to_string()
+            })
+            .collect();
+        let client = client_for(&server);
+
+        let ids = client.attach_files("ch-1", "target", &files).await.unwrap();
+
+        assert_eq!(ids, ["target", "msg-1"]);
+    }
+
+    #[tokio::test]
+    async fn missing_permissions_is_detected_from_error_code() {
+        let server = MockServer::with_responder(|_, idx| {
+            let code = if idx == 0 { 50013 } else { 50001 };
+            MockResponse::json(
+                403,
+                json!({ "message": "Missing Permissions", "code": code }),
+            )
+        })
+        .await;
+        let client = client_for(&server);
+
+        let error = client.send_message("ch-1", "hello").await.unwrap_err();
+        assert!(DiscordError::is_missing_permissions(&error));
+        assert!(error.to_string().contains("missing permissions"));
+
+        let error = client.send_message("ch-1", "hello").await.unwrap_err();
+        assert!(!DiscordError::is_missing_permissions(&error));
+        assert!(error.to_string().contains("403"));
+    }
+
+    #[tokio::test]
+    async fn dry_run_makes_no_requests() {
+        let server = MockServer::start().await;
+        let dir = TempDir::new("dry-run");
+        let chart = dir.write("chart.png", "png");
+        let client = DiscordClient::new(
+            "token".to_string(),
+            DiscordSettings {
+                api_base: server.url.clone(),
+                typing_interval: Some(Duration::ZERO),
+                dry_run: true,
+                ..DiscordSettings::default()
+            },
+        );
+
+        let ids = client
+            .send_message("ch-1", &"word ".repeat(900))
+            .await
+            .unwrap();
+        assert!(ids.is_empty());
+        let files = [chart.display().to_string()];
+        assert!(
+            client
+                .send_files("ch-1", "chart", &files)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(
+            client
+                .create_channel("guild-1", "proj", None)
                 .await
-                .with_context(|| format!("failed to read attachment file: {path}"))?;
+                .unwrap(),
+            "dry-run-proj"
+        );
+        assert!(server.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejected_token_is_reported_as_unauthorized() {
+        let server = MockServer::with_responder(|req, _| {
+            if req.header("authorization") == Some("Bot good") {
+                MockResponse::json(200, json!({ "id": "bot-1", "username": "mudbot" }))
+            } else {
+                MockResponse::json(401, json!({ "message": "401: Unauthorized", "code": 0 }))
+            }
+        })
+        .await;
+        let settings = DiscordSettings {
+            api_base: server.url.clone(),
+            ..DiscordSettings::default()
+        };
+
+        let good = DiscordClient::new("good".to_string(), settings.clone());
+        assert_eq!(good.verify_token().await.unwrap(), "mudbot");
 
-            let filename = Path::new(path)
-                .file_name()
-                .and_then(|v| v.to_str())
-                .filter(|v| !v.trim().is_empty())
-                .unwrap_or("attachment.bin")
-                .to_string();
+        let revoked = DiscordClient::new("revoked".to_string(), settings);
+        let error = revoked.verify_token().await.unwrap_err();
+        assert!(DiscordError::is_unauthorized(&error));
+        assert!(error.to_string().contains("token rejected"));
+        let error = revoked.send_message("ch-1", "hello").await.unwrap_err();
+        assert!(DiscordError::is_unauthorized(&error));
+        assert_eq!(server.requests()[0].path, "/users/@me");
+    }
 
-            let part = Part::bytes(bytes).file_name(filename);
-            form = form.part(format!("files[{idx}]"), part);
+    #[tokio::test]
+    async fn circuit_opens_on_repeated_5xx_and_recovers_after_probe() {
+        let healthy = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let server = MockServer::with_responder({
+            let healthy = Arc::clone(&healthy);
+            move |_, idx| {
+                if healthy.load(std::sync::atomic::Ordering::SeqCst) {
+                    MockResponse::message(format!("msg-{idx}"))
+                } else {
+                    MockResponse::json(502, json!({ "message": "bad gateway" }))
+                }
+            }
+        })
+        .await;
+        let client = DiscordClient::new(
+            "token".to_string(),
+            DiscordSettings {
+                api_base: server.url.clone(),
+                circuit_breaker_threshold: 2,
+                circuit_breaker_cooldown: Duration::from_millis(50),
+                server_error_retries: 0,
+                ..DiscordSettings::default()
+            },
+        );
+
+        for _ in 0..2 {
+            let error = client.send_message("ch-1", "hi").await.unwrap_err();
+            assert!(!DiscordError::is_circuit_open(&error));
         }
+        let error = client.send_message("ch-1", "hi").await.unwrap_err();
+        assert!(DiscordError::is_circuit_open(&error));
+        assert_eq!(server.requests().len(), 2, "open circuit sends nothing");
 
-        let url = format!("https://discord.com/api/v10/channels/{channel_id}/messages");
-        let response = self
-            .http
-            .post(url)
-            .header("Authorization", self.auth_header())
-            .multipart(form)
-            .send()
+        healthy.store(true, std::sync::atomic::Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(client.send_message("ch-1", "probe").await.is_ok());
+        assert!(client.send_message("ch-1", "after").await.is_ok());
+        assert_eq!(server.requests().len(), 4);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn attachment_filename_handles_invalid_utf8_per_mode() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = Path::new("/tmp").join(OsStr::from_bytes(b"chart-\xff\xfe.png"));
+        assert_eq!(attachment_filename(&path, false), "attachment.bin");
+        assert_eq!(attachment_filename(&path, true), "chart-__.png");
+        assert_eq!(
+            attachment_filename(Path::new("/tmp/ok.png"), true),
+            "ok.png"
+        );
+    }
+
+    #[tokio::test]
+    async fn read_response_handles_bodies_empty_responses_and_errors() {
+        let server = MockServer::with_responder(|_, idx| match idx {
+            0 => MockResponse::message("msg-1"),
+            1 => MockResponse {
+                status: 204,
+                headers: Vec::new(),
+                body: String::new(),
+                delay: Duration::ZERO,
+            },
+            _ => MockResponse::json(404, json!({ "message": "Unknown Channel" })),
+        })
+        .await;
+        let http = reqwest::Client::new();
+        let get = || http.get(&server.url).send();
+
+        let ok = read_response("fetch", get().await.unwrap()).await.unwrap();
+        assert_eq!(ok.id().as_deref(), Some("msg-1"));
+
+        let empty = read_response("fetch", get().await.unwrap()).await.unwrap();
+        assert!(empty.body.is_none() && empty.id().is_none());
+
+        let error = read_response("fetch", get().await.unwrap())
             .await
-            .context("failed to send Discord file upload request")?;
+            .unwrap_err();
+        assert!(error.to_string().contains("404"));
+    }
 
-        if response.status().is_success() {
-            return Ok(());
+    #[tokio::test]
+    async fn typing_indicator_fires_once_per_interval_per_channel() {
+        let server = MockServer::start().await;
+        let client = DiscordClient::new(
+            "token".to_string(),
+            DiscordSettings {
+                api_base: server.url.clone(),
+                typing_interval: Some(Duration::from_secs(10)),
+                ..DiscordSettings::default()
+            },
+        );
+
+        for text in ["one", "two", "three"] {
+            client.send_message("ch-1", text).await.unwrap();
+        }
+        client.send_message("ch-2", "other").await.unwrap();
+
+        let typing: Vec<_> = server
+            .wait_for_requests(6)
+            .await
+            .into_iter()
+            .map(|r| r.path)
+            .filter(|path| path.ends_with("/typing"))
+            .collect();
+        assert_eq!(
+            typing,
+            vec!["/channels/ch-1/typing", "/channels/ch-2/typing"]
+        );
+    }
+
+    #[tokio::test]
+    async fn failing_typing_is_not_retried_and_does_not_trip_the_breaker() {
+        let server = MockServer::with_responder(|req, idx| {
+            if req.path.ends_with("/typing") {
+                MockResponse::json(500, json!({ "message": "down" }))
+            } else {
+                MockResponse::message(format!("msg-{idx}"))
+            }
+        })
+        .await;
+        let client = DiscordClient::new(
+            "token".to_string(),
+            DiscordSettings {
+                api_base: server.url.clone(),
+                typing_interval: Some(Duration::from_secs(10)),
+                circuit_breaker_threshold: 1,
+                ..DiscordSettings::default()
+            },
+        );
+
+        for channel in ["ch-1", "ch-2", "ch-3"] {
+            client.send_message(channel, "hello").await.unwrap();
         }
+        let typing = server
+            .wait_for_requests(6)
+            .await
+            .into_iter()
+            .filter(|r| r.path.ends_with("/typing"))
+            .count();
+        assert_eq!(typing, 3);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(server.requests().len(), 6);
+    }
+
+    #[tokio::test]
+    async fn attach_files_reposts_when_the_message_was_deleted() {
+        let dir = TempDir::new("repost-unknown");
+        let file = dir.path().join("out.txt");
+        std::fs::write(&file, "data").unwrap();
+        let files = vec![file.display().to_string()];
+
+        let server = MockServer::with_responder(|req, idx| {
+            if req.method == "PATCH" {
+                MockResponse::json(404, json!({ "message": "Unknown Message", "code": 10008 }))
+            } else {
+                MockResponse::message(format!("msg-{idx}"))
+            }
+        })
+        .await;
+        let settings = |repost| DiscordSettings {
+            api_base: server.url.clone(),
+            repost_on_unknown_message: repost,
+            repost_note: "(continued)".to_string(),
+            ..DiscordSettings::default()
+        };
+
+        let strict = DiscordClient::new("token".to_string(), settings(false));
+        let error = strict
+            .attach_files("ch-1", "gone", &files)
+            .await
+            .unwrap_err();
+        assert!(DiscordError::is_unknown_message(&error));
 
-        let status = response.status();
-        let text = response
-            .text()
+        let lenient = DiscordClient::new("token".to_string(), settings(true));
+        let ids = lenient.attach_files("ch-1", "gone", &files).await.unwrap();
+        assert_eq!(ids, ["msg-2"]);
+
+        let requests = server.requests();
+        let repost = requests.last().unwrap();
+        assert_eq!(
+            (repost.method.as_str(), repost.path.as_str()),
+            ("POST", "/channels/ch-1/messages")
+        );
+        assert_eq!(repost.payload_json()["content"], "(continued)");
+        assert!(repost.body_text().contains("filename=\"out.txt\""));
+    }
+
+    #[tokio::test]
+    async fn unknown_channel_is_not_treated_as_a_deleted_message() {
+        let dir = TempDir::new("repost-unknown-channel");
+        let file = dir.write("out.txt", "data");
+        let files = vec![file.display().to_string()];
+        let server = MockServer::with_responder(|_, _| {
+            MockResponse::json(404, json!({ "message": "Unknown Channel", "code": 10003 }))
+        })
+        .await;
+        let client = DiscordClient::new(
+            "token".to_string(),
+            DiscordSettings {
+                api_base: server.url.clone(),
+                repost_on_unknown_message: true,
+                ..DiscordSettings::default()
+            },
+        );
+
+        let error = client
+            .attach_files("ch-1", "msg-1", &files)
             .await
-            .unwrap_or_else(|e| format!("<failed to read response body: {e}>"));
-        Err(anyhow!("Discord send files failed ({status}): {text}"))
+            .unwrap_err();
+        assert!(!DiscordError::is_unknown_message(&error));
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn verify_delivery_posts_again_when_the_message_is_missing() {
+        let server = MockServer::with_responder(|req, idx| match (req.method.as_str(), idx) {
+            ("GET", 1) => {
+                MockResponse::json(404, json!({ "message": "Unknown Message", "code": 10008 }))
+            }
+            _ => MockResponse::message(format!("msg-{idx}")),
+        })
+        .await;
+        let client = DiscordClient::new(
+            "token".to_string(),
+            DiscordSettings {
+                api_base: server.url.clone(),
+                verify_delivery: true,
+                ..DiscordSettings::default()
+            },
+        );
+
+        let ids = client.send_message("ch-1", "hello").await.unwrap();
+        assert_eq!(ids, vec!["msg-2"]);
+
+        let requests: Vec<_> = server
+            .requests()
+            .into_iter()
+            .map(|r| format!("{} {}", r.method, r.path))
+            .collect();
+        assert_eq!(
+            requests,
+            vec![
+                "POST /channels/ch-1/messages",
+                "GET /channels/ch-1/messages/msg-0",
+                "POST /channels/ch-1/messages",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_size_policy_decides_unsized_files() {
+        let dir = TempDir::new("unknown-size");
+        let path = dir.write("pipe.txt", "0123456789").display().to_string();
+        let read = |remaining, policy| read_capped(&path, None, remaining, policy);
+
+        assert_eq!(read(100, UnknownSize::Skip).await.unwrap(), None);
+        assert_eq!(
+            read(4, UnknownSize::Allow).await.unwrap().as_deref(),
+            Some(&b"0123456789"[..])
+        );
+        assert_eq!(
+            read(10, UnknownSize::ReadThenCheck)
+                .await
+                .unwrap()
+                .as_deref(),
+            Some(&b"0123456789"[..])
+        );
+        assert_eq!(read(9, UnknownSize::ReadThenCheck).await.unwrap(), None);
+    }
+
+    /// A named pipe `name` in `dir` with a thread writing `content` into it. Its
+    /// size can't be known up front, like a file whose metadata call fails.
+    #[cfg(unix)]
+    fn unsized_file(
+        dir: &TempDir,
+        name: &str,
+        content: &'static str,
+    ) -> (std::path::PathBuf, std::thread::JoinHandle<()>) {
+        let pipe = dir.path().join(name);
+        let status = std::process::Command::new("mkfifo")
+            .arg(&pipe)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let writer = {
+            let pipe = pipe.clone();
+            std::thread::spawn(move || {
+                let _ = std::fs::write(pipe, content);
+            })
+        };
+        (pipe, writer)
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unknown_size_policy_applies_to_sent_files() {
+        for (policy, cap, uploaded) in [
+            (UnknownSize::Skip, 100, false),
+            (UnknownSize::Allow, 4, true),
+            (UnknownSize::ReadThenCheck, 100, true),
+            (UnknownSize::ReadThenCheck, 4, false),
+        ] {
+            let server = MockServer::start().await;
+            let dir = TempDir::new("unsized-send");
+            let (pipe, writer) = unsized_file(&dir, "pipe.txt", "0123456789");
+            let client = DiscordClient::new(
+                "token".to_string(),
+                DiscordSettings {
+                    api_base: server.url.clone(),
+                    max_event_attachment_bytes: Some(cap),
+                    unknown_size: policy,
+                    ..DiscordSettings::default()
+                },
+            );
+
+            client
+                .send_files("ch-1", "", &[pipe.display().to_string()])
+                .await
+                .unwrap();
+            if policy == UnknownSize::Skip {
+                // Nothing opened the pipe, so let the writer through.
+                std::fs::read(&pipe).unwrap();
+            }
+            writer.join().unwrap();
+
+            let requests = server.requests();
+            assert_eq!(requests.len(), 1, "{policy:?} cap={cap}");
+            let body = requests[0].body_text();
+            if uploaded {
+                assert!(body.contains("filename=\"pipe.txt\""), "{policy:?}");
+                assert!(body.contains("0123456789"), "{policy:?}");
+            } else {
+                assert_eq!(
+                    requests[0].json()["content"],
+                    json!("⚠️ Skipped 1 file(s) over the attachment size cap: pipe.txt"),
+                    "{policy:?} cap={cap}"
+                );
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn allowed_unsized_files_count_toward_the_event_cap() {
+        let dir = TempDir::new("unsized-counted");
+        let (pipe, writer) = unsized_file(&dir, "pipe.txt", "0123456789");
+        let small = dir.write("small.txt", "12345");
+        let client = DiscordClient::new(
+            "token".to_string(),
+            DiscordSettings {
+                max_event_attachment_bytes: Some(12),
+                unknown_size: UnknownSize::Allow,
+                ..DiscordSettings::default()
+            },
+        );
+        let files = [pipe, small].map(|path| path.display().to_string());
+
+        let (attachments, skipped) = client.read_attachments("ch-1", &files).await.unwrap();
+        writer.join().unwrap();
+
+        let names: Vec<_> = attachments.iter().map(|(name, ..)| name.as_str()).collect();
+        assert_eq!(names, ["pipe.txt"]);
+        assert_eq!(skipped, ["small.txt"]);
     }
 }
@@ -1,9 +1,67 @@
 use serde::Deserialize;
+use serde_json::Value;
+
+pub const DEFAULT_AGENT_TYPE: &str = "opencode";
+
+/// Keys `OpencodeEvent` understands; keep in sync with its serde renames.
+pub const OPENCODE_EVENT_FIELDS: &[&str] = &[
+    "projectName",
+    "projectNames",
+    "agentType",
+    "instanceId",
+    "type",
+    "text",
+    "message",
+    "turnText",
+    "callbackUrl",
+    "eventId",
+    "replyToMessageId",
+];
+
+/// Keys `SendFilesEvent` understands; keep in sync with its serde renames.
+pub const SEND_FILES_EVENT_FIELDS: &[&str] = &[
+    "projectName",
+    "agentType",
+    "instanceId",
+    "files",
+    "glob",
+    "recursive",
+    "content",
+    "callbackUrl",
+];
+
+/// Keys `RegisterProjectRequest` understands; keep in sync with its serde renames.
+pub const REGISTER_PROJECT_FIELDS: &[&str] = &[
+    "projectName",
+    "projectPath",
+    "agentType",
+    "channelId",
+    "instanceId",
+    "overwrite",
+];
+
+/// Top-level keys of `payload` not in `known`, sorted. Non-object payloads have none.
+pub fn unknown_fields(payload: &Value, known: &[&str]) -> Vec<String> {
+    let Some(object) = payload.as_object() else {
+        return Vec::new();
+    };
+
+    let mut unknown: Vec<String> = object
+        .keys()
+        .filter(|key| !known.contains(&key.as_str()))
+        .cloned()
+        .collect();
+    unknown.sort();
+    unknown
+}
 
 #[derive(Debug, Deserialize)]
 pub struct OpencodeEvent {
     #[serde(rename = "projectName")]
     pub project_name: Option<String>,
+    /// Additional target projects for one event, delivered alongside `projectName`.
+    #[serde(default, rename = "projectNames")]
+    pub project_names: Vec<String>,
     #[serde(rename = "agentType")]
     pub agent_type: Option<String>,
     #[serde(rename = "instanceId")]
@@ -14,22 +72,39 @@ pub struct OpencodeEvent {
     pub message: Option<String>,
     #[serde(rename = "turnText")]
     pub turn_text: Option<String>,
+    #[serde(rename = "callbackUrl")]
+    pub callback_url: Option<String>,
+    #[serde(rename = "eventId")]
+    pub event_id: Option<String>,
+    /// Discord message the event's first message replies to.
+    #[serde(rename = "replyToMessageId")]
+    pub reply_to_message_id: Option<String>,
 }
 
 impl OpencodeEvent {
-    pub fn project_name(&self) -> Option<&str> {
-        self.project_name
-            .as_deref()
-            .map(str::trim)
-            .filter(|v| !v.is_empty())
+    /// `projectName` followed by any `projectNames`, trimmed and deduplicated.
+    pub fn project_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = Vec::new();
+        for name in self
+            .project_name
+            .iter()
+            .chain(&self.project_names)
+            .map(|v| v.trim())
+        {
+            if !name.is_empty() && !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        names
     }
 
-    pub fn agent_type(&self) -> &str {
+    /// The event's agent type, or `default` (e.g. the project's) when omitted.
+    pub fn agent_type_or<'a>(&'a self, default: &'a str) -> &'a str {
         self.agent_type
             .as_deref()
             .map(str::trim)
             .filter(|v| !v.is_empty())
-            .unwrap_or("opencode")
+            .unwrap_or(default)
     }
 
     pub fn instance_id(&self) -> Option<&str> {
@@ -39,6 +114,13 @@ impl OpencodeEvent {
             .filter(|v| !v.is_empty())
     }
 
+    pub fn reply_to_message_id(&self) -> Option<&str> {
+        self.reply_to_message_id
+            .as_deref()
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+    }
+
     pub fn event_type(&self) -> Option<&str> {
         self.event_type
             .as_deref()
@@ -69,6 +151,20 @@ impl OpencodeEvent {
             .map(str::trim)
             .filter(|v| !v.is_empty())
     }
+
+    pub fn callback_url(&self) -> Option<&str> {
+        self.callback_url
+            .as_deref()
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+    }
+
+    pub fn event_id(&self) -> Option<&str> {
+        self.event_id
+            .as_deref()
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -81,6 +177,14 @@ pub struct SendFilesEvent {
     pub instance_id: Option<String>,
     #[serde(default)]
     pub files: Vec<String>,
+    /// Treat `files` entries with wildcards as globs; overrides the config default.
+    pub glob: Option<bool>,
+    /// Expand directory entries recursively; overrides the config default.
+    pub recursive: Option<bool>,
+    /// Text posted with the files, or on its own when `files` is empty.
+    pub content: Option<String>,
+    #[serde(rename = "callbackUrl")]
+    pub callback_url: Option<String>,
 }
 
 impl SendFilesEvent {
@@ -91,12 +195,20 @@ impl SendFilesEvent {
             .filter(|v| !v.is_empty())
     }
 
-    pub fn agent_type(&self) -> &str {
+    pub fn content(&self) -> Option<&str> {
+        self.content
+            .as_deref()
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+    }
+
+    /// The event's agent type, or `default` (e.g. the project's) when omitted.
+    pub fn agent_type_or<'a>(&'a self, default: &'a str) -> &'a str {
         self.agent_type
             .as_deref()
             .map(str::trim)
             .filter(|v| !v.is_empty())
-            .unwrap_or("opencode")
+            .unwrap_or(default)
     }
 
     pub fn instance_id(&self) -> Option<&str> {
@@ -105,22 +217,86 @@ impl SendFilesEvent {
             .map(str::trim)
             .filter(|v| !v.is_empty())
     }
+
+    pub fn callback_url(&self) -> Option<&str> {
+        self.callback_url
+            .as_deref()
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+    }
+}
+
+/// Body of `POST /projects`.
+#[derive(Debug, Deserialize)]
+pub struct RegisterProjectRequest {
+    #[serde(rename = "projectName")]
+    pub project_name: Option<String>,
+    #[serde(rename = "projectPath")]
+    pub project_path: Option<String>,
+    #[serde(rename = "agentType")]
+    pub agent_type: Option<String>,
+    #[serde(rename = "channelId")]
+    pub channel_id: Option<String>,
+    #[serde(rename = "instanceId")]
+    pub instance_id: Option<String>,
+    /// Replace an existing mapping instead of answering 409.
+    #[serde(default)]
+    pub overwrite: bool,
+}
+
+/// A validated `POST /projects` body, with every field trimmed.
+#[derive(Debug)]
+pub struct ProjectRegistration<'a> {
+    pub project_name: &'a str,
+    pub project_path: &'a str,
+    pub agent_type: &'a str,
+    pub channel_id: &'a str,
+    pub instance_id: Option<&'a str>,
+}
+
+impl RegisterProjectRequest {
+    pub fn validate(&self) -> Result<ProjectRegistration<'_>, String> {
+        let registration = ProjectRegistration {
+            project_name: required(&self.project_name, "projectName")?,
+            project_path: required(&self.project_path, "projectPath")?,
+            agent_type: required(&self.agent_type, "agentType")?,
+            channel_id: required(&self.channel_id, "channelId")?,
+            instance_id: trimmed(&self.instance_id),
+        };
+        if !std::path::Path::new(registration.project_path).is_absolute() {
+            return Err("projectPath must be an absolute path".to_string());
+        }
+        Ok(registration)
+    }
+}
+
+fn trimmed(value: &Option<String>) -> Option<&str> {
+    value.as_deref().map(str::trim).filter(|v| !v.is_empty())
+}
+
+fn required<'a>(value: &'a Option<String>, name: &str) -> Result<&'a str, String> {
+    trimmed(value).ok_or_else(|| format!("Missing {name}"))
 }
 
 #[cfg(test)]
 mod tests {
-    use super::OpencodeEvent;
+    use super::*;
+    use serde_json::json;
 
     #[test]
     fn event_text_prefers_text_over_message() {
         let event = OpencodeEvent {
             project_name: Some("proj".to_string()),
+            project_names: Vec::new(),
             agent_type: None,
             instance_id: None,
             event_type: Some("session.idle".to_string()),
             text: Some("text value".to_string()),
             message: Some("message value".to_string()),
             turn_text: None,
+            callback_url: None,
+            event_id: None,
+            reply_to_message_id: None,
         };
 
         assert_eq!(event.event_text().as_deref(), Some("text value"));
@@ -130,15 +306,44 @@ mod tests {
     fn event_type_defaults_are_applied() {
         let event = OpencodeEvent {
             project_name: Some("proj".to_string()),
+            project_names: Vec::new(),
             agent_type: None,
             instance_id: None,
             event_type: None,
             text: None,
             message: None,
             turn_text: None,
+            callback_url: None,
+            event_id: None,
+            reply_to_message_id: None,
         };
 
-        assert_eq!(event.agent_type(), "opencode");
+        assert_eq!(event.agent_type_or(DEFAULT_AGENT_TYPE), "opencode");
+        assert_eq!(event.agent_type_or("claude"), "claude");
         assert_eq!(event.event_type(), None);
     }
+
+    #[test]
+    fn unknown_fields_lists_unexpected_keys() {
+        let payload = json!({ "projctName": "proj", "type": "session.idle", "extra": 1 });
+
+        assert_eq!(
+            unknown_fields(&payload, OPENCODE_EVENT_FIELDS),
+            vec!["extra".to_string(), "projctName".to_string()]
+        );
+        assert!(unknown_fields(&json!({ "files": [] }), SEND_FILES_EVENT_FIELDS).is_empty());
+    }
+
+    #[test]
+    fn project_names_merge_singular_and_list() {
+        let event: OpencodeEvent = serde_json::from_value(json!({
+            "projectName": " a ",
+            "projectNames": ["b", "a", ""],
+        }))
+        .unwrap();
+        assert_eq!(event.project_names(), vec!["a", "b"]);
+
+        let event: OpencodeEvent = serde_json::from_value(json!({})).unwrap();
+        assert!(event.project_names().is_empty());
+    }
 }
@@ -0,0 +1,108 @@
+use axum::extract::{Request, State};
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::sync::Arc;
+use tracing::warn;
+
+const SECRET_HEADER: &str = "x-mudcode-secret";
+
+/// Shared secret hook requests must present; `None` leaves the endpoints open.
+#[derive(Clone, Default)]
+pub struct HookSecret(Option<Arc<str>>);
+
+impl HookSecret {
+    pub fn new(secret: Option<String>) -> Self {
+        Self(secret.map(Arc::from))
+    }
+
+    /// Whether `headers` carry the secret as `Authorization: Bearer …` or
+    /// `X-Mudcode-Secret`.
+    fn admits(&self, headers: &HeaderMap) -> bool {
+        let Some(secret) = self.0.as_deref() else {
+            return true;
+        };
+        let bearer = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let custom = headers
+            .get(SECRET_HEADER)
+            .and_then(|value| value.to_str().ok());
+
+        [bearer, custom]
+            .into_iter()
+            .flatten()
+            .any(|given| constant_time_eq(given.trim().as_bytes(), secret.as_bytes()))
+    }
+}
+
+/// Compares without exiting early on the first differing byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Middleware answering 401 unless the request carries the hook secret.
+pub async fn require_hook_secret(
+    State(secret): State<HookSecret>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !secret.admits(request.headers()) {
+        warn!(
+            "rejected hook request without a valid secret path={}",
+            request.uri().path()
+        );
+        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::Router;
+    use axum::routing::post;
+
+    #[tokio::test]
+    async fn requests_need_the_secret_once_one_is_set() {
+        let app = |secret: Option<&str>| {
+            Router::new().route("/hook", post(|| async { "OK" })).layer(
+                axum::middleware::from_fn_with_state(
+                    HookSecret::new(secret.map(str::to_string)),
+                    require_hook_secret,
+                ),
+            )
+        };
+        let serve = |router: Router| async move {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}/hook", listener.local_addr().unwrap());
+            tokio::spawn(async move {
+                let _ = axum::serve(listener, router).await;
+            });
+            url
+        };
+        let client = reqwest::Client::new();
+        let status = |request: reqwest::RequestBuilder| async move {
+            request.send().await.unwrap().status().as_u16()
+        };
+
+        let open = serve(app(None)).await;
+        assert_eq!(status(client.post(&open)).await, 200);
+
+        let guarded = serve(app(Some("s3cret"))).await;
+        assert_eq!(status(client.post(&guarded)).await, 401);
+        assert_eq!(
+            status(client.post(&guarded).bearer_auth("wrong")).await,
+            401
+        );
+        assert_eq!(
+            status(client.post(&guarded).bearer_auth("s3cret")).await,
+            200
+        );
+        assert_eq!(
+            status(client.post(&guarded).header("X-Mudcode-Secret", "s3cret")).await,
+            200
+        );
+    }
+}
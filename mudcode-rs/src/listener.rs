@@ -0,0 +1,97 @@
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+
+/// First inherited descriptor in the systemd socket-activation protocol (`SD_LISTEN_FDS_START`).
+const LISTEN_FDS_START: i32 = 3;
+
+/// Resolve the inherited listener fd when `LISTEN_PID`/`LISTEN_FDS` target this process.
+pub fn socket_activation_fd(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    pid: u32,
+) -> Option<i32> {
+    let target_pid = listen_pid?.trim().parse::<u32>().ok()?;
+    if target_pid != pid {
+        return None;
+    }
+
+    let count = listen_fds?.trim().parse::<u32>().ok()?;
+    if count == 0 {
+        return None;
+    }
+
+    if count > 1 {
+        warn!("socket activation passed {count} fds; only fd {LISTEN_FDS_START} is used");
+    }
+
+    Some(LISTEN_FDS_START)
+}
+
+/// Use a socket-activated listener when systemd provides one, otherwise bind `addr`.
+pub async fn bind_listener(addr: SocketAddr) -> anyhow::Result<TcpListener> {
+    #[cfg(unix)]
+    if let Some(fd) = socket_activation_fd(
+        std::env::var("LISTEN_PID").ok().as_deref(),
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    ) {
+        let listener = listener_from_fd(fd)?;
+        info!("using socket-activated listener fd={fd}");
+        return Ok(listener);
+    }
+
+    Ok(TcpListener::bind(addr).await?)
+}
+
+#[cfg(unix)]
+fn listener_from_fd(fd: i32) -> anyhow::Result<TcpListener> {
+    use anyhow::Context;
+    use std::os::fd::FromRawFd;
+
+    // SAFETY: the socket-activation protocol hands ownership of fds starting at
+    // LISTEN_FDS_START to the process named in LISTEN_PID, which we checked above.
+    let std_listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    std_listener
+        .local_addr()
+        .with_context(|| format!("inherited fd {fd} is not a bound TCP socket"))?;
+    std_listener
+        .set_nonblocking(true)
+        .context("failed to make inherited listener non-blocking")?;
+
+    Ok(TcpListener::from_std(std_listener)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn socket_activation_requires_matching_pid_and_fds() {
+        assert_eq!(socket_activation_fd(Some("42"), Some("1"), 42), Some(3));
+        assert_eq!(socket_activation_fd(Some(" 42 "), Some("2"), 42), Some(3));
+        assert_eq!(socket_activation_fd(Some("41"), Some("1"), 42), None);
+        assert_eq!(socket_activation_fd(Some("42"), Some("0"), 42), None);
+        assert_eq!(socket_activation_fd(None, Some("1"), 42), None);
+        assert_eq!(socket_activation_fd(Some("42"), None, 42), None);
+        assert_eq!(socket_activation_fd(Some("abc"), Some("1"), 42), None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn listener_from_fd_adopts_inherited_socket() {
+        use std::os::fd::IntoRawFd;
+
+        let inherited = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let expected = inherited.local_addr().unwrap();
+        let fd = inherited.into_raw_fd();
+
+        let listener = listener_from_fd(fd).unwrap();
+        assert_eq!(listener.local_addr().unwrap(), expected);
+
+        let connect = tokio::net::TcpStream::connect(expected);
+        let (accepted, connected) = tokio::join!(listener.accept(), connect);
+        assert!(accepted.is_ok());
+        assert!(connected.is_ok());
+    }
+}
@@ -1,28 +1,280 @@
+mod audit;
+mod body_log;
+mod channel_queue;
+mod circuit;
 mod config;
+mod dampening;
+mod dedup;
 mod discord;
 mod event;
+mod hook_auth;
+mod listener;
+mod merge;
 mod parser;
+mod receipt;
+mod response;
+mod shutdown;
 mod state;
+#[cfg(test)]
+mod test_support;
 
-use crate::config::load_runtime_config;
-use crate::discord::DiscordClient;
-use crate::event::{OpencodeEvent, SendFilesEvent};
-use crate::parser::{extract_file_paths, split_for_discord, strip_file_paths};
-use crate::state::BridgeState;
+use crate::audit::{AuditLog, AuditRecord};
+use crate::body_log::{BodyLog, log_request_body};
+use crate::config::{
+    AttachmentPlacement, FileScanScope, FileSettle, FormatOptions, GlobalPauseMode, RuntimeConfig,
+    expand_home_in, home_dir, load_runtime_config,
+};
+use crate::dampening::ErrorIdleDampener;
+use crate::dedup::{Claim, SeenIds};
+use crate::discord::{
+    DiscordClient, DiscordError, DiscordSettings, Embed, MergeFailed, channel_name_for,
+};
+use crate::event::{
+    OPENCODE_EVENT_FIELDS, OpencodeEvent, REGISTER_PROJECT_FIELDS, RegisterProjectRequest,
+    SEND_FILES_EVENT_FIELDS, SendFilesEvent, unknown_fields,
+};
+use crate::hook_auth::{HookSecret, require_hook_secret};
+use crate::listener::bind_listener;
+use crate::merge::RecentIdleMessages;
+use crate::parser::{
+    append_footer, apply_message_template, attachment_caption, bold_headings,
+    collapse_repeated_lines, compact_blank_lines, extract_file_paths, extract_relative_file_paths,
+    file_footer, file_link, file_search_window, fit_chunks, glob_match, is_glob,
+    normalize_typography, original_spellings, percent_decode_path, split_with_continuation_markers,
+    strip_file_paths, strip_lines_matching, truncate_preview, wrap_code_block,
+};
+use crate::receipt::{DeliveryReceipt, callback_allowed, callback_client, spawn_receipt};
+use crate::response::render;
+use crate::shutdown::{
+    InFlight, ShutdownOutcome, ShuttingDown, reject_while_shutting_down,
+    serve_with_shutdown_timeout, shutdown_signal,
+};
+use crate::state::{BridgeState, ChannelLookup, StateStore};
 use axum::extract::State;
 use axum::http::StatusCode;
-use axum::routing::post;
+use axum::middleware;
+use axum::routing::{get, post};
 use axum::{Json, Router};
+use serde::Serialize;
 use serde_json::Value;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::net::SocketAddr;
+use std::future::IntoFuture;
 use std::path::{Path, PathBuf};
-use tracing::{error, info};
+use std::sync::Arc;
+use tracing::{debug, error, info, warn};
+
+const FULL_OUTPUT_FILENAME: &str = "full-output.txt";
+/// Discord's red, used for `session.error` embeds.
+const ERROR_EMBED_COLOR: u32 = 0xED4245;
 
 #[derive(Clone)]
 struct AppState {
+    config: Arc<RuntimeConfig>,
     discord: DiscordClient,
-    state_path: PathBuf,
+    /// Clients for projects with their own Discord API base URL or token.
+    project_discord: Arc<HashMap<String, DiscordClient>>,
+    callbacks: reqwest::Client,
+    state: Arc<StateStore>,
+    in_flight: InFlight,
+    /// Events waiting out a global pause under `GlobalPauseMode::Defer`.
+    deferred: InFlight,
+    /// The `LiveApp` this app was installed in, once it was.
+    live: Arc<std::sync::OnceLock<std::sync::Weak<std::sync::RwLock<AppState>>>>,
+    dampener: Arc<ErrorIdleDampener>,
+    seen_events: Arc<SeenIds>,
+    channel_creation: Arc<tokio::sync::Mutex<()>>,
+    audit: Option<AuditLog>,
+    permission_warned: Arc<std::sync::Mutex<HashSet<String>>>,
+    recent_idles: Arc<RecentIdleMessages>,
+    /// Request body logging; its secrets follow the live config.
+    body_log: BodyLog,
+    started_at: std::time::Instant,
+}
+
+impl AppState {
+    /// This app with its config-derived parts rebuilt from `cfg`. In-flight
+    /// tracking, dedup ids and other runtime bookkeeping carry over; the dampener,
+    /// idle merge memory and audit writer are replaced only when their settings
+    /// change, and Discord clients only when their token or API base does.
+    fn reconfigured(&self, cfg: RuntimeConfig) -> anyhow::Result<Self> {
+        let old = &self.config;
+        let dampener = if (old.error_idle_window, old.substantive_idle_chars)
+            == (cfg.error_idle_window, cfg.substantive_idle_chars)
+        {
+            Arc::clone(&self.dampener)
+        } else {
+            Arc::new(ErrorIdleDampener::new(
+                cfg.error_idle_window,
+                cfg.substantive_idle_chars,
+            ))
+        };
+        let recent_idles = if old.merge_files_window == cfg.merge_files_window {
+            Arc::clone(&self.recent_idles)
+        } else {
+            Arc::new(RecentIdleMessages::new(cfg.merge_files_window))
+        };
+        let discord = self
+            .discord
+            .reconfigured(cfg.discord_token.clone(), cfg.discord.clone());
+        let audit = if (&old.audit_log_path, old.audit_log_rotation)
+            == (&cfg.audit_log_path, cfg.audit_log_rotation)
+        {
+            self.audit.clone()
+        } else {
+            cfg.audit_log_path
+                .clone()
+                .map(|path| AuditLog::open(path, cfg.audit_log_rotation))
+        };
+        Ok(Self {
+            project_discord: Arc::new(project_discord_clients(
+                &cfg,
+                &discord,
+                &self.project_discord,
+            )),
+            discord,
+            dampener,
+            recent_idles,
+            audit,
+            state: Arc::new(state_store(&cfg)?),
+            config: Arc::new(cfg),
+            ..self.clone()
+        })
+    }
+
+    fn discord_for(&self, project_name: &str) -> &DiscordClient {
+        self.config
+            .project_entry(&self.project_discord, project_name)
+            .unwrap_or(&self.discord)
+    }
+
+    /// The app `/reload` installed most recently, for work that outlives the
+    /// request it started in; this app when it was never installed.
+    fn latest(&self) -> AppState {
+        match self.live.get().and_then(std::sync::Weak::upgrade) {
+            Some(live) => LiveApp(live).current(),
+            None => self.clone(),
+        }
+    }
+
+    /// Re-arm the missing-permissions warning for `channel_id` after it accepted a
+    /// delivery, so losing permissions again is reported.
+    fn delivered_to(&self, channel_id: &str) {
+        self.permission_warned.lock().unwrap().remove(channel_id);
+    }
+}
+
+/// The app handlers run against, swapped as a whole by `/reload`.
+#[derive(Clone)]
+struct LiveApp(Arc<std::sync::RwLock<AppState>>);
+
+impl LiveApp {
+    fn new(app: AppState) -> Self {
+        let live = Arc::clone(&app.live);
+        let this = Self(Arc::new(std::sync::RwLock::new(app)));
+        let _ = live.set(Arc::downgrade(&this.0));
+        this
+    }
+
+    fn current(&self) -> AppState {
+        self.0
+            .read()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Apply `cfg`, leaving the running app untouched if it doesn't load or
+    /// Discord rejects one of its tokens.
+    async fn reload(&self, cfg: RuntimeConfig) -> anyhow::Result<()> {
+        let next = self.current().reconfigured(cfg)?;
+        verify_discord_tokens(&next).await?;
+        next.body_log.set_secrets(log_secrets(&next.config));
+        *self
+            .0
+            .write()
+            .unwrap_or_else(std::sync::PoisonError::into_inner) = next;
+        Ok(())
+    }
+}
+
+/// Values masked in logged request bodies: every bot token and the hook secret.
+fn log_secrets(cfg: &RuntimeConfig) -> Vec<String> {
+    std::iter::once(cfg.discord_token.clone())
+        .chain(cfg.project_discord.values().filter_map(|p| p.token.clone()))
+        .chain(cfg.hook_secret.clone())
+        .collect()
+}
+
+fn state_store(cfg: &RuntimeConfig) -> anyhow::Result<StateStore> {
+    let store = StateStore::new(cfg.state_path.clone(), cfg.project_state_paths.clone())
+        .with_case_insensitive_projects(cfg.case_insensitive_projects)
+        .with_project_aliases(cfg.project_aliases.clone())
+        .with_read_retries(cfg.state_read_retries, cfg.state_read_retry_delay);
+    let warnings = store.validate();
+    for warning in &warnings {
+        warn!("state: {warning}");
+    }
+    if cfg.strict_state && !warnings.is_empty() {
+        anyhow::bail!(
+            "state validation found {} problem(s) and strictState is enabled",
+            warnings.len()
+        );
+    }
+    Ok(store)
+}
+
+/// One client per project with a Discord override or its own chunk pacing or
+/// typing interval in `projectFormat`. Clients for the same base URL and token,
+/// `global` included, share rate-limit state and channel queues; a client in
+/// `previous` for the same base URL and token is reconfigured, not replaced.
+fn project_discord_clients(
+    cfg: &RuntimeConfig,
+    global: &DiscordClient,
+    previous: &HashMap<String, DiscordClient>,
+) -> HashMap<String, DiscordClient> {
+    let mut by_endpoint: HashMap<(String, String), DiscordClient> = HashMap::new();
+    let mut clients = HashMap::new();
+    let project_names: HashSet<&String> = cfg
+        .project_discord
+        .keys()
+        .chain(cfg.project_format.keys())
+        .collect();
+
+    for project_name in project_names {
+        let endpoint = cfg.project_entry(&cfg.project_discord, project_name);
+        let api_base = endpoint
+            .and_then(|endpoint| endpoint.api_base.clone())
+            .unwrap_or_else(|| cfg.discord.api_base.clone());
+        let token = endpoint
+            .and_then(|endpoint| endpoint.token.clone())
+            .unwrap_or_else(|| cfg.discord_token.clone());
+        let shared = by_endpoint
+            .entry((api_base.clone(), token.clone()))
+            .or_insert_with(|| {
+                if global.uses(&token, &api_base) {
+                    return global.clone();
+                }
+                let settings = DiscordSettings {
+                    api_base: api_base.clone(),
+                    ..cfg.discord.clone()
+                };
+                match previous.values().find(|old| old.uses(&token, &api_base)) {
+                    Some(old) => old.reconfigured(token.clone(), settings),
+                    None => DiscordClient::new(token.clone(), settings),
+                }
+            });
+        let format = cfg.format_for(project_name);
+        let settings = DiscordSettings {
+            api_base,
+            chunk_pacing: format.chunk_pacing,
+            typing_interval: format.typing_interval,
+            ..cfg.discord.clone()
+        };
+        clients.insert(project_name.clone(), shared.reconfigured(token, settings));
+    }
+
+    clients
 }
 
 #[tokio::main]
@@ -35,63 +287,293 @@ async fn main() -> anyhow::Result<()> {
 
     let cfg = load_runtime_config()?;
     info!("Loaded config from {}", cfg.config_path.display());
+    if cfg.dry_run {
+        warn!("dry run: Discord messages, uploads and channel creation are logged, not sent");
+    }
+
+    let state_store = Arc::new(state_store(&cfg)?);
+
+    let seen_events = Arc::new(match &cfg.event_dedup_path {
+        Some(path) => SeenIds::load(cfg.event_dedup_capacity, path),
+        None => SeenIds::new(cfg.event_dedup_capacity),
+    });
+    if let Some(path) = cfg.event_dedup_path.clone() {
+        let seen_events = Arc::clone(&seen_events);
+        let mut ticks = tokio::time::interval(cfg.event_dedup_flush_interval);
+        tokio::spawn(async move {
+            loop {
+                ticks.tick().await;
+                persist_seen_events(&seen_events, &path).await;
+            }
+        });
+    }
 
+    let in_flight = InFlight::default();
+    let discord = DiscordClient::new(cfg.discord_token.clone(), cfg.discord.clone());
     let app_state = AppState {
-        discord: DiscordClient::new(cfg.discord_token),
-        state_path: cfg.state_path,
+        project_discord: Arc::new(project_discord_clients(&cfg, &discord, &HashMap::new())),
+        discord,
+        callbacks: callback_client(),
+        state: state_store,
+        in_flight: in_flight.clone(),
+        deferred: InFlight::default(),
+        live: Arc::default(),
+        dampener: Arc::new(ErrorIdleDampener::new(
+            cfg.error_idle_window,
+            cfg.substantive_idle_chars,
+        )),
+        seen_events: Arc::clone(&seen_events),
+        channel_creation: Arc::default(),
+        permission_warned: Arc::default(),
+        recent_idles: Arc::new(RecentIdleMessages::new(cfg.merge_files_window)),
+        body_log: BodyLog::new(cfg.log_bodies, cfg.log_body_max_chars, log_secrets(&cfg)),
+        started_at: std::time::Instant::now(),
+        audit: cfg
+            .audit_log_path
+            .clone()
+            .map(|path| AuditLog::open(path, cfg.audit_log_rotation)),
+        config: Arc::new(cfg.clone()),
     };
 
+    verify_discord_tokens(&app_state).await?;
+
+    let format = cfg.response_format;
+    let body_log = app_state.body_log.clone();
+    let require_secret = middleware::from_fn_with_state(
+        HookSecret::new(cfg.hook_secret.clone()),
+        require_hook_secret,
+    );
+    let log_bodies = middleware::from_fn_with_state(body_log, log_request_body);
+    let shutting_down = ShuttingDown::default();
+    let refuse_during_shutdown =
+        middleware::from_fn_with_state(shutting_down.clone(), reject_while_shutting_down);
     let app = Router::new()
-        .route("/reload", post(handle_reload))
-        .route("/send-files", post(handle_send_files))
-        .route("/opencode-event", post(handle_opencode_event))
-        .with_state(app_state);
+        .route(
+            "/health",
+            get(|State(live): State<LiveApp>| async move { handle_health(State(live.current())) }),
+        )
+        .route(
+            "/reload",
+            post(move |live| async move { render(format, handle_reload(live).await) })
+                .layer(require_secret.clone()),
+        )
+        .route(
+            "/projects",
+            get(|State(live): State<LiveApp>| async move {
+                handle_list_projects(State(live.current()))
+            })
+            .post(move |State(live): State<LiveApp>, payload| async move {
+                render(
+                    format,
+                    handle_register_project(State(live.current()), payload).await,
+                )
+            })
+            .layer(log_bodies.clone())
+            .layer(refuse_during_shutdown.clone())
+            .layer(require_secret.clone()),
+        )
+        .route(
+            "/send-files",
+            post(move |State(live): State<LiveApp>, payload| async move {
+                render(
+                    format,
+                    handle_send_files(State(live.current()), payload).await,
+                )
+            })
+            .layer(log_bodies.clone())
+            .layer(refuse_during_shutdown.clone())
+            .layer(require_secret.clone()),
+        )
+        .route(
+            "/opencode-event",
+            post(move |State(live): State<LiveApp>, payload| async move {
+                render(
+                    format,
+                    handle_opencode_event(State(live.current()), payload).await,
+                )
+            })
+            .layer(log_bodies)
+            .layer(refuse_during_shutdown)
+            .layer(require_secret),
+        )
+        .with_state(LiveApp::new(app_state));
+
+    let addr = cfg.hook_server_addr()?;
+    let listener = bind_listener(addr).await?;
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], cfg.hook_server_port));
-    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!(
+        "mudcode-rs bridge listening on http://{}",
+        listener.local_addr()?
+    );
 
-    info!("mudcode-rs bridge listening on http://{}", addr);
+    let (signaled_tx, mut signaled_rx) = tokio::sync::watch::channel(false);
+    let serve = axum::serve(listener, app)
+        .with_graceful_shutdown(async move {
+            shutdown_signal(&shutting_down).await;
+            let _ = signaled_tx.send(true);
+        })
+        .into_future();
+    let signaled = async move {
+        let _ = signaled_rx.wait_for(|fired| *fired).await;
+    };
 
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await?;
+    let outcome =
+        serve_with_shutdown_timeout(serve, signaled, cfg.shutdown_timeout, &in_flight).await?;
+    if let Some(path) = &cfg.event_dedup_path {
+        persist_seen_events(&seen_events, path).await;
+    }
+    if let ShutdownOutcome::TimedOut { .. } = outcome {
+        std::process::exit(1);
+    }
 
     Ok(())
 }
 
-async fn shutdown_signal() {
-    let ctrl_c = async {
-        if let Err(error) = tokio::signal::ctrl_c().await {
-            error!("failed to install Ctrl+C handler: {error}");
+/// Check the global token and every distinct project token before accepting hooks
+/// or a reload. A rejected token is an error; other failures (e.g. Discord
+/// unreachable) only warn.
+async fn verify_discord_tokens(app: &AppState) -> anyhow::Result<()> {
+    let cfg = &app.config;
+    let mut checks = vec![("discordToken".to_string(), app.discord.clone())];
+    let mut seen = HashSet::from([&cfg.discord_token]);
+    let mut project_names: Vec<&String> = cfg.project_discord.keys().collect();
+    project_names.sort();
+    for project_name in project_names {
+        if let Some(token) = &cfg.project_discord[project_name].token
+            && seen.insert(token)
+        {
+            checks.push((
+                format!("token for project {project_name}"),
+                app.discord_for(project_name).clone(),
+            ));
         }
-    };
-
-    #[cfg(unix)]
-    let terminate = async {
-        use tokio::signal::unix::{SignalKind, signal};
+    }
 
-        match signal(SignalKind::terminate()) {
-            Ok(mut stream) => {
-                let _ = stream.recv().await;
-            }
-            Err(error) => {
-                error!("failed to install SIGTERM handler: {error}");
+    for (what, client) in checks {
+        match client.verify_token().await {
+            Ok(username) => info!("Discord {what} accepted (bot {username})"),
+            Err(error) if DiscordError::is_unauthorized(&error) => {
+                anyhow::bail!("Discord rejected the {what}; check config.json: {error}");
             }
+            Err(error) => warn!("could not verify Discord {what}: {error:#}"),
         }
-    };
+    }
+    Ok(())
+}
 
-    #[cfg(not(unix))]
-    let terminate = std::future::pending::<()>();
+/// Re-read config and state files so edits (a rotated token, new projects) apply
+/// without a restart. The listen port, hook secret, response format, shutdown
+/// timeout, body logging switch and size, and the event dedup set (capacity, file
+/// and flush interval) keep their startup values.
+async fn handle_reload(State(live): State<LiveApp>) -> (StatusCode, String) {
+    let reloaded = match load_runtime_config() {
+        Ok(cfg) => live.reload(cfg).await,
+        Err(error) => Err(error),
+    };
+    match reloaded {
+        Ok(()) => {
+            info!("Reloaded config");
+            (StatusCode::OK, "OK".to_string())
+        }
+        Err(error) => {
+            error!("reload failed, keeping the previous config: {error:#}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Reload failed: {error:#}"),
+            )
+        }
+    }
+}
 
-    tokio::select! {
-        _ = ctrl_c => {},
-        _ = terminate => {},
+/// Liveness and readiness: 200 while every state file parses, 503 otherwise.
+/// Open even when a hook secret is set.
+fn handle_health(State(app): State<AppState>) -> (StatusCode, Json<Value>) {
+    let uptime_secs = app.started_at.elapsed().as_secs();
+    match app.state.project_count() {
+        Ok(projects) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "status": "ok",
+                "projects": projects,
+                "uptime_secs": uptime_secs,
+            })),
+        ),
+        Err(error) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(serde_json::json!({
+                "status": "error",
+                "error": format!("state file unreadable: {error}"),
+                "uptime_secs": uptime_secs,
+            })),
+        ),
     }
+}
 
-    info!("shutdown signal received");
+/// Every project's path and channel mappings as the bridge currently reads them.
+fn handle_list_projects(State(app): State<AppState>) -> (StatusCode, Json<Value>) {
+    match app.state.project_mappings() {
+        Ok(projects) => (
+            StatusCode::OK,
+            Json(serde_json::json!({ "projects": projects })),
+        ),
+        Err(error) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(serde_json::json!({ "error": format!("state file unreadable: {error}") })),
+        ),
+    }
 }
 
-async fn handle_reload() -> (StatusCode, String) {
+/// Add or update a project's instance-to-channel mapping in its state file.
+async fn handle_register_project(
+    State(app): State<AppState>,
+    Json(payload): Json<Value>,
+) -> (StatusCode, String) {
+    let _work = app.in_flight.begin();
+    if let Some(rejection) = reject_unknown_fields(&app, &payload, REGISTER_PROJECT_FIELDS) {
+        return rejection;
+    }
+    let Ok(request) = serde_json::from_value::<RegisterProjectRequest>(payload) else {
+        return (StatusCode::BAD_REQUEST, "Invalid payload".to_string());
+    };
+    let registration = match request.validate() {
+        Ok(registration) => registration,
+        Err(message) => return (StatusCode::BAD_REQUEST, message),
+    };
+
+    // Channel creation writes the same files.
+    let _writing = app.channel_creation.lock().await;
+    let path = app.state.path_for(registration.project_name);
+    let mut state = match BridgeState::try_load(path) {
+        Ok(state) => state,
+        Err(error) => {
+            error!(
+                "not registering into unreadable {}: {error}",
+                path.display()
+            );
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "State file is unreadable".to_string(),
+            );
+        }
+    };
+    if let Err(conflict) = state.register(&registration, request.overwrite) {
+        return (StatusCode::CONFLICT, conflict);
+    }
+    if let Err(error) = state.save(path) {
+        error!(
+            "failed to persist registration project={} err={:#}",
+            registration.project_name, error
+        );
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to save state".to_string(),
+        );
+    }
+
+    info!(
+        "registered project={} agent={} channel={}",
+        registration.project_name, registration.agent_type, registration.channel_id
+    );
     (StatusCode::OK, "OK".to_string())
 }
 
@@ -99,166 +581,4575 @@ async fn handle_send_files(
     State(app): State<AppState>,
     Json(payload): Json<Value>,
 ) -> (StatusCode, String) {
+    let _work = app.in_flight.begin();
+    let callback_url = payload_callback_url(&payload);
+    if let Some(rejection) = reject_unknown_fields(&app, &payload, SEND_FILES_EVENT_FIELDS) {
+        return rejected_with_receipt(&app, callback_url.as_deref(), "", rejection);
+    }
     let Ok(event) = serde_json::from_value::<SendFilesEvent>(payload) else {
-        return (StatusCode::BAD_REQUEST, "Invalid payload".to_string());
+        let rejection = (StatusCode::BAD_REQUEST, "Invalid payload".to_string());
+        return rejected_with_receipt(&app, callback_url.as_deref(), "", rejection);
     };
+    if let Some(rejection) = reject_callback_url(&app, event.callback_url()) {
+        return rejection;
+    }
+    let callback_url = event.callback_url();
 
     let Some(project_name) = event.project_name() else {
-        return (StatusCode::BAD_REQUEST, "Missing projectName".to_string());
+        let rejection = (StatusCode::BAD_REQUEST, "Missing projectName".to_string());
+        return rejected_with_receipt(&app, callback_url, "", rejection);
     };
 
-    if event.files.is_empty() {
-        return (StatusCode::BAD_REQUEST, "No files provided".to_string());
+    if event.files.is_empty() && event.content().is_none() {
+        let rejection = (
+            StatusCode::BAD_REQUEST,
+            "No files or content provided".to_string(),
+        );
+        return rejected_with_receipt(&app, callback_url, "", rejection);
     }
 
-    let state = BridgeState::load(&app.state_path);
+    let (state, project_name) = resolve_project(&app, project_name).await;
+    let project_name = project_name.as_str();
     if !state.projects.contains_key(project_name) {
-        return (StatusCode::NOT_FOUND, "Project not found".to_string());
+        let rejection = (StatusCode::NOT_FOUND, "Project not found".to_string());
+        return rejected_with_receipt(&app, callback_url, "", rejection);
     }
+    let agent_type = event.agent_type_or(state.default_agent_type(project_name));
 
-    let Some(channel_id) =
-        state.find_channel_id(project_name, event.agent_type(), event.instance_id())
+    let Some(channel_id) = resolve_channel(
+        &app,
+        &state,
+        "send-files",
+        project_name,
+        agent_type,
+        !event.agent_type_or("").is_empty(),
+        event.instance_id(),
+    )
+    .await
     else {
-        return (
+        let rejection = (
             StatusCode::NOT_FOUND,
             "No channel found for project/agent".to_string(),
         );
+        return rejected_with_receipt(&app, callback_url, "", rejection);
     };
 
+    let _turn = app
+        .discord_for(project_name)
+        .hold_channel(&channel_id)
+        .await;
     let project_path = state.project_path(project_name);
-    let valid_files = validate_file_paths(&event.files, project_path.as_deref());
+    let instance_key = format!(
+        "{project_name}/{}",
+        event.instance_id().unwrap_or(agent_type)
+    );
+    let mut receipt = DeliveryReceipt::new(&channel_id);
+    let mut skipped = 0;
 
-    if valid_files.is_empty() {
-        return (StatusCode::BAD_REQUEST, "No valid files".to_string());
-    }
+    let (what, delivered) =
+        if event.files.is_empty() {
+            let content = event.content().unwrap_or_default();
+            let sent = app
+                .discord_for(project_name)
+                .send_message(&channel_id, content)
+                .await;
+            ("content", sent.map(|message_ids| receipt.sent(message_ids)))
+        } else {
+            let valid_files =
+                match send_files_to_deliver(&app, &event, project_name, project_path.as_deref())
+                    .await
+                {
+                    Ok((files, over_cap)) => {
+                        skipped = over_cap;
+                        files
+                    }
+                    Err(rejection) => {
+                        return rejected_with_receipt(&app, callback_url, &channel_id, rejection);
+                    }
+                };
+            let merge_into = match event.content() {
+                Some(_) => None,
+                None => app.recent_idles.get(&instance_key, &channel_id),
+            };
+            let message = match (event.content(), merge_into.as_deref()) {
+                (Some(content), _) => FilesMessage::Captioned(content),
+                (None, Some(message_id)) => FilesMessage::Merge(message_id),
+                (None, None) => FilesMessage::New,
+            };
+            let delivered = deliver_files(
+                &app,
+                project_name,
+                &channel_id,
+                project_path.as_deref(),
+                &valid_files,
+                message,
+                &mut receipt,
+            )
+            .await;
+            if let (Ok(()), Some(message_id)) = (&delivered, merge_into.as_deref()) {
+                app.recent_idles.used(&instance_key, message_id);
+            }
+            ("files", delivered)
+        };
 
-    match app.discord.send_files(&channel_id, "", &valid_files).await {
-        Ok(_) => (StatusCode::OK, "OK".to_string()),
+    let response = match delivered {
+        Ok(()) => (StatusCode::OK, "OK".to_string()),
         Err(error) => {
-            error!(
-                "send-files failed project={} channel={} err={}",
-                project_name, channel_id, error
-            );
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Internal error".to_string(),
-            )
+            receipt.fail(&error);
+            delivery_failure(&app, what, project_name, &channel_id, &error)
+        }
+    };
+
+    if response.0 == StatusCode::OK {
+        app.delivered_to(&channel_id);
+        react_on_success(&app, &state, project_name, &receipt).await;
+    }
+    let response = with_message_ids(&app, response, &receipt);
+    let response = with_skipped_files(response, skipped);
+
+    if let Some(audit) = &app.audit {
+        audit.record(AuditRecord::new(
+            project_name,
+            agent_type,
+            "send-files",
+            0,
+            &receipt,
+        ));
+    }
+
+    if let Some(url) = callback_url {
+        spawn_receipt(&app.callbacks, url, receipt);
+    }
+
+    response
+}
+
+/// The requested files that may be uploaded and how many directory files were
+/// left out over the cap, or the rejection when none can be uploaded.
+async fn send_files_to_deliver(
+    app: &AppState,
+    event: &SendFilesEvent,
+    project_name: &str,
+    project_path: Option<&Path>,
+) -> Result<(Vec<String>, usize), (StatusCode, String)> {
+    let format = app.config.format_for(project_name);
+    let requested: Vec<String> = if format.decode_percent_paths {
+        event.files.iter().map(|p| percent_decode_path(p)).collect()
+    } else {
+        event.files.clone()
+    };
+    let requested = if event.glob.unwrap_or(app.config.glob_files) {
+        expand_file_globs(&requested, project_path, app.config.max_glob_matches).await
+    } else {
+        requested
+    };
+    let DirectoryExpansion {
+        files: requested,
+        skipped,
+    } = expand_directories(
+        &requested,
+        project_path,
+        &format.attachment_extensions,
+        event.recursive.unwrap_or(app.config.recursive_directories),
+        app.config.max_directory_files,
+    )
+    .await;
+    let valid_files = match project_path {
+        Some(project_path) => {
+            validate_file_paths(&requested, Some(project_path), format.file_settle).await
+        }
+        None if app.config.trust_paths_without_project_path => {
+            warn!("project {project_name} has no projectPath; trusting absolute file paths");
+            requested
+                .into_iter()
+                .filter(|path| Path::new(path).is_absolute() && Path::new(path).is_file())
+                .collect()
+        }
+        None => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "Project {project_name} has no projectPath in state; cannot validate files"
+                ),
+            ));
         }
+    };
+
+    if valid_files.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "No valid files".to_string()));
     }
+
+    Ok((valid_files, skipped))
 }
 
 async fn handle_opencode_event(
     State(app): State<AppState>,
     Json(payload): Json<Value>,
 ) -> (StatusCode, String) {
+    let _work = app.in_flight.begin();
+    let callback_url = payload_callback_url(&payload);
+    if let Some(rejection) = reject_unknown_fields(&app, &payload, OPENCODE_EVENT_FIELDS) {
+        return rejected_with_receipt(&app, callback_url.as_deref(), "", rejection);
+    }
     let Ok(event) = serde_json::from_value::<OpencodeEvent>(payload) else {
-        return (StatusCode::BAD_REQUEST, "Invalid event payload".to_string());
+        let rejection = (StatusCode::BAD_REQUEST, "Invalid event payload".to_string());
+        return rejected_with_receipt(&app, callback_url.as_deref(), "", rejection);
     };
+    if let Some(rejection) = reject_callback_url(&app, event.callback_url()) {
+        return rejection;
+    }
 
-    let Some(project_name) = event.project_name() else {
-        return (StatusCode::BAD_REQUEST, "Invalid event payload".to_string());
-    };
+    let project_names = event.project_names();
+    if project_names.is_empty() {
+        let rejection = (StatusCode::BAD_REQUEST, "Invalid event payload".to_string());
+        return rejected_with_receipt(&app, event.callback_url(), "", rejection);
+    }
 
-    let state = BridgeState::load(&app.state_path);
-    let Some(channel_id) =
-        state.find_channel_id(project_name, event.agent_type(), event.instance_id())
-    else {
-        return (StatusCode::BAD_REQUEST, "Invalid event payload".to_string());
-    };
+    let mut targets: Vec<String> = project_names.iter().map(|name| name.to_string()).collect();
+    if let Some(event_id) = event.event_id() {
+        let mut claimed = Vec::new();
+        let mut in_progress = false;
+        for name in &targets {
+            match app.seen_events.claim(&dedup_key(event_id, name)) {
+                Claim::New => claimed.push(name.clone()),
+                Claim::Seen => {}
+                Claim::InProgress => in_progress = true,
+            }
+        }
+        if in_progress {
+            for name in &claimed {
+                app.seen_events.forget(&dedup_key(event_id, name));
+            }
+            let rejection = (
+                StatusCode::CONFLICT,
+                format!("Event {event_id} is still being delivered"),
+            );
+            return rejected_with_receipt(&app, event.callback_url(), "", rejection);
+        }
+        if claimed.is_empty() {
+            info!(
+                "dropped duplicate event id={event_id} projects={}",
+                project_names.join(",")
+            );
+            return (StatusCode::OK, "OK".to_string());
+        }
+        targets = claimed;
+    }
 
-    match event.event_type() {
-        Some("session.error") => {
-            let msg = event
-                .event_text()
-                .unwrap_or_else(|| "unknown error".to_string());
-            let content = format!("⚠️ OpenCode session error: {msg}");
-            if let Err(error) = app.discord.send_message(&channel_id, &content).await {
-                error!(
-                    "failed to deliver session.error project={} channel={} err={}",
-                    project_name, channel_id, error
+    let paused = project_names
+        .iter()
+        .filter_map(|name| app.discord_for(name).global_pause_remaining())
+        .max();
+    if let Some(remaining) = paused {
+        match app.config.global_pause_mode {
+            GlobalPauseMode::Defer if app.deferred.count() < app.config.max_deferred_events => {
+                info!(
+                    "deferring event for {}ms global rate-limit pause projects={}",
+                    remaining.as_millis(),
+                    project_names.join(",")
                 );
-                return (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "Internal error".to_string(),
+                // Count the deferred work before answering so a shutdown that
+                // starts before the task first runs still waits for it. It is
+                // only held in memory: a restart before the pause clears loses it.
+                let work = (app.in_flight.begin(), app.deferred.begin());
+                tokio::spawn(async move {
+                    let _work = work;
+                    for name in &targets {
+                        app.latest().discord_for(name).wait_for_global_pause().await;
+                    }
+                    deliver_event(&app.latest(), &event, &targets).await
+                });
+                return (StatusCode::ACCEPTED, "Deferred".to_string());
+            }
+            GlobalPauseMode::Wait(None) => {}
+            GlobalPauseMode::Wait(Some(limit)) if remaining <= limit => {}
+            // A pause longer than the wait limit, or too many events deferred already.
+            GlobalPauseMode::Wait(Some(_)) | GlobalPauseMode::Defer => {
+                if let Some(event_id) = event.event_id() {
+                    for name in &targets {
+                        app.seen_events.forget(&dedup_key(event_id, name));
+                    }
+                }
+                let rejection = (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Discord globally rate limited".to_string(),
                 );
+                return rejected_with_receipt(&app, event.callback_url(), "", rejection);
             }
         }
-        Some("session.idle") => {
-            if let Some(text) = event.event_text() {
-                let trimmed = text.trim();
-                if !trimmed.is_empty() {
-                    let file_search_text = event.turn_text().unwrap_or(trimmed);
-                    let project_path = state.project_path(project_name);
+    }
 
-                    let extracted = extract_file_paths(file_search_text);
-                    let valid_files = validate_file_paths(&extracted, project_path.as_deref());
-                    let display_text = if valid_files.is_empty() {
-                        trimmed.to_string()
-                    } else {
-                        strip_file_paths(trimmed, &valid_files)
-                    };
+    deliver_event(&app, &event, &targets).await
+}
 
-                    for chunk in split_for_discord(&display_text) {
-                        if chunk.trim().is_empty() {
-                            continue;
-                        }
+/// Dedup entry for delivering event `event_id` to `project_name`, so a retry of a
+/// partial delivery only reaches the projects that failed.
+fn dedup_key(event_id: &str, project_name: &str) -> String {
+    format!("{event_id}@{project_name}")
+}
 
-                        if let Err(error) = app.discord.send_message(&channel_id, &chunk).await {
-                            error!(
-                                "failed to deliver chunk project={} channel={} err={}",
-                                project_name, channel_id, error
-                            );
-                            return (
-                                StatusCode::INTERNAL_SERVER_ERROR,
-                                "Internal error".to_string(),
-                            );
-                        }
-                    }
+/// Deliver `event` to `targets` (its projects not yet delivered to) and build the
+/// combined response.
+async fn deliver_event(
+    app: &AppState,
+    event: &OpencodeEvent,
+    targets: &[String],
+) -> (StatusCode, String) {
+    let mut results = Vec::with_capacity(targets.len());
+    for project_name in targets {
+        let (status, body) = handle_project_event(app, event, project_name).await;
+        results.push((project_name.clone(), status, body));
+    }
 
-                    if !valid_files.is_empty()
-                        && let Err(error) =
-                            app.discord.send_files(&channel_id, "", &valid_files).await
-                    {
-                        error!(
-                            "failed to deliver files project={} channel={} err={}",
-                            project_name, channel_id, error
-                        );
-                        return (
-                            StatusCode::INTERNAL_SERVER_ERROR,
-                            "Internal error".to_string(),
-                        );
-                    }
-                }
+    // Let a retry of a failed project through instead of treating it as a
+    // duplicate.
+    if let Some(event_id) = event.event_id() {
+        for (project_name, status, _) in &results {
+            let key = dedup_key(event_id, project_name);
+            if *status == StatusCode::OK {
+                app.seen_events.finish(&key);
+            } else {
+                app.seen_events.forget(&key);
             }
         }
-        _ => {}
     }
 
-    (StatusCode::OK, "OK".to_string())
+    match results.pop() {
+        Some((_, status, body)) if results.is_empty() && event.project_names().len() == 1 => {
+            (status, body)
+        }
+        last => {
+            results.extend(last);
+            multi_project_response(results)
+        }
+    }
 }
 
-fn validate_file_paths(paths: &[String], project_path: Option<&Path>) -> Vec<String> {
-    let Some(project_path) = project_path else {
-        return Vec::new();
+/// Flush the dedup ids to `path` on the blocking pool, logging a failure.
+async fn persist_seen_events(seen_events: &Arc<SeenIds>, path: &Path) {
+    let seen_events = Arc::clone(seen_events);
+    let path = path.to_path_buf();
+    match tokio::task::spawn_blocking(move || seen_events.flush(&path)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(error)) => warn!("failed to persist dedup ids: {error:#}"),
+        Err(error) => warn!("failed to persist dedup ids: {error}"),
+    }
+}
+
+/// `StateStore::resolve_project` on the blocking pool: a cache miss reads the
+/// file and may sleep between read retries.
+async fn resolve_project(app: &AppState, project_name: &str) -> (Arc<BridgeState>, String) {
+    let store = Arc::clone(&app.state);
+    let project_name = project_name.to_string();
+    tokio::task::spawn_blocking(move || store.resolve_project(&project_name))
+        .await
+        .expect("state load panicked")
+}
+
+/// Deliver `event` to one project's channel, sending its receipt and audit record.
+async fn handle_project_event(
+    app: &AppState,
+    event: &OpencodeEvent,
+    project_name: &str,
+) -> (StatusCode, String) {
+    let (state, project_name) = resolve_project(app, project_name).await;
+    let project_name = project_name.as_str();
+    let agent_type = event.agent_type_or(state.default_agent_type(project_name));
+    let Some(channel_id) = resolve_channel(
+        app,
+        &state,
+        event.event_type().unwrap_or_default(),
+        project_name,
+        agent_type,
+        !event.agent_type_or("").is_empty(),
+        event.instance_id(),
+    )
+    .await
+    else {
+        let rejection = (StatusCode::BAD_REQUEST, "Invalid event payload".to_string());
+        return rejected_with_receipt(app, event.callback_url(), "", rejection);
     };
 
-    let project_real =
-        fs::canonicalize(project_path).unwrap_or_else(|_| project_path.to_path_buf());
+    let _turn = app
+        .discord_for(project_name)
+        .hold_channel(&channel_id)
+        .await;
+    let mut receipt = DeliveryReceipt::new(&channel_id);
+    let response = relay_opencode_event(
+        app,
+        &state,
+        event,
+        project_name,
+        agent_type,
+        &channel_id,
+        &mut receipt,
+    )
+    .await;
 
-    paths
-        .iter()
-        .filter_map(|raw| {
-            let path = Path::new(raw);
-            if !path.exists() {
-                return None;
-            }
+    if response.0 == StatusCode::OK {
+        app.delivered_to(&channel_id);
+        react_on_success(app, &state, project_name, &receipt).await;
+    }
+    let response = with_message_ids(app, response, &receipt);
 
-            let real = fs::canonicalize(path).ok()?;
-            if real == project_real || real.starts_with(&project_real) {
-                return Some(raw.to_string());
-            }
+    if let Some(audit) = &app.audit {
+        let content_length = event
+            .event_text()
+            .map_or(0, |text| text.trim().chars().count());
+        audit.record(AuditRecord::new(
+            project_name,
+            agent_type,
+            event.event_type().unwrap_or("unknown"),
+            content_length,
+            &receipt,
+        ));
+    }
+
+    if let Some(url) = event.callback_url() {
+        spawn_receipt(&app.callbacks, url, receipt);
+    }
+
+    response
+}
+
+/// The `callbackUrl` of a payload that may not parse as its event type.
+fn payload_callback_url(payload: &Value) -> Option<String> {
+    payload
+        .get("callbackUrl")
+        .and_then(Value::as_str)
+        .map(str::to_string)
+}
+
+/// Refuse an event whose `callbackUrl` isn't on loopback or `callbackAllowedHosts`.
+fn reject_callback_url(app: &AppState, callback_url: Option<&str>) -> Option<(StatusCode, String)> {
+    let url = callback_url?;
+    if callback_allowed(url, &app.config.callback_allowed_hosts) {
+        return None;
+    }
+    warn!("rejecting event with disallowed callbackUrl {url}");
+    Some((
+        StatusCode::BAD_REQUEST,
+        "callbackUrl host is not allowed".to_string(),
+    ))
+}
+
+/// Send a failed receipt for an event turned away before delivery, so a
+/// `callbackUrl` hears about every outcome.
+fn rejected_with_receipt(
+    app: &AppState,
+    callback_url: Option<&str>,
+    channel_id: &str,
+    rejection: (StatusCode, String),
+) -> (StatusCode, String) {
+    if let Some(url) = callback_url
+        && callback_allowed(url, &app.config.callback_allowed_hosts)
+    {
+        let mut receipt = DeliveryReceipt::new(channel_id);
+        receipt.fail(&anyhow::anyhow!("{}", rejection.1));
+        spawn_receipt(&app.callbacks, url, receipt);
+    }
+    rejection
+}
+
+/// Add the posted message ids to a successful response when `returnMessageIds`
+/// is set: a plain `OK` becomes `{"ok": true, "messageIds": [...]}` and JSON
+/// object bodies gain a `messageIds` field.
+fn with_message_ids(
+    app: &AppState,
+    (status, body): (StatusCode, String),
+    receipt: &DeliveryReceipt,
+) -> (StatusCode, String) {
+    if !app.config.return_message_ids || !status.is_success() {
+        return (status, body);
+    }
+
+    let mut value = match serde_json::from_str::<Value>(&body) {
+        Ok(value) if value.is_object() => value,
+        _ => serde_json::json!({ "ok": true }),
+    };
+    value["messageIds"] = serde_json::json!(receipt.message_ids);
+    (status, value.to_string())
+}
+
+/// Report directory files left out over `maxDirectoryFiles` as a `skipped` field
+/// of a successful response, turning a plain `OK` into a JSON object.
+fn with_skipped_files(
+    (status, body): (StatusCode, String),
+    skipped: usize,
+) -> (StatusCode, String) {
+    if skipped == 0 || !status.is_success() {
+        return (status, body);
+    }
+
+    let mut value = match serde_json::from_str::<Value>(&body) {
+        Ok(value) if value.is_object() => value,
+        _ => serde_json::json!({ "ok": true }),
+    };
+    value["skipped"] = serde_json::json!(skipped);
+    (status, value.to_string())
+}
+
+/// Summarize per-project results of a multi-project event: 200 when every
+/// project succeeded, 500 when none did, 207 otherwise.
+fn multi_project_response(results: Vec<(String, StatusCode, String)>) -> (StatusCode, String) {
+    let succeeded = results
+        .iter()
+        .filter(|(_, status, _)| *status == StatusCode::OK)
+        .count();
+    let status = if succeeded == results.len() {
+        StatusCode::OK
+    } else if succeeded == 0 {
+        StatusCode::INTERNAL_SERVER_ERROR
+    } else {
+        StatusCode::MULTI_STATUS
+    };
 
-            None
+    let projects: Vec<Value> = results
+        .into_iter()
+        .map(|(project_name, status, body)| {
+            serde_json::json!({
+                "projectName": project_name,
+                "status": status.as_u16(),
+                "ok": status == StatusCode::OK,
+                "body": body,
+            })
         })
-        .collect()
+        .collect();
+    (
+        status,
+        serde_json::json!({ "projects": projects }).to_string(),
+    )
+}
+
+/// Log a failed delivery and build the handler's response. Missing channel
+/// permissions warn once per channel and get a distinct 403 JSON body.
+fn delivery_failure(
+    app: &AppState,
+    what: &str,
+    project_name: &str,
+    channel_id: &str,
+    error: &anyhow::Error,
+) -> (StatusCode, String) {
+    if DiscordError::is_missing_permissions(error) {
+        let first = app
+            .permission_warned
+            .lock()
+            .unwrap()
+            .insert(channel_id.to_string());
+        if first {
+            warn!(
+                "bot lacks permission to post in channel={} project={}; grant View Channel, \
+                 Send Messages and Attach Files (further failures for this channel are logged at debug)",
+                channel_id, project_name
+            );
+        } else {
+            debug!(
+                "missing permissions delivering {} project={} channel={}",
+                what, project_name, channel_id
+            );
+        }
+        let body = serde_json::json!({ "error": "missingPermissions", "channelId": channel_id });
+        return (StatusCode::FORBIDDEN, body.to_string());
+    }
+
+    if DiscordError::is_unauthorized(error) {
+        error!(
+            "Discord token rejected delivering {} project={} channel={}; check discordToken in config.json",
+            what, project_name, channel_id
+        );
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Discord token rejected".to_string(),
+        );
+    }
+
+    if DiscordError::is_circuit_open(error) {
+        warn!(
+            "skipped delivering {} project={} channel={}: {}",
+            what, project_name, channel_id, error
+        );
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Discord unavailable".to_string(),
+        );
+    }
+
+    error!(
+        "failed to deliver {} project={} channel={} err={}",
+        what, project_name, channel_id, error
+    );
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Internal error".to_string(),
+    )
+}
+
+/// After a fully successful delivery, react to the last posted message with the
+/// project's (or the global) success emoji. Failures are only logged.
+async fn react_on_success(
+    app: &AppState,
+    state: &BridgeState,
+    project_name: &str,
+    receipt: &DeliveryReceipt,
+) {
+    let Some(emoji) = state
+        .success_reaction(project_name)
+        .or(app.config.success_reaction.as_deref())
+    else {
+        return;
+    };
+    let Some(message_id) = receipt.last_message_id.as_deref() else {
+        return;
+    };
+
+    if let Err(error) = app
+        .discord_for(project_name)
+        .add_reaction(&receipt.channel_id, message_id, emoji)
+        .await
+    {
+        warn!(
+            "failed to add success reaction channel={} message={} err={}",
+            receipt.channel_id, message_id, error
+        );
+    }
+}
+
+/// In strict mode, a 400 naming payload keys the event type doesn't define.
+fn reject_unknown_fields(
+    app: &AppState,
+    payload: &Value,
+    known: &[&str],
+) -> Option<(StatusCode, String)> {
+    if !app.config.strict_event_fields {
+        return None;
+    }
+
+    let unknown = unknown_fields(payload, known);
+    if unknown.is_empty() {
+        return None;
+    }
+
+    Some((
+        StatusCode::BAD_REQUEST,
+        format!("Unknown fields: {}", unknown.join(", ")),
+    ))
+}
+
+async fn relay_opencode_event(
+    app: &AppState,
+    state: &BridgeState,
+    event: &OpencodeEvent,
+    project_name: &str,
+    agent_type: &str,
+    channel_id: &str,
+    receipt: &mut DeliveryReceipt,
+) -> (StatusCode, String) {
+    let instance_key = format!(
+        "{project_name}/{}",
+        event.instance_id().unwrap_or(agent_type)
+    );
+
+    match event.event_type() {
+        Some("session.error") => {
+            app.dampener.record_error(&instance_key);
+            let msg = event
+                .event_text()
+                .unwrap_or_else(|| "unknown error".to_string());
+            let msg = match app.config.format_for(project_name).max_blank_lines {
+                Some(max_blank) => compact_blank_lines(&msg, max_blank),
+                None => msg,
+            };
+            let embed = Embed {
+                title: "OpenCode session error".to_string(),
+                description: msg,
+                color: ERROR_EMBED_COLOR,
+                footer: Some(format!(
+                    "{project_name} · {}",
+                    event.instance_id().unwrap_or(agent_type)
+                )),
+            };
+            match app
+                .discord_for(project_name)
+                .send_embed(channel_id, &embed, event.reply_to_message_id())
+                .await
+            {
+                Ok(message_id) => receipt.sent(message_id),
+                Err(error) => {
+                    receipt.fail(&error);
+                    return delivery_failure(
+                        app,
+                        "session.error",
+                        project_name,
+                        channel_id,
+                        &error,
+                    );
+                }
+            }
+        }
+        Some("session.idle") => {
+            if let Some(text) = event.event_text() {
+                let format = app.config.format_for(project_name);
+                let trimmed = text.trim();
+                if app.dampener.should_suppress_idle(&instance_key, trimmed) {
+                    info!(
+                        "suppressed session.idle right after session.error instance={instance_key}"
+                    );
+                    return (StatusCode::OK, "OK".to_string());
+                }
+                if format.ignored_idle_texts.iter().any(|t| t == trimmed) {
+                    debug!("ignored heartbeat session.idle instance={instance_key}");
+                    return (StatusCode::OK, "OK".to_string());
+                }
+
+                if !trimmed.is_empty() {
+                    let file_search_text = match (format.file_scan_scope, event.turn_text()) {
+                        (FileScanScope::Auto, Some(turn_text))
+                        | (FileScanScope::TurnText, Some(turn_text)) => turn_text.to_string(),
+                        (FileScanScope::TurnText, None) => String::new(),
+                        (FileScanScope::Both, Some(turn_text)) => format!("{turn_text}\n{trimmed}"),
+                        (_, _) => trimmed.to_string(),
+                    };
+                    let project_path = state.project_path(project_name);
+
+                    let (valid_files, strip_targets) =
+                        if format.agent_attachments.get(agent_type) == Some(&false) {
+                            (Vec::new(), Vec::new())
+                        } else {
+                            find_event_files(
+                                format,
+                                &file_search_text,
+                                project_name,
+                                project_path.as_deref(),
+                            )
+                            .await
+                        };
+                    let mut display_text = if valid_files.is_empty() || format.file_footer {
+                        trimmed.to_string()
+                    } else if format.strip_resolved_paths {
+                        let mut targets = strip_targets;
+                        targets.extend(spellings_of_files(format, trimmed, &valid_files));
+                        strip_file_paths(trimmed, &targets)
+                    } else {
+                        strip_file_paths(trimmed, &strip_targets)
+                    };
+                    display_text = strip_lines_matching(&display_text, &format.strip_line_patterns);
+                    if let Some(min_run) = format.collapse_repeated_lines {
+                        display_text = collapse_repeated_lines(&display_text, min_run);
+                    }
+                    if let Some(max_blank) = format.max_blank_lines {
+                        display_text = compact_blank_lines(&display_text, max_blank);
+                    }
+                    if format.wrap_code {
+                        display_text =
+                            wrap_code_block(&display_text, state.code_language(project_name));
+                    }
+                    if format.bold_headings {
+                        display_text = bold_headings(&display_text);
+                    }
+
+                    if let Some(template) = format.message_templates.get(channel_id).or_else(|| {
+                        app.config
+                            .project_entry(&format.message_templates, project_name)
+                    }) {
+                        let fields = [
+                            ("project", project_name),
+                            ("agent", agent_type),
+                            ("instance", event.instance_id().unwrap_or(agent_type)),
+                        ];
+                        display_text = apply_message_template(template, &fields, &display_text);
+                    }
+
+                    let mut full_output = None;
+                    let mut chunks = match format.truncate_with_attachment {
+                        Some(limit) if display_text.chars().count() > limit => {
+                            full_output = Some(display_text.as_str());
+                            vec![truncate_preview(
+                                &display_text,
+                                limit.min(format.max_message_length),
+                            )]
+                        }
+                        _ => split_with_continuation_markers(
+                            &display_text,
+                            format.max_message_length,
+                            &format.continuation_prefix,
+                            &format.continuation_suffix,
+                        ),
+                    };
+                    if display_text.trim().is_empty()
+                        && !valid_files.is_empty()
+                        && let Some(lead_in) = format.file_only_lead_in.as_deref()
+                    {
+                        chunks = vec![lead_in.to_string()];
+                    }
+                    if format.file_footer && !valid_files.is_empty() {
+                        append_footer(
+                            &mut chunks,
+                            &file_footer(&valid_files),
+                            format.max_message_length,
+                        );
+                    }
+                    chunks = fit_chunks(chunks, format.max_message_length);
+
+                    let placement = format.attachment_placement;
+                    let caption = match chunks.as_slice() {
+                        [only]
+                            if placement == AttachmentPlacement::Combined
+                                && full_output.is_none()
+                                && !valid_files.is_empty() =>
+                        {
+                            Some(only.clone())
+                        }
+                        _ => None,
+                    };
+                    if caption.is_some() {
+                        chunks.clear();
+                    }
+                    let files_message = caption
+                        .as_deref()
+                        .map_or(FilesMessage::New, FilesMessage::Captioned);
+                    let stages = if placement == AttachmentPlacement::Before {
+                        [IdleStage::Files, IdleStage::Text]
+                    } else {
+                        [IdleStage::Text, IdleStage::Files]
+                    };
+
+                    let posted_full_output = full_output.is_some();
+                    let best_effort = format.best_effort_delivery;
+                    let concurrent = format.concurrent_file_delivery
+                        && placement == AttachmentPlacement::After
+                        && caption.is_none()
+                        && !valid_files.is_empty();
+                    let delivery = IdleDelivery {
+                        chunks: &chunks,
+                        reply_to: event.reply_to_message_id(),
+                        full_output,
+                        files: &valid_files,
+                        files_message,
+                        stages,
+                        concurrent,
+                    };
+                    let results = deliver_idle_stages(
+                        app,
+                        project_name,
+                        channel_id,
+                        project_path.as_deref(),
+                        &delivery,
+                        receipt,
+                    )
+                    .await;
+
+                    let mut outcome = PartialDelivery::new();
+                    for (stage, result) in results {
+                        let Err(error) = result else {
+                            continue;
+                        };
+                        let what = match stage {
+                            IdleStage::Text => "chunk",
+                            IdleStage::Files => "files",
+                        };
+                        let failure = delivery_failure(app, what, project_name, channel_id, &error);
+                        receipt.fail(&error);
+                        if !best_effort {
+                            return failure;
+                        }
+                        match stage {
+                            IdleStage::Text => outcome.text_failed(&error),
+                            IdleStage::Files => outcome.files_failed(&error),
+                        }
+                    }
+
+                    if valid_files.is_empty()
+                        && !posted_full_output
+                        && outcome.errors.is_empty()
+                        && let Some(message_id) = receipt.last_message_id.as_deref()
+                    {
+                        app.recent_idles
+                            .record(&instance_key, channel_id, message_id);
+                    }
+
+                    if best_effort {
+                        return outcome.response();
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+
+    (StatusCode::OK, "OK".to_string())
+}
+
+/// The two halves of an idle delivery, sent in `attachmentPlacement` order.
+#[derive(Clone, Copy)]
+enum IdleStage {
+    Text,
+    Files,
+}
+
+/// What an idle event posts once its text is formatted and its files resolved.
+struct IdleDelivery<'a> {
+    chunks: &'a [String],
+    reply_to: Option<&'a str>,
+    full_output: Option<&'a str>,
+    files: &'a [String],
+    files_message: FilesMessage<'a>,
+    stages: [IdleStage; 2],
+    /// Upload the files alongside the text rather than after it.
+    concurrent: bool,
+}
+
+/// Send the stages of an idle delivery and return each one's result. Sequential
+/// delivery stops at the first failure unless `bestEffortDelivery` is on.
+async fn deliver_idle_stages(
+    app: &AppState,
+    project_name: &str,
+    channel_id: &str,
+    project_path: Option<&Path>,
+    delivery: &IdleDelivery<'_>,
+    receipt: &mut DeliveryReceipt,
+) -> Vec<(IdleStage, anyhow::Result<()>)> {
+    let mut results = Vec::with_capacity(2);
+    if delivery.concurrent {
+        let mut files_receipt = DeliveryReceipt::new(channel_id);
+        let (text, files) = tokio::join!(
+            send_idle_chunks(
+                app,
+                project_name,
+                channel_id,
+                delivery.chunks,
+                delivery.reply_to,
+                delivery.full_output,
+                receipt,
+            ),
+            deliver_files(
+                app,
+                project_name,
+                channel_id,
+                project_path,
+                delivery.files,
+                delivery.files_message,
+                &mut files_receipt,
+            )
+        );
+        receipt.absorb(files_receipt);
+        results.push((IdleStage::Text, text));
+        results.push((IdleStage::Files, files));
+        return results;
+    }
+
+    for stage in delivery.stages {
+        let result = match stage {
+            IdleStage::Text => {
+                send_idle_chunks(
+                    app,
+                    project_name,
+                    channel_id,
+                    delivery.chunks,
+                    delivery.reply_to,
+                    delivery.full_output,
+                    receipt,
+                )
+                .await
+            }
+            IdleStage::Files if delivery.files.is_empty() => continue,
+            IdleStage::Files => {
+                deliver_files(
+                    app,
+                    project_name,
+                    channel_id,
+                    project_path,
+                    delivery.files,
+                    delivery.files_message,
+                    receipt,
+                )
+                .await
+            }
+        };
+        let failed = result.is_err();
+        results.push((stage, result));
+        if failed && !app.config.format_for(project_name).best_effort_delivery {
+            break;
+        }
+    }
+    results
+}
+
+/// Post idle text chunks, the first one with the full output attached when given,
+/// or else replying to `reply_to`.
+async fn send_idle_chunks(
+    app: &AppState,
+    project_name: &str,
+    channel_id: &str,
+    chunks: &[String],
+    mut reply_to: Option<&str>,
+    mut full_output: Option<&str>,
+    receipt: &mut DeliveryReceipt,
+) -> anyhow::Result<()> {
+    let discord = app.discord_for(project_name);
+    let chunks: Vec<&String> = chunks.iter().filter(|c| !c.trim().is_empty()).collect();
+    let delay = discord.chunk_delay(chunks.len());
+    for (idx, chunk) in chunks.iter().enumerate() {
+        if idx > 0 {
+            tokio::time::sleep(delay).await;
+        }
+
+        let reply_to = reply_to.take();
+        let message_ids = match full_output.take() {
+            Some(full) => discord
+                .send_text_attachment(channel_id, chunk, FULL_OUTPUT_FILENAME, full)
+                .await?
+                .into_iter()
+                .collect(),
+            None => discord.send_reply(channel_id, chunk, reply_to).await?,
+        };
+        receipt.sent(message_ids);
+    }
+    Ok(())
+}
+
+/// Files referenced in `text` that exist inside the project, plus the spellings
+/// to strip from the display text (these include the raw form of decoded paths).
+async fn find_event_files(
+    format: &FormatOptions,
+    text: &str,
+    project_name: &str,
+    project_path: Option<&Path>,
+) -> (Vec<String>, Vec<String>) {
+    let Some(window) = file_search_window(
+        text,
+        format.file_search_scan_bytes,
+        format.file_search_max_bytes,
+    ) else {
+        warn!(
+            "skipping file path extraction for {} byte text project={}",
+            text.len(),
+            project_name
+        );
+        return (Vec::new(), Vec::new());
+    };
+    let searched = if format.normalize_typography {
+        Cow::Owned(normalize_typography(window))
+    } else {
+        Cow::Borrowed(window)
+    };
+    let mut extracted = extract_file_paths(&searched, &format.attachment_extensions);
+    if format.relative_paths {
+        extracted.extend(extract_relative_file_paths(
+            &searched,
+            &format.attachment_extensions,
+        ));
+    }
+
+    // Each mention as found, plus how the text really spells it when
+    // normalization changed it: that is what gets stripped, and a real file may
+    // be named with the typographic characters.
+    let mentions: Vec<Vec<String>> = extracted
+        .into_iter()
+        .map(|path| {
+            let mut spellings = original_spellings(window, &path);
+            spellings.insert(0, path);
+            spellings
+        })
+        .collect();
+    let decode = |raw: &String| {
+        if format.decode_percent_paths {
+            percent_decode_path(raw)
+        } else {
+            raw.clone()
+        }
+    };
+    let decoded: Vec<String> = mentions.iter().flatten().map(decode).collect();
+    let mut valid = validate_file_paths(&decoded, project_path, format.file_settle).await;
+    let mut seen = HashSet::new();
+    valid.retain(|file| seen.insert(file.clone()));
+
+    // Strip every spelling of an attached file, longest first so a relative
+    // spelling never eats into the absolute one.
+    let mut strip_targets = Vec::new();
+    for spellings in &mentions {
+        let attached = spellings
+            .iter()
+            .any(|raw| valid.contains(&resolve_in_project(&decode(raw), project_path)));
+        if attached {
+            for raw in spellings {
+                strip_targets.push(decode(raw));
+                strip_targets.push(raw.clone());
+            }
+        }
+    }
+    strip_targets.sort_by_key(|target| std::cmp::Reverse(target.len()));
+    strip_targets.dedup();
+    (valid, strip_targets)
+}
+
+/// `path` as the bridge reads it: relative paths resolve against the project root.
+fn resolve_in_project(path: &str, project_path: Option<&Path>) -> String {
+    resolve_in_project_with_home(path, project_path, home_dir().as_deref())
+}
+
+fn resolve_in_project_with_home(
+    path: &str,
+    project_path: Option<&Path>,
+    home: Option<&Path>,
+) -> String {
+    let expanded = expand_home_in(path, home);
+    match project_path {
+        Some(root) if expanded.is_relative() => root.join(path).display().to_string(),
+        _ => expanded.display().to_string(),
+    }
+}
+
+/// Paths mentioned in `text` that resolve to one of `files` under a different
+/// spelling, e.g. with `./` segments, symlinks or percent-encoding.
+fn spellings_of_files(format: &FormatOptions, text: &str, files: &[String]) -> Vec<String> {
+    let resolved: HashSet<PathBuf> = files
+        .iter()
+        .filter_map(|file| fs::canonicalize(file).ok())
+        .collect();
+    let mentioned: Vec<Vec<String>> = if format.normalize_typography {
+        extract_file_paths(&normalize_typography(text), &format.attachment_extensions)
+            .into_iter()
+            .map(|path| {
+                let mut spellings = original_spellings(text, &path);
+                spellings.insert(0, path);
+                spellings
+            })
+            .collect()
+    } else {
+        extract_file_paths(text, &format.attachment_extensions)
+            .into_iter()
+            .map(|path| vec![path])
+            .collect()
+    };
+    let resolves_to_file = |raw: &String| {
+        let candidate = if format.decode_percent_paths {
+            percent_decode_path(raw)
+        } else {
+            raw.clone()
+        };
+        fs::canonicalize(candidate).is_ok_and(|path| resolved.contains(&path))
+    };
+
+    mentioned
+        .into_iter()
+        .filter(|spellings| spellings.iter().any(resolves_to_file))
+        .flatten()
+        .filter(|raw| !files.contains(raw))
+        .collect()
+}
+
+/// Per-stage idle delivery outcome returned in best-effort mode, so the hook can
+/// retry only what failed.
+#[derive(Debug, Serialize)]
+struct PartialDelivery {
+    #[serde(rename = "textSent")]
+    text_sent: bool,
+    #[serde(rename = "filesSent")]
+    files_sent: bool,
+    errors: Vec<String>,
+}
+
+impl PartialDelivery {
+    fn new() -> Self {
+        Self {
+            text_sent: true,
+            files_sent: true,
+            errors: Vec::new(),
+        }
+    }
+
+    fn text_failed(&mut self, error: &anyhow::Error) {
+        self.text_sent = false;
+        self.errors.push(format!("text: {error:#}"));
+    }
+
+    fn files_failed(&mut self, error: &anyhow::Error) {
+        self.files_sent = false;
+        self.errors.push(format!("files: {error:#}"));
+    }
+
+    /// 200 when everything went out, 207 when one stage failed, 500 when both did.
+    fn response(&self) -> (StatusCode, String) {
+        let status = match (self.text_sent, self.files_sent) {
+            (true, true) => StatusCode::OK,
+            (false, false) => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::MULTI_STATUS,
+        };
+        (status, serde_json::to_string(self).unwrap_or_default())
+    }
+}
+
+/// Resolve the channel for an event, creating one under the configured category
+/// when auto-creation is enabled for `event_type` and the project has nothing
+/// mapped yet.
+async fn resolve_channel(
+    app: &AppState,
+    state: &BridgeState,
+    event_type: &str,
+    project_name: &str,
+    agent_type: &str,
+    agent_type_given: bool,
+    instance_id: Option<&str>,
+) -> Option<String> {
+    // A defaulted agent type says nothing about the instance, so only an explicit
+    // one can conflict with it.
+    let lookup = if agent_type_given {
+        Cow::Borrowed(&app.config.channel_lookup)
+    } else {
+        Cow::Owned(ChannelLookup {
+            strict_instance_agent: false,
+            ..app.config.channel_lookup.clone()
+        })
+    };
+    if let Some(channel_id) = state.find_channel_id(project_name, agent_type, instance_id, &lookup)
+    {
+        return Some(channel_id);
+    }
+
+    let auto_create = app.config.channel_auto_create.as_ref()?;
+    if !state.projects.contains_key(project_name) {
+        return None;
+    }
+    if !app.config.side_effect_event_types.contains(event_type) {
+        info!(
+            "not creating a channel for event type {:?} project={} agent={}",
+            event_type, project_name, agent_type
+        );
+        return None;
+    }
+
+    // Serialize creation and re-check the file so concurrent events don't each
+    // create a channel.
+    let _creating = app.channel_creation.lock().await;
+    let path = app.state.path_for(project_name);
+    let mut fresh = BridgeState::load(path);
+    if let Some(channel_id) = fresh.find_channel_id(project_name, agent_type, instance_id, &lookup)
+    {
+        return Some(channel_id);
+    }
+    let project = fresh.projects.get_mut(project_name)?;
+
+    let name = channel_name_for(project_name, agent_type);
+    let channel_id = match app
+        .discord_for(project_name)
+        .create_channel(
+            &auto_create.guild_id,
+            &name,
+            auto_create.category_id.as_deref(),
+        )
+        .await
+    {
+        Ok(channel_id) => channel_id,
+        Err(error) => {
+            error!(
+                "failed to create channel project={} agent={} err={:#}",
+                project_name, agent_type, error
+            );
+            return None;
+        }
+    };
+    if app.config.dry_run {
+        info!(
+            "dry run: not saving channel {} for project={} agent={}",
+            channel_id, project_name, agent_type
+        );
+        return Some(channel_id);
+    }
+
+    let key = instance_id.unwrap_or(agent_type).to_string();
+    let instance = project.instances.entry(key.clone()).or_default();
+    instance.instance_id.get_or_insert(key);
+    instance
+        .agent_type
+        .get_or_insert_with(|| agent_type.to_string());
+    instance.channel_id = Some(channel_id.clone());
+
+    if let Err(error) = fresh.save(path) {
+        error!(
+            "created channel {} but failed to persist it project={} err={:#}",
+            channel_id, project_name, error
+        );
+    } else {
+        info!(
+            "created channel {} (#{}) for project={} agent={}",
+            channel_id, name, project_name, agent_type
+        );
+    }
+
+    Some(channel_id)
+}
+
+/// The message `deliver_files` puts its uploads in.
+#[derive(Clone, Copy)]
+enum FilesMessage<'a> {
+    /// A new message, captioned per `attachmentCaptions`.
+    New,
+    /// A new message with this content.
+    Captioned(&'a str),
+    /// An existing message the files are added to.
+    Merge(&'a str),
+}
+
+/// Upload `files`, posting links instead for files over the configured link threshold.
+async fn deliver_files(
+    app: &AppState,
+    project_name: &str,
+    channel_id: &str,
+    project_path: Option<&Path>,
+    files: &[String],
+    message: FilesMessage<'_>,
+    receipt: &mut DeliveryReceipt,
+) -> anyhow::Result<()> {
+    let (uploads, links) =
+        split_linked_files(app.config.format_for(project_name), project_path, files);
+
+    if !links.is_empty() {
+        let mut text = links.join("\n");
+        if let FilesMessage::Captioned(caption) = message
+            && uploads.is_empty()
+        {
+            text = format!("{caption}\n{text}");
+        }
+        let message_ids = app
+            .discord_for(project_name)
+            .send_message(channel_id, &text)
+            .await?;
+        receipt.sent(message_ids);
+        receipt.files_sent += links.len();
+    }
+
+    if !uploads.is_empty() {
+        let discord = app.discord_for(project_name);
+        let message_ids = match message {
+            FilesMessage::Merge(message_id) => {
+                match discord.attach_files(channel_id, message_id, &uploads).await {
+                    Ok(message_ids) => message_ids,
+                    Err(error) if error.downcast_ref::<MergeFailed>().is_some() => {
+                        warn!(
+                            "posting files separately after merging into {message_id} failed channel={channel_id}: {error:#}"
+                        );
+                        let caption = files_caption(app.config.format_for(project_name), &uploads);
+                        discord.send_files(channel_id, &caption, &uploads).await?
+                    }
+                    Err(error) => return Err(error),
+                }
+            }
+            FilesMessage::Captioned(caption) => {
+                discord.send_files(channel_id, caption, &uploads).await?
+            }
+            FilesMessage::New => {
+                let caption = files_caption(app.config.format_for(project_name), &uploads);
+                discord.send_files(channel_id, &caption, &uploads).await?
+            }
+        };
+        receipt.sent(message_ids);
+        receipt.files_sent += uploads.len();
+    }
+
+    Ok(())
+}
+
+/// Partition validated files into uploads and link lines for oversized files.
+fn split_linked_files(
+    format: &FormatOptions,
+    project_path: Option<&Path>,
+    files: &[String],
+) -> (Vec<String>, Vec<String>) {
+    let (Some(base_url), Some(project_path)) = (format.file_link_base_url.as_deref(), project_path)
+    else {
+        return (files.to_vec(), Vec::new());
+    };
+
+    let project_real =
+        fs::canonicalize(project_path).unwrap_or_else(|_| project_path.to_path_buf());
+    let mut uploads = Vec::new();
+    let mut links = Vec::new();
+
+    for file in files {
+        let size = fs::metadata(file).map(|meta| meta.len()).unwrap_or(0);
+        let relative = fs::canonicalize(file)
+            .ok()
+            .and_then(|real| real.strip_prefix(&project_real).ok().map(Path::to_path_buf));
+
+        match relative {
+            Some(relative) if size > format.file_link_min_bytes => {
+                let name = Path::new(file)
+                    .file_name()
+                    .map_or_else(|| file.clone(), |n| n.to_string_lossy().into_owned());
+                let link = file_link(
+                    &format.file_link_template,
+                    base_url,
+                    &relative.to_string_lossy(),
+                );
+                links.push(format!("🔗 {name}: {link}"));
+            }
+            _ => uploads.push(file.clone()),
+        }
+    }
+
+    (uploads, links)
+}
+
+fn files_caption(format: &FormatOptions, files: &[String]) -> String {
+    if !format.attachment_captions {
+        return String::new();
+    }
+
+    attachment_caption(files, &format.attachment_prefixes)
+}
+
+/// Replace wildcard entries with the files they match, sorted, relative patterns
+/// resolved against the project root. Matches outside the project are dropped,
+/// then at most `cap` are kept across all patterns; literal entries pass through
+/// untouched. The walk runs on the blocking pool.
+async fn expand_file_globs(
+    files: &[String],
+    project_path: Option<&Path>,
+    cap: usize,
+) -> Vec<String> {
+    let files = files.to_vec();
+    let project_path = project_path.map(Path::to_path_buf);
+    tokio::task::spawn_blocking(move || {
+        expand_file_globs_blocking(&files, project_path.as_deref(), cap)
+    })
+    .await
+    .unwrap_or_else(|error| {
+        warn!("glob expansion failed: {error}");
+        Vec::new()
+    })
+}
+
+fn expand_file_globs_blocking(
+    files: &[String],
+    project_path: Option<&Path>,
+    cap: usize,
+) -> Vec<String> {
+    let root = project_path.map(canonical_root);
+    let mut expanded = Vec::with_capacity(files.len());
+    let mut matched = 0;
+    let mut dropped = 0;
+
+    for file in files {
+        if !is_glob(file) {
+            expanded.push(file.clone());
+            continue;
+        }
+
+        let pattern = match project_path {
+            Some(root) if !Path::new(file).is_absolute() => root.join(file),
+            _ => PathBuf::from(file),
+        };
+        for path in glob_files(&pattern) {
+            if root.as_deref().is_some_and(|root| !is_within(&path, root)) {
+                continue;
+            }
+            if matched < cap {
+                expanded.push(path.display().to_string());
+                matched += 1;
+            } else {
+                dropped += 1;
+            }
+        }
+    }
+
+    if dropped > 0 {
+        warn!("skipped {dropped} glob match(es) over the cap of {cap}");
+    }
+    expanded
+}
+
+/// `project_path` resolved through symlinks, or as given when it can't be.
+fn canonical_root(project_path: &Path) -> PathBuf {
+    fs::canonicalize(project_path).unwrap_or_else(|_| project_path.to_path_buf())
+}
+
+/// Whether `path` exists and resolves inside the canonical `root`.
+fn is_within(path: &Path, root: &Path) -> bool {
+    fs::canonicalize(path).is_ok_and(|real| real.starts_with(root))
+}
+
+/// Files gathered from `/send-files` directory entries.
+#[derive(Debug, Default, PartialEq)]
+struct DirectoryExpansion {
+    files: Vec<String>,
+    /// Matching files left out because the request went over the cap.
+    skipped: usize,
+}
+
+/// Most directory entries one `/send-files` request's directory expansion reads,
+/// so a request naming a huge tree can't keep a blocking thread walking it.
+const MAX_DIRECTORY_ENTRIES_VISITED: usize = 10_000;
+
+/// Replace directory entries with the files inside them that have one of
+/// `extensions`, descending into subdirectories when `recursive`. Only
+/// directories inside a known project root are read, and at most
+/// `MAX_DIRECTORY_ENTRIES_VISITED` entries in all. When more than `cap` files
+/// match across all directories, the newest `cap` by modification time are
+/// kept, in path order, and the rest are counted as skipped; other entries pass
+/// through. The walk runs on the blocking pool.
+async fn expand_directories(
+    files: &[String],
+    project_path: Option<&Path>,
+    extensions: &[String],
+    recursive: bool,
+    cap: usize,
+) -> DirectoryExpansion {
+    let files = files.to_vec();
+    let project_path = project_path.map(Path::to_path_buf);
+    let extensions = extensions.to_vec();
+    tokio::task::spawn_blocking(move || {
+        expand_directories_blocking(
+            &files,
+            project_path.as_deref(),
+            &extensions,
+            recursive,
+            cap,
+            MAX_DIRECTORY_ENTRIES_VISITED,
+        )
+    })
+    .await
+    .unwrap_or_else(|error| {
+        warn!("directory expansion failed: {error}");
+        DirectoryExpansion::default()
+    })
+}
+
+fn expand_directories_blocking(
+    files: &[String],
+    project_path: Option<&Path>,
+    extensions: &[String],
+    recursive: bool,
+    cap: usize,
+    max_visited: usize,
+) -> DirectoryExpansion {
+    let Some(root) = project_path.map(canonical_root) else {
+        if files.iter().any(|file| Path::new(file).is_dir()) {
+            warn!("not expanding directory entries for a project without a projectPath");
+        }
+        return DirectoryExpansion {
+            files: files.to_vec(),
+            skipped: 0,
+        };
+    };
+    let mut budget = max_visited;
+    // Passed-through entries are filled in as they come; each directory file
+    // gets an empty slot that is filled only if the cap keeps it.
+    let mut entries: Vec<Option<String>> = Vec::with_capacity(files.len());
+    let mut found = Vec::new();
+
+    for file in files {
+        let dir = PathBuf::from(resolve_in_project(file, project_path));
+        if !dir.is_dir() || !is_within(&dir, &root) {
+            entries.push(Some(file.clone()));
+            continue;
+        }
+
+        let walk = DirectoryWalk {
+            extensions,
+            recursive,
+            root: &root,
+        };
+        for path in walk.files(&dir, &mut budget) {
+            found.push((entries.len(), path));
+            entries.push(None);
+        }
+    }
+    if budget == 0 {
+        warn!("stopped directory expansion after reading {max_visited} entries");
+    }
+
+    let skipped = found.len().saturating_sub(cap);
+    if skipped > 0 {
+        warn!("skipped {skipped} directory file(s) over the cap of {cap}; keeping the newest");
+        found.sort_by_cached_key(|(idx, path)| {
+            let modified = fs::metadata(path).and_then(|meta| meta.modified()).ok();
+            (std::cmp::Reverse(modified), *idx)
+        });
+        found.truncate(cap);
+    }
+    for (idx, path) in found {
+        entries[idx] = Some(path.display().to_string());
+    }
+
+    DirectoryExpansion {
+        files: entries.into_iter().flatten().collect(),
+        skipped,
+    }
+}
+
+/// How `expand_directories` walks one directory.
+struct DirectoryWalk<'a> {
+    extensions: &'a [String],
+    recursive: bool,
+    /// Canonical project root files must resolve inside.
+    root: &'a Path,
+}
+
+impl DirectoryWalk<'_> {
+    /// Files under `dir` with an allowed extension, in path order, reading at
+    /// most `budget` entries and using them up. Symlinked directories are not
+    /// followed.
+    fn files(&self, dir: &Path, budget: &mut usize) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        let mut pending = sorted_entries(dir, budget);
+        pending.reverse();
+        while let Some((path, is_dir)) = pending.pop() {
+            if is_dir {
+                if self.recursive {
+                    pending.extend(sorted_entries(&path, budget).into_iter().rev());
+                }
+                continue;
+            }
+            if !path.is_file() || !self.allowed(&path) || !is_within(&path, self.root) {
+                continue;
+            }
+            files.push(path);
+        }
+        files
+    }
+
+    fn allowed(&self, path: &Path) -> bool {
+        path.extension().is_some_and(|ext| {
+            let ext = ext.to_string_lossy();
+            self.extensions.iter().any(|allowed| {
+                allowed
+                    .trim()
+                    .trim_start_matches('.')
+                    .eq_ignore_ascii_case(&ext)
+            })
+        })
+    }
+}
+
+/// Entries of `dir` sorted by path, each with whether it is a directory (not
+/// following symlinks). At most `budget` entries are read, and they are taken
+/// off it. An unreadable directory has none.
+fn sorted_entries(dir: &Path, budget: &mut usize) -> Vec<(PathBuf, bool)> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut entries: Vec<(PathBuf, bool)> = entries
+        .flatten()
+        .take(*budget)
+        .map(|entry| {
+            let is_dir = entry.file_type().is_ok_and(|kind| kind.is_dir());
+            (entry.path(), is_dir)
+        })
+        .collect();
+    *budget -= entries.len();
+    entries.sort();
+    entries
+}
+
+/// Files matching `pattern`, whose components may contain `*` and `?`.
+fn glob_files(pattern: &Path) -> Vec<PathBuf> {
+    let mut candidates = vec![PathBuf::new()];
+
+    for component in pattern.components() {
+        let part = component.as_os_str().to_string_lossy();
+        if !is_glob(&part) {
+            candidates.iter_mut().for_each(|c| c.push(component));
+            continue;
+        }
+
+        let mut next = Vec::new();
+        for dir in &candidates {
+            let Ok(entries) = fs::read_dir(dir) else {
+                continue;
+            };
+            next.extend(
+                entries
+                    .flatten()
+                    .filter(|entry| glob_match(&part, &entry.file_name().to_string_lossy()))
+                    .map(|entry| entry.path()),
+            );
+        }
+        candidates = next;
+    }
+
+    let mut files: Vec<PathBuf> = candidates.into_iter().filter(|p| p.is_file()).collect();
+    files.sort();
+    files
+}
+
+/// Paths that exist inside the project, in input order. Missing files are re-checked
+/// per `settle` before being dropped; files outside the project never are.
+async fn validate_file_paths(
+    paths: &[String],
+    project_path: Option<&Path>,
+    settle: FileSettle,
+) -> Vec<String> {
+    let Some(project_path) = project_path else {
+        return Vec::new();
+    };
+
+    let project_real =
+        fs::canonicalize(project_path).unwrap_or_else(|_| project_path.to_path_buf());
+    let contained = |raw: &String| {
+        fs::canonicalize(raw)
+            .map(|real| real == project_real || real.starts_with(&project_real))
+            .ok()
+    };
+
+    let paths: Vec<String> = paths
+        .iter()
+        .map(|path| resolve_in_project(path, Some(project_path)))
+        .collect();
+    let mut results: Vec<Option<bool>> = paths.iter().map(contained).collect();
+    for _ in 0..settle.retries {
+        if results.iter().all(Option::is_some) {
+            break;
+        }
+        tokio::time::sleep(settle.delay).await;
+        for (raw, result) in paths.iter().zip(results.iter_mut()) {
+            if result.is_none() {
+                *result = contained(raw);
+            }
+        }
+    }
+
+    paths
+        .into_iter()
+        .zip(results)
+        .filter(|(_, result)| *result == Some(true))
+        .map(|(path, _)| path)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ChannelAutoCreate, ProjectDiscord};
+    use crate::state::ChannelLookup;
+    use crate::test_support::{MockResponse, MockServer, TempDir};
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn write_state(dir: &TempDir, project_path: &Path) -> PathBuf {
+        let state = json!({
+            "projects": {
+                "proj": {
+                    "projectPath": project_path,
+                    "instances": {
+                        "opencode": {
+                            "instanceId": "opencode",
+                            "agentType": "opencode",
+                            "channelId": "ch-1"
+                        }
+                    }
+                }
+            }
+        });
+        dir.write("state.json", state.to_string())
+    }
+
+    fn test_app(discord: &MockServer, state_path: PathBuf) -> AppState {
+        test_app_with(discord, state_path, RuntimeConfig::default())
+    }
+
+    fn test_app_with(
+        discord: &MockServer,
+        state_path: PathBuf,
+        mut config: RuntimeConfig,
+    ) -> AppState {
+        if config.discord_token.is_empty() {
+            config.discord_token = "token".to_string();
+        }
+        config.discord.api_base = discord.url.clone();
+        let client = DiscordClient::new(config.discord_token.clone(), config.discord.clone());
+        AppState {
+            project_discord: Arc::new(project_discord_clients(&config, &client, &HashMap::new())),
+            discord: client,
+            callbacks: callback_client(),
+            state: Arc::new(StateStore::new(state_path, HashMap::new())),
+            in_flight: InFlight::default(),
+            deferred: InFlight::default(),
+            live: Arc::default(),
+            dampener: Arc::new(ErrorIdleDampener::new(
+                config.error_idle_window,
+                config.substantive_idle_chars,
+            )),
+            seen_events: Arc::new(SeenIds::new(config.event_dedup_capacity)),
+            channel_creation: Arc::default(),
+            permission_warned: Arc::default(),
+            recent_idles: Arc::new(RecentIdleMessages::new(config.merge_files_window)),
+            body_log: BodyLog::new(
+                false,
+                crate::body_log::DEFAULT_LOG_BODY_MAX_CHARS,
+                log_secrets(&config),
+            ),
+            started_at: std::time::Instant::now(),
+            audit: config
+                .audit_log_path
+                .clone()
+                .map(|path| AuditLog::open(path, config.audit_log_rotation)),
+            config: Arc::new(config),
+        }
+    }
+
+    /// A project "proj" rooted in a temp dir, with an app built from `config`,
+    /// for tests that drive idle events through the handler.
+    struct IdleHarness {
+        discord: MockServer,
+        dir: TempDir,
+        app: AppState,
+    }
+
+    impl IdleHarness {
+        fn new(discord: MockServer, dir: TempDir, config: RuntimeConfig) -> Self {
+            let app = test_app_with(&discord, write_state(&dir, dir.path()), config);
+            Self { discord, dir, app }
+        }
+
+        async fn start(label: &str, config: RuntimeConfig) -> Self {
+            Self::new(MockServer::start().await, TempDir::new(label), config)
+        }
+
+        /// Send a session.idle for "proj" carrying `text`.
+        async fn idle(&self, text: &str) -> StatusCode {
+            self.idle_with(json!({ "text": text })).await
+        }
+
+        /// Send a session.idle for "proj" with `fields` added to the event.
+        async fn idle_with(&self, fields: Value) -> StatusCode {
+            let mut event = json!({ "projectName": "proj", "type": "session.idle" });
+            event
+                .as_object_mut()
+                .unwrap()
+                .extend(fields.as_object().unwrap().clone());
+            handle_opencode_event(State(self.app.clone()), Json(event))
+                .await
+                .0
+        }
+    }
+
+    #[tokio::test]
+    async fn callback_receives_receipt_after_successful_delivery() {
+        let discord = MockServer::start().await;
+        let hook = MockServer::start().await;
+        let dir = TempDir::new("receipt-ok");
+        let app = test_app(&discord, write_state(&dir, dir.path()));
+
+        let (status, _) = handle_opencode_event(
+            State(app),
+            Json(json!({
+                "projectName": "proj",
+                "type": "session.idle",
+                "text": "hello",
+                "callbackUrl": format!("{}/receipt", hook.url),
+            })),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        let receipts = hook.wait_for_requests(1).await;
+        assert_eq!(receipts[0].path, "/receipt");
+        assert_eq!(
+            receipts[0].json(),
+            json!({ "ok": true, "channelId": "ch-1", "chunksSent": 1, "error": null })
+        );
+    }
+
+    #[tokio::test]
+    async fn callback_receives_receipt_after_failed_delivery() {
+        let discord =
+            MockServer::with_responder(|_, _| MockResponse::json(403, json!({ "code": 0 }))).await;
+        let hook = MockServer::start().await;
+        let dir = TempDir::new("receipt-fail");
+        let file = dir.write("out.png", "png");
+        let app = test_app(&discord, write_state(&dir, dir.path()));
+
+        let (status, _) = handle_send_files(
+            State(app),
+            Json(json!({
+                "projectName": "proj",
+                "files": [file],
+                "callbackUrl": hook.url,
+            })),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        let receipt = hook.wait_for_requests(1).await[0].json();
+        assert_eq!(receipt["ok"], json!(false));
+        assert_eq!(receipt["channelId"], json!("ch-1"));
+        assert_eq!(receipt["chunksSent"], json!(0));
+        assert!(receipt["error"].as_str().unwrap().contains("403"));
+    }
+
+    #[tokio::test]
+    async fn short_idle_right_after_an_error_is_suppressed() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("dampened-idle");
+        let config = RuntimeConfig {
+            error_idle_window: Duration::from_secs(5),
+            substantive_idle_chars: 200,
+            ..RuntimeConfig::default()
+        };
+        let app = test_app_with(&discord, write_state(&dir, dir.path()), config);
+
+        for event in [
+            json!({ "projectName": "proj", "type": "session.error", "text": "boom" }),
+            json!({ "projectName": "proj", "type": "session.idle", "text": "retrying" }),
+            json!({ "projectName": "proj", "type": "session.idle", "text": "done" }),
+        ] {
+            let (status, _) = handle_opencode_event(State(app.clone()), Json(event)).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+
+        let requests = discord.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].json()["embeds"].is_array());
+        assert_eq!(requests[1].json()["content"], json!("done"));
+    }
+
+    #[tokio::test]
+    async fn callback_receives_receipt_when_no_channel_resolves() {
+        let discord = MockServer::start().await;
+        let hook = MockServer::start().await;
+        let dir = TempDir::new("receipt-no-channel");
+        let app = test_app(&discord, write_state(&dir, dir.path()));
+
+        let (status, _) = handle_opencode_event(
+            State(app),
+            Json(json!({
+                "projectName": "proj",
+                "agentType": "codex",
+                "type": "session.idle",
+                "text": "hello",
+                "callbackUrl": hook.url,
+            })),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let receipt = hook.wait_for_requests(1).await[0].json();
+        assert_eq!(receipt["ok"], json!(false));
+        assert_eq!(receipt["chunksSent"], json!(0));
+        assert!(discord.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn callback_urls_off_loopback_need_an_allowed_host() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("receipt-host");
+        let app = test_app(&discord, write_state(&dir, dir.path()));
+
+        let (status, body) = handle_opencode_event(
+            State(app),
+            Json(json!({
+                "projectName": "proj",
+                "type": "session.idle",
+                "text": "hello",
+                "callbackUrl": "http://169.254.169.254/latest/meta-data",
+            })),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body, "callbackUrl host is not allowed");
+        assert!(discord.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn send_files_posts_content_alone_or_as_the_file_caption() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("send-content");
+        let file = dir.write("out.png", "png");
+        let app = test_app(&discord, write_state(&dir, dir.path()));
+        let send = |payload: Value| handle_send_files(State(app.clone()), Json(payload));
+
+        let (status, body) = send(json!({ "projectName": "proj", "files": [] })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body, "No files or content provided");
+
+        let (status, _) =
+            send(json!({ "projectName": "proj", "files": [], "content": "just text" })).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = send(json!({
+            "projectName": "proj",
+            "files": [file],
+            "content": "see attached",
+        }))
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let requests = discord.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].json()["content"], "just text");
+        assert_eq!(requests[1].payload_json()["content"], "see attached");
+        assert!(requests[1].body_text().contains("filename=\"out.png\""));
+    }
+
+    #[tokio::test]
+    async fn send_files_adds_prefixed_caption_when_enabled() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("caption");
+        let chart = dir.write("chart.png", "png");
+        let report = dir.write("report.pdf", "pdf");
+        let mut config = RuntimeConfig::default();
+        config.format.attachment_captions = true;
+        let app = test_app_with(&discord, write_state(&dir, dir.path()), config);
+
+        let (status, _) = handle_send_files(
+            State(app),
+            Json(json!({ "projectName": "proj", "files": [chart, report] })),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        let upload = &discord.requests()[0];
+        assert_eq!(
+            upload.payload_json()["content"],
+            json!("🖼️ chart.png · 📄 report.pdf")
+        );
+    }
+
+    async fn send_files_only_idle(discord: &MockServer, lead_in: Option<&str>) {
+        let dir = TempDir::new("lead-in");
+        let chart = dir.write("chart.png", "png");
+        let mut config = RuntimeConfig::default();
+        config.format.file_only_lead_in = lead_in.map(str::to_string);
+        let app = test_app_with(discord, write_state(&dir, dir.path()), config);
+
+        let (status, _) = handle_opencode_event(
+            State(app),
+            Json(json!({
+                "projectName": "proj",
+                "type": "session.idle",
+                "text": format!("`{}`", chart.display()),
+            })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn files_only_idle_sends_just_the_upload_by_default() {
+        let discord = MockServer::start().await;
+        send_files_only_idle(&discord, None).await;
+
+        let requests = discord.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].payload_json(), json!({}));
+    }
+
+    #[tokio::test]
+    async fn files_only_idle_sends_configured_lead_in_first() {
+        let discord = MockServer::start().await;
+        send_files_only_idle(&discord, Some("📎 attachments:")).await;
+
+        let requests = discord.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].json()["content"], json!("📎 attachments:"));
+        assert!(requests[1].body_text().contains("chart.png"));
+    }
+
+    #[tokio::test]
+    async fn duplicate_event_id_is_delivered_once() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("dedup");
+        let config = RuntimeConfig {
+            event_dedup_capacity: 16,
+            ..RuntimeConfig::default()
+        };
+        let app = test_app_with(&discord, write_state(&dir, dir.path()), config);
+        let event = |id: &str| {
+            json!({
+                "projectName": "proj",
+                "type": "session.idle",
+                "text": "hello",
+                "eventId": id,
+            })
+        };
+
+        let (first, _) = handle_opencode_event(State(app.clone()), Json(event("turn-1"))).await;
+        let (duplicate, _) = handle_opencode_event(State(app.clone()), Json(event("turn-1"))).await;
+        let (fresh, _) = handle_opencode_event(State(app), Json(event("turn-2"))).await;
+
+        assert_eq!(first, StatusCode::OK);
+        assert_eq!(duplicate, StatusCode::OK);
+        assert_eq!(fresh, StatusCode::OK);
+        assert_eq!(discord.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn duplicate_of_an_in_progress_event_is_refused_until_it_finishes() {
+        let discord = MockServer::with_responder(|_, idx| {
+            let response = MockResponse::message(format!("msg-{idx}"));
+            if idx == 0 {
+                response.delayed(Duration::from_millis(200))
+            } else {
+                response
+            }
+        })
+        .await;
+        let dir = TempDir::new("dedup-in-progress");
+        let config = RuntimeConfig {
+            event_dedup_capacity: 16,
+            ..RuntimeConfig::default()
+        };
+        let app = test_app_with(&discord, write_state(&dir, dir.path()), config);
+        let event = json!({
+            "projectName": "proj",
+            "type": "session.idle",
+            "text": "hello",
+            "eventId": "turn-1",
+        });
+
+        let first = tokio::spawn(handle_opencode_event(
+            State(app.clone()),
+            Json(event.clone()),
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let (status, _) = handle_opencode_event(State(app.clone()), Json(event.clone())).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        assert_eq!(first.await.unwrap().0, StatusCode::OK);
+        let (status, _) = handle_opencode_event(State(app), Json(event)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(discord.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn idle_chunks_are_paced() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("idle-pacing");
+        let mut config = RuntimeConfig::default();
+        config.format.max_message_length = 40;
+        let app = test_app_with(&discord, write_state(&dir, dir.path()), config);
+
+        let text = format!("{}\n{}", "a".repeat(30), "b".repeat(30));
+        let (status, _) = handle_opencode_event(
+            State(app),
+            Json(json!({ "projectName": "proj", "type": "session.idle", "text": text })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let requests = discord.requests();
+        assert_eq!(requests.len(), 2);
+        let gap = requests[1]
+            .received_at
+            .duration_since(requests[0].received_at);
+        assert!(
+            gap >= DiscordSettings::default().chunk_pacing.delay_for(2),
+            "{gap:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn concurrent_events_for_a_channel_do_not_interleave() {
+        let discord = MockServer::with_responder(|_, idx| {
+            MockResponse::message(format!("msg-{idx}")).delayed(Duration::from_millis(20))
+        })
+        .await;
+        let dir = TempDir::new("event-order");
+        let mut config = RuntimeConfig::default();
+        config.format.max_message_length = 40;
+        let app = test_app_with(&discord, write_state(&dir, dir.path()), config);
+        let idle = |c: &str| {
+            let text = format!("{}\n{}", c.repeat(30), c.repeat(30));
+            Json(json!({ "projectName": "proj", "type": "session.idle", "text": text }))
+        };
+
+        let (a, b) = tokio::join!(
+            handle_opencode_event(State(app.clone()), idle("a")),
+            handle_opencode_event(State(app), idle("b")),
+        );
+        assert_eq!((a.0, b.0), (StatusCode::OK, StatusCode::OK));
+
+        let letters: Vec<char> = discord
+            .requests()
+            .iter()
+            .map(|request| {
+                let content = request.json()["content"].as_str().unwrap().to_string();
+                content.chars().next().unwrap()
+            })
+            .collect();
+        assert_eq!(letters.len(), 4);
+        assert_eq!(letters[0], letters[1]);
+        assert_eq!(letters[2], letters[3]);
+    }
+
+    #[tokio::test]
+    async fn files_over_link_threshold_are_posted_as_links() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("file-links");
+        let small = dir.write("small.png", "png");
+        let large = dir.write("out/big report.pdf", "x".repeat(64));
+        let mut config = RuntimeConfig::default();
+        config.format.file_link_base_url = Some("https://files.internal/proj".to_string());
+        config.format.file_link_min_bytes = 16;
+        let app = test_app_with(&discord, write_state(&dir, dir.path()), config);
+
+        let (status, _) = handle_send_files(
+            State(app),
+            Json(json!({ "projectName": "proj", "files": [small, large] })),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        let requests = discord.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            requests[0].json()["content"],
+            json!("🔗 big report.pdf: https://files.internal/proj/out/big%20report.pdf")
+        );
+        assert!(requests[1].body_text().contains("small.png"));
+        assert!(!requests[1].body_text().contains("big report.pdf"));
+    }
+
+    #[tokio::test]
+    async fn dry_run_auto_create_is_not_persisted() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("dry-run-create");
+        let state_path = write_state(&dir, dir.path());
+        let before = fs::read_to_string(&state_path).unwrap();
+        let config = RuntimeConfig {
+            dry_run: true,
+            discord: DiscordSettings {
+                dry_run: true,
+                ..DiscordSettings::default()
+            },
+            channel_auto_create: Some(ChannelAutoCreate {
+                guild_id: "guild-1".to_string(),
+                category_id: None,
+            }),
+            side_effect_event_types: HashSet::from(["session.idle".to_string()]),
+            ..RuntimeConfig::default()
+        };
+        let app = test_app_with(&discord, state_path.clone(), config);
+
+        let (status, _) = handle_opencode_event(
+            State(app),
+            Json(json!({
+                "projectName": "proj",
+                "agentType": "claude",
+                "type": "session.idle",
+                "text": "hello",
+            })),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert!(discord.requests().is_empty());
+        assert_eq!(fs::read_to_string(&state_path).unwrap(), before);
+    }
+
+    #[tokio::test]
+    async fn missing_channel_is_created_persisted_and_reused() {
+        let discord = MockServer::with_responder(|request, idx| {
+            if request.path.starts_with("/guilds/") {
+                MockResponse::json(201, json!({ "id": "new-ch" }))
+            } else {
+                MockResponse::message(format!("msg-{idx}"))
+            }
+        })
+        .await;
+        let dir = TempDir::new("auto-create");
+        let state_path = write_state(&dir, dir.path());
+        let config = RuntimeConfig {
+            channel_auto_create: Some(ChannelAutoCreate {
+                guild_id: "guild-1".to_string(),
+                category_id: Some("cat-1".to_string()),
+            }),
+            side_effect_event_types: HashSet::from(["session.idle".to_string()]),
+            ..RuntimeConfig::default()
+        };
+        let app = test_app_with(&discord, state_path.clone(), config);
+        let event = json!({
+            "projectName": "proj",
+            "agentType": "claude",
+            "type": "session.idle",
+            "text": "hello",
+        });
+
+        let (first, _) = handle_opencode_event(State(app.clone()), Json(event.clone())).await;
+        let (second, _) = handle_opencode_event(State(app), Json(event)).await;
+
+        assert_eq!(first, StatusCode::OK);
+        assert_eq!(second, StatusCode::OK);
+        let paths: Vec<_> = discord.requests().into_iter().map(|r| r.path).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "/guilds/guild-1/channels",
+                "/channels/new-ch/messages",
+                "/channels/new-ch/messages",
+            ]
+        );
+
+        let saved = BridgeState::load(&state_path);
+        assert_eq!(
+            saved
+                .find_channel_id("proj", "claude", None, &ChannelLookup::default())
+                .as_deref(),
+            Some("new-ch")
+        );
+        assert_eq!(
+            saved
+                .find_channel_id("proj", "opencode", None, &ChannelLookup::default())
+                .as_deref(),
+            Some("ch-1")
+        );
+    }
+
+    #[tokio::test]
+    async fn wrapped_code_uses_project_code_language() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("code-language");
+        let state = json!({
+            "projects": {
+                "proj": {
+                    "codeLanguage": "rust",
+                    "instances": { "opencode": { "agentType": "opencode", "channelId": "ch-1" } }
+                }
+            }
+        });
+        let state_path = dir.write("state.json", state.to_string());
+        let mut config = RuntimeConfig::default();
+        config.format.wrap_code = true;
+        let app = test_app_with(&discord, state_path, config);
+
+        let (status, _) = handle_opencode_event(
+            State(app),
+            Json(json!({
+                "projectName": "proj",
+                "type": "session.idle",
+                "text": "fn main() {\n    run();\n}",
+            })),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            discord.requests()[0].json()["content"],
+            json!("```rust\nfn main() {\n    run();\n}\n```")
+        );
+    }
+
+    #[tokio::test]
+    async fn normalized_paths_are_stripped_as_the_text_spells_them() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("typography-strip");
+        let ascii = dir.write("out-v2.png", "png");
+        let dashed = dir.write("out\u{2013}v3.png", "png");
+        let mut config = RuntimeConfig::default();
+        config.format.normalize_typography = true;
+        let app = test_app_with(&discord, write_state(&dir, dir.path()), config);
+        let spelled = ascii
+            .display()
+            .to_string()
+            .replace("out-v2", "out\u{2013}v2");
+
+        let (status, _) = handle_opencode_event(
+            State(app),
+            Json(json!({
+                "projectName": "proj",
+                "type": "session.idle",
+                "text": format!("Saved \u{201C}{spelled}\u{201D}\nand {}", dashed.display()),
+            })),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        let requests = discord.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            requests[0].json()["content"],
+            json!("Saved \u{201C}\u{201D}\nand ")
+        );
+        let upload = requests[1].body_text();
+        assert!(upload.contains("filename=\"out-v2.png\""));
+        assert!(upload.contains("out\u{2013}v3.png"));
+    }
+
+    #[tokio::test]
+    async fn handled_event_is_written_to_audit_log() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("audit-event");
+        let chart = dir.write("chart.png", "png");
+        let audit_path = dir.path().join("audit.jsonl");
+        let config = RuntimeConfig {
+            audit_log_path: Some(audit_path.clone()),
+            ..RuntimeConfig::default()
+        };
+        let app = test_app_with(&discord, write_state(&dir, dir.path()), config);
+
+        let (status, _) = handle_opencode_event(
+            State(app),
+            Json(json!({
+                "projectName": "proj",
+                "type": "session.idle",
+                "text": format!("see {}", chart.display()),
+            })),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        let record = crate::audit::read_records(&audit_path, 1).await.remove(0);
+        assert_eq!(record["project"], json!("proj"));
+        assert_eq!(record["agent"], json!("opencode"));
+        assert_eq!(record["channelId"], json!("ch-1"));
+        assert_eq!(record["eventType"], json!("session.idle"));
+        assert_eq!(record["fileCount"], json!(1));
+        assert_eq!(record["ok"], json!(true));
+    }
+
+    async fn best_effort_idle(fail_text: bool, fail_files: bool) -> (StatusCode, Value) {
+        let discord = MockServer::with_responder(move |request, idx| {
+            let is_upload = request.body_text().contains("name=\"payload_json\"");
+            if (is_upload && fail_files) || (!is_upload && fail_text) {
+                MockResponse::json(403, json!({ "code": 50013 }))
+            } else {
+                MockResponse::message(format!("msg-{idx}"))
+            }
+        })
+        .await;
+        let dir = TempDir::new("best-effort");
+        let chart = dir.write("chart.png", "png");
+        let config = RuntimeConfig {
+            format: FormatOptions {
+                best_effort_delivery: true,
+                ..FormatOptions::default()
+            },
+            ..RuntimeConfig::default()
+        };
+        let app = test_app_with(&discord, write_state(&dir, dir.path()), config);
+
+        let (status, body) = handle_opencode_event(
+            State(app),
+            Json(json!({
+                "projectName": "proj",
+                "type": "session.idle",
+                "text": format!("Rendered the chart: {}", chart.display()),
+            })),
+        )
+        .await;
+
+        assert_eq!(
+            discord.requests().len(),
+            2,
+            "files are tried after text fails"
+        );
+        (status, serde_json::from_str(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn best_effort_reports_each_stage() {
+        let (status, body) = best_effort_idle(false, false).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!({ "textSent": true, "filesSent": true, "errors": [] })
+        );
+
+        let (status, body) = best_effort_idle(true, false).await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        assert_eq!(
+            (body["textSent"].clone(), body["filesSent"].clone()),
+            (json!(false), json!(true))
+        );
+        assert!(body["errors"][0].as_str().unwrap().starts_with("text: "));
+
+        let (status, body) = best_effort_idle(false, true).await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        assert_eq!(
+            (body["textSent"].clone(), body["filesSent"].clone()),
+            (json!(true), json!(false))
+        );
+        assert!(body["errors"][0].as_str().unwrap().starts_with("files: "));
+
+        let (status, body) = best_effort_idle(true, true).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            (body["textSent"].clone(), body["filesSent"].clone()),
+            (json!(false), json!(false))
+        );
+        assert_eq!(body["errors"].as_array().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn channel_creation_is_limited_to_side_effect_event_types() {
+        let discord = MockServer::with_responder(|request, idx| {
+            if request.path.starts_with("/guilds/") {
+                MockResponse::json(201, json!({ "id": "new-ch" }))
+            } else {
+                MockResponse::message(format!("msg-{idx}"))
+            }
+        })
+        .await;
+        let dir = TempDir::new("side-effects");
+        let state_path = write_state(&dir, dir.path());
+        let config = RuntimeConfig {
+            channel_auto_create: Some(ChannelAutoCreate {
+                guild_id: "guild-1".to_string(),
+                category_id: None,
+            }),
+            side_effect_event_types: HashSet::from(["session.idle".to_string()]),
+            ..RuntimeConfig::default()
+        };
+        let app = test_app_with(&discord, state_path, config);
+        let event = |event_type: &str| {
+            json!({
+                "projectName": "proj",
+                "agentType": "claude",
+                "type": event_type,
+                "text": "boom",
+            })
+        };
+
+        let (status, _) =
+            handle_opencode_event(State(app.clone()), Json(event("session.error"))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(discord.requests().is_empty());
+
+        let (status, _) = handle_opencode_event(State(app), Json(event("session.idle"))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(discord.requests()[0].path, "/guilds/guild-1/channels");
+    }
+
+    #[tokio::test]
+    async fn session_error_is_posted_as_a_truncated_embed() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("error-embed");
+        let state_path = write_state(&dir, dir.path());
+        let app = test_app(&discord, state_path);
+        let text = "x".repeat(5000);
+
+        let (status, _) = handle_opencode_event(
+            State(app),
+            Json(json!({
+                "projectName": "proj",
+                "instanceId": "opencode-2",
+                "type": "session.error",
+                "text": text,
+            })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let requests = discord.requests();
+        assert_eq!(requests.len(), 1);
+        let body = requests[0].json();
+        assert_eq!(body["content"], Value::Null);
+        let embed = &body["embeds"][0];
+        assert_eq!(embed["title"], json!("OpenCode session error"));
+        assert_eq!(embed["color"], json!(ERROR_EMBED_COLOR));
+        assert_eq!(embed["footer"]["text"], json!("proj · opencode-2"));
+        let description = embed["description"].as_str().unwrap();
+        assert_eq!(description.chars().count(), 4096);
+        assert!(description.ends_with("x…"));
+    }
+
+    #[tokio::test]
+    async fn rejected_token_fails_delivery_distinctly() {
+        let discord = MockServer::with_responder(|_, _| {
+            MockResponse::json(401, json!({ "message": "401: Unauthorized", "code": 0 }))
+        })
+        .await;
+        let dir = TempDir::new("unauthorized");
+        let app = test_app(&discord, write_state(&dir, dir.path()));
+
+        let (status, body) = handle_opencode_event(
+            State(app.clone()),
+            Json(json!({ "projectName": "proj", "type": "session.idle", "text": "done" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body, "Discord token rejected");
+
+        let error = verify_discord_tokens(&app).await.unwrap_err();
+        assert!(error.to_string().contains("rejected the discordToken"));
+    }
+
+    #[tokio::test]
+    async fn unknown_event_fields_rejected_only_in_strict_mode() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("strict-fields");
+        let state_path = write_state(&dir, dir.path());
+        let event = json!({
+            "projectName": "proj",
+            "type": "session.idle",
+            "text": "hello",
+            "projctName": "typo",
+        });
+
+        let lenient = test_app(&discord, state_path.clone());
+        let (status, _) = handle_opencode_event(State(lenient), Json(event.clone())).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let config = RuntimeConfig {
+            strict_event_fields: true,
+            ..RuntimeConfig::default()
+        };
+        let strict = test_app_with(&discord, state_path, config);
+        let (status, body) = handle_opencode_event(State(strict), Json(event)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body, "Unknown fields: projctName");
+        assert_eq!(discord.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn success_reaction_added_only_after_full_delivery() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("reaction-ok");
+        let chart = dir.write("chart.png", "png");
+        let config = RuntimeConfig {
+            success_reaction: Some("✅".to_string()),
+            ..RuntimeConfig::default()
+        };
+        let app = test_app_with(&discord, write_state(&dir, dir.path()), config);
+
+        let (status, _) = handle_opencode_event(
+            State(app),
+            Json(json!({
+                "projectName": "proj",
+                "type": "session.idle",
+                "text": format!("Chart: {}", chart.display()),
+            })),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        let paths: Vec<_> = discord.requests().into_iter().map(|r| r.path).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "/channels/ch-1/messages",
+                "/channels/ch-1/messages",
+                "/channels/ch-1/messages/msg-1/reactions/%E2%9C%85/@me",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn no_success_reaction_after_partial_delivery() {
+        let discord = MockServer::with_responder(|request, idx| {
+            if request.body_text().contains("name=\"payload_json\"") {
+                MockResponse::json(403, json!({ "code": 50013 }))
+            } else {
+                MockResponse::message(format!("msg-{idx}"))
+            }
+        })
+        .await;
+        let dir = TempDir::new("reaction-partial");
+        let chart = dir.write("chart.png", "png");
+        let config = RuntimeConfig {
+            success_reaction: Some("✅".to_string()),
+            format: FormatOptions {
+                best_effort_delivery: true,
+                ..FormatOptions::default()
+            },
+            ..RuntimeConfig::default()
+        };
+        let app = test_app_with(&discord, write_state(&dir, dir.path()), config);
+
+        let (status, _) = handle_opencode_event(
+            State(app),
+            Json(json!({
+                "projectName": "proj",
+                "type": "session.idle",
+                "text": format!("Chart: {}", chart.display()),
+            })),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        assert!(
+            discord
+                .requests()
+                .iter()
+                .all(|r| !r.path.contains("/reactions/"))
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_permissions_surface_distinctly_and_warn_once() {
+        let allowed = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let discord = MockServer::with_responder({
+            let allowed = Arc::clone(&allowed);
+            move |_, idx| {
+                if allowed.load(std::sync::atomic::Ordering::SeqCst) {
+                    MockResponse::message(format!("msg-{idx}"))
+                } else {
+                    MockResponse::json(
+                        403,
+                        json!({ "message": "Missing Permissions", "code": 50013 }),
+                    )
+                }
+            }
+        })
+        .await;
+        let dir = TempDir::new("missing-perms");
+        let app = test_app(&discord, write_state(&dir, dir.path()));
+        let event = json!({ "projectName": "proj", "type": "session.idle", "text": "hi" });
+
+        for _ in 0..2 {
+            let (status, body) =
+                handle_opencode_event(State(app.clone()), Json(event.clone())).await;
+            assert_eq!(status, StatusCode::FORBIDDEN);
+            assert_eq!(
+                serde_json::from_str::<Value>(&body).unwrap(),
+                json!({ "error": "missingPermissions", "channelId": "ch-1" })
+            );
+        }
+        assert_eq!(
+            *app.permission_warned.lock().unwrap(),
+            HashSet::from(["ch-1".to_string()])
+        );
+
+        allowed.store(true, std::sync::atomic::Ordering::SeqCst);
+        let (status, _) = handle_opencode_event(State(app.clone()), Json(event.clone())).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(app.permission_warned.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn strict_instance_agent_ignores_a_defaulted_agent_type() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("strict-default-agent");
+        let state = json!({
+            "projects": {
+                "proj": {
+                    "defaultAgentType": "opencode",
+                    "instances": {
+                        "opencode": { "agentType": "opencode", "channelId": "ch-open" },
+                        "claude-1": { "agentType": "claude", "channelId": "ch-claude" }
+                    }
+                }
+            }
+        });
+        let mut config = RuntimeConfig::default();
+        config.channel_lookup.strict_instance_agent = true;
+        let app = test_app_with(&discord, dir.write("state.json", state.to_string()), config);
+        let event = |agent: Option<&str>| {
+            let mut event = json!({
+                "projectName": "proj",
+                "instanceId": "claude-1",
+                "type": "session.idle",
+                "text": "hi",
+            });
+            if let Some(agent) = agent {
+                event["agentType"] = json!(agent);
+            }
+            event
+        };
+
+        for agent in [None, Some("opencode")] {
+            let (status, _) = handle_opencode_event(State(app.clone()), Json(event(agent))).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        let paths: Vec<_> = discord.requests().into_iter().map(|r| r.path).collect();
+        assert_eq!(
+            paths,
+            vec!["/channels/ch-claude/messages", "/channels/ch-open/messages"]
+        );
+    }
+
+    #[tokio::test]
+    async fn omitted_agent_type_uses_project_default() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("default-agent");
+        let state = json!({
+            "projects": {
+                "proj": {
+                    "defaultAgentType": "claude",
+                    "instances": {
+                        "opencode": { "agentType": "opencode", "channelId": "ch-open" },
+                        "claude": { "agentType": "claude", "channelId": "ch-claude" }
+                    }
+                }
+            }
+        });
+        let app = test_app(&discord, dir.write("state.json", state.to_string()));
+
+        for event in [
+            json!({ "projectName": "proj", "type": "session.idle", "text": "a" }),
+            json!({ "projectName": "proj", "agentType": "opencode", "type": "session.idle", "text": "b" }),
+        ] {
+            let (status, _) = handle_opencode_event(State(app.clone()), Json(event)).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+
+        let paths: Vec<_> = discord.requests().into_iter().map(|r| r.path).collect();
+        assert_eq!(
+            paths,
+            vec!["/channels/ch-claude/messages", "/channels/ch-open/messages"]
+        );
+    }
+
+    async fn truncated_idle(text: &str) -> Vec<crate::test_support::RecordedRequest> {
+        let mut config = RuntimeConfig::default();
+        config.format.truncate_with_attachment = Some(100);
+        let harness = IdleHarness::start("truncate", config).await;
+
+        assert_eq!(harness.idle(text).await, StatusCode::OK);
+        harness.discord.requests()
+    }
+
+    #[tokio::test]
+    async fn long_idle_text_is_truncated_with_full_output_attached() {
+        let text = "line of output\n".repeat(400);
+        let requests = truncated_idle(&text).await;
+
+        assert_eq!(requests.len(), 1);
+        let content = requests[0].payload_json()["content"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert!(content.chars().count() <= 100);
+        assert!(content.ends_with(crate::parser::TRUNCATION_SUFFIX));
+        let body = requests[0].body_text();
+        assert!(body.contains("filename=\"full-output.txt\""));
+        assert!(body.contains(text.trim()));
+    }
+
+    #[tokio::test]
+    async fn short_idle_text_is_sent_without_attachment() {
+        let requests = truncated_idle("all done").await;
+
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].json()["content"], json!("all done"));
+    }
+
+    #[tokio::test]
+    async fn file_footer_keeps_paths_and_lists_attachments() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("footer");
+        dir.write("out.png", "png");
+        let mut config = RuntimeConfig::default();
+        config.format.file_footer = true;
+        let app = test_app_with(&discord, write_state(&dir, dir.path()), config);
+
+        let path = format!("{}/out.png", dir.path().display());
+        let (status, _) = handle_opencode_event(
+            State(app),
+            Json(json!({ "projectName": "proj", "type": "session.idle", "text": format!("Saved {path}") })),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        let requests = discord.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            requests[0].json()["content"],
+            json!(format!("Saved {path}\n📎 out.png"))
+        );
+        assert!(requests[1].body_text().contains("filename=\"out.png\""));
+    }
+
+    #[tokio::test]
+    async fn idle_matching_ignore_list_is_not_relayed() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("ignored-idle");
+        let config = RuntimeConfig {
+            format: FormatOptions {
+                ignored_idle_texts: vec!["...".to_string(), "🤔".to_string()],
+                ..FormatOptions::default()
+            },
+            ..RuntimeConfig::default()
+        };
+        let app = test_app_with(&discord, write_state(&dir, dir.path()), config);
+
+        for text in [" 🤔 ", "...", "... done"] {
+            let (status, _) = handle_opencode_event(
+                State(app.clone()),
+                Json(json!({ "projectName": "proj", "type": "session.idle", "text": text })),
+            )
+            .await;
+            assert_eq!(status, StatusCode::OK);
+        }
+
+        let requests = discord.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].json()["content"], json!("... done"));
+    }
+
+    #[tokio::test]
+    async fn missing_file_is_rechecked_until_it_appears() {
+        let dir = TempDir::new("settle");
+        let path = dir.path().join("late.png");
+        let paths = vec![path.display().to_string()];
+        let settle = FileSettle {
+            retries: 10,
+            delay: Duration::from_millis(20),
+        };
+
+        assert!(
+            validate_file_paths(&paths, Some(dir.path()), FileSettle::default())
+                .await
+                .is_empty()
+        );
+
+        let writer = {
+            let path = path.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                fs::write(path, "png").unwrap();
+            })
+        };
+        let valid = validate_file_paths(&paths, Some(dir.path()), settle).await;
+        writer.await.unwrap();
+
+        assert_eq!(valid, paths);
+    }
+
+    async fn send_files_without_project_path(
+        trust: bool,
+        directory: bool,
+    ) -> (StatusCode, String, usize) {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("no-project-path");
+        let chart = dir.write("chart.png", "png");
+        let file = if directory {
+            dir.path().display().to_string()
+        } else {
+            chart.display().to_string()
+        };
+        let state = json!({
+            "projects": {
+                "proj": { "instances": { "opencode": { "agentType": "opencode", "channelId": "ch-1" } } }
+            }
+        });
+        let config = RuntimeConfig {
+            trust_paths_without_project_path: trust,
+            ..RuntimeConfig::default()
+        };
+        let app = test_app_with(&discord, dir.write("state.json", state.to_string()), config);
+
+        let (status, body) = handle_send_files(
+            State(app),
+            Json(json!({ "projectName": "proj", "files": [file], "recursive": true })),
+        )
+        .await;
+        (status, body, discord.requests().len())
+    }
+
+    #[tokio::test]
+    async fn send_files_names_missing_project_path_by_default() {
+        let (status, body, sent) = send_files_without_project_path(false, false).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.contains("proj has no projectPath"));
+        assert_eq!(sent, 0);
+    }
+
+    #[tokio::test]
+    async fn send_files_can_trust_paths_without_project_path() {
+        let (status, _, sent) = send_files_without_project_path(true, false).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(sent, 1);
+    }
+
+    #[tokio::test]
+    async fn trusted_paths_without_project_path_do_not_expand_directories() {
+        let (status, body, sent) = send_files_without_project_path(true, true).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body, "No valid files");
+        assert_eq!(sent, 0);
+    }
+
+    async fn templated_idle(text: &str) -> Vec<String> {
+        let config = RuntimeConfig {
+            format: FormatOptions {
+                message_templates: HashMap::from([(
+                    "proj".to_string(),
+                    "**[{project}/{agent}]** {text}".to_string(),
+                )]),
+                ..FormatOptions::default()
+            },
+            ..RuntimeConfig::default()
+        };
+        let harness = IdleHarness::start("template", config).await;
+
+        assert_eq!(harness.idle(text).await, StatusCode::OK);
+        harness
+            .discord
+            .requests()
+            .iter()
+            .map(|r| r.json()["content"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn message_template_wraps_idle_text() {
+        assert_eq!(
+            templated_idle("done").await,
+            vec!["**[proj/opencode]** done".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn message_template_lead_in_counts_toward_the_limit() {
+        let chunks = templated_idle(&"word ".repeat(399)).await;
+
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks[0].starts_with("**[proj/opencode]** word"));
+        assert!(!chunks[1].contains("**["));
+        assert!(
+            chunks
+                .iter()
+                .all(|c| c.chars().count() <= crate::parser::DISCORD_MAX_MESSAGE_LENGTH)
+        );
+    }
+
+    #[tokio::test]
+    async fn send_files_glob_expands_within_the_project() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("glob");
+        dir.write("charts/a.png", "png");
+        dir.write("charts/b.png", "png");
+        dir.write("charts/notes.txt", "txt");
+        let outside = TempDir::new("glob-outside");
+        outside.write("c.png", "png");
+        let config = RuntimeConfig {
+            max_glob_matches: 10,
+            ..RuntimeConfig::default()
+        };
+        let app = test_app_with(&discord, write_state(&dir, dir.path()), config);
+
+        let outside_pattern = format!("{}/*.png", outside.path().display());
+        let (status, _) = handle_send_files(
+            State(app),
+            Json(json!({
+                "projectName": "proj",
+                "files": ["charts/*.png", outside_pattern],
+                "glob": true,
+            })),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        let body = discord.requests()[0].body_text();
+        assert!(body.contains("filename=\"a.png\"") && body.contains("filename=\"b.png\""));
+        assert!(!body.contains("notes.txt") && !body.contains("c.png"));
+    }
+
+    #[tokio::test]
+    async fn glob_expansion_is_capped_and_sorted() {
+        let dir = TempDir::new("glob-cap");
+        for name in ["c.log", "a.log", "b.log"] {
+            dir.write(name, "log");
+        }
+
+        let expanded = expand_file_globs(&["*.log".to_string()], Some(dir.path()), 2).await;
+        let names: Vec<_> = expanded
+            .iter()
+            .map(|p| {
+                Path::new(p)
+                    .file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+        assert_eq!(names, vec!["a.log", "b.log"]);
+    }
+
+    #[tokio::test]
+    async fn glob_matches_outside_the_project_do_not_use_up_the_cap() {
+        let dir = TempDir::new("glob-contained");
+        let outside = TempDir::new("glob-contained-outside");
+        dir.write("a.log", "log");
+        for name in ["x.log", "y.log"] {
+            outside.write(name, "log");
+        }
+        let patterns = [
+            outside.path().join("*.log").display().to_string(),
+            "*.log".to_string(),
+        ];
+
+        let expanded = expand_file_globs(&patterns, Some(dir.path()), 1).await;
+        assert_eq!(
+            expanded,
+            vec![dir.path().join("a.log").display().to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn directories_expand_to_allowed_files_inside_the_project() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("send-dir");
+        fs::create_dir_all(dir.path().join("out/nested")).unwrap();
+        dir.write("out/b.PNG", "png");
+        dir.write("out/a.pdf", "pdf");
+        dir.write("out/notes.exe", "bin");
+        dir.write("out/nested/c.png", "png");
+        let outside = TempDir::new("send-dir-outside");
+        outside.write("d.png", "png");
+        let config = RuntimeConfig {
+            max_directory_files: 10,
+            ..RuntimeConfig::default()
+        };
+        let app = test_app_with(&discord, write_state(&dir, dir.path()), config);
+        let uploaded = |idx: usize| {
+            let body = discord.requests()[idx].body_text();
+            ["a.pdf", "b.PNG", "c.png", "d.png", "notes.exe"]
+                .into_iter()
+                .filter(|name| body.contains(&format!("filename=\"{name}\"")))
+                .collect::<Vec<_>>()
+        };
+
+        let (status, _) = handle_send_files(
+            State(app.clone()),
+            Json(json!({
+                "projectName": "proj",
+                "files": ["out", outside.path().display().to_string()],
+            })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(uploaded(0), vec!["a.pdf", "b.PNG"]);
+
+        let (status, _) = handle_send_files(
+            State(app),
+            Json(json!({ "projectName": "proj", "files": ["out"], "recursive": true })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(uploaded(1), vec!["a.pdf", "b.PNG", "c.png"]);
+    }
+
+    #[tokio::test]
+    async fn send_files_reports_directory_files_skipped_over_the_cap() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("send-dir-cap");
+        for name in ["a.png", "b.png", "c.png"] {
+            dir.write(&format!("out/{name}"), "png");
+        }
+        let config = RuntimeConfig {
+            max_directory_files: 2,
+            ..RuntimeConfig::default()
+        };
+        let app = test_app_with(&discord, write_state(&dir, dir.path()), config);
+
+        let (status, body) = handle_send_files(
+            State(app),
+            Json(json!({ "projectName": "proj", "files": ["out"] })),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            serde_json::from_str::<Value>(&body).unwrap(),
+            json!({ "ok": true, "skipped": 1 })
+        );
+        assert_eq!(discord.requests().len(), 1);
+    }
+
+    #[test]
+    fn directory_expansion_keeps_the_newest_files_over_the_cap() {
+        let dir = TempDir::new("dir-cap");
+        let now = std::time::SystemTime::now();
+        for (name, age_secs) in [("c.png", 10), ("a.png", 30), ("b.png", 20), ("d.png", 40)] {
+            let path = dir.write(name, "png");
+            fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(now - Duration::from_secs(age_secs))
+                .unwrap();
+        }
+
+        let extensions = vec!["png".to_string()];
+        let expanded = expand_directories_blocking(
+            &[".".to_string()],
+            Some(dir.path()),
+            &extensions,
+            false,
+            2,
+            MAX_DIRECTORY_ENTRIES_VISITED,
+        );
+        assert_eq!(
+            expanded,
+            DirectoryExpansion {
+                files: vec![
+                    dir.path().join("./b.png").display().to_string(),
+                    dir.path().join("./c.png").display().to_string(),
+                ],
+                skipped: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn directories_outside_the_project_are_not_expanded() {
+        let dir = TempDir::new("dir-outside-project");
+        let outside = TempDir::new("dir-outside");
+        outside.write("nested/a.png", "png");
+        let outside_path = outside.path().display().to_string();
+
+        let expanded = expand_directories_blocking(
+            std::slice::from_ref(&outside_path),
+            Some(dir.path()),
+            &["png".to_string()],
+            true,
+            10,
+            MAX_DIRECTORY_ENTRIES_VISITED,
+        );
+        assert_eq!(expanded.files, vec![outside_path]);
+    }
+
+    #[test]
+    fn directories_are_not_expanded_without_a_project_root() {
+        let dir = TempDir::new("dir-no-root");
+        dir.write("a.png", "png");
+        let entry = dir.path().display().to_string();
+
+        let expanded = expand_directories_blocking(
+            std::slice::from_ref(&entry),
+            None,
+            &["png".to_string()],
+            true,
+            10,
+            MAX_DIRECTORY_ENTRIES_VISITED,
+        );
+        assert_eq!(expanded.files, vec![entry]);
+    }
+
+    #[test]
+    fn directory_walk_stops_after_the_visit_limit() {
+        let dir = TempDir::new("dir-visit-limit");
+        for name in ["a", "b", "c", "d", "e"] {
+            dir.write(&format!("{name}/{name}.png"), "png");
+        }
+
+        let expanded = expand_directories_blocking(
+            &[".".to_string()],
+            Some(dir.path()),
+            &["png".to_string()],
+            true,
+            10,
+            6,
+        );
+        // Five subdirectories and one file inside the first of them.
+        assert_eq!(
+            expanded.files,
+            vec![dir.path().join("./a/a.png").display().to_string()]
+        );
+        assert_eq!(expanded.skipped, 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_subdirectories_are_not_followed() {
+        let dir = TempDir::new("dir-symlink");
+        dir.write("out/a.png", "png");
+        let outside = TempDir::new("dir-symlink-target");
+        outside.write("b.png", "png");
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("out/linked")).unwrap();
+        std::os::unix::fs::symlink(outside.path().join("b.png"), dir.path().join("out/c.png"))
+            .unwrap();
+
+        let expanded = expand_directories_blocking(
+            &["out".to_string()],
+            Some(dir.path()),
+            &["png".to_string()],
+            true,
+            10,
+            MAX_DIRECTORY_ENTRIES_VISITED,
+        );
+        assert_eq!(
+            expanded.files,
+            vec![dir.path().join("out/a.png").display().to_string()]
+        );
+    }
+
+    async fn idle_during_global_pause(
+        mode: GlobalPauseMode,
+        pause: Duration,
+    ) -> (StatusCode, MockServer, TempDir) {
+        let config = RuntimeConfig {
+            global_pause_mode: mode,
+            max_deferred_events: 10,
+            ..RuntimeConfig::default()
+        };
+        let harness = IdleHarness::start("global-pause", config).await;
+        harness.app.discord.pause_globally(pause);
+
+        let status = harness.idle("done").await;
+        (status, harness.discord, harness.dir)
+    }
+
+    #[tokio::test]
+    async fn deferred_event_is_accepted_and_sent_after_global_pause() {
+        let (status, discord, _dir) =
+            idle_during_global_pause(GlobalPauseMode::Defer, Duration::from_millis(200)).await;
+
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert!(discord.requests().is_empty());
+        let requests = discord.wait_for_requests(1).await;
+        assert_eq!(requests[0].json()["content"], json!("done"));
+    }
+
+    #[tokio::test]
+    async fn deferred_event_counts_as_in_flight_before_its_task_runs() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("defer-in-flight");
+        let config = RuntimeConfig {
+            global_pause_mode: GlobalPauseMode::Defer,
+            max_deferred_events: 10,
+            ..RuntimeConfig::default()
+        };
+        let app = test_app_with(&discord, write_state(&dir, dir.path()), config);
+        app.discord.pause_globally(Duration::from_millis(100));
+
+        let (status, _) = handle_opencode_event(
+            State(app.clone()),
+            Json(json!({ "projectName": "proj", "type": "session.idle", "text": "done" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(app.in_flight.count(), 1);
+
+        discord.wait_for_requests(1).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(app.in_flight.count(), 0);
+    }
+
+    #[tokio::test]
+    async fn deferred_events_are_capped() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("defer-cap");
+        let config = RuntimeConfig {
+            global_pause_mode: GlobalPauseMode::Defer,
+            max_deferred_events: 1,
+            ..RuntimeConfig::default()
+        };
+        let app = test_app_with(&discord, write_state(&dir, dir.path()), config);
+        app.discord.pause_globally(Duration::from_millis(200));
+
+        let idle =
+            || Json(json!({ "projectName": "proj", "type": "session.idle", "text": "done" }));
+        let (status, _) = handle_opencode_event(State(app.clone()), idle()).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        let (status, _) = handle_opencode_event(State(app.clone()), idle()).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        discord.wait_for_requests(1).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(app.deferred.count(), 0);
+        assert_eq!(discord.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn deferred_event_waits_for_every_target_to_unpause() {
+        let default_server = MockServer::start().await;
+        let other_server = MockServer::start().await;
+        let dir = TempDir::new("defer-targets");
+        let state = json!({
+            "projects": {
+                "a": { "instances": { "opencode": { "agentType": "opencode", "channelId": "ch-a" } } },
+                "b": { "instances": { "opencode": { "agentType": "opencode", "channelId": "ch-b" } } }
+            }
+        });
+        let config = RuntimeConfig {
+            global_pause_mode: GlobalPauseMode::Defer,
+            max_deferred_events: 10,
+            project_discord: HashMap::from([(
+                "b".to_string(),
+                ProjectDiscord {
+                    api_base: Some(other_server.url.clone()),
+                    token: None,
+                },
+            )]),
+            ..RuntimeConfig::default()
+        };
+        let app = test_app_with(
+            &default_server,
+            dir.write("state.json", state.to_string()),
+            config,
+        );
+        app.discord_for("b")
+            .pause_globally(Duration::from_millis(300));
+
+        let (status, _) = handle_opencode_event(
+            State(app),
+            Json(json!({ "projectNames": ["a", "b"], "type": "session.idle", "text": "done" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(default_server.requests().is_empty());
+        assert_eq!(
+            default_server.wait_for_requests(1).await[0].path,
+            "/channels/ch-a/messages"
+        );
+        assert_eq!(
+            other_server.wait_for_requests(1).await[0].path,
+            "/channels/ch-b/messages"
+        );
+    }
+
+    #[tokio::test]
+    async fn deferred_event_is_delivered_with_the_reloaded_config() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("defer-reload");
+        let state_path = write_state(&dir, dir.path());
+        let config = |template: Option<&str>| RuntimeConfig {
+            discord: DiscordSettings {
+                api_base: discord.url.clone(),
+                ..DiscordSettings::default()
+            },
+            state_path: state_path.clone(),
+            global_pause_mode: GlobalPauseMode::Defer,
+            max_deferred_events: 10,
+            format: FormatOptions {
+                message_templates: template
+                    .map(|t| HashMap::from([("proj".to_string(), t.to_string())]))
+                    .unwrap_or_default(),
+                ..FormatOptions::default()
+            },
+            ..RuntimeConfig::default()
+        };
+        let app = test_app_with(&discord, state_path.clone(), config(None));
+        app.discord.pause_globally(Duration::from_millis(300));
+        let live = LiveApp::new(app);
+
+        let (status, _) = handle_opencode_event(
+            State(live.current()),
+            Json(json!({ "projectName": "proj", "type": "session.idle", "text": "done" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        live.reload(config(Some("[{project}] {text}")))
+            .await
+            .unwrap();
+
+        let sent = loop {
+            let sent: Vec<_> = discord
+                .requests()
+                .into_iter()
+                .filter(|r| r.path == "/channels/ch-1/messages")
+                .collect();
+            if !sent.is_empty() {
+                break sent;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        };
+        assert_eq!(sent[0].json()["content"], json!("[proj] done"));
+    }
+
+    #[tokio::test]
+    async fn waiting_event_is_sent_after_short_pause_and_rejected_after_long_one() {
+        let limit = GlobalPauseMode::Wait(Some(Duration::from_millis(500)));
+
+        let (status, discord, _) =
+            idle_during_global_pause(limit, Duration::from_millis(100)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(discord.requests().len(), 1);
+
+        let (status, discord, _) = idle_during_global_pause(limit, Duration::from_secs(30)).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(discord.requests().is_empty());
+    }
+
+    async fn idle_attachments(scope: FileScanScope) -> Vec<String> {
+        let mut config = RuntimeConfig::default();
+        config.format.file_scan_scope = scope;
+        let harness = IdleHarness::start("scan-scope", config).await;
+        let in_text = harness.dir.write("text.png", "png");
+        let in_turn = harness.dir.write("turn.png", "png");
+
+        let status = harness
+            .idle_with(json!({
+                "text": format!("Saved {}", in_text.display()),
+                "turnText": format!("Wrote {}", in_turn.display()),
+            }))
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let mut names: Vec<String> = harness
+            .discord
+            .requests()
+            .iter()
+            .flat_map(|r| {
+                ["text.png", "turn.png"]
+                    .into_iter()
+                    .filter(|name| r.body_text().contains(&format!("filename=\"{name}\"")))
+                    .map(str::to_string)
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn file_scan_scope_selects_the_scanned_fields() {
+        assert_eq!(
+            idle_attachments(FileScanScope::Auto).await,
+            vec!["turn.png"]
+        );
+        assert_eq!(
+            idle_attachments(FileScanScope::TurnText).await,
+            vec!["turn.png"]
+        );
+        assert_eq!(
+            idle_attachments(FileScanScope::Text).await,
+            vec!["text.png"]
+        );
+        assert_eq!(
+            idle_attachments(FileScanScope::Both).await,
+            vec!["text.png", "turn.png"]
+        );
+    }
+
+    /// Requests of an idle event with `text` plus one file, as ("files" or
+    /// "text", content) in send order.
+    async fn idle_placement_sends(
+        placement: AttachmentPlacement,
+        concurrent: bool,
+        text: &str,
+    ) -> Vec<(&'static str, String)> {
+        // Hold the first text chunk back so a concurrent upload lands before the next.
+        let discord = MockServer::with_responder(|_, idx| {
+            let delay = if idx == 0 {
+                Duration::from_millis(150)
+            } else {
+                Duration::ZERO
+            };
+            MockResponse::message(format!("msg-{idx}")).delayed(delay)
+        })
+        .await;
+        let mut config = RuntimeConfig::default();
+        config.format.attachment_placement = placement;
+        config.format.concurrent_file_delivery = concurrent;
+        let harness = IdleHarness::new(discord, TempDir::new("placement"), config);
+        let chart = harness.dir.write("chart.png", "png");
+
+        let status = harness.idle(&format!("{text} {}", chart.display())).await;
+        assert_eq!(status, StatusCode::OK);
+
+        harness
+            .discord
+            .requests()
+            .iter()
+            .map(|r| {
+                if r.body_text().contains("filename=\"chart.png\"") {
+                    let content = r.payload_json()["content"]
+                        .as_str()
+                        .unwrap_or("")
+                        .trim()
+                        .to_string();
+                    ("files", content)
+                } else {
+                    (
+                        "text",
+                        r.json()["content"].as_str().unwrap().trim().to_string(),
+                    )
+                }
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn attachment_placement_orders_text_and_files() {
+        let text = || "Rendered the chart".to_string();
+        assert_eq!(
+            idle_placement_sends(AttachmentPlacement::After, false, "Rendered the chart").await,
+            vec![("text", text()), ("files", String::new())]
+        );
+        assert_eq!(
+            idle_placement_sends(AttachmentPlacement::Before, false, "Rendered the chart").await,
+            vec![("files", String::new()), ("text", text())]
+        );
+        assert_eq!(
+            idle_placement_sends(AttachmentPlacement::Combined, false, "Rendered the chart").await,
+            vec![("files", text())]
+        );
+    }
+
+    #[tokio::test]
+    async fn combined_placement_falls_back_to_after_for_long_text() {
+        let long = "word ".repeat(500);
+        let sends = idle_placement_sends(AttachmentPlacement::Combined, false, &long).await;
+
+        let kinds: Vec<_> = sends.iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(kinds, vec!["text", "text", "files"]);
+        assert_eq!(sends[2].1, "");
+    }
+
+    #[tokio::test]
+    async fn text_only_agents_skip_file_attachments() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("agent-attachments");
+        let chart = dir.write("chart.png", "png");
+        let config = RuntimeConfig {
+            format: FormatOptions {
+                agent_attachments: HashMap::from([("opencode".to_string(), false)]),
+                ..FormatOptions::default()
+            },
+            ..RuntimeConfig::default()
+        };
+        let app = test_app_with(&discord, write_state(&dir, dir.path()), config);
+
+        let text = format!("Saved {}", chart.display());
+        let (status, _) = handle_opencode_event(
+            State(app),
+            Json(json!({ "projectName": "proj", "type": "session.idle", "text": text })),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        let requests = discord.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].json()["content"], text);
+    }
+
+    async fn idle_display_text(strip_resolved_paths: bool) -> String {
+        let mut config = RuntimeConfig::default();
+        config.format.strip_resolved_paths = strip_resolved_paths;
+        let harness = IdleHarness::start("strip-resolved", config).await;
+        let chart = harness.dir.write("chart.png", "png");
+        let dotted = harness.dir.path().join(".").join("chart.png");
+
+        let status = harness
+            .idle_with(json!({
+                "text": format!("Chart: {}\nAlso at {}", chart.display(), dotted.display()),
+                "turnText": format!("Wrote {}", chart.display()),
+            }))
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        harness.discord.requests()[0].json()["content"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn paths_found_in_turn_text_are_stripped_from_text() {
+        let kept = idle_display_text(false).await;
+        assert!(kept.starts_with("Chart: \nAlso at /"));
+        assert!(kept.ends_with("/./chart.png"));
+
+        assert_eq!(idle_display_text(true).await, "Chart: \nAlso at ");
+    }
+
+    #[tokio::test]
+    async fn decorated_chunks_stay_within_the_message_limit() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("decorated-limit");
+        let chart = dir.write("chart.png", "png");
+        let mut config = RuntimeConfig {
+            format: FormatOptions {
+                message_templates: HashMap::from([(
+                    "proj".to_string(),
+                    "[{project}/{agent}] {text}".to_string(),
+                )]),
+                ..FormatOptions::default()
+            },
+            ..RuntimeConfig::default()
+        };
+        config.format.max_message_length = 40;
+        config.format.continuation_prefix = "… ".to_string();
+        config.format.continuation_suffix = " …".to_string();
+        config.format.file_footer = true;
+        config.format.bold_headings = true;
+        let app = test_app_with(&discord, write_state(&dir, dir.path()), config);
+
+        let text = format!("# Result\n{}\n{}", "x".repeat(40), chart.display());
+        let (status, _) = handle_opencode_event(
+            State(app),
+            Json(json!({ "projectName": "proj", "type": "session.idle", "text": text })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let contents: Vec<String> = discord
+            .requests()
+            .iter()
+            .filter(|r| !r.body_text().contains("filename="))
+            .map(|r| r.json()["content"].as_str().unwrap().to_string())
+            .collect();
+        assert!(contents.len() > 1);
+        assert!(
+            contents.iter().all(|c| c.chars().count() <= 40),
+            "{contents:?}"
+        );
+        assert!(contents.concat().contains("📎 chart.png"));
+    }
+
+    #[tokio::test]
+    async fn concurrent_file_delivery_overlaps_text_unless_order_is_fixed() {
+        let long = "word ".repeat(500);
+        let kinds = |sends: Vec<(&'static str, String)>| {
+            sends.into_iter().map(|(kind, _)| kind).collect::<Vec<_>>()
+        };
+
+        let concurrent = kinds(idle_placement_sends(AttachmentPlacement::After, true, &long).await);
+        assert_eq!(concurrent.len(), 3);
+        assert_eq!(concurrent.last(), Some(&"text"));
+
+        let sequential =
+            kinds(idle_placement_sends(AttachmentPlacement::After, false, &long).await);
+        assert_eq!(sequential, vec!["text", "text", "files"]);
+
+        let ordered = kinds(idle_placement_sends(AttachmentPlacement::Before, true, &long).await);
+        assert_eq!(ordered, vec!["files", "text", "text"]);
+    }
+
+    #[tokio::test]
+    async fn responses_carry_posted_message_ids_when_enabled() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("message-ids");
+        let chart = dir.write("chart.png", "png");
+        let config = RuntimeConfig {
+            return_message_ids: true,
+            ..RuntimeConfig::default()
+        };
+        let app = test_app_with(&discord, write_state(&dir, dir.path()), config);
+
+        let text = format!("{}\n{}", "word ".repeat(500), chart.display());
+        let (status, body) = handle_opencode_event(
+            State(app.clone()),
+            Json(json!({ "projectName": "proj", "type": "session.idle", "text": text })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            serde_json::from_str::<Value>(&body).unwrap(),
+            json!({ "ok": true, "messageIds": ["msg-0", "msg-1", "msg-2"] })
+        );
+
+        let (status, body) = handle_send_files(
+            State(app),
+            Json(json!({ "projectName": "proj", "files": [chart] })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            serde_json::from_str::<Value>(&body).unwrap(),
+            json!({ "ok": true, "messageIds": ["msg-3"] })
+        );
+    }
+
+    #[tokio::test]
+    async fn relative_and_absolute_paths_mix_in_one_event() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("mixed-paths");
+        let outside = TempDir::new("mixed-paths-outside");
+        fs::create_dir_all(dir.path().join("charts")).unwrap();
+        dir.write("charts/b.png", "png");
+        let inside = dir.write("c.png", "png");
+        let stray = outside.write("a.png", "png");
+        let mut config = RuntimeConfig::default();
+        config.format.relative_paths = true;
+        let app = test_app_with(&discord, write_state(&dir, dir.path()), config);
+
+        let text = format!(
+            "Outside {}\nRelative charts/b.png\nInside {}",
+            stray.display(),
+            inside.display()
+        );
+        let (status, _) = handle_opencode_event(
+            State(app),
+            Json(json!({ "projectName": "proj", "type": "session.idle", "text": text })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let requests = discord.requests();
+        assert_eq!(
+            requests[0].json()["content"],
+            format!("Outside {}\nRelative \nInside ", stray.display())
+        );
+        let upload = requests[1].body_text();
+        assert!(upload.contains("filename=\"b.png\"") && upload.contains("filename=\"c.png\""));
+        assert!(!upload.contains("filename=\"a.png\""));
+    }
+
+    #[tokio::test]
+    async fn relative_paths_cannot_climb_out_of_the_project() {
+        let dir = TempDir::new("relative-escape");
+        let outside = TempDir::new("relative-escape-outside");
+        fs::create_dir_all(dir.path().join("charts")).unwrap();
+        dir.write("charts/b.png", "png");
+        outside.write("a.png", "png");
+        let outside_name = outside.path().file_name().unwrap().to_string_lossy();
+        let escaping = format!("charts/../../{outside_name}/a.png");
+        assert!(dir.path().join(&escaping).exists());
+
+        let valid = validate_file_paths(
+            &["charts/b.png".to_string(), escaping],
+            Some(dir.path()),
+            FileSettle::default(),
+        )
+        .await;
+        assert_eq!(
+            valid,
+            vec![dir.path().join("charts/b.png").display().to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn tilde_project_paths_resolve_and_validate_files() {
+        let home = TempDir::new("tilde");
+        home.write("proj/a.png", "png");
+        home.write("proj/b.png", "png");
+
+        let mut state = BridgeState::default();
+        state.projects.insert(
+            "proj".to_string(),
+            serde_json::from_value(json!({ "projectPath": "~/proj" })).unwrap(),
+        );
+        let project_path = state.project_path_in("proj", Some(home.path())).unwrap();
+        assert_eq!(project_path, home.path().join("proj"));
+
+        let resolved: Vec<String> = ["a.png", "~/proj/b.png"]
+            .iter()
+            .map(|path| resolve_in_project_with_home(path, Some(&project_path), Some(home.path())))
+            .collect();
+        let valid =
+            validate_file_paths(&resolved, Some(&project_path), FileSettle::default()).await;
+        assert_eq!(
+            valid,
+            vec![
+                project_path.join("a.png").display().to_string(),
+                home.path().join("proj/b.png").display().to_string(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn home_relative_paths_in_idle_text_are_attached() {
+        let home = home_dir().expect("tests need a home directory");
+        let harness = IdleHarness::new(
+            MockServer::start().await,
+            TempDir::new_in(&home, ".tilde-idle"),
+            RuntimeConfig::default(),
+        );
+        harness.dir.write("out.png", "png");
+        let under_home = harness.dir.path().strip_prefix(&home).unwrap();
+
+        let text = format!("Saved ~/{}/out.png", under_home.display());
+        assert_eq!(harness.idle(&text).await, StatusCode::OK);
+
+        let requests = harness.discord.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].json()["content"], json!("Saved "));
+        assert!(requests[1].body_text().contains("filename=\"out.png\""));
+    }
+
+    #[tokio::test]
+    async fn reload_swaps_config_and_keeps_it_when_loading_fails() {
+        let old_discord = MockServer::start().await;
+        let new_discord = MockServer::start().await;
+        let dir = TempDir::new("reload");
+        let live = LiveApp::new(test_app(&old_discord, write_state(&dir, dir.path())));
+        let moved_state = dir.write(
+            "moved.json",
+            json!({
+                "projects": {
+                    "proj": {
+                        "instances": {
+                            "opencode": { "agentType": "opencode", "channelId": "ch-2" }
+                        }
+                    }
+                }
+            })
+            .to_string(),
+        );
+        let reloaded = |state_path: PathBuf, strict_state: bool| RuntimeConfig {
+            discord_token: "rotated".to_string(),
+            discord: DiscordSettings {
+                api_base: new_discord.url.clone(),
+                ..DiscordSettings::default()
+            },
+            state_path,
+            strict_state,
+            ..RuntimeConfig::default()
+        };
+
+        live.reload(reloaded(moved_state, false)).await.unwrap();
+        let idle = || Json(json!({ "projectName": "proj", "type": "session.idle", "text": "hi" }));
+        let (status, _) = handle_opencode_event(State(live.current()), idle()).await;
+        assert_eq!(status, StatusCode::OK);
+        let paths: Vec<_> = new_discord.requests().into_iter().map(|r| r.path).collect();
+        assert_eq!(paths, ["/users/@me", "/channels/ch-2/messages"]);
+        assert_eq!(live.current().body_log.render(b"rotated"), "[redacted]");
+
+        let broken = dir.write(
+            "broken.json",
+            json!({
+                "projects": {
+                    "proj": {
+                        "instances": {
+                            "a": { "instanceId": "dup", "channelId": "ch-3" },
+                            "b": { "instanceId": "dup", "channelId": "ch-4" }
+                        }
+                    }
+                }
+            })
+            .to_string(),
+        );
+        assert!(live.reload(reloaded(broken, true)).await.is_err());
+        let (status, _) = handle_opencode_event(State(live.current()), idle()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(new_discord.requests().len(), 3);
+        assert!(old_discord.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn reload_keeps_sends_queued_behind_a_held_one() {
+        let discord = MockServer::with_responder(|_, idx| {
+            let delay = if idx == 0 {
+                Duration::from_millis(300)
+            } else {
+                Duration::ZERO
+            };
+            MockResponse::message(format!("msg-{idx}")).delayed(delay)
+        })
+        .await;
+        let dir = TempDir::new("reload-held");
+        let state_path = write_state(&dir, dir.path());
+        let config = |repost_note: &str| RuntimeConfig {
+            discord_token: "token".to_string(),
+            discord: DiscordSettings {
+                api_base: discord.url.clone(),
+                repost_note: repost_note.to_string(),
+                ..DiscordSettings::default()
+            },
+            state_path: state_path.clone(),
+            ..RuntimeConfig::default()
+        };
+        let live = LiveApp::new(test_app_with(&discord, state_path.clone(), config("")));
+        let idle = |text: &str| {
+            Json(json!({ "projectName": "proj", "type": "session.idle", "text": text }))
+        };
+
+        let held = tokio::spawn(handle_opencode_event(State(live.current()), idle("first")));
+        discord.wait_for_requests(1).await;
+        live.reload(config("continued")).await.unwrap();
+        let next = tokio::spawn(handle_opencode_event(State(live.current()), idle("second")));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let queued: Vec<_> = discord.requests().into_iter().map(|r| r.path).collect();
+        assert_eq!(queued, ["/channels/ch-1/messages", "/users/@me"]);
+
+        assert_eq!(held.await.unwrap().0, StatusCode::OK);
+        assert_eq!(next.await.unwrap().0, StatusCode::OK);
+        let contents: Vec<_> = discord
+            .requests()
+            .into_iter()
+            .filter(|r| r.path == "/channels/ch-1/messages")
+            .map(|r| r.json()["content"].clone())
+            .collect();
+        assert_eq!(contents, [json!("first"), json!("second")]);
+    }
+
+    #[tokio::test]
+    async fn reload_is_refused_when_discord_rejects_a_token() {
+        let discord = MockServer::start().await;
+        let rejecting = MockServer::with_responder(|_, _| {
+            MockResponse::json(401, json!({ "message": "401: Unauthorized", "code": 0 }))
+        })
+        .await;
+        let dir = TempDir::new("reload-unauthorized");
+        let state_path = write_state(&dir, dir.path());
+        let live = LiveApp::new(test_app(&discord, state_path.clone()));
+
+        let error = live
+            .reload(RuntimeConfig {
+                discord_token: "revoked".to_string(),
+                discord: DiscordSettings {
+                    api_base: rejecting.url.clone(),
+                    ..DiscordSettings::default()
+                },
+                state_path,
+                ..RuntimeConfig::default()
+            })
+            .await
+            .unwrap_err();
+
+        assert!(error.to_string().contains("rejected the discordToken"));
+        assert_ne!(live.current().config.discord_token, "revoked");
+    }
+
+    #[tokio::test]
+    async fn project_token_matching_the_global_one_is_checked_once() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("token-checks");
+        let config = RuntimeConfig {
+            discord_token: "shared".to_string(),
+            discord: DiscordSettings {
+                api_base: discord.url.clone(),
+                ..DiscordSettings::default()
+            },
+            project_discord: HashMap::from([(
+                "proj".to_string(),
+                ProjectDiscord {
+                    api_base: None,
+                    token: Some("shared".to_string()),
+                },
+            )]),
+            ..RuntimeConfig::default()
+        };
+        let app = test_app_with(&discord, write_state(&dir, dir.path()), config);
+
+        verify_discord_tokens(&app).await.unwrap();
+
+        assert_eq!(discord.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn reload_reopens_the_audit_log_when_its_path_changes() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("reload-audit");
+        let state_path = write_state(&dir, dir.path());
+        let old_path = dir.path().join("old.jsonl");
+        let new_path = dir.path().join("new.jsonl");
+        let config = |audit_path: &Path| RuntimeConfig {
+            discord: DiscordSettings {
+                api_base: discord.url.clone(),
+                ..DiscordSettings::default()
+            },
+            state_path: state_path.clone(),
+            audit_log_path: Some(audit_path.to_path_buf()),
+            ..RuntimeConfig::default()
+        };
+        let live = LiveApp::new(test_app_with(
+            &discord,
+            state_path.clone(),
+            config(&old_path),
+        ));
+
+        live.reload(config(&new_path)).await.unwrap();
+        let (status, _) = handle_opencode_event(
+            State(live.current()),
+            Json(json!({ "projectName": "proj", "type": "session.idle", "text": "hi" })),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        let record = crate::audit::read_records(&new_path, 1).await.remove(0);
+        assert_eq!(record["project"], json!("proj"));
+        assert!(!old_path.exists());
+    }
+
+    #[tokio::test]
+    async fn project_format_override_applies_to_its_project_only() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("project-format");
+        let mut config = RuntimeConfig::default();
+        let mut wrapped = config.format.clone();
+        wrapped.wrap_code = true;
+        config.project_format.insert("proj".to_string(), wrapped);
+        let app = test_app_with(&discord, write_state(&dir, dir.path()), config);
+
+        let (status, _) = handle_opencode_event(
+            State(app.clone()),
+            Json(json!({ "projectName": "proj", "type": "session.idle", "text": "fn main() {\n    run();\n}" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            discord.requests()[0].json()["content"],
+            json!("```text\nfn main() {\n    run();\n}\n```")
+        );
+        assert!(!app.config.format_for("other").wrap_code);
+    }
+
+    #[tokio::test]
+    async fn project_delivery_overrides_follow_the_resolved_project_name() {
+        let mut config = RuntimeConfig {
+            case_insensitive_projects: true,
+            ..RuntimeConfig::default()
+        };
+        let mut project = config.format.clone();
+        project.ignored_idle_texts = vec!["...".to_string()];
+        project.typing_interval = Some(Duration::from_secs(10));
+        config.project_format.insert("PROJ".to_string(), project);
+        let harness = IdleHarness::start("project-delivery", config).await;
+
+        assert_eq!(harness.idle("...").await, StatusCode::OK);
+        assert_eq!(harness.idle("done").await, StatusCode::OK);
+
+        let paths: Vec<_> = harness
+            .discord
+            .requests()
+            .into_iter()
+            .map(|r| r.path)
+            .collect();
+        assert_eq!(paths, ["/channels/ch-1/typing", "/channels/ch-1/messages"]);
+        assert!(harness.app.discord_for("other").chunk_delay(2) > Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn idle_replies_to_the_given_message() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("idle-reply");
+        let app = test_app(&discord, write_state(&dir, dir.path()));
+
+        for reply_to in [Some("orig-1"), None] {
+            let (status, _) = handle_opencode_event(
+                State(app.clone()),
+                Json(json!({
+                    "projectName": "proj",
+                    "type": "session.idle",
+                    "text": "done",
+                    "replyToMessageId": reply_to,
+                })),
+            )
+            .await;
+            assert_eq!(status, StatusCode::OK);
+        }
+
+        let requests = discord.requests();
+        assert_eq!(
+            requests[0].json()["message_reference"]["message_id"],
+            json!("orig-1")
+        );
+        assert!(requests[1].json().get("message_reference").is_none());
+    }
+
+    #[tokio::test]
+    async fn registered_projects_resolve_their_channel() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("register");
+        let state_path = dir.path().join("state.json");
+        let app = test_app(&discord, state_path.clone());
+        let register = |channel_id: &str, overwrite: bool| {
+            handle_register_project(
+                State(app.clone()),
+                Json(json!({
+                    "projectName": "new-proj",
+                    "projectPath": dir.path(),
+                    "agentType": "claude",
+                    "channelId": channel_id,
+                    "overwrite": overwrite,
+                })),
+            )
+        };
+
+        assert_eq!(register("ch-9", false).await.0, StatusCode::OK);
+        let (state, _) = app.state.resolve_project("new-proj");
+        let lookup = ChannelLookup::default();
+        assert_eq!(
+            state.find_channel_id("new-proj", "claude", None, &lookup),
+            Some("ch-9".to_string())
+        );
+
+        let (status, message) = register("ch-10", false).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(message.contains("already maps to channel ch-9"));
+        assert_eq!(register("ch-10", true).await.0, StatusCode::OK);
+        let saved = BridgeState::load(&state_path);
+        assert_eq!(
+            saved.find_channel_id("new-proj", "claude", None, &lookup),
+            Some("ch-10".to_string())
+        );
+
+        let (status, message) = handle_register_project(
+            State(app.clone()),
+            Json(json!({ "projectName": "p", "projectPath": "rel", "agentType": "a", "channelId": "c" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(message, "projectPath must be an absolute path");
+    }
+
+    #[tokio::test]
+    async fn health_reports_projects_and_unreadable_state() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("health");
+        let state_path = write_state(&dir, dir.path());
+        let app = test_app(&discord, state_path.clone());
+
+        let (status, Json(body)) = handle_health(State(app.clone()));
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "ok");
+        assert_eq!(body["projects"], 1);
+
+        fs::write(&state_path, "{ broken").unwrap();
+        let (status, Json(body)) = handle_health(State(app));
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["status"], "error");
+    }
+
+    #[tokio::test]
+    async fn list_projects_reports_instances_and_legacy_channels() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("list-projects");
+        let state_path = dir.write(
+            "state.json",
+            json!({
+                "projects": {
+                    "proj": {
+                        "projectPath": "/work/proj",
+                        "instances": {
+                            "opencode": {
+                                "instanceId": "opencode",
+                                "agentType": "opencode",
+                                "channelId": "chan-1",
+                                "note": "ignored",
+                            },
+                        },
+                        "discordChannels": { "claude": "chan-2" },
+                    },
+                },
+            })
+            .to_string(),
+        );
+        let app = test_app(&discord, state_path.clone());
+
+        let (status, Json(body)) = handle_list_projects(State(app));
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body["projects"]["proj"],
+            json!({
+                "projectPath": "/work/proj",
+                "stateFile": state_path.display().to_string(),
+                "instances": [{
+                    "key": "opencode",
+                    "instanceId": "opencode",
+                    "agentType": "opencode",
+                    "channelId": "chan-1",
+                }],
+                "discordChannels": { "claude": "chan-2" },
+            })
+        );
+    }
+
+    async fn idle_then_send_files(merge_window: Duration) -> Vec<(String, String)> {
+        let config = RuntimeConfig {
+            merge_files_window: merge_window,
+            ..RuntimeConfig::default()
+        };
+        let harness = IdleHarness::start("merge", config).await;
+        let chart = harness.dir.write("chart.png", "png");
+
+        assert_eq!(harness.idle("done").await, StatusCode::OK);
+        let (status, _) = handle_send_files(
+            State(harness.app.clone()),
+            Json(json!({ "projectName": "proj", "files": [chart.display().to_string()] })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        harness
+            .discord
+            .requests()
+            .into_iter()
+            .map(|r| (r.method, r.path))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn send_files_right_after_idle_is_attached_to_its_message() {
+        let requests = idle_then_send_files(Duration::from_secs(5)).await;
+
+        assert_eq!(
+            requests,
+            vec![
+                ("POST".to_string(), "/channels/ch-1/messages".to_string()),
+                (
+                    "PATCH".to_string(),
+                    "/channels/ch-1/messages/msg-0".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn send_files_is_posted_separately_without_merge_window() {
+        let requests = idle_then_send_files(Duration::ZERO).await;
+
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|(method, _)| method == "POST"));
+    }
+
+    #[tokio::test]
+    async fn files_are_posted_anew_when_merging_into_the_idle_message_fails() {
+        let discord = MockServer::with_responder(|req, idx| {
+            if req.method == "PATCH" {
+                MockResponse::json(
+                    400,
+                    json!({ "message": "Invalid Form Body", "code": 50035 }),
+                )
+            } else {
+                MockResponse::message(format!("msg-{idx}"))
+            }
+        })
+        .await;
+        let dir = TempDir::new("merge-fallback");
+        let chart = dir.write("chart.png", "png");
+        let config = RuntimeConfig {
+            merge_files_window: Duration::from_secs(5),
+            ..RuntimeConfig::default()
+        };
+        let app = test_app_with(&discord, write_state(&dir, dir.path()), config);
+
+        let (status, _) = handle_opencode_event(
+            State(app.clone()),
+            Json(json!({ "projectName": "proj", "type": "session.idle", "text": "done" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let send = json!({ "projectName": "proj", "files": [chart.display().to_string()] });
+        let (status, _) = handle_send_files(State(app.clone()), Json(send.clone())).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = handle_send_files(State(app), Json(send)).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let requests: Vec<(String, String)> = discord
+            .requests()
+            .into_iter()
+            .map(|r| (r.method, r.path))
+            .collect();
+        let post = ("POST".to_string(), "/channels/ch-1/messages".to_string());
+        let patch = (
+            "PATCH".to_string(),
+            "/channels/ch-1/messages/msg-0".to_string(),
+        );
+        assert_eq!(requests, vec![post.clone(), patch, post.clone(), post]);
+        assert!(
+            discord.requests()[2]
+                .body_text()
+                .contains("filename=\"chart.png\"")
+        );
+    }
+
+    #[tokio::test]
+    async fn rejected_send_files_leaves_the_idle_message_to_merge_into() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("merge-rejected");
+        let chart = dir.write("chart.png", "png");
+        let config = RuntimeConfig {
+            merge_files_window: Duration::from_secs(5),
+            ..RuntimeConfig::default()
+        };
+        let app = test_app_with(&discord, write_state(&dir, dir.path()), config);
+
+        let (status, _) = handle_opencode_event(
+            State(app.clone()),
+            Json(json!({ "projectName": "proj", "type": "session.idle", "text": "done" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let missing = dir.path().join("missing.png").display().to_string();
+        let (status, _) = handle_send_files(
+            State(app.clone()),
+            Json(json!({ "projectName": "proj", "files": [missing] })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = handle_send_files(
+            State(app),
+            Json(json!({ "projectName": "proj", "files": [chart.display().to_string()] })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let requests = discord.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].method, "PATCH");
+    }
+
+    #[tokio::test]
+    async fn projects_route_to_their_own_discord_endpoints() {
+        let default_server = MockServer::start().await;
+        let other_server = MockServer::start().await;
+        let dir = TempDir::new("project-endpoints");
+        let state = json!({
+            "projects": {
+                "a": { "instances": { "opencode": { "agentType": "opencode", "channelId": "ch-a" } } },
+                "b": { "instances": { "opencode": { "agentType": "opencode", "channelId": "ch-b" } } }
+            }
+        });
+        let config = RuntimeConfig {
+            project_discord: HashMap::from([(
+                "b".to_string(),
+                ProjectDiscord {
+                    api_base: Some(other_server.url.clone()),
+                    token: Some("other-token".to_string()),
+                },
+            )]),
+            ..RuntimeConfig::default()
+        };
+        let app = test_app_with(
+            &default_server,
+            dir.write("state.json", state.to_string()),
+            config,
+        );
+
+        for project in ["a", "b"] {
+            let event = json!({ "projectName": project, "type": "session.idle", "text": "hi" });
+            let (status, _) = handle_opencode_event(State(app.clone()), Json(event)).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+
+        let paths = |server: &MockServer| -> Vec<String> {
+            server.requests().into_iter().map(|r| r.path).collect()
+        };
+        assert_eq!(paths(&default_server), vec!["/channels/ch-a/messages"]);
+        assert_eq!(paths(&other_server), vec!["/channels/ch-b/messages"]);
+    }
+
+    async fn idle_with_percent_path(decode: bool, on_disk: &str, referenced: &str) -> Vec<String> {
+        let mut config = RuntimeConfig::default();
+        config.format.decode_percent_paths = decode;
+        let harness = IdleHarness::start("percent", config).await;
+        harness.dir.write(on_disk, "png");
+
+        let text = format!("Chart: {}/{referenced}", harness.dir.path().display());
+        assert_eq!(harness.idle(&text).await, StatusCode::OK);
+        harness
+            .discord
+            .requests()
+            .into_iter()
+            .map(|r| r.body_text())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn percent_encoded_path_is_decoded_when_enabled() {
+        let bodies = idle_with_percent_path(true, "my file.png", "my%20file.png").await;
+
+        assert_eq!(bodies.len(), 2);
+        assert!(
+            !bodies[0].contains("%20"),
+            "encoded path stripped from text"
+        );
+        assert!(bodies[1].contains("filename=\"my file.png\""));
+    }
+
+    #[tokio::test]
+    async fn literal_percent_path_is_kept_when_disabled() {
+        let bodies = idle_with_percent_path(false, "my%20file.png", "my%20file.png").await;
+
+        assert_eq!(bodies.len(), 2);
+        assert!(bodies[1].contains("filename=\"my%20file.png\""));
+    }
+
+    #[tokio::test]
+    async fn multi_project_event_delivers_to_each_and_reports_per_project() {
+        let discord = MockServer::start().await;
+        let dir = TempDir::new("multi-project");
+        let state = json!({
+            "projects": {
+                "a": { "instances": { "opencode": { "agentType": "opencode", "channelId": "ch-a" } } },
+                "b": { "instances": { "opencode": { "agentType": "opencode", "channelId": "ch-b" } } }
+            }
+        });
+        let app = test_app(&discord, dir.write("state.json", state.to_string()));
+
+        let (status, body) = handle_opencode_event(
+            State(app),
+            Json(json!({
+                "projectName": "a",
+                "projectNames": ["b", "a", "missing"],
+                "type": "session.idle",
+                "text": "deployed",
+            })),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        let paths: Vec<_> = discord.requests().into_iter().map(|r| r.path).collect();
+        assert_eq!(
+            paths,
+            vec!["/channels/ch-a/messages", "/channels/ch-b/messages"]
+        );
+        let body: Value = serde_json::from_str(&body).unwrap();
+        let outcomes: Vec<_> = body["projects"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|p| (p["projectName"].clone(), p["ok"].clone()))
+            .collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                (json!("a"), json!(true)),
+                (json!("b"), json!(true)),
+                (json!("missing"), json!(false)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn retry_of_a_partial_delivery_only_reaches_failed_projects() {
+        let b_up = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let discord = MockServer::with_responder({
+            let b_up = Arc::clone(&b_up);
+            move |request, idx| {
+                if request.path.contains("ch-b") && !b_up.load(std::sync::atomic::Ordering::SeqCst)
+                {
+                    MockResponse::json(500, json!({ "message": "down" }))
+                } else {
+                    MockResponse::message(format!("msg-{idx}"))
+                }
+            }
+        })
+        .await;
+        let dir = TempDir::new("partial-retry");
+        let state = json!({
+            "projects": {
+                "a": { "instances": { "opencode": { "agentType": "opencode", "channelId": "ch-a" } } },
+                "b": { "instances": { "opencode": { "agentType": "opencode", "channelId": "ch-b" } } }
+            }
+        });
+        let mut config = RuntimeConfig {
+            event_dedup_capacity: 16,
+            ..RuntimeConfig::default()
+        };
+        config.discord.connect_retries = 0;
+        let app = test_app_with(&discord, dir.write("state.json", state.to_string()), config);
+        let event = json!({
+            "projectNames": ["a", "b"],
+            "type": "session.idle",
+            "text": "deployed",
+            "eventId": "turn-1",
+        });
+
+        let (status, _) = handle_opencode_event(State(app.clone()), Json(event.clone())).await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+
+        b_up.store(true, std::sync::atomic::Ordering::SeqCst);
+        let (status, _) = handle_opencode_event(State(app.clone()), Json(event.clone())).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = handle_opencode_event(State(app), Json(event)).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let posted_to = |channel: &str| {
+            discord
+                .requests()
+                .iter()
+                .filter(|r| r.path == format!("/channels/{channel}/messages"))
+                .count()
+        };
+        assert_eq!(posted_to("ch-a"), 1);
+        assert!(posted_to("ch-b") >= 2);
+        assert_eq!(
+            discord.requests().last().unwrap().path,
+            "/channels/ch-b/messages"
+        );
+    }
+
+    #[tokio::test]
+    async fn blank_lines_are_compacted_only_when_configured() {
+        let text = "Done.\n\n\n\nNext steps.";
+        for (max_blank_lines, expected) in [(None, text), (Some(1), "Done.\n\nNext steps.")] {
+            let discord = MockServer::start().await;
+            let dir = TempDir::new("blank-lines");
+            let mut config = RuntimeConfig::default();
+            config.format.max_blank_lines = max_blank_lines;
+            let app = test_app_with(&discord, write_state(&dir, dir.path()), config);
+
+            let (status, _) = handle_opencode_event(
+                State(app),
+                Json(json!({ "projectName": "proj", "type": "session.idle", "text": text })),
+            )
+            .await;
+
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(discord.requests()[0].json()["content"], json!(expected));
+        }
+    }
 }
@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Remembers the last idle message posted per instance so a `/send-files` that
+/// follows shortly after can be attached to it instead of posted separately.
+#[derive(Debug)]
+pub struct RecentIdleMessages {
+    window: Duration,
+    last: Mutex<HashMap<String, RecentIdle>>,
+}
+
+#[derive(Debug)]
+struct RecentIdle {
+    channel_id: String,
+    message_id: String,
+    at: Instant,
+}
+
+impl RecentIdleMessages {
+    /// A zero `window` disables merging.
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            last: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn record(&self, key: &str, channel_id: &str, message_id: &str) {
+        if self.window.is_zero() {
+            return;
+        }
+
+        let mut last = self.last.lock().unwrap();
+        last.retain(|_, idle| idle.at.elapsed() < self.window);
+        last.insert(
+            key.to_string(),
+            RecentIdle {
+                channel_id: channel_id.to_string(),
+                message_id: message_id.to_string(),
+                at: Instant::now(),
+            },
+        );
+    }
+
+    /// The message to attach files to for `key` in `channel_id`, if one was posted
+    /// within the window. It stays available until `used`.
+    pub fn get(&self, key: &str, channel_id: &str) -> Option<String> {
+        let last = self.last.lock().unwrap();
+        let idle = last.get(key)?;
+        (idle.at.elapsed() < self.window && idle.channel_id == channel_id)
+            .then(|| idle.message_id.clone())
+    }
+
+    /// Stop handing out `message_id` for `key` once files were delivered for it,
+    /// unless a newer idle message replaced it meanwhile.
+    pub fn used(&self, key: &str, message_id: &str) {
+        let mut last = self.last.lock().unwrap();
+        if last
+            .get(key)
+            .is_some_and(|idle| idle.message_id == message_id)
+        {
+            last.remove(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hands_out_recent_message_until_used() {
+        let recent = RecentIdleMessages::new(Duration::from_secs(5));
+        recent.record("proj/opencode", "ch-1", "msg-1");
+
+        assert_eq!(recent.get("proj/claude", "ch-1"), None);
+        assert_eq!(recent.get("proj/opencode", "ch-2"), None);
+
+        recent.record("proj/opencode", "ch-1", "msg-2");
+        recent.used("proj/opencode", "msg-1");
+        assert_eq!(
+            recent.get("proj/opencode", "ch-1").as_deref(),
+            Some("msg-2")
+        );
+        assert_eq!(
+            recent.get("proj/opencode", "ch-1").as_deref(),
+            Some("msg-2")
+        );
+        recent.used("proj/opencode", "msg-2");
+        assert_eq!(recent.get("proj/opencode", "ch-1"), None);
+    }
+
+    #[test]
+    fn ignores_messages_outside_window_or_when_disabled() {
+        let recent = RecentIdleMessages::new(Duration::from_millis(10));
+        recent.record("proj/opencode", "ch-1", "msg-1");
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(recent.get("proj/opencode", "ch-1"), None);
+
+        let disabled = RecentIdleMessages::new(Duration::ZERO);
+        disabled.record("proj/opencode", "ch-1", "msg-1");
+        assert_eq!(disabled.get("proj/opencode", "ch-1"), None);
+    }
+}
@@ -1,60 +1,238 @@
 use regex::Regex;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
 
 pub const DISCORD_MAX_MESSAGE_LENGTH: usize = 2000;
+pub const GENERIC_ATTACHMENT_PREFIX: &str = "📎";
+pub const TRUNCATION_SUFFIX: &str = "… (full output attached)";
+pub const DEFAULT_FILE_SEARCH_SCAN_BYTES: usize = 256 * 1024;
+pub const DEFAULT_FILE_SEARCH_MAX_BYTES: usize = 8 * 1024 * 1024;
 
 /// Split a message into chunks that respect Discord's 2000-character limit.
 /// Tries to split at newline/space boundaries before hard splits.
 pub fn split_message_for_discord(message: &str) -> Vec<String> {
-    if message.chars().count() <= DISCORD_MAX_MESSAGE_LENGTH {
+    split_message_with_limit(message, DISCORD_MAX_MESSAGE_LENGTH)
+}
+
+/// Chunks of at most `limit` characters. A chunk ending inside a code fence is
+/// closed with ` ``` ` and the next one reopens it with the same language tag.
+fn split_message_with_limit(message: &str, limit: usize) -> Vec<String> {
+    let limit = limit.max(1);
+    if message.chars().count() <= limit {
         return vec![message.to_string()];
     }
 
+    let fence_aware = limit >= MIN_FENCE_AWARE_LIMIT;
     let mut chunks = Vec::new();
     let mut remaining = message;
+    let mut open_fence: Option<String> = None;
 
     while !remaining.is_empty() {
-        let hard_split = remaining
-            .char_indices()
-            .nth(DISCORD_MAX_MESSAGE_LENGTH)
-            .map_or(remaining.len(), |(idx, _)| idx);
+        if !fence_aware {
+            let end = chunk_end(remaining, limit);
+            chunks.push(remaining[..end].to_string());
+            remaining = &remaining[end..];
+            continue;
+        }
 
-        let chunk_end = if hard_split == remaining.len() {
-            hard_split
-        } else {
-            let search_area = &remaining[..hard_split];
+        let reopen = open_fence
+            .as_deref()
+            .map_or_else(String::new, |lang| format!("```{lang}\n"));
+        let budget = limit - reopen.chars().count();
+        let mut end = chunk_end(remaining, budget);
+        let mut still_open = fence_after(&remaining[..end], open_fence.clone());
+        if still_open.is_some() && end < remaining.len() {
+            end = chunk_end(remaining, budget - CLOSING_FENCE.len());
+            still_open = fence_after(&remaining[..end], open_fence.clone());
+        }
 
-            if let Some(pos) = search_area.rfind('\n') {
-                if search_area[..pos].chars().count() >= DISCORD_MAX_MESSAGE_LENGTH / 2 {
-                    pos + 1
-                } else {
-                    search_area.rfind(' ').map_or(hard_split, |space| space + 1)
-                }
-            } else if let Some(pos) = search_area.rfind(' ') {
-                pos + 1
+        let mut chunk = reopen;
+        chunk.push_str(&remaining[..end]);
+        remaining = &remaining[end..];
+        if still_open.is_some() && !remaining.is_empty() {
+            let next_line = remaining.split_inclusive('\n').next().unwrap_or_default();
+            if chunk.ends_with('\n') && next_line.trim() == "```" {
+                // The block closes right here anyway; keep its own fence.
+                chunk.push_str(next_line);
+                remaining = &remaining[next_line.len()..];
+                still_open = None;
             } else {
-                hard_split
+                chunk.push_str(if chunk.ends_with('\n') {
+                    "```"
+                } else {
+                    CLOSING_FENCE
+                });
             }
-        };
-
-        chunks.push(remaining[..chunk_end].to_string());
-        remaining = &remaining[chunk_end..];
+        }
+        open_fence = still_open;
+        chunks.push(chunk);
     }
 
     chunks
 }
 
+/// Below this limit the fence markers would crowd out content, so chunks are
+/// split on length alone.
+const MIN_FENCE_AWARE_LIMIT: usize = 100;
+const CLOSING_FENCE: &str = "\n```";
+/// Longer language tags are not repeated when reopening a fence.
+const MAX_FENCE_LANGUAGE_CHARS: usize = 32;
+
+/// Byte index to end the next chunk of `remaining` at, preferring a newline in
+/// the second half of the window, then a space.
+fn chunk_end(remaining: &str, limit: usize) -> usize {
+    let hard_split = remaining
+        .char_indices()
+        .nth(limit)
+        .map_or(remaining.len(), |(idx, _)| idx);
+    if hard_split == remaining.len() {
+        return hard_split;
+    }
+
+    let search_area = &remaining[..hard_split];
+    if let Some(pos) = search_area.rfind('\n') {
+        if search_area[..pos].chars().count() >= limit / 2 {
+            pos + 1
+        } else {
+            search_area.rfind(' ').map_or(hard_split, |space| space + 1)
+        }
+    } else if let Some(pos) = search_area.rfind(' ') {
+        pos + 1
+    } else {
+        hard_split
+    }
+}
+
+/// The language tag of the fence still open after `text`, given the fence open
+/// before it (`Some("")` for an untagged fence).
+fn fence_after(text: &str, mut open: Option<String>) -> Option<String> {
+    for line in text.lines() {
+        let Some(info) = line.trim_start().strip_prefix("```") else {
+            continue;
+        };
+        open = match open {
+            Some(_) => None,
+            None => Some(
+                info.split_whitespace()
+                    .next()
+                    .filter(|lang| lang.chars().count() <= MAX_FENCE_LANGUAGE_CHARS)
+                    .unwrap_or_default()
+                    .to_string(),
+            ),
+        };
+    }
+    open
+}
+
 pub fn split_for_discord(message: &str) -> Vec<String> {
     split_message_for_discord(message)
 }
 
-/// Extract absolute file paths with supported extensions.
-pub fn extract_file_paths(text: &str) -> Vec<String> {
-    let path_re = Regex::new(
-        r#"(?i)(?:^|[\s`"'(\[])(/[^\s`"')\]]+\.(?:png|jpg|jpeg|gif|webp|svg|bmp|pdf|docx|pptx|xlsx|csv|json|txt))(?:$|[\s`"')\].,;:!?])"#,
-    )
+/// Split `message` into chunks of at most `limit` characters (capped at Discord's
+/// limit), then mark continuations: every chunk after the first starts with
+/// `prefix` and every chunk before the last ends with `suffix`. The markers
+/// count against the limit; when they would take more than half of it they are
+/// dropped so chunks keep room for content. Next to a code fence a marker goes on
+/// its own line, outside the fence.
+pub fn split_with_continuation_markers(
+    message: &str,
+    limit: usize,
+    prefix: &str,
+    suffix: &str,
+) -> Vec<String> {
+    let limit = limit.clamp(1, DISCORD_MAX_MESSAGE_LENGTH);
+    let reserved = prefix.chars().count() + suffix.chars().count();
+    if reserved == 0
+        || !continuation_markers_fit(limit, prefix, suffix)
+        || message.chars().count() <= limit
+    {
+        return split_message_with_limit(message, limit);
+    }
+
+    // Room for the line break that keeps a marker off a fence line.
+    let reserved = if message.contains("```") {
+        reserved + usize::from(!prefix.is_empty()) + usize::from(!suffix.is_empty())
+    } else {
+        reserved
+    };
+    let mut chunks = split_message_with_limit(message, limit - reserved);
+    let last = chunks.len() - 1;
+    for (idx, chunk) in chunks.iter_mut().enumerate() {
+        if idx > 0 && !prefix.is_empty() {
+            if chunk.starts_with("```") {
+                chunk.insert(0, '\n');
+            }
+            chunk.insert_str(0, prefix);
+        }
+        if idx < last && !suffix.is_empty() {
+            if chunk.trim_end().ends_with("```") && !chunk.ends_with('\n') {
+                chunk.push('\n');
+            }
+            chunk.push_str(suffix);
+        }
+    }
+    chunks
+}
+
+/// Whether continuation markers leave chunks of `limit` characters at least half
+/// their room for content; `split_with_continuation_markers` drops them otherwise.
+pub fn continuation_markers_fit(limit: usize, prefix: &str, suffix: &str) -> bool {
+    (prefix.chars().count() + suffix.chars().count()) * 2 <= limit
+}
+
+/// Extract absolute file paths, including home-relative ones (`~/out.png`),
+/// ending in one of `extensions` (any case).
+pub fn extract_file_paths(text: &str, extensions: &[String]) -> Vec<String> {
+    let Some(extensions) = extension_alternation(extensions) else {
+        return Vec::new();
+    };
+    let path_re = Regex::new(&format!(
+        r#"(?i)(?:^|[\s`"'(\[])(~?/[^\s`"')\]]+\.(?:{extensions}))(?:$|[\s`"')\].,;:!?])"#
+    ))
     .expect("valid file path regex");
+    collect_paths(&path_re, text)
+}
+
+/// Extract project-relative file paths with supported extensions. They need a
+/// directory part (`charts/b.png`, `./b.png`) so bare file names in prose and
+/// URLs aren't picked up.
+pub fn extract_relative_file_paths(text: &str, extensions: &[String]) -> Vec<String> {
+    let Some(extensions) = extension_alternation(extensions) else {
+        return Vec::new();
+    };
+    let path_re = Regex::new(&format!(
+        r#"(?i)(?:^|[\s`"'(\[])((?:\./[^\s`"')\]:]+|[\w-][^\s`"')\]:/]*/[^\s`"')\]:]+)\.(?:{extensions}))(?:$|[\s`"')\].,;:!?])"#
+    ))
+    .expect("valid relative path regex");
+    collect_paths(&path_re, text)
+}
+
+const DEFAULT_ATTACHMENT_EXTENSIONS: &[&str] = &[
+    "png", "jpg", "jpeg", "gif", "webp", "svg", "bmp", "pdf", "docx", "pptx", "xlsx", "csv",
+    "json", "txt",
+];
 
+/// Extensions whose paths are attached unless `attachmentExtensions` says otherwise.
+pub fn default_attachment_extensions() -> Vec<String> {
+    DEFAULT_ATTACHMENT_EXTENSIONS
+        .iter()
+        .map(|ext| ext.to_string())
+        .collect()
+}
+
+/// `extensions` as an escaped regex alternation, or `None` when there are none.
+fn extension_alternation(extensions: &[String]) -> Option<String> {
+    let alternation = extensions
+        .iter()
+        .map(|ext| ext.trim().trim_start_matches('.'))
+        .filter(|ext| !ext.is_empty())
+        .map(regex::escape)
+        .collect::<Vec<_>>()
+        .join("|");
+    (!alternation.is_empty()).then_some(alternation)
+}
+
+fn collect_paths(path_re: &Regex, text: &str) -> Vec<String> {
     let mut seen = HashSet::new();
     let mut paths = Vec::new();
 
@@ -72,6 +250,373 @@ pub fn extract_file_paths(text: &str) -> Vec<String> {
     paths
 }
 
+/// Replace typographic quotes, dashes and slash lookalikes with their ASCII
+/// forms so paths an agent wrapped in smart quotes still match extraction.
+pub fn normalize_typography(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{2032}' => '\'',
+            '\u{201C}' | '\u{201D}' | '\u{201E}' | '\u{2033}' => '"',
+            '\u{2010}' | '\u{2011}' | '\u{2012}' | '\u{2013}' | '\u{2014}' | '\u{2212}' => '-',
+            '\u{2044}' | '\u{2215}' | '\u{FF0F}' => '/',
+            '\u{00A0}' | '\u{202F}' => ' ',
+            other => other,
+        })
+        .collect()
+}
+
+/// How `original` spells each occurrence of `normalized`, a substring of
+/// `normalize_typography(original)`, where that differs from `normalized`.
+/// Normalization maps char for char, so char offsets line up between the two.
+pub fn original_spellings(original: &str, normalized: &str) -> Vec<String> {
+    let normalized_text = normalize_typography(original);
+    if normalized_text == original {
+        return Vec::new();
+    }
+
+    let char_starts = |text: &str| -> Vec<usize> {
+        text.char_indices()
+            .map(|(idx, _)| idx)
+            .chain(std::iter::once(text.len()))
+            .collect()
+    };
+    let original_starts = char_starts(original);
+    let normalized_starts = char_starts(&normalized_text);
+    let char_at = |byte: usize| normalized_starts.binary_search(&byte).ok();
+
+    let mut spellings = Vec::new();
+    for (idx, _) in normalized_text.match_indices(normalized) {
+        let (Some(start), Some(end)) = (char_at(idx), char_at(idx + normalized.len())) else {
+            continue;
+        };
+        let spelling = &original[original_starts[start]..original_starts[end]];
+        if spelling != normalized && !spellings.iter().any(|s| s == spelling) {
+            spellings.push(spelling.to_string());
+        }
+    }
+    spellings
+}
+
+/// Decode `%XX` escapes in a path. Paths with malformed escapes or that don't
+/// decode to UTF-8 are returned unchanged.
+pub fn percent_decode_path(path: &str) -> String {
+    if !path.contains('%') {
+        return path.to_string();
+    }
+
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        if bytes[idx] == b'%' {
+            let hex = path
+                .get(idx + 1..idx + 3)
+                .filter(|h| h.bytes().all(|b| b.is_ascii_hexdigit()))
+                .and_then(|h| u8::from_str_radix(h, 16).ok());
+            let Some(byte) = hex else {
+                return path.to_string();
+            };
+            decoded.push(byte);
+            idx += 3;
+        } else {
+            decoded.push(bytes[idx]);
+            idx += 1;
+        }
+    }
+
+    String::from_utf8(decoded).unwrap_or_else(|_| path.to_string())
+}
+
+/// Bound how much of `text` is scanned for file paths: `None` when it exceeds
+/// `max_bytes` (skip extraction), otherwise at most the first `scan_bytes`,
+/// cut on a char boundary.
+pub fn file_search_window(text: &str, scan_bytes: usize, max_bytes: usize) -> Option<&str> {
+    if text.len() > max_bytes {
+        return None;
+    }
+
+    if text.len() <= scan_bytes {
+        return Some(text);
+    }
+
+    let mut end = scan_bytes;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    Some(&text[..end])
+}
+
+/// Build a link to a project file from a template with `{baseUrl}` and
+/// `{relativePath}` placeholders, percent-encoding each path segment.
+pub fn file_link(template: &str, base_url: &str, relative_path: &str) -> String {
+    let encoded = relative_path
+        .replace('\\', "/")
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(encode_url_segment)
+        .collect::<Vec<_>>()
+        .join("/");
+
+    template
+        .replace("{baseUrl}", base_url.trim_end_matches('/'))
+        .replace("{relativePath}", &encoded)
+}
+
+/// Percent-encode everything but RFC 3986 unreserved characters.
+pub fn encode_url_segment(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+/// Drop every line matching any of `patterns`, e.g. tool-call scaffolding.
+pub fn strip_lines_matching(text: &str, patterns: &[Regex]) -> String {
+    if patterns.is_empty() {
+        return text.to_string();
+    }
+
+    text.lines()
+        .filter(|line| !patterns.iter().any(|re| re.is_match(line)))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Shorten `text` to at most `limit` characters (and one Discord message),
+/// ending in a marker that the full output is attached.
+pub fn truncate_preview(text: &str, limit: usize) -> String {
+    let limit = limit.clamp(1, DISCORD_MAX_MESSAGE_LENGTH);
+    if text.chars().count() <= limit {
+        return text.to_string();
+    }
+
+    let suffix_len = TRUNCATION_SUFFIX.chars().count();
+    if limit <= suffix_len {
+        return text.chars().take(limit).collect();
+    }
+
+    let kept: String = text.chars().take(limit - suffix_len).collect();
+    format!("{}{TRUNCATION_SUFFIX}", kept.trim_end())
+}
+
+/// Limit runs of blank lines to `max_blank` outside code fences, and drop
+/// leading and trailing blank lines.
+pub fn compact_blank_lines(text: &str, max_blank: usize) -> String {
+    let mut out: Vec<&str> = Vec::new();
+    let mut in_fence = false;
+    let mut blank_run = 0;
+
+    for line in text.lines() {
+        if line.trim_start().starts_with("```") {
+            in_fence = !in_fence;
+        }
+
+        if !in_fence && line.trim().is_empty() {
+            blank_run += 1;
+            if blank_run > max_blank || out.is_empty() {
+                continue;
+            }
+            out.push("");
+            continue;
+        }
+
+        blank_run = 0;
+        out.push(line);
+    }
+
+    while out.last().is_some_and(|line| line.trim().is_empty()) {
+        out.pop();
+    }
+    out.join("\n")
+}
+
+/// Collapse runs of at least `min_run` identical consecutive lines into
+/// `line  (×N)`. Lines inside code fences are left as they are.
+pub fn collapse_repeated_lines(text: &str, min_run: usize) -> String {
+    let min_run = min_run.max(2);
+    let mut out: Vec<String> = Vec::new();
+    let mut in_fence = false;
+    let mut run: Option<(&str, usize)> = None;
+
+    let flush = |out: &mut Vec<String>, run: Option<(&str, usize)>| {
+        if let Some((line, count)) = run {
+            if count >= min_run {
+                out.push(format!("{line}  (×{count})"));
+            } else {
+                out.extend(std::iter::repeat_n(line.to_string(), count));
+            }
+        }
+    };
+
+    for line in text.lines() {
+        let is_fence = line.trim_start().starts_with("```");
+        if in_fence || is_fence {
+            flush(&mut out, run.take());
+            out.push(line.to_string());
+            if is_fence {
+                in_fence = !in_fence;
+            }
+            continue;
+        }
+
+        match run {
+            Some((prev, count)) if prev == line && !line.trim().is_empty() => {
+                run = Some((prev, count + 1));
+            }
+            _ => {
+                flush(&mut out, run.take());
+                run = Some((line, 1));
+            }
+        }
+    }
+    flush(&mut out, run);
+
+    out.join("\n")
+}
+
+/// Whether `pattern` contains glob wildcards (`*` or `?`).
+pub fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?'])
+}
+
+/// Match one path component against a glob where `*` is any run of characters
+/// and `?` is exactly one.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    n = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Turn markdown headings (`#` to `######` followed by a space) outside code fences
+/// into bold lines, since Discord renders only some heading levels.
+pub fn bold_headings(text: &str) -> String {
+    let mut in_fence = false;
+
+    text.lines()
+        .map(|line| {
+            if line.trim_start().starts_with("```") {
+                in_fence = !in_fence;
+                return line.to_string();
+            }
+            if in_fence {
+                return line.to_string();
+            }
+
+            let hashes = line.chars().take_while(|&c| c == '#').count();
+            let heading = line[hashes..]
+                .strip_prefix(' ')
+                .map(|rest| rest.trim().trim_end_matches('#').trim_end());
+            match heading {
+                Some(heading) if (1..=6).contains(&hashes) && !heading.is_empty() => {
+                    format!("**{heading}**")
+                }
+                _ => line.to_string(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Fill `{project}`, `{agent}`, `{instance}` and `{text}` in `template` in one pass,
+/// so placeholders inside the substituted values are left alone. Unknown
+/// placeholders are kept verbatim.
+pub fn apply_message_template(template: &str, fields: &[(&str, &str)], text: &str) -> String {
+    let mut out = String::with_capacity(template.len() + text.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let Some(end) = after.find('}') else {
+            out.push_str(&rest[start..]);
+            return out;
+        };
+
+        let name = &after[..end];
+        match fields.iter().find(|(key, _)| *key == name) {
+            Some((_, value)) => out.push_str(value),
+            None if name == "text" => out.push_str(text),
+            None => out.push_str(&rest[start..start + end + 2]),
+        }
+        rest = &after[end + 1..];
+    }
+
+    out.push_str(rest);
+    out
+}
+
+/// Wrap `text` in a code fence when it is a JSON document (pretty-printed, tagged
+/// `json`) or mostly looks like code (tagged `language`, else `text`). Text that
+/// already contains a fence is returned unchanged.
+pub fn wrap_code_block(text: &str, language: Option<&str>) -> String {
+    let trimmed = text.trim();
+    if trimmed.is_empty() || trimmed.contains("```") {
+        return text.to_string();
+    }
+
+    if (trimmed.starts_with('{') || trimmed.starts_with('['))
+        && let Ok(value) = serde_json::from_str::<serde_json::Value>(trimmed)
+        && let Ok(pretty) = serde_json::to_string_pretty(&value)
+    {
+        return format!("```json\n{pretty}\n```");
+    }
+
+    if !looks_like_code(trimmed) {
+        return text.to_string();
+    }
+
+    format!("```{}\n{trimmed}\n```", language.unwrap_or("text"))
+}
+
+fn looks_like_code(text: &str) -> bool {
+    let lines: Vec<&str> = text.lines().filter(|l| !l.trim().is_empty()).collect();
+    if lines.len() < 2 {
+        return false;
+    }
+
+    let code_like = lines
+        .iter()
+        .filter(|line| {
+            let end = line.trim_end();
+            line.starts_with("    ")
+                || line.starts_with('\t')
+                || end.ends_with(';')
+                || end.ends_with('{')
+                || end.ends_with('}')
+        })
+        .count();
+    code_like * 2 > lines.len()
+}
+
 /// Remove absolute file paths from user-visible text.
 pub fn strip_file_paths(text: &str, file_paths: &[String]) -> String {
     let mut result = text.to_string();
@@ -79,15 +624,20 @@ pub fn strip_file_paths(text: &str, file_paths: &[String]) -> String {
     for path in file_paths {
         let escaped = regex::escape(path);
 
-        let image_re =
-            Regex::new(&format!(r#"!\[[^\]]*\]\({escaped}\)"#)).expect("valid image regex");
-        result = image_re.replace_all(&result, "").to_string();
+        // Image embeds and links alike; an optional `"title"` may follow the target.
+        let link_re = Regex::new(&format!(r#"!?\[[^\]]*\]\(<?{escaped}>?(?:\s+"[^"]*")?\)"#))
+            .expect("valid link regex");
+        result = link_re.replace_all(&result, "").to_string();
 
         let tick_re = Regex::new(&format!(r#"`{escaped}`"#)).expect("valid backtick regex");
         result = tick_re.replace_all(&result, "").to_string();
 
-        let path_re = Regex::new(&escaped).expect("valid path regex");
-        result = path_re.replace_all(&result, "").to_string();
+        // Parentheses or brackets wrapping nothing but the path go with it.
+        let wrapped_re =
+            Regex::new(&format!(r#"\({escaped}\)|\[{escaped}\]"#)).expect("valid wrapped regex");
+        result = wrapped_re.replace_all(&result, "").to_string();
+
+        result = strip_bare_path(&result, path);
     }
 
     let newline_re = Regex::new(r#"\n{3,}"#).expect("valid newline regex");
@@ -97,6 +647,162 @@ pub fn strip_file_paths(text: &str, file_paths: &[String]) -> String {
     blank_ws_line.replace_all(&result, "").to_string()
 }
 
+/// Remove `path` where it stands on its own: after the start, whitespace, a
+/// straight or curly quote, a backtick or an opening bracket, and before the end,
+/// whitespace, a closer or trailing punctuation. `out/a.png` stays in
+/// `docs/out/a.png` and `out/a.png.bak`.
+fn strip_bare_path(text: &str, path: &str) -> String {
+    if path.is_empty() {
+        return text.to_string();
+    }
+    let starts_at_boundary = |before: &str| {
+        before.chars().next_back().is_none_or(|c| {
+            c.is_whitespace() || matches!(c, '"' | '\'' | '`' | '(' | '[' | '\u{201C}' | '\u{2018}')
+        })
+    };
+    let ends_at_boundary = |after: &str| {
+        after
+            .trim_start_matches(['.', ',', ';', ':', '!', '?'])
+            .chars()
+            .next()
+            .is_none_or(|c| {
+                c.is_whitespace()
+                    || matches!(c, '"' | '\'' | '`' | ')' | ']' | '\u{201D}' | '\u{2019}')
+            })
+    };
+
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(pos) = rest.find(path) {
+        let end = pos + path.len();
+        let before = &text[..text.len() - rest.len() + pos];
+        if starts_at_boundary(before) && ends_at_boundary(&rest[end..]) {
+            result.push_str(&rest[..pos]);
+        } else {
+            result.push_str(&rest[..end]);
+        }
+        rest = &rest[end..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Caption prefixes keyed by lowercase extension.
+pub fn default_attachment_prefixes() -> HashMap<String, String> {
+    [
+        ("png", "🖼️"),
+        ("jpg", "🖼️"),
+        ("jpeg", "🖼️"),
+        ("gif", "🖼️"),
+        ("webp", "🖼️"),
+        ("svg", "🖼️"),
+        ("bmp", "🖼️"),
+        ("pdf", "📄"),
+        ("docx", "📄"),
+        ("txt", "📝"),
+        ("json", "📝"),
+        ("csv", "📊"),
+        ("xlsx", "📊"),
+        ("pptx", "📽️"),
+    ]
+    .into_iter()
+    .map(|(ext, prefix)| (ext.to_string(), prefix.to_string()))
+    .collect()
+}
+
+pub fn attachment_prefix<'a>(path: &str, prefixes: &'a HashMap<String, String>) -> &'a str {
+    Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(|ext| prefixes.get(&ext.to_ascii_lowercase()))
+        .map_or(GENERIC_ATTACHMENT_PREFIX, String::as_str)
+}
+
+/// Build a one-line caption such as `🖼️ chart.png · 📄 report.pdf`, cut short to fit
+/// a single Discord message.
+pub fn attachment_caption(file_paths: &[String], prefixes: &HashMap<String, String>) -> String {
+    let mut caption = String::new();
+
+    for (idx, path) in file_paths.iter().enumerate() {
+        let name = Path::new(path)
+            .file_name()
+            .map_or_else(|| path.clone(), |n| n.to_string_lossy().into_owned());
+        let entry = format!("{} {name}", attachment_prefix(path, prefixes));
+        let separator = if caption.is_empty() { "" } else { " · " };
+
+        let remaining = file_paths.len() - idx;
+        let needed = caption.chars().count() + separator.chars().count() + entry.chars().count();
+        // Keep room for a trailing " …" when more entries follow.
+        let reserve = if remaining > 1 { 2 } else { 0 };
+        if needed + reserve > DISCORD_MAX_MESSAGE_LENGTH {
+            caption.push_str(" …");
+            break;
+        }
+
+        caption.push_str(separator);
+        caption.push_str(&entry);
+    }
+
+    caption
+}
+
+/// Build a footer such as `📎 out.png, report.pdf` naming the attached files, cut
+/// short to fit a single Discord message.
+pub fn file_footer(file_paths: &[String]) -> String {
+    let mut footer = "📎".to_string();
+
+    for (idx, path) in file_paths.iter().enumerate() {
+        let name = Path::new(path)
+            .file_name()
+            .map_or_else(|| path.clone(), |n| n.to_string_lossy().into_owned());
+        let separator = if idx == 0 { " " } else { ", " };
+
+        let remaining = file_paths.len() - idx;
+        let needed = footer.chars().count() + separator.chars().count() + name.chars().count();
+        let reserve = if remaining > 1 { 2 } else { 0 };
+        if needed + reserve > DISCORD_MAX_MESSAGE_LENGTH {
+            footer.push_str(" …");
+            break;
+        }
+
+        footer.push_str(separator);
+        footer.push_str(&name);
+    }
+
+    footer
+}
+
+/// Re-split any chunk over `limit` after decoration, leaving the others as-is.
+pub fn fit_chunks(chunks: Vec<String>, limit: usize) -> Vec<String> {
+    let limit = limit.clamp(1, DISCORD_MAX_MESSAGE_LENGTH);
+    chunks
+        .into_iter()
+        .flat_map(|chunk| {
+            if chunk.chars().count() <= limit {
+                vec![chunk]
+            } else {
+                split_message_with_limit(&chunk, limit)
+            }
+        })
+        .collect()
+}
+
+/// Append `footer` to the last chunk, or send it as its own chunks when the last
+/// one has no room left within `limit`.
+pub fn append_footer(chunks: &mut Vec<String>, footer: &str, limit: usize) {
+    let limit = limit.clamp(1, DISCORD_MAX_MESSAGE_LENGTH);
+    match chunks.last_mut() {
+        Some(last)
+            if !last.trim().is_empty()
+                && last.chars().count() + 1 + footer.chars().count() <= limit =>
+        {
+            last.push('\n');
+            last.push_str(footer);
+        }
+        _ => chunks.extend(split_message_with_limit(footer, limit)),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -144,10 +850,67 @@ mod tests {
         assert!(chunks[1].starts_with('b'));
     }
 
+    fn fence_lines(chunk: &str) -> Vec<&str> {
+        chunk
+            .lines()
+            .filter(|line| line.trim_start().starts_with("```"))
+            .collect()
+    }
+
+    #[test]
+    fn split_closes_and_reopens_a_code_block_across_the_limit() {
+        let code = "let x = 1;\n".repeat(300);
+        let msg = format!("intro\n```rust\n{code}```\noutro");
+        let chunks = split_message_for_discord(&msg);
+
+        assert_eq!(chunks.len(), 2);
+        assert!(
+            chunks
+                .iter()
+                .all(|c| c.chars().count() <= DISCORD_MAX_MESSAGE_LENGTH)
+        );
+        assert!(chunks[0].starts_with("intro\n```rust\n"));
+        assert!(chunks[0].ends_with("let x = 1;\n```"));
+        assert!(chunks[1].starts_with("```rust\nlet x = 1;\n"));
+        assert!(chunks[1].ends_with("```\noutro"));
+        for chunk in &chunks {
+            assert_eq!(fence_lines(chunk).len() % 2, 0, "unbalanced: {chunk}");
+        }
+        let lines = |text: &str| text.matches("let x = 1;").count();
+        assert_eq!(chunks.iter().map(|c| lines(c)).sum::<usize>(), 300);
+    }
+
+    #[test]
+    fn split_tracks_fences_across_consecutive_blocks() {
+        let block = |lang: &str, line: &str| format!("```{lang}\n{}```\n", line.repeat(120));
+        let msg = format!(
+            "{}{}{}",
+            block("py", "print('hello')\n"),
+            block("js", "console.log(1);\n"),
+            block("", "plain text line\n"),
+        );
+        let chunks = split_message_for_discord(&msg);
+
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(chunk.chars().count() <= DISCORD_MAX_MESSAGE_LENGTH);
+            assert_eq!(fence_lines(chunk).len() % 2, 0, "unbalanced: {chunk}");
+        }
+        let reopened: Vec<&str> = chunks[1..]
+            .iter()
+            .map(|chunk| chunk.lines().next().unwrap())
+            .collect();
+        assert_eq!(reopened, vec!["```js", "```"]);
+        let text: String = chunks.concat();
+        assert_eq!(text.matches("print('hello')").count(), 120);
+        assert_eq!(text.matches("console.log(1);").count(), 120);
+        assert_eq!(text.matches("plain text line").count(), 120);
+    }
+
     #[test]
     fn extract_file_paths_deduplicates() {
         let text = "See `/tmp/a.png` and again /tmp/a.png and /tmp/b.pdf";
-        let paths = extract_file_paths(text);
+        let paths = extract_file_paths(text, &default_attachment_extensions());
         assert_eq!(
             paths,
             vec!["/tmp/a.png".to_string(), "/tmp/b.pdf".to_string()]
@@ -162,4 +925,411 @@ mod tests {
         assert!(!stripped.contains(&path));
         assert!(stripped.contains("Result:"));
     }
+
+    #[test]
+    fn strip_file_paths_leaves_longer_paths_alone() {
+        let path = "out/a.png".to_string();
+        let text = "Wrote out/a.png, kept /x/out/a.png.bak and docs/out/a.png.";
+        assert_eq!(
+            strip_file_paths(text, std::slice::from_ref(&path)),
+            "Wrote , kept /x/out/a.png.bak and docs/out/a.png."
+        );
+        assert_eq!(
+            strip_file_paths("see out/a.png.bak", std::slice::from_ref(&path)),
+            "see out/a.png.bak"
+        );
+    }
+
+    #[test]
+    fn strip_file_paths_removes_links_and_empty_remnants() {
+        let image = "/tmp/out/chart.png".to_string();
+        let report = "/tmp/out/report.pdf".to_string();
+        let text = format!(
+            "Done ![chart]({image}) and [the report]({report} \"PDF\").\n\
+             Also `{image}`, ({report}) and [{image}].\n\
+             Keep [docs](https://example.com) and call foo()."
+        );
+        let stripped = strip_file_paths(&text, &[image.clone(), report.clone()]);
+        assert_eq!(
+            stripped,
+            "Done  and .\nAlso ,  and .\nKeep [docs](https://example.com) and call foo()."
+        );
+    }
+
+    #[test]
+    fn attachment_prefix_matches_extension_case_insensitively() {
+        let prefixes = default_attachment_prefixes();
+        assert_eq!(attachment_prefix("/tmp/chart.PNG", &prefixes), "🖼️");
+        assert_eq!(attachment_prefix("/tmp/report.pdf", &prefixes), "📄");
+        assert_eq!(attachment_prefix("/tmp/data.csv", &prefixes), "📊");
+    }
+
+    #[test]
+    fn attachment_prefix_falls_back_to_generic() {
+        let prefixes = default_attachment_prefixes();
+        assert_eq!(
+            attachment_prefix("/tmp/archive.zip", &prefixes),
+            GENERIC_ATTACHMENT_PREFIX
+        );
+        assert_eq!(
+            attachment_prefix("/tmp/Makefile", &prefixes),
+            GENERIC_ATTACHMENT_PREFIX
+        );
+    }
+
+    #[test]
+    fn attachment_caption_lists_prefixed_basenames() {
+        let prefixes = default_attachment_prefixes();
+        let files = vec![
+            "/tmp/out/chart.png".to_string(),
+            "/tmp/out/report.pdf".to_string(),
+        ];
+        assert_eq!(
+            attachment_caption(&files, &prefixes),
+            "🖼️ chart.png · 📄 report.pdf"
+        );
+    }
+
+    #[test]
+    fn file_search_window_truncates_scan_to_limit() {
+        let text = format!("/tmp/early.png {}/tmp/late.png", "x ".repeat(100));
+        let window = file_search_window(&text, 20, 1024).unwrap();
+        assert_eq!(window, &text[..20]);
+        assert_eq!(
+            extract_file_paths(window, &default_attachment_extensions()),
+            vec!["/tmp/early.png".to_string()]
+        );
+    }
+
+    #[test]
+    fn file_search_window_respects_char_boundaries() {
+        let text = "🦀".repeat(10);
+        let window = file_search_window(&text, 5, 1024).unwrap();
+        assert_eq!(window, "🦀");
+    }
+
+    #[test]
+    fn file_search_window_skips_text_over_hard_limit() {
+        let text = "a".repeat(64);
+        assert_eq!(file_search_window(&text, 16, 32), None);
+        assert_eq!(file_search_window(&text, 128, 128), Some(text.as_str()));
+    }
+
+    #[test]
+    fn file_link_substitutes_template_and_encodes_segments() {
+        assert_eq!(
+            file_link(
+                "{baseUrl}/{relativePath}",
+                "https://files.internal/proj/",
+                "out/big report.pdf"
+            ),
+            "https://files.internal/proj/out/big%20report.pdf"
+        );
+        assert_eq!(
+            file_link("{baseUrl}/raw?path={relativePath}", "http://h", "a/b#1.bin"),
+            "http://h/raw?path=a/b%231.bin"
+        );
+    }
+
+    #[test]
+    fn wraps_code_with_configured_language() {
+        let code = "fn main() {\n    println!(\"hi\");\n}";
+
+        assert_eq!(
+            wrap_code_block(code, Some("rust")),
+            format!("```rust\n{code}\n```")
+        );
+        assert_eq!(wrap_code_block(code, None), format!("```text\n{code}\n```"));
+    }
+
+    #[test]
+    fn wraps_json_as_json_and_leaves_prose_alone() {
+        assert_eq!(
+            wrap_code_block(r#"{"a":1}"#, Some("rust")),
+            "```json\n{\n  \"a\": 1\n}\n```"
+        );
+
+        let prose = "Done.\nUpdated the parser and added tests.";
+        assert_eq!(wrap_code_block(prose, Some("rust")), prose);
+        let fenced = "```rust\nlet x = 1;\n```";
+        assert_eq!(wrap_code_block(fenced, Some("rust")), fenced);
+    }
+
+    #[test]
+    fn extracts_curly_quoted_path_after_normalization() {
+        let text = "Saved to \u{201C}/tmp/out\u{2013}v2.png\u{201D}.";
+        assert!(extract_file_paths(text, &default_attachment_extensions()).is_empty());
+
+        let normalized = normalize_typography(text);
+        assert_eq!(normalized, "Saved to \"/tmp/out-v2.png\".");
+        assert_eq!(
+            extract_file_paths(&normalized, &default_attachment_extensions()),
+            vec!["/tmp/out-v2.png"]
+        );
+    }
+
+    #[test]
+    fn original_spellings_map_normalized_paths_back() {
+        let text = "Saved /tmp/out\u{2013}v2.png and\u{00A0}/tmp/out-v2.png.";
+        assert_eq!(
+            original_spellings(text, "/tmp/out-v2.png"),
+            vec!["/tmp/out\u{2013}v2.png"]
+        );
+        assert!(original_spellings("plain /tmp/a.png", "/tmp/a.png").is_empty());
+    }
+
+    #[test]
+    fn strips_lines_matching_patterns() {
+        let patterns = vec![
+            Regex::new(r"^\[tool: [^\]]+\]").unwrap(),
+            Regex::new(r"^\s*<thinking>").unwrap(),
+        ];
+        let text = "Reading files.\n[tool: read_file] src/main.rs\n  <thinking> hmm\nAll done.";
+
+        assert_eq!(
+            strip_lines_matching(text, &patterns),
+            "Reading files.\nAll done."
+        );
+        assert_eq!(strip_lines_matching(text, &[]), text);
+    }
+
+    #[test]
+    fn collapses_runs_of_repeated_lines() {
+        let text = format!("start\n{}done\nok\nok", "downloading…\n".repeat(120));
+
+        assert_eq!(
+            collapse_repeated_lines(&text, 3),
+            "start\ndownloading…  (×120)\ndone\nok\nok"
+        );
+    }
+
+    #[test]
+    fn does_not_collapse_inside_code_fences() {
+        let text = "```\nx\nx\nx\n```\ny\ny\ny";
+
+        assert_eq!(
+            collapse_repeated_lines(text, 3),
+            "```\nx\nx\nx\n```\ny  (×3)"
+        );
+    }
+
+    #[test]
+    fn truncate_preview_fits_limit_with_marker() {
+        let text = "word ".repeat(100);
+        let preview = truncate_preview(&text, 60);
+
+        assert!(preview.chars().count() <= 60);
+        assert!(preview.ends_with(TRUNCATION_SUFFIX));
+        assert_eq!(truncate_preview("short", 60), "short");
+        assert_eq!(
+            truncate_preview(&"x".repeat(5000), 9000).chars().count(),
+            2000
+        );
+    }
+
+    #[test]
+    fn percent_decodes_paths_conservatively() {
+        assert_eq!(
+            percent_decode_path("/tmp/my%20file.png"),
+            "/tmp/my file.png"
+        );
+        assert_eq!(
+            percent_decode_path("/tmp/%C3%A9t%C3%A9.png"),
+            "/tmp/été.png"
+        );
+        assert_eq!(percent_decode_path("/tmp/100%.png"), "/tmp/100%.png");
+        assert_eq!(percent_decode_path("/tmp/%zz.png"), "/tmp/%zz.png");
+        assert_eq!(percent_decode_path("/tmp/a%+5.png"), "/tmp/a%+5.png");
+        assert_eq!(percent_decode_path("/tmp/%FF.png"), "/tmp/%FF.png");
+    }
+
+    #[test]
+    fn compacts_blank_line_runs_outside_fences() {
+        let text = "\n\nintro\n\n\n\nbody\n \n\t\nend\n\n";
+        assert_eq!(compact_blank_lines(text, 1), "intro\n\nbody\n\nend");
+        assert_eq!(compact_blank_lines(text, 0), "intro\nbody\nend");
+        assert_eq!(compact_blank_lines("a\n\n\n\nb", 2), "a\n\n\nb");
+
+        let fenced = "```\nx\n\n\n\ny\n```";
+        assert_eq!(compact_blank_lines(fenced, 1), fenced);
+    }
+
+    #[test]
+    fn file_footer_lists_basenames() {
+        let files = vec!["/p/out/out.png".to_string(), "/p/report.pdf".to_string()];
+        assert_eq!(file_footer(&files), "📎 out.png, report.pdf");
+    }
+
+    #[test]
+    fn file_footer_respects_message_limit() {
+        let files: Vec<String> = (0..300).map(|i| format!("/p/file-{i:04}.txt")).collect();
+        let footer = file_footer(&files);
+        assert!(footer.chars().count() <= DISCORD_MAX_MESSAGE_LENGTH);
+        assert!(footer.ends_with(" …"));
+
+        let mut chunks = vec!["a".repeat(DISCORD_MAX_MESSAGE_LENGTH - 5)];
+        append_footer(&mut chunks, "📎 out.png", DISCORD_MAX_MESSAGE_LENGTH);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[1], "📎 out.png");
+
+        let mut chunks = vec!["done".to_string()];
+        append_footer(&mut chunks, "📎 out.png", DISCORD_MAX_MESSAGE_LENGTH);
+        assert_eq!(chunks, vec!["done\n📎 out.png"]);
+    }
+
+    #[test]
+    fn continuation_markers_mark_the_right_chunks_within_limit() {
+        let msg = "word ".repeat(900);
+        let chunks = split_with_continuation_markers(&msg, DISCORD_MAX_MESSAGE_LENGTH, "… ", " …");
+
+        assert_eq!(chunks.len(), 3);
+        assert!(!chunks[0].starts_with('…') && chunks[0].ends_with(" …"));
+        assert!(chunks[1].starts_with("… ") && chunks[1].ends_with(" …"));
+        assert!(chunks[2].starts_with("… ") && !chunks[2].ends_with('…'));
+        assert!(
+            chunks
+                .iter()
+                .all(|c| c.chars().count() <= DISCORD_MAX_MESSAGE_LENGTH)
+        );
+
+        let prefix_only =
+            split_with_continuation_markers(&msg, DISCORD_MAX_MESSAGE_LENGTH, "↪ ", "");
+        assert!(prefix_only[0].ends_with(' ') && prefix_only[1].starts_with("↪ "));
+        assert_eq!(
+            split_with_continuation_markers("short", DISCORD_MAX_MESSAGE_LENGTH, "… ", " …"),
+            vec!["short"]
+        );
+    }
+
+    #[test]
+    fn continuation_markers_stay_outside_reopened_fences() {
+        let code = (0..60)
+            .map(|i| format!("let value_{i} = compute({i});"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let msg = format!("Here it is:\n```rust\n{code}\n```\nDone.");
+        let chunks = split_with_continuation_markers(&msg, 500, "… ", " …");
+
+        assert!(chunks.len() > 2);
+        assert!(chunks.iter().all(|c| c.chars().count() <= 500));
+        for (idx, chunk) in chunks.iter().enumerate() {
+            let lines: Vec<&str> = chunk.lines().collect();
+            if idx > 0 {
+                assert_eq!(lines[0], "… ", "prefix on its own line in {chunk:?}");
+                assert!(lines[1].starts_with("```rust") || !chunk.contains("```"));
+            }
+            if idx < chunks.len() - 1 {
+                assert_eq!(lines[lines.len() - 1], " …", "suffix on its own line");
+                assert_eq!(lines[lines.len() - 2], "```");
+            }
+            let fences = lines.iter().filter(|l| l.starts_with("```")).count();
+            assert_eq!(fences % 2, 0, "balanced fences in {chunk:?}");
+        }
+    }
+
+    #[test]
+    fn tiny_limits_never_produce_oversized_chunks() {
+        let msg = "alpha beta gamma delta epsilon zeta eta theta";
+        let fits = |chunks: &[String]| chunks.iter().all(|c| c.chars().count() <= 10);
+
+        let marked = split_with_continuation_markers(msg, 10, "… ", "");
+        assert!(fits(&marked) && marked.len() > 1);
+        assert!(marked[1..].iter().all(|c| c.starts_with("… ")));
+
+        assert!(continuation_markers_fit(10, "… ", ""));
+        assert!(!continuation_markers_fit(10, "[continued] ", " [more]"));
+        let unmarked = split_with_continuation_markers(msg, 10, "[continued] ", " [more]");
+        assert!(fits(&unmarked));
+        assert_eq!(unmarked.concat(), msg);
+
+        let mut chunks = marked;
+        append_footer(&mut chunks, "📎 chart.png, report.pdf", 10);
+        assert!(fits(&chunks));
+        assert!(chunks.iter().all(|c| !c.is_empty()));
+    }
+
+    #[test]
+    fn fit_chunks_resplits_only_oversized_chunks() {
+        let chunks = vec!["short".to_string(), "a much longer chunk".to_string()];
+        assert_eq!(
+            fit_chunks(chunks, 8),
+            vec!["short", "a much ", "longer ", "chunk"]
+        );
+    }
+
+    #[test]
+    fn extract_file_paths_uses_the_given_extensions() {
+        let extensions = vec!["md".to_string(), ".tar.gz".to_string()];
+        let text = "Wrote /tmp/NOTES.MD, /tmp/dist.tar.gz and /tmp/chart.png (not /tmp/a.mdx)";
+        assert_eq!(
+            extract_file_paths(text, &extensions),
+            vec!["/tmp/NOTES.MD", "/tmp/dist.tar.gz"]
+        );
+        assert!(extract_file_paths(text, &[]).is_empty());
+    }
+
+    #[test]
+    fn home_relative_paths_are_extracted_whole() {
+        let text = "Saved ~/out.png and `~/charts/b.pdf` (not ~user/c.png)";
+        let extensions = default_attachment_extensions();
+        assert_eq!(
+            extract_file_paths(text, &extensions),
+            vec!["~/out.png", "~/charts/b.pdf"]
+        );
+        assert!(extract_relative_file_paths(text, &extensions).is_empty());
+    }
+
+    #[test]
+    fn relative_paths_need_a_directory_part() {
+        let text = "Saved charts/b.png and ./out.csv (see report.pdf, /abs/c.png, \
+                    https://example.com/x.png)";
+        assert_eq!(
+            extract_relative_file_paths(text, &default_attachment_extensions()),
+            vec!["charts/b.png", "./out.csv"]
+        );
+        assert_eq!(
+            extract_file_paths(text, &default_attachment_extensions()),
+            vec!["/abs/c.png"]
+        );
+    }
+
+    #[test]
+    fn message_template_substitutes_placeholders_once() {
+        let fields = [
+            ("project", "proj"),
+            ("agent", "claude"),
+            ("instance", "claude-2"),
+        ];
+        assert_eq!(
+            apply_message_template(
+                "**[{project}/{agent}]** {text} ({instance}) {other}",
+                &fields,
+                "uses {agent}"
+            ),
+            "**[proj/claude]** uses {agent} (claude-2) {other}"
+        );
+        assert_eq!(apply_message_template("{text", &fields, "x"), "{text");
+    }
+
+    #[test]
+    fn bold_headings_converts_h1_to_h6_only() {
+        let text = "# One\n## Two ##\n### Three\n#### Four\n##### Five\n###### Six\n####### Seven";
+        assert_eq!(
+            bold_headings(text),
+            "**One**\n**Two**\n**Three**\n**Four**\n**Five**\n**Six**\n####### Seven"
+        );
+
+        let untouched = "#include <stdio.h>\n#hashtag\n# \n```\n# comment\n```";
+        assert_eq!(bold_headings(untouched), untouched);
+    }
+
+    #[test]
+    fn glob_match_handles_star_and_question_mark() {
+        assert!(glob_match("*.png", "chart.png"));
+        assert!(glob_match("run-?.log", "run-1.log"));
+        assert!(glob_match("a*b*c", "aXXbYc"));
+        assert!(!glob_match("*.png", "chart.pdf"));
+        assert!(!glob_match("run-?.log", "run-10.log"));
+        assert!(is_glob("charts/*.png") && !is_glob("charts/a.png"));
+    }
 }
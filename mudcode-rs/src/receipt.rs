@@ -0,0 +1,138 @@
+use serde::Serialize;
+use std::net::IpAddr;
+use std::time::Duration;
+use tracing::warn;
+
+const CALLBACK_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const CALLBACK_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Delivery outcome POSTed to an event's `callbackUrl` after the bridge attempts delivery.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DeliveryReceipt {
+    pub ok: bool,
+    #[serde(rename = "channelId")]
+    pub channel_id: String,
+    #[serde(rename = "chunksSent")]
+    pub chunks_sent: usize,
+    pub error: Option<String>,
+    /// Files uploaded or linked; recorded in the audit log but not sent back.
+    #[serde(skip)]
+    pub files_sent: usize,
+    /// Id of the most recent message posted for this delivery.
+    #[serde(skip)]
+    pub last_message_id: Option<String>,
+    /// Ids of every message posted for this delivery, in order.
+    #[serde(skip)]
+    pub message_ids: Vec<String>,
+}
+
+impl DeliveryReceipt {
+    pub fn new(channel_id: &str) -> Self {
+        Self {
+            ok: true,
+            channel_id: channel_id.to_string(),
+            ..Self::default()
+        }
+    }
+
+    /// Count one successful send and remember the last message id it posted.
+    pub fn sent(&mut self, message_ids: impl IntoIterator<Item = String>) {
+        self.chunks_sent += 1;
+        let start = self.message_ids.len();
+        self.message_ids.extend(message_ids);
+        if self.message_ids.len() > start {
+            self.last_message_id = self.message_ids.last().cloned();
+        }
+    }
+
+    /// Fold in the counts of a delivery that ran alongside this one; its last
+    /// message id wins when it has one.
+    pub fn absorb(&mut self, other: DeliveryReceipt) {
+        self.chunks_sent += other.chunks_sent;
+        self.files_sent += other.files_sent;
+        self.message_ids.extend(other.message_ids);
+        self.last_message_id = other.last_message_id.or(self.last_message_id.take());
+        if !other.ok {
+            self.ok = false;
+            self.error = other.error.or(self.error.take());
+        }
+    }
+
+    pub fn fail(&mut self, error: &anyhow::Error) {
+        self.ok = false;
+        self.error = Some(format!("{error:#}"));
+    }
+}
+
+/// HTTP client for receipt callbacks, bounded so a slow `callbackUrl` can't hold
+/// its task forever.
+pub fn callback_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .connect_timeout(CALLBACK_CONNECT_TIMEOUT)
+        .timeout(CALLBACK_REQUEST_TIMEOUT)
+        .build()
+        .expect("failed to build the callback HTTP client")
+}
+
+/// Whether receipts may be POSTed to `url`: an http(s) URL on a loopback host or
+/// one of `allowed_hosts`, so an event can't make the bridge call arbitrary hosts.
+pub fn callback_allowed(url: &str, allowed_hosts: &[String]) -> bool {
+    let Ok(url) = reqwest::Url::parse(url) else {
+        return false;
+    };
+    if !matches!(url.scheme(), "http" | "https") {
+        return false;
+    }
+    let Some(host) = url.host_str() else {
+        return false;
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let loopback = match host.parse::<IpAddr>() {
+        Ok(ip) => ip.is_loopback(),
+        Err(_) => host.eq_ignore_ascii_case("localhost"),
+    };
+    loopback
+        || allowed_hosts
+            .iter()
+            .any(|allowed| host.eq_ignore_ascii_case(allowed))
+}
+
+/// Fire-and-forget POST of `receipt` to `url`; failures are only logged.
+pub fn spawn_receipt(http: &reqwest::Client, url: &str, receipt: DeliveryReceipt) {
+    let request = http.post(url).json(&receipt);
+    let url = url.to_string();
+
+    tokio::spawn(async move {
+        match request.send().await {
+            Ok(response) if !response.status().is_success() => {
+                warn!(
+                    "delivery receipt callback rejected url={} status={}",
+                    url,
+                    response.status()
+                );
+            }
+            Ok(_) => {}
+            Err(error) => {
+                warn!("delivery receipt callback failed url={} err={}", url, error);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn callbacks_are_limited_to_loopback_and_allowed_hosts() {
+        let allowed = vec!["hooks.internal".to_string()];
+        assert!(callback_allowed("http://127.0.0.1:9000/receipt", &[]));
+        assert!(callback_allowed("http://[::1]/receipt", &[]));
+        assert!(callback_allowed("http://localhost/receipt", &[]));
+        assert!(callback_allowed("https://Hooks.Internal/r", &allowed));
+        assert!(!callback_allowed("https://example.com/r", &allowed));
+        assert!(!callback_allowed("http://169.254.169.254/latest", &allowed));
+        assert!(!callback_allowed("file:///etc/passwd", &allowed));
+        assert!(!callback_allowed("not a url", &allowed));
+    }
+}
@@ -0,0 +1,91 @@
+use axum::Json;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use serde_json::{Value, json};
+
+/// Shape of hook server response bodies.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ResponseFormat {
+    /// The handler's plain string (or JSON text) as-is.
+    #[default]
+    Text,
+    /// Always a JSON object; plain strings become `{"ok", "status", "message"}`.
+    Json,
+    /// Status code only, no body.
+    Empty,
+}
+
+impl ResponseFormat {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "text" => Some(Self::Text),
+            "json" => Some(Self::Json),
+            "empty" => Some(Self::Empty),
+            _ => None,
+        }
+    }
+}
+
+/// Render a handler outcome in `format`.
+pub fn render(format: ResponseFormat, (status, body): (StatusCode, String)) -> Response {
+    match format {
+        ResponseFormat::Text => (status, body).into_response(),
+        ResponseFormat::Json => (status, Json(json_body(status, body))).into_response(),
+        ResponseFormat::Empty => status.into_response(),
+    }
+}
+
+fn json_body(status: StatusCode, body: String) -> Value {
+    match serde_json::from_str::<Value>(&body) {
+        Ok(value) if value.is_object() => value,
+        _ => json!({
+            "ok": status.is_success(),
+            "status": status.as_u16(),
+            "message": body,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn rendered(format: ResponseFormat, body: &str) -> (StatusCode, String) {
+        let response = render(format, (StatusCode::NOT_FOUND, body.to_string()));
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, String::from_utf8(bytes.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn renders_the_same_outcome_in_each_format() {
+        let text = rendered(ResponseFormat::Text, "Project not found").await;
+        assert_eq!(
+            text,
+            (StatusCode::NOT_FOUND, "Project not found".to_string())
+        );
+
+        let (status, body) = rendered(ResponseFormat::Json, "Project not found").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(
+            serde_json::from_str::<Value>(&body).unwrap(),
+            json!({ "ok": false, "status": 404, "message": "Project not found" })
+        );
+
+        let empty = rendered(ResponseFormat::Empty, "Project not found").await;
+        assert_eq!(empty, (StatusCode::NOT_FOUND, String::new()));
+    }
+
+    #[tokio::test]
+    async fn json_format_passes_structured_bodies_through() {
+        let (_, body) = rendered(ResponseFormat::Json, r#"{"error":"missingPermissions"}"#).await;
+        assert_eq!(
+            serde_json::from_str::<Value>(&body).unwrap(),
+            json!({ "error": "missingPermissions" })
+        );
+        assert_eq!(ResponseFormat::parse(" JSON "), Some(ResponseFormat::Json));
+        assert_eq!(ResponseFormat::parse("xml"), None);
+    }
+}
@@ -0,0 +1,206 @@
+use axum::extract::{Request, State};
+use axum::http::{StatusCode, header};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+/// Seconds a hook is told to wait before retrying an event refused during shutdown.
+const SHUTDOWN_RETRY_AFTER_SECS: u64 = 5;
+
+/// Counts handler work that is still running so shutdown can report what it abandons.
+#[derive(Clone, Default)]
+pub struct InFlight(Arc<AtomicUsize>);
+
+/// Marks one unit of work as in flight until dropped.
+pub struct InFlightGuard(Arc<AtomicUsize>);
+
+impl InFlight {
+    pub fn begin(&self) -> InFlightGuard {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard(Arc::clone(&self.0))
+    }
+
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Set once the shutdown signal fires, so new events are refused instead of
+/// racing the drain.
+#[derive(Clone, Default)]
+pub struct ShuttingDown(Arc<AtomicBool>);
+
+impl ShuttingDown {
+    pub fn set(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Middleware answering 503 with `Retry-After` once shutdown has begun.
+pub async fn reject_while_shutting_down(
+    State(shutting_down): State<ShuttingDown>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if shutting_down.is_set() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, SHUTDOWN_RETRY_AFTER_SECS.to_string())],
+            "Shutting down",
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ShutdownOutcome {
+    Drained,
+    TimedOut { abandoned: usize },
+}
+
+pub async fn shutdown_signal(shutting_down: &ShuttingDown) {
+    let ctrl_c = async {
+        if let Err(error) = tokio::signal::ctrl_c().await {
+            error!("failed to install Ctrl+C handler: {error}");
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut stream) => {
+                let _ = stream.recv().await;
+            }
+            Err(error) => {
+                error!("failed to install SIGTERM handler: {error}");
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    shutting_down.set();
+    info!("shutdown signal received");
+}
+
+/// Drive `serve` to completion, but stop waiting `timeout` after `signaled` resolves.
+pub async fn serve_with_shutdown_timeout<S, T>(
+    serve: S,
+    signaled: T,
+    timeout: Duration,
+    in_flight: &InFlight,
+) -> std::io::Result<ShutdownOutcome>
+where
+    S: Future<Output = std::io::Result<()>>,
+    T: Future<Output = ()>,
+{
+    tokio::pin!(serve);
+
+    tokio::select! {
+        result = &mut serve => return result.map(|_| ShutdownOutcome::Drained),
+        _ = signaled => {}
+    }
+
+    match tokio::time::timeout(timeout, serve).await {
+        Ok(result) => result.map(|_| ShutdownOutcome::Drained),
+        Err(_) => {
+            let abandoned = in_flight.count();
+            warn!(
+                "graceful shutdown timed out after {}ms; abandoning {} in-flight request(s)",
+                timeout.as_millis(),
+                abandoned
+            );
+            Ok(ShutdownOutcome::TimedOut { abandoned })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn shutdown_timeout_fires_with_stuck_sink() {
+        let in_flight = InFlight::default();
+        let _stuck = in_flight.begin();
+
+        let outcome = serve_with_shutdown_timeout(
+            std::future::pending::<std::io::Result<()>>(),
+            async {},
+            Duration::from_millis(20),
+            &in_flight,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(outcome, ShutdownOutcome::TimedOut { abandoned: 1 });
+    }
+
+    #[tokio::test]
+    async fn shutdown_completes_when_work_drains_in_time() {
+        let in_flight = InFlight::default();
+        let guard = in_flight.begin();
+
+        let serve = async move {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            drop(guard);
+            Ok(())
+        };
+
+        let outcome =
+            serve_with_shutdown_timeout(serve, async {}, Duration::from_secs(5), &in_flight)
+                .await
+                .unwrap();
+
+        assert_eq!(outcome, ShutdownOutcome::Drained);
+        assert_eq!(in_flight.count(), 0);
+    }
+
+    #[tokio::test]
+    async fn events_during_shutdown_get_503_with_retry_after() {
+        let shutting_down = ShuttingDown::default();
+        let app = axum::Router::new()
+            .route("/opencode-event", axum::routing::post(|| async { "OK" }))
+            .layer(axum::middleware::from_fn_with_state(
+                shutting_down.clone(),
+                reject_while_shutting_down,
+            ));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/opencode-event", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let _ = axum::serve(listener, app).await;
+        });
+        let client = reqwest::Client::new();
+
+        let before = client.post(&url).send().await.unwrap();
+        assert_eq!(before.status(), StatusCode::OK);
+
+        shutting_down.set();
+        let during = client.post(&url).send().await.unwrap();
+        assert_eq!(during.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(during.headers()[header::RETRY_AFTER], "5");
+    }
+}
@@ -1,41 +1,213 @@
-use serde::Deserialize;
+use crate::config::{expand_home_in, home_dir};
+use crate::event::{DEFAULT_AGENT_TYPE, ProjectRegistration};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use std::collections::HashMap;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, warn};
 
-#[derive(Debug, Default, Deserialize)]
+// Fields the bridge doesn't model are kept in `extra` so saving never drops
+// data written by the JS side.
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct BridgeState {
     #[serde(default)]
     pub projects: HashMap<String, ProjectState>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Debug, Default, Deserialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct ProjectState {
-    #[serde(rename = "projectPath")]
+    #[serde(rename = "projectPath", skip_serializing_if = "Option::is_none")]
     pub project_path: Option<String>,
     #[serde(default)]
     pub instances: HashMap<String, ProjectInstance>,
     #[serde(default, rename = "discordChannels")]
     pub discord_channels: HashMap<String, Option<String>>,
+    /// Fence language for wrapped code when none can be inferred, e.g. `rust`.
+    #[serde(rename = "codeLanguage", skip_serializing_if = "Option::is_none")]
+    pub code_language: Option<String>,
+    /// Emoji the bot reacts with once a delivery fully succeeds; overrides the config.
+    #[serde(rename = "successReaction", skip_serializing_if = "Option::is_none")]
+    pub success_reaction: Option<String>,
+    /// Agent type assumed for events that omit `agentType`.
+    #[serde(rename = "defaultAgentType", skip_serializing_if = "Option::is_none")]
+    pub default_agent_type: Option<String>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Debug, Default, Deserialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct ProjectInstance {
-    #[serde(rename = "instanceId")]
+    #[serde(rename = "instanceId", skip_serializing_if = "Option::is_none")]
     pub instance_id: Option<String>,
-    #[serde(rename = "agentType")]
+    #[serde(rename = "agentType", skip_serializing_if = "Option::is_none")]
     pub agent_type: Option<String>,
-    #[serde(rename = "channelId", alias = "discordChannelId")]
+    #[serde(
+        rename = "channelId",
+        alias = "discordChannelId",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub channel_id: Option<String>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl ProjectState {
+    fn mapping(&self, state_file: &Path) -> Value {
+        let mut keys: Vec<&String> = self.instances.keys().collect();
+        keys.sort();
+        let instances: Vec<Value> = keys
+            .into_iter()
+            .map(|key| {
+                let instance = &self.instances[key];
+                serde_json::json!({
+                    "key": key,
+                    "instanceId": instance.instance_id,
+                    "agentType": instance.agent_type,
+                    "channelId": instance.channel_id,
+                })
+            })
+            .collect();
+        serde_json::json!({
+            "projectPath": self.project_path,
+            "stateFile": state_file.display().to_string(),
+            "instances": instances,
+            "discordChannels": self.discord_channels,
+        })
+    }
+}
+
+/// Where the legacy per-project `discordChannels` map sits in channel lookup.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LegacyChannels {
+    /// Consult the legacy map only when no instance matches.
+    #[default]
+    Auto,
+    /// Consult the legacy map before agent-type instance matching.
+    First,
+    /// Ignore the legacy map entirely.
+    Off,
+}
+
+impl LegacyChannels {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "auto" => Some(Self::Auto),
+            "first" => Some(Self::First),
+            "off" => Some(Self::Off),
+            _ => None,
+        }
+    }
+}
+
+/// Options for `BridgeState::find_channel_id`.
+#[derive(Clone, Debug, Default)]
+pub struct ChannelLookup {
+    pub legacy: LegacyChannels,
+    /// Only honour a requested `instanceId` whose stored agent type matches the
+    /// event's; otherwise fall back to agent-type resolution.
+    pub strict_instance_agent: bool,
+    /// Agent types tried in order when the event's own has no channel.
+    pub agent_fallbacks: Vec<String>,
 }
 
 impl BridgeState {
     pub fn load(path: &Path) -> Self {
+        Self::try_load(path).unwrap_or_default()
+    }
+
+    /// Like `load`, but a file that exists and fails to parse is an error rather
+    /// than an empty state.
+    pub fn try_load(path: &Path) -> Result<Self, serde_json::Error> {
         let Ok(data) = fs::read_to_string(path) else {
-            return Self::default();
+            return Ok(Self::default());
         };
 
-        serde_json::from_str::<Self>(&data).unwrap_or_default()
+        serde_json::from_str::<Self>(&data)
+    }
+
+    /// Write the state atomically: serialize to a sibling temp file, then rename.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let data = serde_json::to_string_pretty(self).context("failed to serialize state")?;
+
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create {}", parent.display()))?;
+        }
+
+        let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(format!(".tmp-{}", std::process::id()));
+        let tmp_path = path.with_file_name(tmp_name);
+
+        fs::write(&tmp_path, data)
+            .with_context(|| format!("failed to write {}", tmp_path.display()))?;
+        fs::rename(&tmp_path, path).with_context(|| {
+            let _ = fs::remove_file(&tmp_path);
+            format!("failed to replace {}", path.display())
+        })
+    }
+
+    /// Map the registered instance (`instanceId`, else the agent type) to its
+    /// channel, adding the project when it is new. An existing instance with that
+    /// id is updated in place, whatever its key. Without `overwrite`, a different
+    /// existing channel or project path is a conflict, returned as the error
+    /// message, and nothing changes.
+    pub fn register(
+        &mut self,
+        registration: &ProjectRegistration,
+        overwrite: bool,
+    ) -> Result<(), String> {
+        let id = registration.instance_id.unwrap_or(registration.agent_type);
+        let existing = self.projects.get(registration.project_name);
+        let slot = existing.and_then(|project| {
+            project
+                .instances
+                .iter()
+                .find(|(key, instance)| {
+                    non_empty(instance.instance_id.as_deref()).unwrap_or(key) == id
+                })
+                .map(|(key, _)| key.clone())
+        });
+
+        if !overwrite && let Some(project) = existing {
+            if let Some(path) = non_empty(project.project_path.as_deref())
+                && path != registration.project_path
+            {
+                return Err(format!(
+                    "project {} already has projectPath {path}",
+                    registration.project_name
+                ));
+            }
+            if let Some(channel) = slot
+                .as_ref()
+                .and_then(|key| non_empty(project.instances[key].channel_id.as_deref()))
+                && channel != registration.channel_id
+            {
+                return Err(format!(
+                    "instance {id} of project {} already maps to channel {channel}",
+                    registration.project_name
+                ));
+            }
+        }
+
+        let project = self
+            .projects
+            .entry(registration.project_name.to_string())
+            .or_default();
+        project.project_path = Some(registration.project_path.to_string());
+        let instance = project
+            .instances
+            .entry(slot.unwrap_or_else(|| id.to_string()))
+            .or_default();
+        instance.instance_id.get_or_insert_with(|| id.to_string());
+        instance.agent_type = Some(registration.agent_type.to_string());
+        instance.channel_id = Some(registration.channel_id.to_string());
+        Ok(())
     }
 
     pub fn find_channel_id(
@@ -43,17 +215,52 @@ impl BridgeState {
         project_name: &str,
         agent_type: &str,
         instance_id: Option<&str>,
+        lookup: &ChannelLookup,
+    ) -> Option<String> {
+        self.find_agent_channel_id(project_name, agent_type, instance_id, lookup)
+            .or_else(|| {
+                lookup
+                    .agent_fallbacks
+                    .iter()
+                    .filter(|fallback| fallback.as_str() != agent_type)
+                    .find_map(|fallback| {
+                        self.find_agent_channel_id(project_name, fallback, None, lookup)
+                    })
+            })
+    }
+
+    fn find_agent_channel_id(
+        &self,
+        project_name: &str,
+        agent_type: &str,
+        instance_id: Option<&str>,
+        lookup: &ChannelLookup,
     ) -> Option<String> {
         let project = self.projects.get(project_name)?;
+        let legacy = || {
+            project
+                .discord_channels
+                .get(agent_type)
+                .and_then(|ch| ch.as_deref())
+                .map(str::trim)
+                .filter(|ch| !ch.is_empty())
+                .map(str::to_string)
+        };
 
-        if let Some(requested) = instance_id {
-            if let Some(instance) = project.instances.get(requested) {
-                if let Some(channel) = instance.channel_id.as_deref() {
-                    if !channel.trim().is_empty() {
-                        return Some(channel.to_string());
-                    }
-                }
-            }
+        if let Some(requested) = instance_id
+            && let Some(instance) = project.instances.get(requested)
+            && (!lookup.strict_instance_agent
+                || instance.agent_type.as_deref().map(str::trim) == Some(agent_type))
+            && let Some(channel) = instance.channel_id.as_deref()
+            && !channel.trim().is_empty()
+        {
+            return Some(channel.to_string());
+        }
+
+        if lookup.legacy == LegacyChannels::First
+            && let Some(channel) = legacy()
+        {
+            return Some(channel);
         }
 
         let mut instances = project
@@ -91,26 +298,338 @@ impl BridgeState {
             return Some(channel);
         }
 
-        project
-            .discord_channels
-            .get(agent_type)
-            .and_then(|ch| ch.as_deref())
-            .map(str::trim)
-            .filter(|ch| !ch.is_empty())
-            .map(str::to_string)
+        match lookup.legacy {
+            LegacyChannels::Auto => legacy(),
+            LegacyChannels::First | LegacyChannels::Off => None,
+        }
     }
 
     pub fn project_path(&self, project_name: &str) -> Option<PathBuf> {
+        self.project_path_in(project_name, home_dir().as_deref())
+    }
+
+    /// `project_path` with a leading `~` expanded to `home`.
+    pub fn project_path_in(&self, project_name: &str, home: Option<&Path>) -> Option<PathBuf> {
         self.projects
             .get(project_name)
             .and_then(|p| p.project_path.as_deref())
-            .map(PathBuf::from)
+            .map(|path| expand_home_in(path, home))
+    }
+
+    /// Describe ambiguous instance mappings: several keys claiming the same
+    /// `instanceId`, or one agent type mapped to different channels so the
+    /// agent-type fallback depends on instance id ordering.
+    pub fn validate(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        let mut project_names: Vec<_> = self.projects.keys().collect();
+        project_names.sort();
+
+        for project_name in project_names {
+            let project = &self.projects[project_name];
+            let mut by_id: HashMap<&str, Vec<&str>> = HashMap::new();
+            let mut by_agent: HashMap<&str, Vec<(&str, &str)>> = HashMap::new();
+
+            for (key, instance) in &project.instances {
+                let id = non_empty(instance.instance_id.as_deref()).unwrap_or(key);
+                by_id.entry(id).or_default().push(key);
+
+                if let Some(agent_type) = non_empty(instance.agent_type.as_deref())
+                    && let Some(channel) = non_empty(instance.channel_id.as_deref())
+                {
+                    by_agent.entry(agent_type).or_default().push((id, channel));
+                }
+            }
+
+            let mut duplicate_ids: Vec<_> =
+                by_id.into_iter().filter(|(_, k)| k.len() > 1).collect();
+            duplicate_ids.sort();
+            for (id, mut keys) in duplicate_ids {
+                keys.sort();
+                warnings.push(format!(
+                    "project {project_name}: instanceId {id} is used by entries {}",
+                    keys.join(", ")
+                ));
+            }
+
+            let mut agents: Vec<_> = by_agent.into_iter().collect();
+            agents.sort();
+            for (agent_type, mut mapped) in agents {
+                mapped.sort();
+                let mut channels: Vec<_> = mapped.iter().map(|(_, ch)| *ch).collect();
+                channels.sort();
+                channels.dedup();
+                if channels.len() > 1 {
+                    warnings.push(format!(
+                        "project {project_name}: agentType {agent_type} maps to channels {} \
+                         (instances without an instanceId resolve to {})",
+                        channels.join(", "),
+                        mapped[0].1
+                    ));
+                }
+            }
+        }
+
+        warnings
+    }
+
+    pub fn default_agent_type(&self, project_name: &str) -> &str {
+        self.projects
+            .get(project_name)
+            .and_then(|p| p.default_agent_type.as_deref())
+            .map(str::trim)
+            .filter(|a| !a.is_empty())
+            .unwrap_or(DEFAULT_AGENT_TYPE)
+    }
+
+    pub fn success_reaction(&self, project_name: &str) -> Option<&str> {
+        self.projects
+            .get(project_name)
+            .and_then(|p| p.success_reaction.as_deref())
+            .map(str::trim)
+            .filter(|emoji| !emoji.is_empty())
+    }
+
+    pub fn code_language(&self, project_name: &str) -> Option<&str> {
+        self.projects
+            .get(project_name)
+            .and_then(|p| p.code_language.as_deref())
+            .map(str::trim)
+            .filter(|lang| !lang.is_empty())
+    }
+}
+
+pub fn normalize_project_name(name: &str) -> String {
+    name.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+fn non_empty(value: Option<&str>) -> Option<&str> {
+    value.map(str::trim).filter(|v| !v.is_empty())
+}
+
+/// Loads bridge state per project, routing projects with a dedicated state file
+/// to it and falling back to the shared file. Parsed files are cached until their
+/// modification time or length changes.
+#[derive(Debug)]
+pub struct StateStore {
+    shared_path: PathBuf,
+    project_paths: HashMap<String, PathBuf>,
+    case_insensitive: bool,
+    aliases: HashMap<String, String>,
+    read_retries: u32,
+    read_retry_delay: Duration,
+    cache: Mutex<HashMap<PathBuf, CachedState>>,
+}
+
+#[derive(Debug)]
+struct CachedState {
+    stamp: FileStamp,
+    state: Arc<BridgeState>,
+    /// Why the file didn't parse, when `state` is the empty fallback.
+    error: Option<String>,
+}
+
+type FileStamp = Option<(SystemTime, u64)>;
+
+fn file_stamp(path: &Path) -> FileStamp {
+    let meta = fs::metadata(path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+impl StateStore {
+    pub fn new(shared_path: PathBuf, project_paths: HashMap<String, PathBuf>) -> Self {
+        Self {
+            shared_path,
+            project_paths,
+            case_insensitive: false,
+            aliases: HashMap::new(),
+            read_retries: 0,
+            read_retry_delay: Duration::ZERO,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Match project names ignoring case and runs of whitespace.
+    pub fn with_case_insensitive_projects(mut self, enabled: bool) -> Self {
+        self.case_insensitive = enabled;
+        self
+    }
+
+    /// Resolve these alternate project names to their canonical key first.
+    pub fn with_project_aliases(mut self, aliases: HashMap<String, String>) -> Self {
+        self.aliases = aliases;
+        self
+    }
+
+    /// Re-read a state file that fails to parse up to `retries` times, `delay`
+    /// apart, before treating it as empty. Covers writers that rewrite in place.
+    pub fn with_read_retries(mut self, retries: u32, delay: Duration) -> Self {
+        self.read_retries = retries;
+        self.read_retry_delay = delay;
+        self
+    }
+
+    pub fn path_for(&self, project_name: &str) -> &Path {
+        if let Some(path) = self.project_paths.get(project_name) {
+            return path;
+        }
+
+        if self.case_insensitive {
+            let wanted = normalize_project_name(project_name);
+            if let Some((_, path)) = self
+                .project_paths
+                .iter()
+                .find(|(name, _)| normalize_project_name(name) == wanted)
+            {
+                return path;
+            }
+        }
+
+        &self.shared_path
+    }
+
+    /// Load the state for `project_name` and return it with the project's key as
+    /// written in the state file (differs from the input for aliases and in
+    /// case-insensitive mode).
+    pub fn resolve_project(&self, project_name: &str) -> (Arc<BridgeState>, String) {
+        let project_name = self
+            .aliases
+            .get(project_name)
+            .map_or(project_name, String::as_str);
+        let state = self.load_for(project_name);
+        if !self.case_insensitive || state.projects.contains_key(project_name) {
+            return (state, project_name.to_string());
+        }
+
+        let wanted = normalize_project_name(project_name);
+        let mut matches: Vec<&String> = state
+            .projects
+            .keys()
+            .filter(|name| normalize_project_name(name) == wanted)
+            .collect();
+        matches.sort();
+        let canonical = matches
+            .first()
+            .map_or_else(|| project_name.to_string(), |name| name.to_string());
+        (state, canonical)
+    }
+
+    /// Every state file this store reads, sorted and deduplicated.
+    fn paths(&self) -> Vec<&Path> {
+        let mut paths: Vec<&Path> = std::iter::once(self.shared_path.as_path())
+            .chain(self.project_paths.values().map(PathBuf::as_path))
+            .collect();
+        paths.sort();
+        paths.dedup();
+        paths
+    }
+
+    /// Validation warnings for every state file this store reads, prefixed with the file.
+    pub fn validate(&self) -> Vec<String> {
+        self.paths()
+            .into_iter()
+            .flat_map(|path| {
+                self.load_path(path)
+                    .validate()
+                    .into_iter()
+                    .map(move |warning| format!("{}: {warning}", path.display()))
+            })
+            .collect()
+    }
+
+    /// Number of projects across the state files, or the first file that exists
+    /// but doesn't parse.
+    pub fn project_count(&self) -> Result<usize, String> {
+        self.paths().into_iter().try_fold(0, |count, path| {
+            self.load_parsed(path)
+                .map(|state| count + state.projects.len())
+        })
+    }
+
+    /// Channel routing for every project, keyed by name, as served by `GET /projects`.
+    /// A project listed in several files is taken from the one its events load.
+    pub fn project_mappings(&self) -> Result<Map<String, Value>, String> {
+        let mut mappings = Map::new();
+        for path in self.paths() {
+            let state = self.load_parsed(path)?;
+            for (name, project) in &state.projects {
+                if self.path_for(name) == path {
+                    mappings.insert(name.clone(), project.mapping(path));
+                }
+            }
+        }
+        Ok(mappings)
+    }
+
+    pub fn load_for(&self, project_name: &str) -> Arc<BridgeState> {
+        self.load_path(self.path_for(project_name))
+    }
+
+    fn load_path(&self, path: &Path) -> Arc<BridgeState> {
+        self.load_cached(path, self.read_retries).0
+    }
+
+    /// The state in `path` through the cache without read retries, or why the
+    /// file exists but doesn't parse.
+    fn load_parsed(&self, path: &Path) -> Result<Arc<BridgeState>, String> {
+        match self.load_cached(path, 0) {
+            (state, None) => Ok(state),
+            (_, Some(error)) => Err(format!("{}: {error}", path.display())),
+        }
+    }
+
+    /// The cached state of `path`, re-read when the file changed, with the parse
+    /// error when it doesn't parse.
+    fn load_cached(&self, path: &Path, retries: u32) -> (Arc<BridgeState>, Option<String>) {
+        let mut stamp = file_stamp(path);
+        if let Some(cached) = self.cache.lock().unwrap().get(path)
+            && stamp.is_some()
+            && cached.stamp == stamp
+        {
+            return (Arc::clone(&cached.state), cached.error.clone());
+        }
+
+        // Parse and retry without the lock so one slow file doesn't stall
+        // readers of the others.
+
+        let mut attempt = 0;
+        let (state, error) = loop {
+            match BridgeState::try_load(path) {
+                Ok(state) => break (state, None),
+                Err(error) if attempt < retries => {
+                    attempt += 1;
+                    debug!(
+                        "state file {} unreadable ({error}); retry {attempt}/{retries}",
+                        path.display()
+                    );
+                    std::thread::sleep(self.read_retry_delay);
+                    stamp = file_stamp(path);
+                }
+                Err(error) => {
+                    warn!("state file {} unreadable: {error}", path.display());
+                    break (BridgeState::default(), Some(error.to_string()));
+                }
+            }
+        };
+        let state = Arc::new(state);
+        self.cache.lock().unwrap().insert(
+            path.to_path_buf(),
+            CachedState {
+                stamp,
+                state: Arc::clone(&state),
+                error: error.clone(),
+            },
+        );
+        (state, error)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_support::TempDir;
 
     #[test]
     fn finds_channel_by_exact_instance_first() {
@@ -125,6 +644,7 @@ mod tests {
                             instance_id: Some("claude".to_string()),
                             agent_type: Some("claude".to_string()),
                             channel_id: Some("ch-1".to_string()),
+                            ..ProjectInstance::default()
                         },
                     ),
                     (
@@ -133,6 +653,7 @@ mod tests {
                             instance_id: Some("claude-2".to_string()),
                             agent_type: Some("claude".to_string()),
                             channel_id: Some("ch-2".to_string()),
+                            ..ProjectInstance::default()
                         },
                     ),
                 ]),
@@ -140,7 +661,12 @@ mod tests {
             },
         );
 
-        let found = state.find_channel_id("proj", "claude", Some("claude-2"));
+        let found = state.find_channel_id(
+            "proj",
+            "claude",
+            Some("claude-2"),
+            &ChannelLookup::default(),
+        );
         assert_eq!(found.as_deref(), Some("ch-2"));
     }
 
@@ -157,6 +683,7 @@ mod tests {
                             instance_id: Some("claude-2".to_string()),
                             agent_type: Some("claude".to_string()),
                             channel_id: Some("ch-2".to_string()),
+                            ..ProjectInstance::default()
                         },
                     ),
                     (
@@ -165,6 +692,7 @@ mod tests {
                             instance_id: Some("claude".to_string()),
                             agent_type: Some("claude".to_string()),
                             channel_id: Some("ch-1".to_string()),
+                            ..ProjectInstance::default()
                         },
                     ),
                 ]),
@@ -172,7 +700,7 @@ mod tests {
             },
         );
 
-        let found = state.find_channel_id("proj", "claude", None);
+        let found = state.find_channel_id("proj", "claude", None, &ChannelLookup::default());
         assert_eq!(found.as_deref(), Some("ch-1"));
     }
 
@@ -190,7 +718,435 @@ mod tests {
             },
         );
 
-        let found = state.find_channel_id("proj", "claude", None);
+        let found = state.find_channel_id("proj", "claude", None, &ChannelLookup::default());
         assert_eq!(found.as_deref(), Some("legacy-1"));
     }
+
+    fn legacy_and_instance_state() -> BridgeState {
+        let mut state = BridgeState::default();
+        state.projects.insert(
+            "proj".to_string(),
+            ProjectState {
+                instances: HashMap::from([(
+                    "claude".to_string(),
+                    ProjectInstance {
+                        agent_type: Some("claude".to_string()),
+                        channel_id: Some("ch-1".to_string()),
+                        ..ProjectInstance::default()
+                    },
+                )]),
+                discord_channels: HashMap::from([
+                    ("claude".to_string(), Some("legacy-1".to_string())),
+                    ("codex".to_string(), Some("legacy-2".to_string())),
+                ]),
+                ..ProjectState::default()
+            },
+        );
+        state
+    }
+
+    #[test]
+    fn legacy_channel_modes_control_lookup_order() {
+        let state = legacy_and_instance_state();
+        let find = |legacy, agent| {
+            let lookup = ChannelLookup {
+                legacy,
+                ..ChannelLookup::default()
+            };
+            state.find_channel_id("proj", agent, None, &lookup)
+        };
+
+        assert_eq!(
+            find(LegacyChannels::Auto, "claude").as_deref(),
+            Some("ch-1")
+        );
+        assert_eq!(
+            find(LegacyChannels::Auto, "codex").as_deref(),
+            Some("legacy-2")
+        );
+        assert_eq!(
+            find(LegacyChannels::First, "claude").as_deref(),
+            Some("legacy-1")
+        );
+        assert_eq!(
+            find(LegacyChannels::First, "codex").as_deref(),
+            Some("legacy-2")
+        );
+        assert_eq!(find(LegacyChannels::Off, "claude").as_deref(), Some("ch-1"));
+        assert_eq!(find(LegacyChannels::Off, "codex"), None);
+    }
+
+    #[test]
+    fn strict_instance_agent_rejects_conflicting_instance() {
+        let mut state = legacy_and_instance_state();
+        state.projects.get_mut("proj").unwrap().instances.insert(
+            "reused".to_string(),
+            ProjectInstance {
+                agent_type: Some("codex".to_string()),
+                channel_id: Some("ch-codex".to_string()),
+                ..ProjectInstance::default()
+            },
+        );
+        let lenient = ChannelLookup::default();
+        let strict = ChannelLookup {
+            strict_instance_agent: true,
+            ..ChannelLookup::default()
+        };
+
+        let find = |agent, lookup| state.find_channel_id("proj", agent, Some("reused"), lookup);
+        assert_eq!(find("claude", &lenient).as_deref(), Some("ch-codex"));
+        assert_eq!(find("claude", &strict).as_deref(), Some("ch-1"));
+        assert_eq!(find("codex", &strict).as_deref(), Some("ch-codex"));
+    }
+
+    #[test]
+    fn agent_fallbacks_are_walked_in_order() {
+        let state = legacy_and_instance_state();
+        let lookup = ChannelLookup {
+            agent_fallbacks: vec![
+                "missing".to_string(),
+                "codex".to_string(),
+                "claude".to_string(),
+            ],
+            ..ChannelLookup::default()
+        };
+
+        let plain = ChannelLookup::default();
+        let find = |agent, lookup| state.find_channel_id("proj", agent, None, lookup);
+        assert_eq!(find("claude-code", &plain), None);
+        assert_eq!(find("claude-code", &lookup).as_deref(), Some("legacy-2"));
+        assert_eq!(find("claude", &lookup).as_deref(), Some("ch-1"));
+    }
+
+    fn write_project_state(dir: &TempDir, file: &str, project: &str, channel: &str) -> PathBuf {
+        let state = serde_json::json!({
+            "projects": {
+                project: {
+                    "instances": {
+                        "claude": { "agentType": "claude", "channelId": channel }
+                    }
+                }
+            }
+        });
+        dir.write(file, state.to_string())
+    }
+
+    #[test]
+    fn state_store_routes_projects_to_dedicated_files() {
+        let dir = TempDir::new("state-store");
+        let shared = write_project_state(&dir, "state.json", "shared-proj", "shared-ch");
+        let dedicated = write_project_state(&dir, "tenant.json", "tenant-proj", "tenant-ch");
+        let store = StateStore::new(
+            shared.clone(),
+            HashMap::from([("tenant-proj".to_string(), dedicated.clone())]),
+        );
+
+        assert_eq!(store.path_for("tenant-proj"), dedicated.as_path());
+        assert_eq!(store.path_for("shared-proj"), shared.as_path());
+
+        let tenant = store.load_for("tenant-proj");
+        assert_eq!(
+            tenant
+                .find_channel_id("tenant-proj", "claude", None, &ChannelLookup::default())
+                .as_deref(),
+            Some("tenant-ch")
+        );
+
+        let shared_state = store.load_for("shared-proj");
+        assert_eq!(
+            shared_state
+                .find_channel_id("shared-proj", "claude", None, &ChannelLookup::default())
+                .as_deref(),
+            Some("shared-ch")
+        );
+        assert!(!shared_state.projects.contains_key("tenant-proj"));
+    }
+
+    #[test]
+    fn state_store_reuses_cache_until_file_changes() {
+        let dir = TempDir::new("state-store-cache");
+        let shared = write_project_state(&dir, "state.json", "proj", "ch-1");
+        let store = StateStore::new(shared, HashMap::new());
+
+        let first = store.load_for("proj");
+        let second = store.load_for("proj");
+        assert!(Arc::ptr_eq(&first, &second));
+
+        write_project_state(&dir, "state.json", "proj", "ch-changed");
+        let reloaded = store.load_for("proj");
+        assert_eq!(
+            reloaded
+                .find_channel_id("proj", "claude", None, &ChannelLookup::default())
+                .as_deref(),
+            Some("ch-changed")
+        );
+    }
+
+    #[test]
+    fn project_count_goes_through_the_cache() {
+        let dir = TempDir::new("state-count-cache");
+        let shared = dir.write("state.json", "{ not json");
+        let store = StateStore::new(shared.clone(), HashMap::new());
+
+        let error = store.project_count().unwrap_err();
+        assert!(error.starts_with(&shared.display().to_string()));
+        assert!(store.cache.lock().unwrap()[&shared].error.is_some());
+        assert_eq!(store.project_count().unwrap_err(), error);
+
+        write_project_state(&dir, "state.json", "proj", "ch-1");
+        assert_eq!(store.project_count(), Ok(1));
+        let counted = Arc::clone(&store.cache.lock().unwrap()[&shared].state);
+        assert!(Arc::ptr_eq(&counted, &store.load_for("proj")));
+    }
+
+    #[test]
+    fn save_round_trips_and_keeps_unknown_fields() {
+        let dir = TempDir::new("state-save");
+        let path = dir.write(
+            "state.json",
+            serde_json::json!({
+                "guildId": "g-1",
+                "projects": {
+                    "proj": {
+                        "projectPath": "/work/proj",
+                        "tmuxSession": "mud",
+                        "discordChannels": { "claude": "legacy-1" },
+                        "instances": {
+                            "claude": {
+                                "instanceId": "claude",
+                                "agentType": "claude",
+                                "discordChannelId": "ch-1",
+                                "tmuxWindow": "claude"
+                            }
+                        }
+                    }
+                }
+            })
+            .to_string(),
+        );
+
+        BridgeState::load(&path).save(&path).unwrap();
+
+        let saved: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(saved["guildId"], "g-1");
+        assert_eq!(saved["projects"]["proj"]["projectPath"], "/work/proj");
+        assert_eq!(saved["projects"]["proj"]["tmuxSession"], "mud");
+        assert_eq!(
+            saved["projects"]["proj"]["discordChannels"]["claude"],
+            "legacy-1"
+        );
+        let instance = &saved["projects"]["proj"]["instances"]["claude"];
+        assert_eq!(instance["channelId"], "ch-1");
+        assert_eq!(instance["tmuxWindow"], "claude");
+        assert_eq!(
+            BridgeState::load(&path)
+                .find_channel_id("proj", "claude", None, &ChannelLookup::default())
+                .as_deref(),
+            Some("ch-1")
+        );
+    }
+
+    fn instance(id: Option<&str>, agent_type: &str, channel: &str) -> ProjectInstance {
+        ProjectInstance {
+            instance_id: id.map(str::to_string),
+            agent_type: Some(agent_type.to_string()),
+            channel_id: Some(channel.to_string()),
+            ..ProjectInstance::default()
+        }
+    }
+
+    #[test]
+    fn validate_reports_ambiguous_instances() {
+        let mut project = ProjectState::default();
+        project
+            .instances
+            .insert("a".to_string(), instance(Some("main"), "claude", "ch-1"));
+        project
+            .instances
+            .insert("b".to_string(), instance(Some("main"), "claude", "ch-2"));
+        let mut state = BridgeState::default();
+        state.projects.insert("proj".to_string(), project);
+
+        assert_eq!(
+            state.validate(),
+            vec![
+                "project proj: instanceId main is used by entries a, b".to_string(),
+                "project proj: agentType claude maps to channels ch-1, ch-2 \
+                 (instances without an instanceId resolve to ch-1)"
+                    .to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_accepts_clean_state() {
+        let mut project = ProjectState::default();
+        project
+            .instances
+            .insert("claude".to_string(), instance(None, "claude", "ch-1"));
+        project
+            .instances
+            .insert("claude-2".to_string(), instance(None, "claude", "ch-1"));
+        project
+            .instances
+            .insert("opencode".to_string(), instance(None, "opencode", "ch-2"));
+        let mut state = BridgeState::default();
+        state.projects.insert("proj".to_string(), project);
+
+        assert!(state.validate().is_empty());
+    }
+
+    #[test]
+    fn project_lookup_is_case_sensitive_by_default() {
+        let dir = TempDir::new("case-default");
+        let shared = write_project_state(&dir, "state.json", "MyProj", "ch-1");
+        let store = StateStore::new(shared, HashMap::new());
+
+        let (state, name) = store.resolve_project("myproj");
+        assert_eq!(name, "myproj");
+        assert!(!state.projects.contains_key(&name));
+    }
+
+    #[test]
+    fn project_lookup_can_ignore_case_and_whitespace() {
+        let dir = TempDir::new("case-insensitive");
+        let shared = write_project_state(&dir, "state.json", "My  Proj", "ch-1");
+        let dedicated = write_project_state(&dir, "tenant.json", "Tenant", "ch-2");
+        let store = StateStore::new(
+            shared,
+            HashMap::from([("Tenant".to_string(), dedicated.clone())]),
+        )
+        .with_case_insensitive_projects(true);
+
+        let (state, name) = store.resolve_project(" my proj ");
+        assert_eq!(name, "My  Proj");
+        assert_eq!(
+            state
+                .find_channel_id(&name, "claude", None, &ChannelLookup::default())
+                .as_deref(),
+            Some("ch-1")
+        );
+
+        assert_eq!(store.path_for("tenant"), dedicated.as_path());
+        let (state, name) = store.resolve_project("TENANT");
+        assert_eq!(name, "Tenant");
+        assert_eq!(
+            state
+                .find_channel_id(&name, "claude", None, &ChannelLookup::default())
+                .as_deref(),
+            Some("ch-2")
+        );
+    }
+
+    #[test]
+    fn project_aliases_resolve_to_the_canonical_entry() {
+        let dir = TempDir::new("project-aliases");
+        let shared = write_project_state(&dir, "state.json", "my-project", "ch-1");
+        let store = StateStore::new(shared, HashMap::new()).with_project_aliases(HashMap::from([
+            ("my_project".to_string(), "my-project".to_string()),
+            ("My Project".to_string(), "my-project".to_string()),
+        ]));
+
+        for requested in ["my_project", "My Project", "my-project"] {
+            let (state, name) = store.resolve_project(requested);
+            assert_eq!(name, "my-project");
+            assert!(state.projects.contains_key(&name));
+        }
+
+        let (state, name) = store.resolve_project("other");
+        assert_eq!(name, "other");
+        assert!(!state.projects.contains_key(&name));
+    }
+
+    #[test]
+    fn register_matches_instances_by_id_whatever_their_key() {
+        let mut state = BridgeState::default();
+        state.projects.insert(
+            "proj".to_string(),
+            ProjectState {
+                project_path: Some("/repo".to_string()),
+                instances: HashMap::from([(
+                    "primary".to_string(),
+                    ProjectInstance {
+                        instance_id: Some("claude-1".to_string()),
+                        agent_type: Some("claude".to_string()),
+                        channel_id: Some("ch-1".to_string()),
+                        ..ProjectInstance::default()
+                    },
+                )]),
+                ..ProjectState::default()
+            },
+        );
+        let registration = |channel_id| ProjectRegistration {
+            project_name: "proj",
+            project_path: "/repo",
+            agent_type: "claude",
+            channel_id,
+            instance_id: Some("claude-1"),
+        };
+
+        let error = state.register(&registration("ch-2"), false).unwrap_err();
+        assert_eq!(
+            error,
+            "instance claude-1 of project proj already maps to channel ch-1"
+        );
+        assert_eq!(state.projects["proj"].instances.len(), 1);
+
+        state.register(&registration("ch-2"), true).unwrap();
+        let instances = &state.projects["proj"].instances;
+        assert_eq!(instances.len(), 1);
+        assert_eq!(instances["primary"].channel_id.as_deref(), Some("ch-2"));
+        assert!(state.validate().is_empty());
+    }
+
+    #[test]
+    fn read_retries_ride_out_a_partial_write() {
+        let dir = TempDir::new("partial-write");
+        let shared = dir.write("state.json", r#"{"projects": {"proj": {"inst"#);
+        let no_retry = StateStore::new(shared.clone(), HashMap::new());
+        assert!(no_retry.load_for("proj").projects.is_empty());
+
+        let store = StateStore::new(shared.clone(), HashMap::new())
+            .with_read_retries(20, Duration::from_millis(10));
+        let writer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(30));
+            write_project_state(&dir, "state.json", "proj", "ch-1");
+            dir
+        });
+
+        let state = store.load_for("proj");
+        let _dir = writer.join().unwrap();
+        assert_eq!(
+            state
+                .find_channel_id("proj", "claude", None, &ChannelLookup::default())
+                .as_deref(),
+            Some("ch-1")
+        );
+    }
+
+    #[test]
+    fn retrying_one_file_does_not_block_the_others() {
+        let dir = TempDir::new("retry-unlocked");
+        let shared = dir.write("state.json", "{");
+        let tenant = write_project_state(&dir, "tenant.json", "tenant-proj", "ch-2");
+        let store = Arc::new(
+            StateStore::new(shared, HashMap::from([("tenant-proj".to_string(), tenant)]))
+                .with_read_retries(10, Duration::from_millis(50)),
+        );
+
+        let retrying = {
+            let store = Arc::clone(&store);
+            std::thread::spawn(move || store.load_for("proj"))
+        };
+        std::thread::sleep(Duration::from_millis(20));
+        let started = std::time::Instant::now();
+        assert!(
+            store
+                .load_for("tenant-proj")
+                .projects
+                .contains_key("tenant-proj")
+        );
+        assert!(started.elapsed() < Duration::from_millis(200));
+        assert!(retrying.join().unwrap().projects.is_empty());
+    }
 }
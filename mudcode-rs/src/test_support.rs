@@ -0,0 +1,275 @@
+use axum::Router;
+use axum::body::Bytes;
+use axum::extract::Request;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use serde_json::{Value, json};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: axum::http::HeaderMap,
+    pub body: Bytes,
+    pub received_at: Instant,
+}
+
+impl RecordedRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).unwrap_or(Value::Null)
+    }
+
+    pub fn body_text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    /// The `payload_json` part of a multipart upload (or the JSON body).
+    pub fn payload_json(&self) -> Value {
+        let body = self.body_text();
+        let Some(start) = body.find("name=\"payload_json\"") else {
+            return self.json();
+        };
+
+        body[start..]
+            .split_once("\r\n\r\n")
+            .and_then(|(_, rest)| rest.split("\r\n--").next())
+            .and_then(|raw| serde_json::from_str(raw.trim()).ok())
+            .unwrap_or(Value::Null)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    /// Held back this long before answering.
+    pub delay: Duration,
+}
+
+impl MockResponse {
+    pub fn json(status: u16, body: Value) -> Self {
+        Self {
+            status,
+            headers: vec![("content-type".to_string(), "application/json".to_string())],
+            body: body.to_string(),
+            delay: Duration::ZERO,
+        }
+    }
+
+    pub fn delayed(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// A successful Discord message create response.
+    pub fn message(id: impl Into<String>) -> Self {
+        Self::json(200, json!({ "id": id.into() }))
+    }
+}
+
+type Responder = Arc<dyn Fn(&RecordedRequest, usize) -> MockResponse + Send + Sync>;
+
+/// A local HTTP server that records every request and answers via a responder closure.
+pub struct MockServer {
+    pub url: String,
+    requests: Arc<Mutex<Vec<RecordedRequest>>>,
+    arrived: Arc<Notify>,
+}
+
+impl MockServer {
+    /// Answers every request with a message create response (`msg-0`, `msg-1`, ...).
+    pub async fn start() -> Self {
+        Self::with_responder(|_, idx| MockResponse::message(format!("msg-{idx}"))).await
+    }
+
+    pub async fn with_responder<F>(responder: F) -> Self
+    where
+        F: Fn(&RecordedRequest, usize) -> MockResponse + Send + Sync + 'static,
+    {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let arrived = Arc::new(Notify::new());
+        let responder: Responder = Arc::new(responder);
+        let counter = Arc::new(AtomicUsize::new(0));
+
+        let handler = {
+            let requests = Arc::clone(&requests);
+            let arrived = Arc::clone(&arrived);
+            move |request: Request| {
+                let requests = Arc::clone(&requests);
+                let arrived = Arc::clone(&arrived);
+                let responder = Arc::clone(&responder);
+                let counter = Arc::clone(&counter);
+                async move {
+                    let (parts, body) = request.into_parts();
+                    let body = axum::body::to_bytes(body, usize::MAX)
+                        .await
+                        .unwrap_or_default();
+                    let recorded = RecordedRequest {
+                        method: parts.method.to_string(),
+                        path: parts.uri.path().to_string(),
+                        headers: parts.headers,
+                        body,
+                        received_at: Instant::now(),
+                    };
+
+                    let idx = counter.fetch_add(1, Ordering::SeqCst);
+                    let reply = responder(&recorded, idx);
+                    requests.lock().unwrap().push(recorded);
+                    arrived.notify_waiters();
+                    tokio::time::sleep(reply.delay).await;
+                    into_response(reply)
+                }
+            }
+        };
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let _ = axum::serve(listener, Router::new().fallback(handler)).await;
+        });
+
+        Self {
+            url,
+            requests,
+            arrived,
+        }
+    }
+
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Wait until at least `count` requests have been recorded.
+    pub async fn wait_for_requests(&self, count: usize) -> Vec<RecordedRequest> {
+        let wait = async {
+            loop {
+                let notified = self.arrived.notified();
+                let current = self.requests();
+                if current.len() >= count {
+                    return current;
+                }
+                notified.await;
+            }
+        };
+
+        tokio::time::timeout(Duration::from_secs(5), wait)
+            .await
+            .unwrap_or_else(|_| panic!("timed out waiting for {count} mock request(s)"))
+    }
+}
+
+fn into_response(reply: MockResponse) -> Response {
+    let status = StatusCode::from_u16(reply.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    let mut response = (status, reply.body).into_response();
+    for (name, value) in reply.headers {
+        if let (Ok(name), Ok(value)) = (
+            axum::http::HeaderName::from_bytes(name.as_bytes()),
+            axum::http::HeaderValue::from_str(&value),
+        ) {
+            response.headers_mut().insert(name, value);
+        }
+    }
+    response
+}
+
+/// A TCP proxy in front of `target` that drops the first `drop_first` connections
+/// without answering. Returns the proxy's base URL.
+pub async fn flaky_proxy(target: &MockServer, drop_first: usize) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    spawn_proxy(listener, proxy_target(target), drop_first);
+    url
+}
+
+/// A TCP proxy in front of `target` that refuses connections until `delay` has
+/// passed. Returns the proxy's base URL.
+pub async fn late_proxy(target: &MockServer, delay: Duration) -> String {
+    let addr = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let target_addr = proxy_target(target);
+    tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+        let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+        spawn_proxy(listener, target_addr, 0);
+    });
+    format!("http://{addr}")
+}
+
+fn proxy_target(target: &MockServer) -> String {
+    target.url.trim_start_matches("http://").to_string()
+}
+
+fn spawn_proxy(listener: tokio::net::TcpListener, target_addr: String, drop_first: usize) {
+    tokio::spawn(async move {
+        let mut accepted = 0;
+        while let Ok((mut inbound, _)) = listener.accept().await {
+            accepted += 1;
+            if accepted <= drop_first {
+                drop(inbound);
+                continue;
+            }
+
+            let target_addr = target_addr.clone();
+            tokio::spawn(async move {
+                if let Ok(mut outbound) = tokio::net::TcpStream::connect(target_addr).await {
+                    let _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await;
+                }
+            });
+        }
+    });
+}
+
+/// A uniquely named directory under the system temp dir, removed on drop.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(label: &str) -> Self {
+        Self::new_in(&std::env::temp_dir(), label)
+    }
+
+    /// A fresh directory inside `parent` instead of the system temp dir.
+    pub fn new_in(parent: &Path, label: &str) -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = parent.join(format!(
+            "mudcode-rs-{label}-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Write `contents` to `relative` inside the directory and return its full path.
+    pub fn write(&self, relative: &str, contents: impl AsRef<[u8]>) -> PathBuf {
+        let path = self.0.join(relative);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).unwrap();
+        }
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}